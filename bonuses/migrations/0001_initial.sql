@@ -0,0 +1,79 @@
+-- Written to also adopt databases created before migrations were tracked,
+-- hence IF NOT EXISTS everywhere and the backfills at the end.
+CREATE TABLE IF NOT EXISTS privilege
+(
+    id       SERIAL PRIMARY KEY,
+    username VARCHAR(80) NOT NULL UNIQUE,
+    status   VARCHAR(80) NOT NULL DEFAULT 'BRONZE'
+        CHECK (status IN ('BRONZE', 'SILVER', 'GOLD')),
+    balance  INT
+);
+
+CREATE TABLE IF NOT EXISTS privilege_history
+(
+    id             SERIAL PRIMARY KEY,
+    privilege_id   INT REFERENCES privilege (id),
+    ticket_uid     uuid        NOT NULL,
+    datetime       TIMESTAMP   NOT NULL,
+    balance_diff   INT         NOT NULL,
+    operation_type VARCHAR(20) NOT NULL
+);
+
+ALTER TABLE privilege_history ADD COLUMN IF NOT EXISTS rule_version VARCHAR(80);
+ALTER TABLE privilege_history DROP CONSTRAINT IF EXISTS privilege_history_operation_type_check;
+ALTER TABLE privilege_history ADD CONSTRAINT privilege_history_operation_type_check
+    CHECK (operation_type IN ('FILL_IN_BALANCE', 'DEBIT_THE_ACCOUNT', 'EXPIRED', 'REFUND',
+                              'MANUAL_CREDIT', 'MANUAL_DEBIT', 'TRANSFER_IN', 'TRANSFER_OUT'));
+ALTER TABLE privilege_history ADD COLUMN IF NOT EXISTS refund_of INT REFERENCES privilege_history (id);
+CREATE UNIQUE INDEX IF NOT EXISTS privilege_history_refund_of_key ON privilege_history (refund_of);
+ALTER TABLE privilege_history ADD COLUMN IF NOT EXISTS reason VARCHAR(80);
+ALTER TABLE privilege_history ADD COLUMN IF NOT EXISTS operator VARCHAR(80);
+ALTER TABLE privilege_history ADD COLUMN IF NOT EXISTS counterparty VARCHAR(80);
+
+CREATE TABLE IF NOT EXISTS privilege_lot
+(
+    id           SERIAL PRIMARY KEY,
+    privilege_id INT REFERENCES privilege (id),
+    ticket_uid   uuid      NOT NULL,
+    datetime     TIMESTAMP NOT NULL,
+    expires      TIMESTAMP NOT NULL,
+    amount       INT       NOT NULL,
+    remaining    INT       NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS ledger_entry
+(
+    id           SERIAL PRIMARY KEY,
+    history_id   INT REFERENCES privilege_history (id),
+    account      VARCHAR(80) NOT NULL,
+    privilege_id INT REFERENCES privilege (id),
+    amount       INT         NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS privilege_purchase
+(
+    id           SERIAL PRIMARY KEY,
+    privilege_id INT REFERENCES privilege (id),
+    ticket_uid   uuid UNIQUE NOT NULL,
+    price        INT       NOT NULL,
+    datetime     TIMESTAMP NOT NULL,
+    refunded     BOOLEAN   NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE IF NOT EXISTS privilege_tier_history
+(
+    id              SERIAL PRIMARY KEY,
+    privilege_id    INT REFERENCES privilege (id),
+    datetime        TIMESTAMP   NOT NULL,
+    previous_status VARCHAR(80) NOT NULL,
+    status          VARCHAR(80) NOT NULL
+);
+
+-- Balances accrued before lots were tracked get a single lot with a fresh lifetime
+INSERT INTO privilege_lot(privilege_id, ticket_uid, datetime, expires, amount, remaining)
+SELECT id, '00000000-0000-0000-0000-000000000000', now(), now() + INTERVAL '365 days', balance, balance
+FROM privilege p
+WHERE balance > 0 AND NOT EXISTS (SELECT 1 FROM privilege_lot l WHERE l.privilege_id = p.id);
+
+-- History written before the ledger existed has no entries yet, the startup check reports it
+-- and `bonuses reconcile --repair` posts it
@@ -0,0 +1,16 @@
+-- Lots every debit drew its points from, so that refunding the debit puts them back
+-- into the same lots with their original expiry
+CREATE TABLE privilege_lot_use
+(
+    id         SERIAL PRIMARY KEY,
+    history_id INT NOT NULL REFERENCES privilege_history (id),
+    lot_id     INT NOT NULL REFERENCES privilege_lot (id),
+    amount     INT NOT NULL
+);
+
+CREATE INDEX privilege_lot_use_history_id_idx ON privilege_lot_use (history_id);
+
+-- Balances adopted from before lots were tracked got a lot without a ticket,
+-- it gets a reference uid of its own like the other operations without one
+UPDATE privilege_lot SET ticket_uid = gen_random_uuid()
+WHERE ticket_uid = '00000000-0000-0000-0000-000000000000';
@@ -0,0 +1,28 @@
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use crate::PrivilegeRepository;
+
+/// Accrued points expire this long after they were credited
+pub const POINTS_LIFETIME_DAYS: i64 = 365;
+pub const EXPIRATION_JOB_PERIOD_SECS: u64 = 60 * 60;
+
+/// Pays what it can of `debt` out of `amount` and returns the rest, which is what a lot
+/// gets of a credit: points that only paid a negative balance off can not expire.
+pub fn pay_debt(debt: &mut i32, amount: i32) -> i32 {
+    let paid = amount.min(*debt);
+    *debt -= paid;
+    amount - paid
+}
+
+/// Periodically writes off the lots that have lapsed.
+pub async fn run_expiration_job(privilege_repository: Arc<Mutex<dyn PrivilegeRepository>>) {
+    loop {
+        let now = chrono::offset::Utc::now().naive_local();
+        match privilege_repository.lock().await.expire_lots(now).await {
+            Ok(0) => {},
+            Ok(count) => tracing::info!(count, "expired bonus lots"),
+            Err(e) => tracing::error!(error = %e, "failed to expire bonus lots")
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(EXPIRATION_JOB_PERIOD_SECS)).await;
+    }
+}
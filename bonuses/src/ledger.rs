@@ -0,0 +1,56 @@
+use std::{error::Error, sync::Arc};
+use tokio::sync::Mutex;
+use structs::ReconciliationReport;
+use crate::PrivilegeRepository;
+
+/// What an operation books: `amount` is added to the user's account and taken from
+/// `counter_account`, so the two ledger entries of an operation always sum to zero
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Posting {
+    pub amount: i32,
+    pub counter_account: &'static str
+}
+
+/// The only place that decides which way an operation moves the balance. `refunded` is the
+/// type of the operation a refund reverses, a refund moves the balance the opposite way.
+pub fn classify(operation_type: &str, balance_diff: i32, refunded: Option<&str>) -> Posting {
+    let amount = match (operation_type, refunded) {
+        ("FILL_IN_BALANCE" | "MANUAL_CREDIT" | "TRANSFER_IN", _) => balance_diff,
+        ("REFUND", Some("DEBIT_THE_ACCOUNT")) => balance_diff,
+        _ => -balance_diff
+    };
+    let counter_account = match operation_type {
+        "FILL_IN_BALANCE" => "system:accrual",
+        "DEBIT_THE_ACCOUNT" => "system:redemption",
+        "EXPIRED" => "system:expiry",
+        "REFUND" => "system:refund",
+        "TRANSFER_IN" | "TRANSFER_OUT" => "system:transfer",
+        _ => "system:adjustment"
+    };
+    Posting { amount, counter_account }
+}
+
+pub fn is_consistent(report: &ReconciliationReport) -> bool {
+    report.mismatches.is_empty() && report.unbalancedOperations.is_empty() && report.unpostedOperations.is_empty()
+}
+
+/// Compares every balance with the one computed from the ledger and logs what does not add up.
+pub async fn check_ledger(privilege_repository: Arc<Mutex<dyn PrivilegeRepository>>,
+                          repair: bool) -> Result<ReconciliationReport, Box<dyn Error>> {
+    let report = privilege_repository.lock().await.reconcile(repair).await?;
+    tracing::info!(accounts = report.checkedAccounts, "checked bonus accounts");
+    for mismatch in &report.mismatches {
+        tracing::error!(username = %mismatch.username, balance = ?mismatch.balance, ledger_balance = mismatch.ledgerBalance,
+                        "balance does not match the ledger");
+    }
+    for operation in &report.unbalancedOperations {
+        tracing::error!(operation, "ledger entries of operation do not sum to zero");
+    }
+    for operation in &report.unpostedOperations {
+        tracing::error!(operation, "operation has no ledger entries");
+    }
+    if repair && !is_consistent(&report) {
+        tracing::info!("balances were recomputed from the ledger");
+    }
+    Ok(report)
+}
@@ -0,0 +1,33 @@
+pub mod expiration;
+pub mod ledger;
+pub mod memory;
+pub mod migrations;
+pub mod repository;
+pub mod rules;
+pub mod seed;
+pub mod server;
+pub mod tiers;
+#[cfg(test)]
+mod test;
+use repository::*;
+
+use custom_error::custom_error;
+
+custom_error!{pub PrivilegeError
+    NotFoundError                            = "Ticket was not found",
+    InvalidRulesError                        = "Bonus rules are invalid",
+    InsufficientBalanceError                 = "Balance is too low",
+    InvalidValueError                        = "Value is not allowed",
+    DuplicateError                           = "Record already exists",
+    TransferLimitError                       = "Daily transfer limit exceeded",
+    InconsistentLedgerError                  = "Balances do not match the ledger, run `bonuses reconcile --repair`",
+}
+
+#[macro_export]
+macro_rules! arc{
+    ($a:expr)=>{
+        {
+            std::sync::Arc::new(tokio::sync::Mutex::new($a))
+        }
+    }
+}
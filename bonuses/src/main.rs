@@ -1,35 +1,70 @@
-mod repository;
-mod server;
-#[cfg(test)]
-mod test;
 use jwtchecker::JWTChecker;
-use repository::*;
-use server::*;
+use bonuses::{arc, expiration, ledger, seed, tiers, PrivilegeError};
+use bonuses::memory::MemoryRepository;
+use bonuses::repository::*;
+use bonuses::rules::RuleSet;
+use bonuses::server::*;
+use structs::RateTable;
 
 use std::error::Error;
+use std::sync::Arc;
+use tokio::sync::Mutex;
 use std::env;
-use custom_error::custom_error;
 
-custom_error!{pub PrivilegeError
-    NotFoundError                            = "Ticket was not found",
-}
-
-#[macro_export]
-macro_rules! arc{
-    ($a:expr)=>{
-        {
-            std::sync::Arc::new(tokio::sync::Mutex::new($a))
-        }
+/// `STORAGE=memory` keeps the data in the process, otherwise it is stored in `PSQL_CONNECTION`
+async fn open_repository() -> Result<Arc<Mutex<dyn PrivilegeRepository>>, Box<dyn Error>> {
+    if env::var("STORAGE").as_deref() == Ok("memory") {
+        return Ok(arc!(MemoryRepository::default()));
     }
+    Ok(arc!(Repository::new(&env::var("PSQL_CONNECTION")?).await?))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>>{
-    let connection_str = env::var("PSQL_CONNECTION")?;
-    let port = env::var("SERVER_PORT")?.parse()?;
-    let repository = arc!(Repository::new(&connection_str).await?);
+    let _telemetry = telemetry::init("bonuses");
+    let args: Vec<String> = env::args().collect();
+    let repository = open_repository().await?;
     repository.lock().await.init().await?;
-    run_server(repository, port, arc!(JWTChecker::new(&env::var("RSA_PUB")?))).await;
+    // `bonuses migrate` only brings the schema up to date
+    if args.get(1).map(String::as_str) == Some("migrate") {
+        return Ok(());
+    }
+    // `bonuses seed <fixtures.json>` opens the accounts of the fixtures and exits
+    if args.get(1).map(String::as_str) == Some("seed") {
+        let path = args.get(2).map(String::as_str).unwrap_or("fixtures.json");
+        seed::seed(repository, seed::Fixtures::load(path)?).await?;
+        return Ok(());
+    }
+    // Lets a service with `STORAGE=memory` start with data
+    if let Ok(path) = env::var("FIXTURES") {
+        seed::seed(repository.clone(), seed::Fixtures::load(&path)?).await?;
+    }
+    // `bonuses reconcile [--repair]` checks the balances against the ledger and exits
+    if args.get(1).map(String::as_str) == Some("reconcile") {
+        let repair = args.iter().any(|x| x == "--repair");
+        let report = ledger::check_ledger(repository, repair).await?;
+        if !repair && !ledger::is_consistent(&report) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    // Serving on top of balances that do not add up would only spread the damage
+    if !ledger::is_consistent(&ledger::check_ledger(repository.clone(), false).await?) {
+        return Err(PrivilegeError::InconsistentLedgerError.into());
+    }
+    let port = env::var("SERVER_PORT")?.parse()?;
+    let rules = match env::var("BONUS_RULES") {
+        Ok(path) => RuleSet::load(&path)?,
+        Err(_) => RuleSet::default()
+    };
+    tracing::info!(version = %rules.version, "using bonus rules");
+    let rates = match env::var("CURRENCY_RATES") {
+        Ok(path) => RateTable::load(&path)?,
+        Err(_) => RateTable::default()
+    };
+    tokio::task::spawn(tiers::run_tier_job(repository.clone()));
+    tokio::task::spawn(expiration::run_expiration_job(repository.clone()));
+    run_server(repository, port, arc!(JWTChecker::new(&env::var("RSA_PUB")?)), arc!(rules), arc!(rates), env::var("EVENTS_SECRET").unwrap_or_default()).await?;
     Ok(())
 
 }
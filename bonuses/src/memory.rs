@@ -0,0 +1,431 @@
+use std::error::Error;
+use async_trait::async_trait;
+use database::Sequence;
+use chrono::{Duration, NaiveDateTime};
+use structs::{BalanceMismatch, PointsLot, Privilege, PrivilegeHistory, PrivilegeHistoryPost, ReconciliationReport, TierHistory};
+use uuid::Uuid;
+use crate::{expiration::{pay_debt, POINTS_LIFETIME_DAYS}, ledger::{classify, Posting}, repository::HistoryFilter, PrivilegeError, PrivilegeRepository};
+
+const STATUSES: [&str; 3] = ["BRONZE", "SILVER", "GOLD"];
+const OPERATION_TYPES: [&str; 8] = ["FILL_IN_BALANCE", "DEBIT_THE_ACCOUNT", "EXPIRED", "REFUND",
+                                    "MANUAL_CREDIT", "MANUAL_DEBIT", "TRANSFER_IN", "TRANSFER_OUT"];
+
+struct Purchase {
+    privilege_id: i32,
+    ticket_uid: Uuid,
+    price: i32,
+    datetime: NaiveDateTime,
+    refunded: bool
+}
+
+struct LotUse {
+    history_id: i32,
+    lot_id: i32,
+    amount: i32
+}
+
+struct LedgerEntry {
+    history_id: i32,
+    account: String,
+    privilege_id: Option<i32>,
+    amount: i32
+}
+
+/// Accounts, their history and ledger for `STORAGE=memory`. Follows `Repository` operation
+/// by operation, the constraints of the schema and the ledger postings included, so the two
+/// can be checked against each other.
+#[derive(Default)]
+pub struct MemoryRepository {
+    privileges: Vec<Privilege>,
+    history: Vec<PrivilegeHistory>,
+    lots: Vec<PointsLot>,
+    lot_uses: Vec<LotUse>,
+    ledger: Vec<LedgerEntry>,
+    purchases: Vec<Purchase>,
+    tiers: Vec<TierHistory>,
+    privilege_ids: Sequence,
+    history_ids: Sequence,
+    lot_ids: Sequence,
+    tier_ids: Sequence
+}
+
+impl MemoryRepository {
+    fn privilege_mut(&mut self, privilege_id: i32) -> Result<&mut Privilege, Box<dyn Error>> {
+        self.privileges.iter_mut()
+            .find(|x| x.id == privilege_id)
+            .ok_or_else(|| PrivilegeError::NotFoundError.into())
+    }
+
+    fn privilege_id(&self, username: &str) -> Result<i32, Box<dyn Error>> {
+        self.privileges.iter()
+            .find(|x| x.username == username)
+            .map(|x| x.id)
+            .ok_or_else(|| PrivilegeError::NotFoundError.into())
+    }
+
+    /// Classifies the operation of a history record
+    fn posting(&self, history_id: i32) -> Option<(i32, Posting)> {
+        let record = self.history.iter().find(|x| x.id == history_id)?;
+        let refunded = record.refund_of
+            .and_then(|id| self.history.iter().find(|x| x.id == id))
+            .map(|x| x.operation_type.as_str());
+        Some((record.privilege_id, classify(&record.operation_type, record.balance_diff, refunded)))
+    }
+
+    fn post(&mut self, history_id: i32) {
+        let Some((privilege_id, posting)) = self.posting(history_id) else {
+            return;
+        };
+        self.ledger.push(LedgerEntry { history_id, account: "privilege".to_owned(), privilege_id: Some(privilege_id), amount: posting.amount });
+        self.ledger.push(LedgerEntry { history_id, account: posting.counter_account.to_owned(), privilege_id: None, amount: -posting.amount });
+    }
+
+    fn insert_history(&mut self, privilege_id: i32, data: &PrivilegeHistoryPost, datetime: NaiveDateTime) -> Result<i32, Box<dyn Error>> {
+        if !OPERATION_TYPES.contains(&data.operation_type.as_str()) {
+            return Err(PrivilegeError::InvalidValueError.into());
+        }
+        if let Some(refund_of) = data.refund_of {
+            if !self.history.iter().any(|x| x.id == refund_of) {
+                return Err(PrivilegeError::NotFoundError.into());
+            }
+            if self.history.iter().any(|x| x.refund_of == Some(refund_of)) {
+                return Err(PrivilegeError::DuplicateError.into());
+            }
+        }
+        let id = self.history_ids.nextval();
+        self.history.push(PrivilegeHistory {
+            id,
+            privilege_id,
+            ticket_uid: data.ticket_uid,
+            datetime,
+            balance_diff: data.balance_diff,
+            operation_type: data.operation_type.clone(),
+            rule_version: data.rule_version.clone(),
+            refund_of: data.refund_of,
+            reason: data.reason.clone(),
+            operator: data.operator.clone(),
+            counterparty: data.counterparty.clone()
+        });
+        self.post(id);
+        Ok(id)
+    }
+
+    /// Same as `apply_operation` of `Repository`. Returns the new balance.
+    fn apply_operation(&mut self, privilege_id: i32, data: &PrivilegeHistoryPost, datetime: NaiveDateTime) -> Result<i32, Box<dyn Error>> {
+        let refunded = match data.refund_of {
+            Some(refund_of) => match self.history.iter().find(|x| x.id == refund_of) {
+                Some(original) => Some(original.operation_type.as_str()),
+                None => return Err(PrivilegeError::NotFoundError.into())
+            },
+            None => None
+        };
+        let posting = classify(&data.operation_type, data.balance_diff, refunded);
+        let credit = posting.amount > 0;
+        let balance = self.privilege_mut(privilege_id)?.balance;
+        // Nothing is written for a debit the lots or the balance can not cover,
+        // unless it takes back the spent points of a refunded ticket
+        let available: i32 = self.lots.iter()
+            .filter(|x| x.privilege_id == privilege_id)
+            .map(|x| x.remaining)
+            .sum();
+        if !credit && data.operation_type != "REFUND" && available.min(balance) < data.balance_diff {
+            return Err(PrivilegeError::InsufficientBalanceError.into());
+        }
+        let history_id = self.insert_history(privilege_id, data, datetime)?;
+        let privilege = self.privilege_mut(privilege_id)?;
+        privilege.balance += posting.amount;
+        let balance = privilege.balance;
+        if credit {
+            let mut left = data.balance_diff;
+            let mut debt = (data.balance_diff - balance.max(0)).max(0);
+            if let (true, Some(refund_of)) = (data.operation_type == "REFUND", data.refund_of) {
+                for used in self.lot_uses.iter().filter(|x| x.history_id == refund_of) {
+                    let restored = used.amount.min(left);
+                    if let Some(lot) = self.lots.iter_mut().find(|x| x.id == used.lot_id) {
+                        lot.remaining += pay_debt(&mut debt, restored);
+                    }
+                    left -= restored;
+                }
+            }
+            if data.operation_type == "TRANSFER_IN" {
+                let sources: Vec<(i32, NaiveDateTime)> = self.history.iter()
+                    .filter(|x| x.operation_type == "TRANSFER_OUT" && x.ticket_uid == data.ticket_uid)
+                    .flat_map(|x| self.lot_uses.iter().filter(move |y| y.history_id == x.id))
+                    .filter_map(|x| self.lots.iter().find(|y| y.id == x.lot_id).map(|y| (x.amount, y.expires)))
+                    .collect();
+                for (amount, expires) in sources {
+                    let amount = amount.min(left);
+                    self.lots.push(PointsLot {
+                        id: self.lot_ids.nextval(),
+                        privilege_id,
+                        ticket_uid: data.ticket_uid,
+                        datetime,
+                        expires,
+                        amount,
+                        remaining: pay_debt(&mut debt, amount)
+                    });
+                    left -= amount;
+                }
+            }
+            if left > 0 {
+                self.lots.push(PointsLot {
+                    id: self.lot_ids.nextval(),
+                    privilege_id,
+                    ticket_uid: data.ticket_uid,
+                    datetime,
+                    expires: datetime + Duration::days(POINTS_LIFETIME_DAYS),
+                    amount: left,
+                    remaining: pay_debt(&mut debt, left)
+                });
+            }
+        }
+        else {
+            let mut lots: Vec<&mut PointsLot> = self.lots.iter_mut()
+                .filter(|x| x.privilege_id == privilege_id && x.remaining > 0)
+                .collect();
+            lots.sort_by_key(|x| (x.ticket_uid != data.ticket_uid, x.expires, x.id));
+            let mut left = data.balance_diff;
+            for lot in lots {
+                if left <= 0 {
+                    break;
+                }
+                let consumed = lot.remaining.min(left);
+                lot.remaining -= consumed;
+                self.lot_uses.push(LotUse { history_id, lot_id: lot.id, amount: consumed });
+                left -= consumed;
+            }
+        }
+        Ok(balance)
+    }
+}
+
+#[async_trait]
+impl PrivilegeRepository for MemoryRepository {
+    async fn init(&mut self) ->  Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+    async fn ping(&mut self) ->  Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+    async fn get_privilege(&mut self, username: String) ->  Result<Privilege, Box<dyn Error>> {
+        self.privileges.iter()
+            .find(|x| x.username == username)
+            .cloned()
+            .ok_or_else(|| PrivilegeError::NotFoundError.into())
+    }
+    async fn get_privilege_by_id(&mut self, id: i32) ->  Result<Privilege, Box<dyn Error>> {
+        self.privileges.iter()
+            .find(|x| x.id == id)
+            .cloned()
+            .ok_or_else(|| PrivilegeError::NotFoundError.into())
+    }
+    async fn create_privilege(&mut self, username: String) ->  Result<Privilege, Box<dyn Error>> {
+        if self.privilege_id(&username).is_err() {
+            self.privileges.push(Privilege {
+                id: self.privilege_ids.nextval(),
+                username: username.clone(),
+                status: "BRONZE".to_owned(),
+                balance: 0
+            });
+        }
+        self.get_privilege(username).await
+    }
+    async fn get_privilege_history(&mut self, username: String) ->  Result<Vec<PrivilegeHistory>, Box<dyn Error>> {
+        let privilege_id = self.privilege_id(&username)?;
+        Ok(self.history.iter().filter(|x| x.privilege_id == privilege_id).cloned().collect())
+    }
+    async fn get_privilege_history_by_ticket(&mut self, ticket_uid: Uuid) ->  Result<Vec<PrivilegeHistory>, Box<dyn Error>> {
+        Ok(self.history.iter().filter(|x| x.ticket_uid == ticket_uid).cloned().collect())
+    }
+    async fn get_privilege_history_page(&mut self, username: String, filter: HistoryFilter, offset: i64, limit: i64) ->  Result<(Vec<PrivilegeHistory>, i64), Box<dyn Error>> {
+        let privilege_id = self.privilege_id(&username)?;
+        let mut list: Vec<PrivilegeHistory> = self.history.iter()
+            .filter(|x| x.privilege_id == privilege_id)
+            .filter(|x| filter.operation_type.as_ref().is_none_or(|y| x.operation_type == *y))
+            .filter(|x| filter.from.is_none_or(|y| x.datetime >= y))
+            .filter(|x| filter.to.is_none_or(|y| x.datetime < y))
+            .filter(|x| filter.ticket_uid.is_none_or(|y| x.ticket_uid == y))
+            .cloned()
+            .collect();
+        list.sort_by_key(|x| std::cmp::Reverse((x.datetime, x.id)));
+        let total = list.len() as i64;
+        let page = list.into_iter().skip(offset.max(0) as usize).take(limit.max(0) as usize).collect();
+        Ok((page, total))
+    }
+    async fn add_history(&mut self, data: PrivilegeHistoryPost) ->  Result<(), Box<dyn Error>> {
+        let privilege_id = self.privilege_id(&data.username)?;
+        let datetime = chrono::offset::Utc::now().naive_local();
+        self.apply_operation(privilege_id, &data, datetime)?;
+        Ok(())
+    }
+    async fn list_privileges(&mut self) ->  Result<Vec<Privilege>, Box<dyn Error>> {
+        Ok(self.privileges.clone())
+    }
+    async fn add_purchase(&mut self, username: String, ticket_uid: Uuid, price: i32) ->  Result<(), Box<dyn Error>> {
+        let privilege_id = self.privilege_id(&username)?;
+        if self.purchases.iter().any(|x| x.ticket_uid == ticket_uid) {
+            return Err(PrivilegeError::DuplicateError.into());
+        }
+        self.purchases.push(Purchase {
+            privilege_id,
+            ticket_uid,
+            price,
+            datetime: chrono::offset::Utc::now().naive_local(),
+            refunded: false
+        });
+        Ok(())
+    }
+    async fn refund_ticket(&mut self, ticket_uid: Uuid, refunds: Vec<PrivilegeHistoryPost>) ->  Result<(), Box<dyn Error>> {
+        let datetime = chrono::offset::Utc::now().naive_local();
+        // Checked before anything is written, as the transaction of `Repository` would roll back whole
+        let mut privilege_ids = vec![];
+        for (i, data) in refunds.iter().enumerate() {
+            privilege_ids.push(self.privilege_id(&data.username)?);
+            if data.operation_type != "REFUND" {
+                return Err(PrivilegeError::InvalidValueError.into());
+            }
+            let Some(refund_of) = data.refund_of else {
+                continue;
+            };
+            if !self.history.iter().any(|x| x.id == refund_of) {
+                return Err(PrivilegeError::NotFoundError.into());
+            }
+            if self.history.iter().any(|x| x.refund_of == Some(refund_of)) || refunds[..i].iter().any(|x| x.refund_of == Some(refund_of)) {
+                return Err(PrivilegeError::DuplicateError.into());
+            }
+        }
+        for (privilege_id, data) in privilege_ids.into_iter().zip(&refunds) {
+            self.apply_operation(privilege_id, data, datetime)?;
+        }
+        for purchase in self.purchases.iter_mut().filter(|x| x.ticket_uid == ticket_uid) {
+            purchase.refunded = true;
+        }
+        Ok(())
+    }
+    async fn get_activity(&mut self, privilege_id: i32, since: NaiveDateTime) ->  Result<(i64, i64), Box<dyn Error>> {
+        let purchases: Vec<&Purchase> = self.purchases.iter()
+            .filter(|x| x.privilege_id == privilege_id && !x.refunded && x.datetime >= since)
+            .collect();
+        Ok((purchases.iter().map(|x| x.price as i64).sum(), purchases.len() as i64))
+    }
+    async fn change_status(&mut self, privilege_id: i32, status: String) ->  Result<TierHistory, Box<dyn Error>> {
+        if !STATUSES.contains(&status.as_str()) {
+            return Err(PrivilegeError::InvalidValueError.into());
+        }
+        let privilege = self.privilege_mut(privilege_id)?;
+        let previous_status = std::mem::replace(&mut privilege.status, status.clone());
+        let tier = TierHistory {
+            id: self.tier_ids.nextval(),
+            privilege_id,
+            datetime: chrono::offset::Utc::now().naive_local(),
+            previous_status,
+            status
+        };
+        self.tiers.push(tier.clone());
+        Ok(tier)
+    }
+    async fn get_tier_history(&mut self, username: String) ->  Result<Vec<TierHistory>, Box<dyn Error>> {
+        let privilege_id = self.privilege_id(&username)?;
+        let mut list: Vec<TierHistory> = self.tiers.iter().filter(|x| x.privilege_id == privilege_id).cloned().collect();
+        list.sort_by_key(|x| x.datetime);
+        Ok(list)
+    }
+    async fn get_expirations(&mut self, username: String) ->  Result<Vec<PointsLot>, Box<dyn Error>> {
+        let privilege_id = self.privilege_id(&username)?;
+        let mut list: Vec<PointsLot> = self.lots.iter()
+            .filter(|x| x.privilege_id == privilege_id && x.remaining > 0)
+            .cloned()
+            .collect();
+        list.sort_by_key(|x| (x.expires, x.id));
+        Ok(list)
+    }
+    async fn expire_lots(&mut self, now: NaiveDateTime) ->  Result<usize, Box<dyn Error>> {
+        let lapsed: Vec<(usize, i32, Uuid, i32)> = self.lots.iter().enumerate()
+            .filter(|(_, x)| x.expires <= now && x.remaining > 0)
+            .map(|(i, x)| (i, x.privilege_id, x.ticket_uid, x.remaining))
+            .collect();
+        for (index, privilege_id, ticket_uid, remaining) in &lapsed {
+            self.lots[*index].remaining = 0;
+            self.insert_history(*privilege_id, &PrivilegeHistoryPost {
+                ticket_uid: *ticket_uid,
+                balance_diff: *remaining,
+                operation_type: "EXPIRED".to_owned(),
+                ..Default::default()
+            }, now)?;
+            self.privilege_mut(*privilege_id)?.balance += classify("EXPIRED", *remaining, None).amount;
+        }
+        Ok(lapsed.len())
+    }
+    async fn transfer(&mut self, sender: String, recipient: String, amount: i32, max_daily_amount: i32, operation_uid: Uuid) ->  Result<i32, Box<dyn Error>> {
+        let sender_id = self.privilege_id(&sender)?;
+        let recipient_id = self.privilege_id(&recipient)?;
+        let datetime = chrono::offset::Utc::now().naive_local();
+        if self.privilege_mut(sender_id)?.balance < amount {
+            return Err(PrivilegeError::InsufficientBalanceError.into());
+        }
+        let transferred: i64 = self.history.iter()
+            .filter(|x| x.privilege_id == sender_id && x.operation_type == "TRANSFER_OUT" && x.datetime >= datetime - Duration::days(1))
+            .map(|x| x.balance_diff as i64)
+            .sum();
+        if transferred + amount as i64 > max_daily_amount as i64 {
+            return Err(PrivilegeError::TransferLimitError.into());
+        }
+        let balance = self.apply_operation(sender_id, &PrivilegeHistoryPost {
+            username: sender.clone(),
+            ticket_uid: operation_uid,
+            balance_diff: amount,
+            operation_type: "TRANSFER_OUT".to_owned(),
+            counterparty: Some(recipient.clone()),
+            ..Default::default()
+        }, datetime)?;
+        self.apply_operation(recipient_id, &PrivilegeHistoryPost {
+            username: recipient,
+            ticket_uid: operation_uid,
+            balance_diff: amount,
+            operation_type: "TRANSFER_IN".to_owned(),
+            counterparty: Some(sender),
+            ..Default::default()
+        }, datetime)?;
+        Ok(balance)
+    }
+    async fn reconcile(&mut self, repair: bool) ->  Result<ReconciliationReport, Box<dyn Error>> {
+        let unposted_operations: Vec<i32> = self.history.iter()
+            .filter(|x| !self.ledger.iter().any(|y| y.history_id == x.id))
+            .map(|x| x.id)
+            .collect();
+        if repair {
+            for id in &unposted_operations {
+                self.post(*id);
+            }
+        }
+        let mut unbalanced_operations: Vec<i32> = self.ledger.iter().map(|x| x.history_id).collect();
+        unbalanced_operations.sort();
+        unbalanced_operations.dedup();
+        unbalanced_operations.retain(|id| self.ledger.iter().filter(|x| x.history_id == *id).map(|x| x.amount).sum::<i32>() != 0);
+        let mismatches: Vec<BalanceMismatch> = self.privileges.iter()
+            .map(|x| BalanceMismatch {
+                username: x.username.clone(),
+                balance: Some(x.balance),
+                ledgerBalance: self.ledger.iter()
+                    .filter(|y| y.account == "privilege" && y.privilege_id == Some(x.id))
+                    .map(|y| y.amount)
+                    .sum()
+            })
+            .filter(|x| x.balance != Some(x.ledgerBalance))
+            .collect();
+        if repair {
+            for mismatch in &mismatches {
+                if let Some(privilege) = self.privileges.iter_mut().find(|x| x.username == mismatch.username) {
+                    privilege.balance = mismatch.ledgerBalance;
+                }
+            }
+        }
+        Ok(ReconciliationReport {
+            checkedAccounts: self.privileges.len(),
+            mismatches,
+            unbalancedOperations: unbalanced_operations,
+            unpostedOperations: unposted_operations,
+            repaired: repair
+        })
+    }
+}
@@ -0,0 +1,7 @@
+use database::Migration;
+
+/// Schema of the service, newest last. Applied migrations must never be edited, changes go into a new file.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "initial", sql: include_str!("../migrations/0001_initial.sql") },
+    Migration { version: 2, name: "lot_uses", sql: include_str!("../migrations/0002_lot_uses.sql") },
+];
@@ -1,66 +1,201 @@
 use std::error::Error;
 use async_trait::async_trait;
-use tokio_postgres::{Client, NoTls};
-use crate::PrivilegeError;
-use structs::{Privilege,  PrivilegeHistory, PrivilegeHistoryPost};
+use database::Database;
+use crate::migrations::MIGRATIONS;
+use tokio_postgres::{error::SqlState, types::ToSql, Row, Transaction};
+use crate::{expiration::{pay_debt, POINTS_LIFETIME_DAYS}, ledger::{classify, Posting}, PrivilegeError};
+use chrono::{Duration, NaiveDateTime};
+use structs::{BalanceMismatch, PointsLot, Privilege,  PrivilegeHistory, PrivilegeHistoryPost, ReconciliationReport, TierHistory};
 use uuid::Uuid;
 
+#[derive(Debug, Clone, Default)]
+pub struct HistoryFilter {
+    pub operation_type: Option<String>,
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    pub ticket_uid: Option<Uuid>
+}
+
 #[async_trait]
 pub trait PrivilegeRepository: Sync + Send {
     async fn init(&mut self) ->  Result<(), Box<dyn Error>>;
+    async fn ping(&mut self) ->  Result<(), Box<dyn Error>>;
     async fn get_privilege(&mut self, username: String) ->  Result<Privilege, Box<dyn Error>>;
+    async fn get_privilege_by_id(&mut self, id: i32) ->  Result<Privilege, Box<dyn Error>>;
+    async fn create_privilege(&mut self, username: String) ->  Result<Privilege, Box<dyn Error>>;
     async fn get_privilege_history(&mut self, username: String) ->  Result<Vec<PrivilegeHistory>, Box<dyn Error>>;
     async fn get_privilege_history_by_ticket(&mut self, ticket_uid: Uuid) ->  Result<Vec<PrivilegeHistory>, Box<dyn Error>>;
+    async fn get_privilege_history_page(&mut self, username: String, filter: HistoryFilter, offset: i64, limit: i64) ->  Result<(Vec<PrivilegeHistory>, i64), Box<dyn Error>>;
     async fn add_history(&mut self, data: PrivilegeHistoryPost) ->  Result<(), Box<dyn Error>>;
-    async fn update_balance(&mut self, username: String, difference: i32) ->  Result<(), Box<dyn Error>>;
+    async fn list_privileges(&mut self) ->  Result<Vec<Privilege>, Box<dyn Error>>;
+    async fn add_purchase(&mut self, username: String, ticket_uid: Uuid, price: i32) ->  Result<(), Box<dyn Error>>;
+    async fn refund_ticket(&mut self, ticket_uid: Uuid, refunds: Vec<PrivilegeHistoryPost>) ->  Result<(), Box<dyn Error>>;
+    async fn get_activity(&mut self, privilege_id: i32, since: NaiveDateTime) ->  Result<(i64, i64), Box<dyn Error>>;
+    async fn change_status(&mut self, privilege_id: i32, status: String) ->  Result<TierHistory, Box<dyn Error>>;
+    async fn get_tier_history(&mut self, username: String) ->  Result<Vec<TierHistory>, Box<dyn Error>>;
+    async fn get_expirations(&mut self, username: String) ->  Result<Vec<PointsLot>, Box<dyn Error>>;
+    async fn expire_lots(&mut self, now: NaiveDateTime) ->  Result<usize, Box<dyn Error>>;
+    async fn transfer(&mut self, sender: String, recipient: String, amount: i32, max_daily_amount: i32, operation_uid: Uuid) ->  Result<i32, Box<dyn Error>>;
+    async fn reconcile(&mut self, repair: bool) ->  Result<ReconciliationReport, Box<dyn Error>>;
+}
+
+/// Writes the two ledger entries of a history record
+async fn post(transaction: &Transaction<'_>, history_id: i32, privilege_id: i32, posting: Posting) -> Result<(), Box<dyn Error>> {
+    transaction.execute("
+        INSERT INTO ledger_entry(history_id, account, privilege_id, amount) VALUES
+            ($1, 'privilege', $2, $3),
+            ($1, $4, NULL, $5)
+    ", &[&history_id, &privilege_id, &posting.amount, &posting.counter_account, &-posting.amount]).await?;
+    Ok(())
+}
+
+fn history_from_row(row: &Row) -> PrivilegeHistory {
+    PrivilegeHistory {
+        id: row.get(0),
+        privilege_id: row.get(1),
+        ticket_uid: row.get(2),
+        datetime: row.get(3),
+        balance_diff: row.get(4),
+        operation_type: row.get(5),
+        rule_version: row.get(6),
+        refund_of: row.get(7),
+        reason: row.get(8),
+        operator: row.get(9),
+        counterparty: row.get(10)
+    }
+}
+
+/// Writes the history record and moves the balance and the lots accordingly. Returns the new balance.
+async fn apply_operation(transaction: &Transaction<'_>,
+                         privilege_id: i32,
+                         data: &PrivilegeHistoryPost,
+                         datetime: NaiveDateTime) -> Result<i32, Box<dyn Error>> {
+    let refunded: Option<String> = match data.refund_of {
+        Some(refund_of) => Some(transaction.query_one("
+            SELECT operation_type FROM privilege_history WHERE id = $1
+        ", &[&refund_of]).await?.get(0)),
+        None => None
+    };
+    let posting = classify(&data.operation_type, data.balance_diff, refunded.as_deref());
+    let credit = posting.amount > 0;
+    let history_id: i32 = match transaction.query_one("
+        INSERT INTO privilege_history(privilege_id, ticket_uid, datetime, balance_diff, operation_type, rule_version, refund_of, reason, operator, counterparty) VALUES
+            ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        RETURNING id
+    ", &[&privilege_id, &data.ticket_uid, &datetime, &data.balance_diff, &data.operation_type, &data.rule_version, &data.refund_of,
+         &data.reason, &data.operator, &data.counterparty]).await {
+        Ok(row) => row.get(0),
+        // A concurrent refund of the same operation got in first
+        Err(e) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => return Err(PrivilegeError::DuplicateError.into()),
+        Err(e) => return Err(e.into())
+    };
+    post(transaction, history_id, privilege_id, posting).await?;
+    let balance: i32 = transaction.query_one("
+        UPDATE privilege SET balance = balance + $1 WHERE id = $2 RETURNING balance
+    ", &[&posting.amount, &privilege_id]).await?.get(0);
+    if credit {
+        let mut left = data.balance_diff;
+        // While the balance is negative the credit pays the debt off first and only the rest goes into lots
+        let mut debt = (data.balance_diff - balance.max(0)).max(0);
+        // A refunded debit puts the points back into the lots they were taken from,
+        // so they keep the expiry they had instead of starting a new lifetime
+        if let (true, Some(refund_of)) = (data.operation_type == "REFUND", data.refund_of) {
+            for row in transaction.query("
+                SELECT lot_id, amount FROM privilege_lot_use WHERE history_id = $1 ORDER BY id
+            ", &[&refund_of]).await? {
+                let restored = row.get::<_, i32>("amount").min(left);
+                transaction.execute("
+                    UPDATE privilege_lot SET remaining = remaining + $1 WHERE id = $2
+                ", &[&pay_debt(&mut debt, restored), &row.get::<_, i32>("lot_id")]).await?;
+                left -= restored;
+            }
+        }
+        // Transferred points keep the expiry of the sender's lots they were taken from
+        if data.operation_type == "TRANSFER_IN" {
+            for row in transaction.query("
+                SELECT u.amount, l.expires FROM privilege_history h
+                JOIN privilege_lot_use u ON u.history_id = h.id
+                JOIN privilege_lot l ON l.id = u.lot_id
+                WHERE h.operation_type = 'TRANSFER_OUT' AND h.ticket_uid = $1
+                ORDER BY u.id
+            ", &[&data.ticket_uid]).await? {
+                let amount = row.get::<_, i32>("amount").min(left);
+                let expires: NaiveDateTime = row.get("expires");
+                transaction.execute("
+                    INSERT INTO privilege_lot(privilege_id, ticket_uid, datetime, expires, amount, remaining) VALUES
+                        ($1, $2, $3, $4, $5, $6)
+                ", &[&privilege_id, &data.ticket_uid, &datetime, &expires, &amount, &pay_debt(&mut debt, amount)]).await?;
+                left -= amount;
+            }
+        }
+        if left > 0 {
+            let remaining = pay_debt(&mut debt, left);
+            transaction.execute("
+                INSERT INTO privilege_lot(privilege_id, ticket_uid, datetime, expires, amount, remaining) VALUES
+                    ($1, $2, $3, $4, $5, $6)
+            ", &[&privilege_id, &data.ticket_uid, &datetime, &(datetime + Duration::days(POINTS_LIFETIME_DAYS)), &left, &remaining]).await?;
+        }
+    }
+    else {
+        // Debits consume the lots of the same ticket and then the ones that expire first
+        let mut left = data.balance_diff;
+        for row in transaction.query("
+            SELECT id, remaining FROM privilege_lot
+            WHERE privilege_id = $1 AND remaining > 0
+            ORDER BY ticket_uid = $2 DESC, expires, id
+            FOR UPDATE
+        ", &[&privilege_id, &data.ticket_uid]).await? {
+            if left <= 0 {
+                break;
+            }
+            let id: i32 = row.get(0);
+            let remaining: i32 = row.get(1);
+            let consumed = remaining.min(left);
+            transaction.execute("
+                UPDATE privilege_lot SET remaining = remaining - $1 WHERE id = $2
+            ", &[&consumed, &id]).await?;
+            transaction.execute("
+                INSERT INTO privilege_lot_use(history_id, lot_id, amount) VALUES ($1, $2, $3)
+            ", &[&history_id, &id, &consumed]).await?;
+            left -= consumed;
+        }
+        // A debit is never applied in part and never takes the balance below zero,
+        // dropping the transaction rolls it back whole. Only taking back the points of a refunded
+        // ticket that were already spent goes through, leaving a debt the next credits pay off.
+        if data.operation_type != "REFUND" && (left > 0 || balance < 0) {
+            return Err(PrivilegeError::InsufficientBalanceError.into());
+        }
+    }
+    Ok(balance)
 }
 
 pub struct Repository {
-    client: Client
+    db: Database
 }
 
 impl Repository {
     pub async fn new(connection_str: &str) -> Result<Self,  Box<dyn Error>> {
-        let (client, connection) = tokio_postgres::connect(connection_str, NoTls).await?;
-        tokio::spawn(async move {
-            connection.await
-        });
-        Ok(Self { 
-            client
+        Ok(Self {
+            db: Database::connect(connection_str).await?
         })
     }
 }
 
 #[async_trait]
 impl PrivilegeRepository for Repository {
+    #[tracing::instrument(skip_all)]
+    async fn ping(&mut self) ->  Result<(), Box<dyn Error>> {
+        self.db.client().await?.simple_query("SELECT 1").await?;
+        Ok(())
+    }
+    #[tracing::instrument(skip_all)]
     async fn init(&mut self) ->  Result<(), Box<dyn Error>> {
-        self.client.execute("
-            CREATE TABLE IF NOT EXISTS privilege
-            (
-                id       SERIAL PRIMARY KEY,
-                username VARCHAR(80) NOT NULL UNIQUE,
-                status   VARCHAR(80) NOT NULL DEFAULT 'BRONZE'
-                    CHECK (status IN ('BRONZE', 'SILVER', 'GOLD')),
-                balance  INT
-            );
-        ", &[]).await?;
-        self.client.execute("
-            CREATE TABLE IF NOT EXISTS privilege_history
-            (
-                id             SERIAL PRIMARY KEY,
-                privilege_id   INT REFERENCES privilege (id),
-                ticket_uid     uuid        NOT NULL,
-                datetime       TIMESTAMP   NOT NULL,
-                balance_diff   INT         NOT NULL,
-                operation_type VARCHAR(20) NOT NULL
-                    CHECK (operation_type IN ('FILL_IN_BALANCE', 'DEBIT_THE_ACCOUNT'))
-            );
-        ", &[]).await?;
+        self.db.migrate(MIGRATIONS).await?;
         Ok(())
     }
+    #[tracing::instrument(skip_all)]
     async fn get_privilege(&mut self, username: String) ->  Result<Privilege, Box<dyn Error>> {
-        println!("searching for '{}'", username);
-        for row in self.client.query(&format!("
+        for row in self.db.client().await?.query(&format!("
         SELECT * FROM privilege WHERE username = '{}'
         ", username), &[]).await? {
             return Ok(Privilege {
@@ -70,65 +205,331 @@ impl PrivilegeRepository for Repository {
                 balance: row.get(3)
             })
         }
-        println!("Couldnt find any");
         Err(PrivilegeError::NotFoundError.into())
     }
+    #[tracing::instrument(skip_all)]
+    async fn get_privilege_by_id(&mut self, id: i32) ->  Result<Privilege, Box<dyn Error>> {
+        let Some(row) = self.db.client().await?.query_opt("
+            SELECT id, username, status, balance FROM privilege WHERE id = $1
+        ", &[&id]).await? else {
+            return Err(PrivilegeError::NotFoundError.into());
+        };
+        Ok(Privilege {
+            id: row.get("id"),
+            username: row.get("username"),
+            status: row.get("status"),
+            balance: row.get("balance")
+        })
+    }
+    #[tracing::instrument(skip_all)]
+    async fn create_privilege(&mut self, username: String) ->  Result<Privilege, Box<dyn Error>> {
+        // Concurrent first requests race on the unique username, the loser keeps the winner's row
+        self.db.client().await?.execute("
+            INSERT INTO privilege(username, status, balance) VALUES
+                ($1, 'BRONZE', 0)
+            ON CONFLICT (username) DO NOTHING
+        ", &[&username]).await?;
+        self.get_privilege(username).await
+    }
+    #[tracing::instrument(skip_all)]
     async fn get_privilege_history(&mut self, username: String) ->  Result<Vec<PrivilegeHistory>, Box<dyn Error>> {
         let privilege_id = self.get_privilege(username).await?.id;
         let mut list = vec![];
-        for row in self.client.query(&format!("
+        for row in self.db.client().await?.query(&format!("
             SELECT * FROM privilege_history WHERE privilege_id = {}
         ", privilege_id), &[]).await? {
-            list.push(PrivilegeHistory {
-                id: row.get(0),
-                privilege_id: row.get(1),
-                ticket_uid: row.get(2),
-                datetime: row.get(3),
-                balance_diff: row.get(4),
-                operation_type: row.get(5)
-            })
+            list.push(history_from_row(&row))
         }
         Ok(list)
     }
+    #[tracing::instrument(skip_all)]
     async fn get_privilege_history_by_ticket(&mut self, ticket_uid: Uuid) ->  Result<Vec<PrivilegeHistory>, Box<dyn Error>> {
         let mut list = vec![];
-        for row in self.client.query(&format!("
+        for row in self.db.client().await?.query(&format!("
             SELECT * FROM privilege_history WHERE ticket_uid = '{}'
         ", ticket_uid), &[]).await? {
-            list.push(PrivilegeHistory {
+            list.push(history_from_row(&row))
+        }
+        Ok(list)
+    }
+    #[tracing::instrument(skip_all)]
+    async fn get_privilege_history_page(&mut self, username: String, filter: HistoryFilter, offset: i64, limit: i64) ->  Result<(Vec<PrivilegeHistory>, i64), Box<dyn Error>> {
+        let privilege_id = self.get_privilege(username).await?.id;
+        let mut conditions = vec!["privilege_id = $1".to_owned()];
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![&privilege_id];
+        if let Some(operation_type) = &filter.operation_type {
+            params.push(operation_type);
+            conditions.push(format!("operation_type = ${}", params.len()));
+        }
+        if let Some(from) = &filter.from {
+            params.push(from);
+            conditions.push(format!("datetime >= ${}", params.len()));
+        }
+        if let Some(to) = &filter.to {
+            params.push(to);
+            conditions.push(format!("datetime < ${}", params.len()));
+        }
+        if let Some(ticket_uid) = &filter.ticket_uid {
+            params.push(ticket_uid);
+            conditions.push(format!("ticket_uid = ${}", params.len()));
+        }
+        let condition = conditions.join(" AND ");
+        let total: i64 = self.db.client().await?.query_one(&format!("
+            SELECT COUNT(*) FROM privilege_history WHERE {}
+        ", condition), &params).await?.get(0);
+        params.push(&limit);
+        params.push(&offset);
+        let mut list = vec![];
+        for row in self.db.client().await?.query(&format!("
+            SELECT * FROM privilege_history WHERE {}
+            ORDER BY datetime DESC, id DESC
+            LIMIT ${} OFFSET ${}
+        ", condition, params.len() - 1, params.len()), &params).await? {
+            list.push(history_from_row(&row));
+        }
+        Ok((list, total))
+    }
+    #[tracing::instrument(skip_all)]
+    async fn add_history(&mut self, data: PrivilegeHistoryPost) ->  Result<(), Box<dyn Error>> {
+        let privilege_id = self.get_privilege(data.username.clone()).await?.id;
+        let datetime = chrono::offset::Utc::now().naive_local();
+        let transaction = self.db.client().await?.transaction().await?;
+        apply_operation(&transaction, privilege_id, &data, datetime).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+    #[tracing::instrument(skip_all)]
+    async fn list_privileges(&mut self) ->  Result<Vec<Privilege>, Box<dyn Error>> {
+        let mut list = vec![];
+        for row in self.db.client().await?.query("
+            SELECT * FROM privilege
+        ", &[]).await? {
+            list.push(Privilege {
+                id: row.get(0),
+                username: row.get(1),
+                status: row.get(2),
+                balance: row.get(3)
+            })
+        }
+        Ok(list)
+    }
+    #[tracing::instrument(skip_all)]
+    async fn add_purchase(&mut self, username: String, ticket_uid: Uuid, price: i32) ->  Result<(), Box<dyn Error>> {
+        let privilege_id = self.get_privilege(username).await?.id;
+        let datetime = chrono::offset::Utc::now().naive_local();
+        self.db.client().await?.execute("
+            INSERT INTO privilege_purchase(privilege_id, ticket_uid, price, datetime) VALUES
+                ($1, $2, $3, $4)
+        ", &[&privilege_id, &ticket_uid, &price, &datetime]).await?;
+        Ok(())
+    }
+    #[tracing::instrument(skip_all)]
+    async fn refund_ticket(&mut self, ticket_uid: Uuid, refunds: Vec<PrivilegeHistoryPost>) ->  Result<(), Box<dyn Error>> {
+        let mut privilege_ids = vec![];
+        for data in &refunds {
+            privilege_ids.push(self.get_privilege(data.username.clone()).await?.id);
+        }
+        let datetime = chrono::offset::Utc::now().naive_local();
+        // The ticket is refunded whole or not at all, so a failed refund can be retried
+        let transaction = self.db.client().await?.transaction().await?;
+        for (privilege_id, data) in privilege_ids.into_iter().zip(&refunds) {
+            apply_operation(&transaction, privilege_id, data, datetime).await?;
+        }
+        transaction.execute("
+            UPDATE privilege_purchase SET refunded = TRUE WHERE ticket_uid = $1
+        ", &[&ticket_uid]).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+    #[tracing::instrument(skip_all)]
+    async fn get_activity(&mut self, privilege_id: i32, since: NaiveDateTime) ->  Result<(i64, i64), Box<dyn Error>> {
+        let row = self.db.client().await?.query_one("
+            SELECT COALESCE(SUM(price), 0), COUNT(*) FROM privilege_purchase
+            WHERE privilege_id = $1 AND NOT refunded AND datetime >= $2
+        ", &[&privilege_id, &since]).await?;
+        Ok((row.get(0), row.get(1)))
+    }
+    #[tracing::instrument(skip_all)]
+    async fn change_status(&mut self, privilege_id: i32, status: String) ->  Result<TierHistory, Box<dyn Error>> {
+        let datetime = chrono::offset::Utc::now().naive_local();
+        let transaction = self.db.client().await?.transaction().await?;
+        let previous_status: String = transaction.query_one("
+            SELECT status FROM privilege WHERE id = $1 FOR UPDATE
+        ", &[&privilege_id]).await?.get(0);
+        transaction.execute("
+            UPDATE privilege SET status = $1 WHERE id = $2
+        ", &[&status, &privilege_id]).await?;
+        let id: i32 = transaction.query_one("
+            INSERT INTO privilege_tier_history(privilege_id, datetime, previous_status, status) VALUES
+                ($1, $2, $3, $4)
+            RETURNING id
+        ", &[&privilege_id, &datetime, &previous_status, &status]).await?.get(0);
+        transaction.commit().await?;
+        Ok(TierHistory {
+            id,
+            privilege_id,
+            datetime,
+            previous_status,
+            status
+        })
+    }
+    #[tracing::instrument(skip_all)]
+    async fn get_tier_history(&mut self, username: String) ->  Result<Vec<TierHistory>, Box<dyn Error>> {
+        let privilege_id = self.get_privilege(username).await?.id;
+        let mut list = vec![];
+        for row in self.db.client().await?.query("
+            SELECT * FROM privilege_tier_history WHERE privilege_id = $1 ORDER BY datetime
+        ", &[&privilege_id]).await? {
+            list.push(TierHistory {
+                id: row.get(0),
+                privilege_id: row.get(1),
+                datetime: row.get(2),
+                previous_status: row.get(3),
+                status: row.get(4)
+            })
+        }
+        Ok(list)
+    }
+    #[tracing::instrument(skip_all)]
+    async fn get_expirations(&mut self, username: String) ->  Result<Vec<PointsLot>, Box<dyn Error>> {
+        let privilege_id = self.get_privilege(username).await?.id;
+        let mut list = vec![];
+        for row in self.db.client().await?.query("
+            SELECT * FROM privilege_lot WHERE privilege_id = $1 AND remaining > 0 ORDER BY expires, id
+        ", &[&privilege_id]).await? {
+            list.push(PointsLot {
                 id: row.get(0),
                 privilege_id: row.get(1),
                 ticket_uid: row.get(2),
                 datetime: row.get(3),
-                balance_diff: row.get(4),
-                operation_type: row.get(5)
+                expires: row.get(4),
+                amount: row.get(5),
+                remaining: row.get(6)
             })
         }
         Ok(list)
     }
-    async fn add_history(&mut self, data: PrivilegeHistoryPost) ->  Result<(), Box<dyn Error>> {
-        let privilege_id = self.get_privilege(data.username.clone()).await?.id;
+    #[tracing::instrument(skip_all)]
+    async fn expire_lots(&mut self, now: NaiveDateTime) ->  Result<usize, Box<dyn Error>> {
+        let transaction = self.db.client().await?.transaction().await?;
+        let rows = transaction.query("
+            SELECT id, privilege_id, ticket_uid, remaining FROM privilege_lot
+            WHERE expires <= $1 AND remaining > 0
+            ORDER BY id
+            FOR UPDATE
+        ", &[&now]).await?;
+        for row in &rows {
+            let id: i32 = row.get(0);
+            let privilege_id: i32 = row.get(1);
+            let ticket_uid: Uuid = row.get(2);
+            let remaining: i32 = row.get(3);
+            transaction.execute("
+                UPDATE privilege_lot SET remaining = 0 WHERE id = $1
+            ", &[&id]).await?;
+            let history_id: i32 = transaction.query_one("
+                INSERT INTO privilege_history(privilege_id, ticket_uid, datetime, balance_diff, operation_type) VALUES
+                    ($1, $2, $3, $4, 'EXPIRED')
+                RETURNING id
+            ", &[&privilege_id, &ticket_uid, &now, &remaining]).await?.get(0);
+            let posting = classify("EXPIRED", remaining, None);
+            post(&transaction, history_id, privilege_id, posting).await?;
+            transaction.execute("
+                UPDATE privilege SET balance = balance + $1 WHERE id = $2
+            ", &[&posting.amount, &privilege_id]).await?;
+        }
+        transaction.commit().await?;
+        Ok(rows.len())
+    }
+    #[tracing::instrument(skip_all)]
+    async fn transfer(&mut self, sender: String, recipient: String, amount: i32, max_daily_amount: i32, operation_uid: Uuid) ->  Result<i32, Box<dyn Error>> {
+        let sender_id = self.get_privilege(sender.clone()).await?.id;
+        let recipient_id = self.get_privilege(recipient.clone()).await?.id;
         let datetime = chrono::offset::Utc::now().naive_local();
-        self.client.batch_execute(&format!("
-            INSERT INTO privilege_history(privilege_id, ticket_uid, datetime, balance_diff, operation_type) VALUES
-                ('{}', '{}', '{}', {}, '{}')
-        ", privilege_id, data.ticket_uid, datetime, data.balance_diff, data.operation_type)).await?;
-        if data.operation_type == "FILL_IN_BALANCE" {
-            self.update_balance(data.username, data.balance_diff).await?;
+        let transaction = self.db.client().await?.transaction().await?;
+        // Both rows are locked in the same order by every transfer so they cannot deadlock
+        transaction.execute("
+            SELECT id FROM privilege WHERE id = $1 OR id = $2 ORDER BY id FOR UPDATE
+        ", &[&sender_id, &recipient_id]).await?;
+        let balance: i32 = transaction.query_one("
+            SELECT balance FROM privilege WHERE id = $1
+        ", &[&sender_id]).await?.get(0);
+        if balance < amount {
+            return Err(PrivilegeError::InsufficientBalanceError.into());
         }
-        else {
-            self.update_balance(data.username, -data.balance_diff).await?;
+        // The sender's row is locked, so concurrent transfers see each other's amounts here
+        let transferred: i64 = transaction.query_one("
+            SELECT COALESCE(SUM(balance_diff), 0) FROM privilege_history
+            WHERE privilege_id = $1 AND operation_type = 'TRANSFER_OUT' AND datetime >= $2
+        ", &[&sender_id, &(datetime - Duration::days(1))]).await?.get(0);
+        if transferred + amount as i64 > max_daily_amount as i64 {
+            return Err(PrivilegeError::TransferLimitError.into());
         }
-        Ok(())
+        let balance = apply_operation(&transaction, sender_id, &PrivilegeHistoryPost {
+            username: sender.clone(),
+            ticket_uid: operation_uid,
+            balance_diff: amount,
+            operation_type: "TRANSFER_OUT".to_owned(),
+            counterparty: Some(recipient.clone()),
+            ..Default::default()
+        }, datetime).await?;
+        apply_operation(&transaction, recipient_id, &PrivilegeHistoryPost {
+            username: recipient,
+            ticket_uid: operation_uid,
+            balance_diff: amount,
+            operation_type: "TRANSFER_IN".to_owned(),
+            counterparty: Some(sender),
+            ..Default::default()
+        }, datetime).await?;
+        transaction.commit().await?;
+        Ok(balance)
     }
-    async fn update_balance(&mut self, username: String, difference: i32) ->  Result<(), Box<dyn Error>> {
-        let balance = self.get_privilege(username.clone()).await?.balance;
-        let new_balance = (balance + difference).max(0);
-        self.client.batch_execute(&format!("
-            UPDATE privilege SET
-                balance = {}
-            WHERE username = '{}'
-        ", new_balance, username)).await?;
-        Ok(())
+    #[tracing::instrument(skip_all)]
+    async fn reconcile(&mut self, repair: bool) ->  Result<ReconciliationReport, Box<dyn Error>> {
+        let transaction = self.db.client().await?.transaction().await?;
+        let unposted = transaction.query("
+            SELECT h.id, h.privilege_id, h.operation_type, h.balance_diff, o.operation_type AS refunded
+            FROM privilege_history h LEFT JOIN privilege_history o ON o.id = h.refund_of
+            WHERE NOT EXISTS (SELECT 1 FROM ledger_entry l WHERE l.history_id = h.id)
+            ORDER BY h.id
+        ", &[]).await?;
+        if repair {
+            for row in &unposted {
+                let posting = classify(row.get("operation_type"), row.get("balance_diff"), row.get("refunded"));
+                post(&transaction, row.get("id"), row.get("privilege_id"), posting).await?;
+            }
+        }
+        let unposted_operations = unposted.iter().map(|x| x.get("id")).collect();
+        let unbalanced_operations = transaction.query("
+            SELECT history_id FROM ledger_entry GROUP BY history_id HAVING SUM(amount) <> 0 ORDER BY history_id
+        ", &[]).await?.iter().map(|x| x.get(0)).collect();
+        let checked_accounts: i64 = transaction.query_one("
+            SELECT COUNT(*) FROM privilege
+        ", &[]).await?.get(0);
+        let mismatches: Vec<BalanceMismatch> = transaction.query("
+            SELECT p.username, p.balance, COALESCE(SUM(l.amount), 0)::INT FROM privilege p
+            LEFT JOIN ledger_entry l ON l.privilege_id = p.id
+            GROUP BY p.id
+            HAVING p.balance IS DISTINCT FROM COALESCE(SUM(l.amount), 0)
+            ORDER BY p.id
+        ", &[]).await?.iter().map(|x| BalanceMismatch {
+            username: x.get(0),
+            balance: x.get(1),
+            ledgerBalance: x.get(2)
+        }).collect();
+        if repair {
+            for mismatch in &mismatches {
+                transaction.execute("
+                    UPDATE privilege SET balance = $1 WHERE username = $2
+                ", &[&mismatch.ledgerBalance, &mismatch.username]).await?;
+            }
+        }
+        transaction.commit().await?;
+        Ok(ReconciliationReport {
+            checkedAccounts: checked_accounts as usize,
+            mismatches,
+            unbalancedOperations: unbalanced_operations,
+            unpostedOperations: unposted_operations,
+            repaired: repair
+        })
     }
 }
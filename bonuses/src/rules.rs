@@ -0,0 +1,132 @@
+use std::{collections::HashMap, error::Error, fs};
+use chrono::NaiveDate;
+use serde::{Serialize, Deserialize};
+use structs::Money;
+use crate::PrivilegeError;
+
+/// Multiplies the accrual for purchases on a route and/or inside a date window.
+/// Fields left empty match anything.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Promotion {
+    pub name: String,
+    #[serde(default)]
+    pub from_airport: Option<String>,
+    #[serde(default)]
+    pub to_airport: Option<String>,
+    #[serde(default)]
+    pub starts: Option<NaiveDate>,
+    #[serde(default)]
+    pub ends: Option<NaiveDate>,
+    pub multiplier: f64
+}
+
+impl Promotion {
+    fn applies(&self, from_airport: Option<&str>, to_airport: Option<&str>, date: NaiveDate) -> bool {
+        let airport_matches = |rule: &Option<String>, airport: Option<&str>| match rule {
+            Some(rule) => airport.is_some_and(|x| x.eq_ignore_ascii_case(rule)),
+            None => true
+        };
+        airport_matches(&self.from_airport, from_airport)
+            && airport_matches(&self.to_airport, to_airport)
+            && self.starts.is_none_or(|x| date >= x)
+            && self.ends.is_none_or(|x| date <= x)
+    }
+}
+
+/// Limits on moving points between accounts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferLimits {
+    pub min_amount: i32,
+    /// Total a user may send within a day
+    pub max_daily_amount: i32
+}
+
+impl Default for TransferLimits {
+    fn default() -> Self {
+        TransferLimits {
+            min_amount: 100,
+            max_daily_amount: 10_000
+        }
+    }
+}
+
+/// Bonus accrual and redemption rules. The version is written to every history
+/// record so that past operations can be traced to the rules that produced them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleSet {
+    pub version: String,
+    /// Percent of the fare credited to the balance, by tier
+    pub accrual_percent: HashMap<String, f64>,
+    /// Largest percent of a fare that may be paid with bonuses
+    pub max_bonus_share_percent: f64,
+    /// Smallest amount of bonuses that may be redeemed in one purchase
+    pub min_redeem: i32,
+    #[serde(default)]
+    pub promotions: Vec<Promotion>,
+    #[serde(default)]
+    pub transfers: TransferLimits
+}
+
+/// Rules shipped with the service, so that running without `BONUS_RULES` accrues the same
+const SHIPPED_RULES: &str = include_str!("../rules.json");
+
+impl Default for RuleSet {
+    fn default() -> Self {
+        RuleSet::parse(SHIPPED_RULES).expect("shipped rules.json is valid")
+    }
+}
+
+impl RuleSet {
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(rules: &str) -> Result<Self, Box<dyn Error>> {
+        let rules: RuleSet = serde_json::from_str(rules)?;
+        if rules.version.is_empty()
+            || !(0.0..=100.0).contains(&rules.max_bonus_share_percent)
+            || rules.min_redeem < 0
+            || rules.accrual_percent.values().any(|x| *x < 0.0)
+            || rules.promotions.iter().any(|x| x.multiplier < 0.0)
+            || rules.transfers.min_amount <= 0
+            || rules.transfers.max_daily_amount < rules.transfers.min_amount {
+            return Err(PrivilegeError::InvalidRulesError.into());
+        }
+        Ok(rules)
+    }
+
+    /// Bonuses credited for a purchase paid with money, one point per whole unit
+    /// of the base currency. Percents and multipliers are taken to basis points and
+    /// the product is rounded down once. When several promotions match only the
+    /// largest multiplier is applied.
+    pub fn accrual(&self,
+                   status: &str,
+                   price: &Money,
+                   from_airport: Option<&str>,
+                   to_airport: Option<&str>,
+                   date: NaiveDate) -> i32 {
+        let percent = basis_points(self.accrual_percent.get(status).copied().unwrap_or(0.0));
+        let multiplier = self.promotions.iter()
+            .filter(|x| x.applies(from_airport, to_airport, date))
+            .map(|x| basis_points(x.multiplier * 100.0))
+            .fold(10_000, i64::max);
+        let amount = price.amount as i128 * percent as i128 * multiplier as i128 / 100_000_000;
+        Money::new(amount as i64, &price.currency).major() as i32
+    }
+
+    /// Part of the fare covered by the balance, zero if it falls below the minimum.
+    /// The fare is expected in the base currency.
+    pub fn redeemable(&self, balance: i32, price: &Money) -> i32 {
+        let max_share = price.share(basis_points(self.max_bonus_share_percent)).major() as i32;
+        let amount = balance.min(max_share).max(0);
+        if amount < self.min_redeem {
+            return 0;
+        }
+        amount
+    }
+}
+
+/// Percent as a whole number of basis points
+fn basis_points(percent: f64) -> i64 {
+    (percent * 100.0).round() as i64
+}
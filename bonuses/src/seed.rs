@@ -0,0 +1,70 @@
+use std::{error::Error, fs, sync::Arc};
+use serde::Deserialize;
+use structs::PrivilegeHistoryPost;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+use crate::{PrivilegeError, PrivilegeRepository};
+
+fn default_status() -> String {
+    "BRONZE".to_owned()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PrivilegeFixture {
+    pub username: String,
+    #[serde(default = "default_status")]
+    pub status: String,
+    #[serde(default)]
+    pub balance: i32
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Fixtures {
+    #[serde(default)]
+    pub privileges: Vec<PrivilegeFixture>
+}
+
+impl Fixtures {
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+/// Opens the accounts of the fixtures and credits their starting balance as a manual
+/// operation, so it is in the history and the ledger like any other. Accounts with history
+/// are left alone, which makes seeding again a no-op, while an account an interrupted seed
+/// opened without its credit is completed.
+pub async fn seed(repository: Arc<Mutex<dyn PrivilegeRepository>>, fixtures: Fixtures) -> Result<(), Box<dyn Error>> {
+    let mut seeded = 0;
+    for fixture in fixtures.privileges {
+        let stored = repository.lock().await.get_privilege(fixture.username.clone()).await;
+        let privilege = match stored {
+            Ok(privilege) => privilege,
+            Err(e) if matches!(e.downcast_ref::<PrivilegeError>(), Some(PrivilegeError::NotFoundError)) => {
+                repository.lock().await.create_privilege(fixture.username.clone()).await?
+            },
+            Err(e) => return Err(e)
+        };
+        if !repository.lock().await.get_privilege_history(fixture.username.clone()).await?.is_empty() {
+            continue;
+        }
+        if fixture.status != privilege.status {
+            repository.lock().await.change_status(privilege.id, fixture.status).await?;
+        }
+        // Last, as the credit marks the account as seeded
+        if fixture.balance > 0 {
+            repository.lock().await.add_history(PrivilegeHistoryPost {
+                username: fixture.username.clone(),
+                ticket_uid: Uuid::new_v4(),
+                balance_diff: fixture.balance,
+                operation_type: "MANUAL_CREDIT".to_owned(),
+                reason: Some("SEED".to_owned()),
+                operator: Some("seed".to_owned()),
+                ..Default::default()
+            }).await?;
+        }
+        seeded += 1;
+    }
+    tracing::info!(seeded, "seeded fixtures");
+    Ok(())
+}
@@ -1,24 +1,63 @@
-use std::{convert::Infallible, sync::Arc};
-use jwtchecker::JWTChecker;
+use std::{convert::Infallible, error::Error, sync::Arc};
+use jwtchecker::{service_token_matches, JWTChecker, JWTError, SERVICE_TOKEN_HEADER};
 use tokio::sync::Mutex;
 use uuid::Uuid;
 use warp::{reply::{self, Reply}, Filter, Rejection};
 use serde::{Serialize, Deserialize};
-use crate::PrivilegeRepository;
-use structs::{PrivilegeGet, PrivilegeHistory, PrivilegeHistoryGet, PurchasePost, PurchaseResponse, PrivilegeHistoryPost};
+use crate::{ledger::check_ledger, repository::HistoryFilter, rules::RuleSet, tiers::evaluate_tier, PrivilegeError, PrivilegeRepository};
+use structs::{AdjustmentPost, Money, RateTable, Balance, PrivilegeHistoryPage, PrivilegeHistoryQuery, BalanceOperationResponse, ExpirationGet, PointsLot, Privilege, PrivilegeGet, TransferPost, PrivilegeHistory, PrivilegeHistoryGet, PurchasePost, PurchaseResponse, PrivilegeHistoryPost, TicketRefundEvent, TierChangeGet, TierHistory};
 
 pub type WebResult<T> = std::result::Result<T, Rejection>;
 
+/// Realm role of the support staff allowed to adjust balances
+const ADMIN_ROLE: &str = "bonuses-admin";
+const ADJUSTMENT_REASONS: [&str; 4] = ["GOODWILL", "COMPENSATION", "CORRECTION", "FRAUD"];
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Serialize, Deserialize)]
+struct PrivilegeQuery {
+    #[serde(default)]
+    summary: bool
+}
+
 fn privilege_history_to_web(history: &PrivilegeHistory) -> PrivilegeHistoryGet {
     PrivilegeHistoryGet { 
         date: history.datetime.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
         ticketUid: history.ticket_uid,
         balanceDiff: history.balance_diff,
-        operationType: history.operation_type.clone()
+        operationType: history.operation_type.clone(),
+        ruleVersion: history.rule_version.clone()
     }
 }
 
+fn tier_history_to_web(history: &TierHistory) -> TierChangeGet {
+    TierChangeGet {
+        date: history.datetime.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+        previousStatus: history.previous_status.clone(),
+        status: history.status.clone()
+    }
+}
+
+fn lot_to_expiration(lot: &PointsLot) -> ExpirationGet {
+    ExpirationGet {
+        date: lot.expires.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+        ticketUid: lot.ticket_uid,
+        amount: lot.remaining
+    }
+}
+
+/// Users coming from the identity provider get a BRONZE account the first time they show up
+async fn ensure_privilege(username: String,
+                          privilege_repository: Arc<Mutex<dyn PrivilegeRepository>>) -> Result<Privilege, Box<dyn Error>> {
+    let mut repository = privilege_repository.lock().await;
+    if let Ok(privilege) = repository.get_privilege(username.clone()).await {
+        return Ok(privilege);
+    }
+    repository.create_privilege(username).await
+}
+
 async fn get_handler(auth_token: String,
+                     query: PrivilegeQuery,
                      privilege_repository: Arc<Mutex<dyn PrivilegeRepository>>,
                      checker: Arc<Mutex<JWTChecker>>) -> WebResult<Box<dyn Reply>> {
     let jwtchecker = checker.lock().await.clone();
@@ -26,64 +65,147 @@ async fn get_handler(auth_token: String,
         return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
     };
 
-    println!("{}", username);
-
-    let Ok(privilege) = privilege_repository.lock().await.get_privilege(username.clone()).await else {
+    let Ok(privilege) = ensure_privilege(username.clone(), privilege_repository.clone()).await else {
         let reply = warp::reply::with_status("Could not find user", warp::http::StatusCode::NOT_FOUND);
         return Ok(Box::new(reply));
     };
-    let Ok(privilege_history) = privilege_repository.lock().await.get_privilege_history(username).await else {
+    if query.summary {
+        return Ok(Box::new(reply::json(&Balance {
+            balance: privilege.balance,
+            status: privilege.status
+        })));
+    }
+    let Ok(privilege_history) = privilege_repository.lock().await.get_privilege_history(username.clone()).await else {
         let reply = warp::reply::with_status("Could not find history", warp::http::StatusCode::NOT_FOUND);
         return Ok(Box::new(reply));
     };
+    let Ok(tier_history) = privilege_repository.lock().await.get_tier_history(username.clone()).await else {
+        let reply = warp::reply::with_status("Could not find history", warp::http::StatusCode::NOT_FOUND);
+        return Ok(Box::new(reply));
+    };
+    let Ok(expirations) = privilege_repository.lock().await.get_expirations(username).await else {
+        let reply = warp::reply::with_status("Could not find expirations", warp::http::StatusCode::NOT_FOUND);
+        return Ok(Box::new(reply));
+    };
     let web_privilege_history = privilege_history.iter().map(|x| privilege_history_to_web(x)).collect();
     return Ok(Box::new(reply::json(&PrivilegeGet {
         balance: privilege.balance,
         status: privilege.status,
-        history: web_privilege_history
+        history: web_privilege_history,
+        tierHistory: tier_history.iter().map(tier_history_to_web).collect(),
+        upcomingExpirations: expirations.iter().map(lot_to_expiration).collect()
     })));
 }
 
+async fn history_handler(auth_token: String,
+                         query: PrivilegeHistoryQuery,
+                         privilege_repository: Arc<Mutex<dyn PrivilegeRepository>>,
+                         checker: Arc<Mutex<JWTChecker>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = checker.lock().await.clone();
+    let Ok(username) = jwtchecker.decode_header(&auth_token) else {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
+    };
+
+    let page = query.page.unwrap_or(1);
+    let size = query.size.unwrap_or(10);
+    if page == 0 || size == 0 || size > MAX_PAGE_SIZE {
+        return Ok(Box::new(warp::reply::with_status("Invalid paging", warp::http::StatusCode::BAD_REQUEST)));
+    }
+    // Both ends of the date range are inclusive
+    let filter = HistoryFilter {
+        operation_type: query.operationType,
+        from: query.from.and_then(|x| x.and_hms_opt(0, 0, 0)),
+        to: query.to.and_then(|x| x.succ_opt()).and_then(|x| x.and_hms_opt(0, 0, 0)),
+        ticket_uid: query.ticketUid
+    };
+    if ensure_privilege(username.clone(), privilege_repository.clone()).await.is_err() {
+        return Ok(Box::new(warp::reply::with_status("Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
+    }
+    let Ok((history, total)) = privilege_repository.lock().await.get_privilege_history_page(
+        username,
+        filter,
+        ((page - 1) * size) as i64,
+        size as i64).await else {
+        return Ok(Box::new(warp::reply::with_status("Could not find history", warp::http::StatusCode::NOT_FOUND)));
+    };
+    Ok(Box::new(reply::json(&PrivilegeHistoryPage {
+        page,
+        pageSize: size,
+        totalElements: total as usize,
+        items: history.iter().map(privilege_history_to_web).collect()
+    })))
+}
+
 async fn purchase_handler(auth_token: String,
                           body: PurchasePost,
                           privilege_repository: Arc<Mutex<dyn PrivilegeRepository>>,
-                          checker: Arc<Mutex<JWTChecker>>) -> WebResult<Box<dyn Reply>> {
+                          checker: Arc<Mutex<JWTChecker>>,
+                          rules: Arc<Mutex<RuleSet>>,
+                          rates: Arc<Mutex<RateTable>>) -> WebResult<Box<dyn Reply>> {
     let jwtchecker = checker.lock().await.clone();
     let Ok(username) = jwtchecker.decode_header(&auth_token) else {
         return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
     };
 
+    let rules = rules.lock().await.clone();
+    let rates = rates.lock().await.clone();
+    // Points are counted in the base currency whatever the fare was priced in
+    let Ok(base_price) = rates.to_base(&body.price) else {
+        return Ok(Box::new(warp::reply::with_status("Unsupported currency", warp::http::StatusCode::BAD_REQUEST)))
+    };
+    let Ok(privilege) = ensure_privilege(username.clone(), privilege_repository.clone()).await else {
+        let reply = warp::reply::with_status("Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+        return Ok(Box::new(reply));
+    };
     let mut paid_by_bonuses = 0;
     let paid_by_money;
     if body.paid_from_balance {
-        let Ok(privilege) = privilege_repository.lock().await.get_privilege(username.clone()).await else {
-            let reply = warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
-            return Ok(Box::new(reply));
+        paid_by_bonuses = rules.redeemable(privilege.balance, &base_price);
+        let Ok(covered) = rates.convert(&Money::from_major(paid_by_bonuses as i64, &rates.base), &body.price.currency) else {
+            return Ok(Box::new(warp::reply::with_status("Unsupported currency", warp::http::StatusCode::BAD_REQUEST)))
         };
-        paid_by_bonuses = privilege.balance.min(body.price);
-        paid_by_money = body.price - paid_by_bonuses;
+        paid_by_money = Money::new((body.price.amount - covered.amount).max(0), &body.price.currency);
         let Ok(_) = privilege_repository.lock().await.add_history(PrivilegeHistoryPost {
             username: username.clone(),
             ticket_uid: body.ticket_uid,
             balance_diff: paid_by_bonuses,
-            operation_type: "DEBIT_THE_ACCOUNT".to_string()
+            operation_type: "DEBIT_THE_ACCOUNT".to_string(),
+            rule_version: Some(rules.version.clone()),
+            ..Default::default()
         }).await else {
             let reply = warp::reply::with_status("Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
             return Ok(Box::new(reply));
         };
+        telemetry::count_bonus_points(0, paid_by_bonuses);
     }
     else {
-        paid_by_money = body.price;
+        paid_by_money = body.price.clone();
+        let accrual = rules.accrual(&privilege.status,
+                                    &base_price,
+                                    body.from_airport.as_deref(),
+                                    body.to_airport.as_deref(),
+                                    chrono::offset::Utc::now().date_naive());
         let Ok(_) = privilege_repository.lock().await.add_history(PrivilegeHistoryPost {
             username: username.clone(),
             ticket_uid: body.ticket_uid,
-            balance_diff: (body.price as f64 * 0.1) as i32,
-            operation_type: "FILL_IN_BALANCE".to_string()
+            balance_diff: accrual,
+            operation_type: "FILL_IN_BALANCE".to_string(),
+            rule_version: Some(rules.version.clone()),
+            ..Default::default()
         }).await else {
             let reply = warp::reply::with_status("Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
             return Ok(Box::new(reply));
         };
+        telemetry::count_bonus_points(accrual, 0);
     }
+    let Ok(_) = privilege_repository.lock().await.add_purchase(username.clone(), body.ticket_uid, base_price.major() as i32).await else {
+        let reply = warp::reply::with_status("Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+        return Ok(Box::new(reply));
+    };
+    let Ok(tier_change) = evaluate_tier(username.clone(), privilege_repository.clone()).await else {
+        let reply = warp::reply::with_status("Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+        return Ok(Box::new(reply));
+    };
     let Ok(privilege) = privilege_repository.lock().await.get_privilege(username.clone()).await else {
         let reply = warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
         return Ok(Box::new(reply));
@@ -94,7 +216,8 @@ async fn purchase_handler(auth_token: String,
         paid_by_money,
         paid_by_bonuses,
         balance: privilege.balance,
-        status: privilege.status
+        status: privilege.status,
+        tier_change: tier_change.as_ref().map(tier_history_to_web)
     });
     return Ok(Box::new(reply));
 }
@@ -104,6 +227,60 @@ struct RefundQuery {
     ticket_uid: Uuid,
 }
 
+async fn refund_ticket(username: String,
+                       ticket_uid: Uuid,
+                       privilege_repository: Arc<Mutex<dyn PrivilegeRepository>>) -> Result<(), warp::http::StatusCode> {
+    let Ok(privilege) = privilege_repository.lock().await.get_privilege(username.clone()).await else {
+        return Err(warp::http::StatusCode::NOT_FOUND);
+    };
+    let Ok(operations) = privilege_repository.lock().await.get_privilege_history_by_ticket(ticket_uid).await else {
+        return Err(warp::http::StatusCode::NOT_FOUND);
+    };
+    let operations: Vec<PrivilegeHistory> = operations.into_iter()
+        .filter(|x| x.privilege_id == privilege.id)
+        .collect();
+    if operations.iter().any(|x| x.operation_type == "REFUND") {
+        return Err(warp::http::StatusCode::CONFLICT);
+    }
+    let purchases: Vec<&PrivilegeHistory> = operations.iter()
+        .filter(|x| x.operation_type == "FILL_IN_BALANCE" || x.operation_type == "DEBIT_THE_ACCOUNT")
+        .collect();
+    if purchases.is_empty() {
+        return Err(warp::http::StatusCode::NOT_FOUND);
+    }
+    // Points of this ticket that already expired are gone and are not taken back twice
+    let expired: i32 = operations.iter()
+        .filter(|x| x.operation_type == "EXPIRED")
+        .map(|x| x.balance_diff)
+        .sum();
+    let refunds = purchases.into_iter().map(|operation| PrivilegeHistoryPost {
+        username: username.clone(),
+        ticket_uid,
+        balance_diff: if operation.operation_type == "FILL_IN_BALANCE" {
+            (operation.balance_diff - expired).max(0)
+        }
+        else {
+            operation.balance_diff
+        },
+        operation_type: "REFUND".to_string(),
+        rule_version: operation.rule_version.clone(),
+        refund_of: Some(operation.id),
+        ..Default::default()
+    }).collect();
+    match privilege_repository.lock().await.refund_ticket(ticket_uid, refunds).await {
+        Ok(_) => {},
+        // A concurrent refund of the same ticket got in first
+        Err(e) if matches!(e.downcast_ref::<PrivilegeError>(), Some(PrivilegeError::DuplicateError)) => {
+            return Err(warp::http::StatusCode::CONFLICT);
+        },
+        Err(_) => return Err(warp::http::StatusCode::INTERNAL_SERVER_ERROR)
+    }
+    let Ok(_) = evaluate_tier(username, privilege_repository).await else {
+        return Err(warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    Ok(())
+}
+
 async fn refund_handler(auth_token: String,
                         ticket_uid: RefundQuery,
                         privilege_repository: Arc<Mutex<dyn PrivilegeRepository>>,
@@ -113,107 +290,264 @@ async fn refund_handler(auth_token: String,
         return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
     };
 
-    let Ok(privilege) = privilege_repository.lock().await.get_privilege(username.clone()).await else {
-        let reply = warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
-        return Ok(Box::new(reply));
+    if ensure_privilege(username.clone(), privilege_repository.clone()).await.is_err() {
+        return Ok(Box::new(warp::reply::with_status("Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
+    }
+    if let Err(code) = refund_ticket(username, ticket_uid.ticket_uid, privilege_repository).await {
+        return Ok(Box::new(warp::reply::with_status("Error", code)));
+    }
+    let reply = warp::reply::with_status("Ticket refunded", warp::http::StatusCode::NO_CONTENT);
+    return Ok(Box::new(reply));
+}
+
+async fn refund_event_handler(service_token: Option<String>,
+                              event: TicketRefundEvent,
+                              privilege_repository: Arc<Mutex<dyn PrivilegeRepository>>,
+                              events_secret: Arc<Mutex<String>>) -> WebResult<Box<dyn Reply>> {
+    let secret = events_secret.lock().await.clone();
+    if !service_token_matches(&secret, service_token.as_deref()) {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)));
+    }
+    // The owner is whoever paid for the ticket, the event only names the ticket
+    let Ok(operations) = privilege_repository.lock().await.get_privilege_history_by_ticket(event.ticket_uid).await else {
+        return Ok(Box::new(warp::reply::with_status("Encountered an error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
     };
-    let Ok(operations) = privilege_repository.lock().await.get_privilege_history_by_ticket(ticket_uid.ticket_uid).await else {
-        let reply = warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
-        return Ok(Box::new(reply));
+    let Some(purchase) = operations.iter()
+        .find(|x| x.operation_type == "FILL_IN_BALANCE" || x.operation_type == "DEBIT_THE_ACCOUNT") else {
+        return Ok(Box::new(warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND)));
     };
-    for operation in operations {
-        if operation.privilege_id == privilege.id {
-            if operation.operation_type == "DEBIT_THE_ACCOUNT" {
-                let Ok(_) = privilege_repository.lock().await.add_history(PrivilegeHistoryPost {
-                    username: username.clone(),
-                    ticket_uid: ticket_uid.ticket_uid,
-                    balance_diff: operation.balance_diff,
-                    operation_type: "FILL_IN_BALANCE".to_string()
-                }).await else {
-                    let reply = warp::reply::with_status("Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
-                    return Ok(Box::new(reply));
-                };
-            }
-            else {
-                let Ok(_) = privilege_repository.lock().await.add_history(PrivilegeHistoryPost {
-                    username: username.clone(),
-                    ticket_uid: ticket_uid.ticket_uid,
-                    balance_diff: operation.balance_diff,
-                    operation_type: "DEBIT_THE_ACCOUNT".to_string()
-                }).await else {
-                    let reply = warp::reply::with_status("Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
-                    return Ok(Box::new(reply));
-                };
-            }
-        }
-        else {
-            let reply = warp::reply::with_status("Error", warp::http::StatusCode::FORBIDDEN);
-            return Ok(Box::new(reply));
-        }
+    let Ok(privilege) = privilege_repository.lock().await.get_privilege_by_id(purchase.privilege_id).await else {
+        return Ok(Box::new(warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND)));
+    };
+    if let Err(code) = refund_ticket(privilege.username, event.ticket_uid, privilege_repository).await {
+        return Ok(Box::new(warp::reply::with_status("Error", code)));
     }
+    Ok(Box::new(warp::reply::with_status("Ticket refunded", warp::http::StatusCode::NO_CONTENT)))
+}
 
-    let reply = warp::reply::with_status("Ticket refunded", warp::http::StatusCode::NO_CONTENT);
-    return Ok(Box::new(reply));
+fn decode_admin(jwtchecker: &JWTChecker, auth_token: &str) -> Result<String, warp::http::StatusCode> {
+    match jwtchecker.decode_header_with_role(auth_token, ADMIN_ROLE) {
+        Ok(val) => Ok(val),
+        Err(JWTError::MissingRole) => Err(warp::http::StatusCode::FORBIDDEN),
+        Err(_) => Err(warp::http::StatusCode::UNAUTHORIZED)
+    }
 }
 
-async fn health_check_handler() -> WebResult<impl Reply> {
-    return Ok(warp::reply::with_status("Up and running", warp::http::StatusCode::OK))
+async fn adjustment_handler(auth_token: String,
+                            body: AdjustmentPost,
+                            privilege_repository: Arc<Mutex<dyn PrivilegeRepository>>,
+                            checker: Arc<Mutex<JWTChecker>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = checker.lock().await.clone();
+    let operator = match decode_admin(&jwtchecker, &auth_token) {
+        Ok(val) => val,
+        Err(code) => return Ok(Box::new(warp::reply::with_status("Not authorized", code)))
+    };
+
+    if body.amount == 0 || !ADJUSTMENT_REASONS.contains(&body.reason.as_str()) {
+        return Ok(Box::new(warp::reply::with_status("Invalid adjustment", warp::http::StatusCode::BAD_REQUEST)));
+    }
+    if privilege_repository.lock().await.get_privilege(body.username.clone()).await.is_err() {
+        return Ok(Box::new(warp::reply::with_status("Could not find user", warp::http::StatusCode::NOT_FOUND)));
+    }
+    // Operations without a ticket get a reference uid of their own
+    let operation_uid = Uuid::new_v4();
+    let operation_type = if body.amount > 0 { "MANUAL_CREDIT" } else { "MANUAL_DEBIT" };
+    match privilege_repository.lock().await.add_history(PrivilegeHistoryPost {
+        username: body.username.clone(),
+        ticket_uid: operation_uid,
+        balance_diff: body.amount.abs(),
+        operation_type: operation_type.to_string(),
+        reason: Some(body.reason),
+        operator: Some(operator),
+        ..Default::default()
+    }).await {
+        Ok(_) => {},
+        Err(e) if matches!(e.downcast_ref::<PrivilegeError>(), Some(PrivilegeError::InsufficientBalanceError)) => {
+            return Ok(Box::new(warp::reply::with_status("Balance is too low", warp::http::StatusCode::UNPROCESSABLE_ENTITY)));
+        },
+        Err(_) => return Ok(Box::new(warp::reply::with_status("Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)))
+    }
+    let Ok(privilege) = privilege_repository.lock().await.get_privilege(body.username).await else {
+        return Ok(Box::new(warp::reply::with_status("Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
+    };
+    Ok(Box::new(reply::json(&BalanceOperationResponse {
+        operationUid: operation_uid,
+        balance: privilege.balance
+    })))
+}
+
+async fn reconciliation_handler(auth_token: String,
+                                repair: bool,
+                                privilege_repository: Arc<Mutex<dyn PrivilegeRepository>>,
+                                checker: Arc<Mutex<JWTChecker>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = checker.lock().await.clone();
+    if let Err(code) = decode_admin(&jwtchecker, &auth_token) {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", code)));
+    }
+
+    let Ok(report) = check_ledger(privilege_repository, repair).await else {
+        return Ok(Box::new(warp::reply::with_status("Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
+    };
+    Ok(Box::new(reply::json(&report)))
+}
+
+async fn transfer_handler(auth_token: String,
+                          body: TransferPost,
+                          privilege_repository: Arc<Mutex<dyn PrivilegeRepository>>,
+                          checker: Arc<Mutex<JWTChecker>>,
+                          rules: Arc<Mutex<RuleSet>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = checker.lock().await.clone();
+    let Ok(username) = jwtchecker.decode_header(&auth_token) else {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
+    };
+
+    let limits = rules.lock().await.transfers.clone();
+    if body.recipient == username || body.amount < limits.min_amount {
+        return Ok(Box::new(warp::reply::with_status("Invalid transfer", warp::http::StatusCode::BAD_REQUEST)));
+    }
+    if privilege_repository.lock().await.get_privilege(body.recipient.clone()).await.is_err() {
+        return Ok(Box::new(warp::reply::with_status("Could not find recipient", warp::http::StatusCode::NOT_FOUND)));
+    }
+    if ensure_privilege(username.clone(), privilege_repository.clone()).await.is_err() {
+        return Ok(Box::new(warp::reply::with_status("Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
+    }
+    let operation_uid = Uuid::new_v4();
+    // The balance and the daily limit are checked in the transfer's own transaction
+    let balance = match privilege_repository.lock().await.transfer(username, body.recipient, body.amount, limits.max_daily_amount, operation_uid).await {
+        Ok(balance) => balance,
+        Err(e) => match e.downcast_ref::<PrivilegeError>() {
+            Some(PrivilegeError::InsufficientBalanceError) => {
+                return Ok(Box::new(warp::reply::with_status("Balance is too low", warp::http::StatusCode::UNPROCESSABLE_ENTITY)));
+            },
+            Some(PrivilegeError::TransferLimitError) => {
+                return Ok(Box::new(warp::reply::with_status("Daily transfer limit exceeded", warp::http::StatusCode::UNPROCESSABLE_ENTITY)));
+            },
+            _ => return Ok(Box::new(warp::reply::with_status("Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)))
+        }
+    };
+    Ok(Box::new(reply::json(&BalanceOperationResponse {
+        operationUid: operation_uid,
+        balance
+    })))
+}
+
+async fn health_check_handler(repository: Arc<Mutex<dyn PrivilegeRepository>>) -> WebResult<impl Reply> {
+    let database = telemetry::check(async { repository.lock().await.ping().await }).await;
+    Ok(telemetry::Readiness::new(vec![("database", database)]).reply())
 }
 
 fn with_arc<T: Send + ?Sized>(arc: Arc<Mutex<T>>) -> impl Filter<Extract = (Arc<Mutex<T>>,), Error = Infallible> + Clone {
     warp::any().map(move || arc.clone())
 }
 
-pub fn router(repository: Arc<Mutex<dyn PrivilegeRepository>>, checker: Arc<Mutex<jwtchecker::JWTChecker>>) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    let log = warp::log::custom(|info| {
-        eprintln!(
-            "{} {} {}",
-            info.method(),
-            info.path(),
-            info.status(),
-        );
-        for header in info.request_headers() {
-            eprintln!(
-                "   {} {:?}",
-                header.0,
-                header.1
-            );
-        }
-    });
+pub fn router(repository: Arc<Mutex<dyn PrivilegeRepository>>,
+              checker: Arc<Mutex<jwtchecker::JWTChecker>>,
+              rules: Arc<Mutex<RuleSet>>,
+              rates: Arc<Mutex<RateTable>>,
+              events_secret: String) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     let get_route = warp::path!("privilege")
+        .and(telemetry::route("/privilege"))
         .and(warp::get())
         .and(warp::header("Authorization"))
+        .and(warp::query::<PrivilegeQuery>())
         .and(with_arc(repository.clone()))
         .and(with_arc(checker.clone()))
         .and_then(get_handler);
+    let history_route = warp::path!("privilege" / "history")
+        .and(telemetry::route("/privilege/history"))
+        .and(warp::get())
+        .and(warp::header("Authorization"))
+        .and(warp::query::<PrivilegeHistoryQuery>())
+        .and(with_arc(repository.clone()))
+        .and(with_arc(checker.clone()))
+        .and_then(history_handler);
     let purchase_route = warp::path!("privilege")
+        .and(telemetry::route("/privilege"))
         .and(warp::post())
         .and(warp::header("Authorization"))
         .and(warp::body::json())
         .and(with_arc(repository.clone()))
         .and(with_arc(checker.clone()))
+        .and(with_arc(rules.clone()))
+        .and(with_arc(rates.clone()))
         .and_then(purchase_handler);
     let refund_route = warp::path!("privilege")
+        .and(telemetry::route("/privilege"))
         .and(warp::delete())
         .and(warp::header("Authorization"))
         .and(warp::query::<RefundQuery>())
         .and(with_arc(repository.clone()))
         .and(with_arc(checker.clone()))
         .and_then(refund_handler);
+    let adjustment_route = warp::path!("privilege" / "adjustments")
+        .and(telemetry::route("/privilege/adjustments"))
+        .and(warp::post())
+        .and(warp::header("Authorization"))
+        .and(warp::body::json())
+        .and(with_arc(repository.clone()))
+        .and(with_arc(checker.clone()))
+        .and_then(adjustment_handler);
+    let reconciliation_route = warp::path!("privilege" / "reconciliation")
+        .and(telemetry::route("/privilege/reconciliation"))
+        .and(warp::get())
+        .and(warp::header("Authorization"))
+        .and(warp::any().map(|| false))
+        .and(with_arc(repository.clone()))
+        .and(with_arc(checker.clone()))
+        .and_then(reconciliation_handler);
+    let repair_route = warp::path!("privilege" / "reconciliation")
+        .and(telemetry::route("/privilege/reconciliation"))
+        .and(warp::post())
+        .and(warp::header("Authorization"))
+        .and(warp::any().map(|| true))
+        .and(with_arc(repository.clone()))
+        .and(with_arc(checker.clone()))
+        .and_then(reconciliation_handler);
+    let transfer_route = warp::path!("privilege" / "transfers")
+        .and(telemetry::route("/privilege/transfers"))
+        .and(warp::post())
+        .and(warp::header("Authorization"))
+        .and(warp::body::json())
+        .and(with_arc(repository.clone()))
+        .and(with_arc(checker.clone()))
+        .and(with_arc(rules.clone()))
+        .and_then(transfer_handler);
+    let refund_event_route = warp::path!("events" / "refunds")
+        .and(telemetry::route("/events/refunds"))
+        .and(warp::post())
+        .and(warp::header::optional::<String>(SERVICE_TOKEN_HEADER))
+        .and(warp::body::json())
+        .and(with_arc(repository.clone()))
+        .and(with_arc(Arc::new(Mutex::new(events_secret))))
+        .and_then(refund_event_handler);
     let health_route = warp::path!("manage" / "health")
+        .and(telemetry::route("/manage/health"))
+        .or(warp::path!("manage" / "health" / "ready").and(telemetry::route("/manage/health/ready")))
+        .unify()
         .and(warp::get())
+        .and(with_arc(repository.clone()))
         .and_then(health_check_handler);
     let routes = get_route
+        .or(history_route)
         .or(purchase_route)
         .or(refund_route)
+        .or(adjustment_route)
+        .or(reconciliation_route)
+        .or(repair_route)
+        .or(transfer_route)
+        .or(refund_event_route)
         .or(health_route)
-        .with(log);
+        .or(telemetry::live_route())
+        .or(telemetry::metrics_route());
     routes
 }
 
-pub async fn run_server(repository: Arc<Mutex<dyn PrivilegeRepository>>, port: u16, checker: Arc<Mutex<jwtchecker::JWTChecker>>) {
-    let router = router(repository, checker);
-    warp::serve(router)
-        .run(([0, 0, 0, 0], port))
-        .await
+pub async fn run_server(repository: Arc<Mutex<dyn PrivilegeRepository>>,
+                        port: u16,
+                        checker: Arc<Mutex<jwtchecker::JWTChecker>>,
+                        rules: Arc<Mutex<RuleSet>>,
+                        rates: Arc<Mutex<RateTable>>,
+                        events_secret: String) -> std::io::Result<()> {
+    let router = router(repository, checker, rules, rates, events_secret);
+    telemetry::serve(router, port).await
 }
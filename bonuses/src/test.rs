@@ -1,20 +1,38 @@
 use std::error::Error;
-use crate::{arc, repository::PrivilegeRepository, server::router};
+use crate::{arc, ledger::{classify, is_consistent, Posting}, memory::MemoryRepository, PrivilegeError, repository::{HistoryFilter, PrivilegeRepository, Repository}, rules::{Promotion, RuleSet}, seed::{seed, Fixtures}, server::router, tiers::tier_for};
 use async_trait::async_trait;
-use structs::{Privilege, PrivilegeHistory, PrivilegeHistoryPost};
+use database::{testing::TestDatabase, Database};
+use chrono::{NaiveDate, NaiveDateTime};
+use jwtchecker::testing;
+use proptest::prelude::*;
+use structs::{AdjustmentPost, Money, RateTable, BalanceMismatch, ReconciliationReport, PrivilegeHistoryPage, BalanceOperationResponse, TransferPost, PointsLot, PrivilegeGet, Privilege, PrivilegeHistory, PrivilegeHistoryPost, PurchasePost, PurchaseResponse, TicketRefundEvent, TierHistory};
 use uuid::Uuid;
 
 
 struct MockRepository {
     privilege: Option<Privilege>,
-    privilege_history: Option<Vec<PrivilegeHistory>>
+    privilege_history: Option<Vec<PrivilegeHistory>>,
+    added_history: Vec<PrivilegeHistoryPost>,
+    activity: (i64, i64),
+    tier_history: Vec<TierHistory>,
+    lots: Vec<PointsLot>,
+    transfers: Vec<(String, String, i32)>,
+    history_page: Option<(HistoryFilter, i64, i64)>,
+    mismatches: Vec<BalanceMismatch>
 }
 
 impl MockRepository {
     pub fn new(privilege: Option<Privilege>, privilege_history: Option<Vec<PrivilegeHistory>>) -> Self {
         MockRepository {
             privilege,
-            privilege_history
+            privilege_history,
+            added_history: vec![],
+            activity: (0, 0),
+            tier_history: vec![],
+            lots: vec![],
+            transfers: vec![],
+            history_page: None,
+            mismatches: vec![]
         }
     }
 }
@@ -25,8 +43,27 @@ impl PrivilegeRepository for MockRepository {
     async fn init(&mut self) ->  Result<(), Box<dyn Error>> {
         Ok(())
     }
+    async fn ping(&mut self) ->  Result<(), Box<dyn Error>> {
+        Ok(())
+    }
     async fn get_privilege(&mut self, username: String) ->  Result<Privilege, Box<dyn Error>> {
-        Ok(self.privilege.clone().unwrap())
+        self.privilege.clone()
+            .filter(|x| x.username == username)
+            .ok_or_else(|| PrivilegeError::NotFoundError.into())
+    }
+    async fn get_privilege_by_id(&mut self, id: i32) ->  Result<Privilege, Box<dyn Error>> {
+        self.privilege.clone()
+            .filter(|x| x.id == id)
+            .ok_or_else(|| PrivilegeError::NotFoundError.into())
+    }
+    async fn create_privilege(&mut self, username: String) ->  Result<Privilege, Box<dyn Error>> {
+        let privilege = self.privilege.get_or_insert(Privilege {
+            id: 1,
+            username,
+            status: "BRONZE".to_owned(),
+            balance: 0
+        });
+        Ok(privilege.clone())
     }
     async fn get_privilege_history(&mut self, username: String) ->  Result<Vec<PrivilegeHistory>, Box<dyn Error>> {
         Ok(self.privilege_history.clone().unwrap())
@@ -34,12 +71,71 @@ impl PrivilegeRepository for MockRepository {
     async fn get_privilege_history_by_ticket(&mut self, ticket_uid: Uuid) ->  Result<Vec<PrivilegeHistory>, Box<dyn Error>> {
         Ok(self.privilege_history.clone().unwrap())
     }
+    async fn get_privilege_history_page(&mut self, username: String, filter: HistoryFilter, offset: i64, limit: i64) ->  Result<(Vec<PrivilegeHistory>, i64), Box<dyn Error>> {
+        self.history_page = Some((filter, offset, limit));
+        let history = self.privilege_history.clone().unwrap();
+        let total = history.len() as i64;
+        Ok((history.into_iter().skip(offset as usize).take(limit as usize).collect(), total))
+    }
     async fn add_history(&mut self, data: PrivilegeHistoryPost) ->  Result<(), Box<dyn Error>> {
-        todo!()
+        self.added_history.push(data);
+        Ok(())
     }
-    async fn update_balance(&mut self, username: String, difference: i32) ->  Result<(), Box<dyn Error>> {
+    async fn list_privileges(&mut self) ->  Result<Vec<Privilege>, Box<dyn Error>> {
+        Ok(self.privilege.clone().into_iter().collect())
+    }
+    async fn add_purchase(&mut self, username: String, ticket_uid: Uuid, price: i32) ->  Result<(), Box<dyn Error>> {
         Ok(())
     }
+    async fn refund_ticket(&mut self, ticket_uid: Uuid, refunds: Vec<PrivilegeHistoryPost>) ->  Result<(), Box<dyn Error>> {
+        self.added_history.extend(refunds);
+        Ok(())
+    }
+    async fn get_activity(&mut self, privilege_id: i32, since: NaiveDateTime) ->  Result<(i64, i64), Box<dyn Error>> {
+        Ok(self.activity)
+    }
+    async fn change_status(&mut self, privilege_id: i32, status: String) ->  Result<TierHistory, Box<dyn Error>> {
+        let privilege = self.privilege.as_mut().unwrap();
+        let history = TierHistory {
+            id: self.tier_history.len() as i32,
+            privilege_id,
+            datetime: NaiveDateTime::default(),
+            previous_status: privilege.status.clone(),
+            status: status.clone()
+        };
+        privilege.status = status;
+        self.tier_history.push(history.clone());
+        Ok(history)
+    }
+    async fn get_tier_history(&mut self, username: String) ->  Result<Vec<TierHistory>, Box<dyn Error>> {
+        Ok(self.tier_history.clone())
+    }
+    async fn get_expirations(&mut self, username: String) ->  Result<Vec<PointsLot>, Box<dyn Error>> {
+        Ok(self.lots.clone())
+    }
+    async fn expire_lots(&mut self, now: NaiveDateTime) ->  Result<usize, Box<dyn Error>> {
+        Ok(0)
+    }
+    async fn transfer(&mut self, sender: String, recipient: String, amount: i32, max_daily_amount: i32, operation_uid: Uuid) ->  Result<i32, Box<dyn Error>> {
+        self.transfers.push((sender, recipient, amount));
+        let privilege = self.privilege.as_mut().unwrap();
+        privilege.balance -= amount;
+        Ok(privilege.balance)
+    }
+    async fn reconcile(&mut self, repair: bool) ->  Result<ReconciliationReport, Box<dyn Error>> {
+        let report = ReconciliationReport {
+            checkedAccounts: 1,
+            mismatches: self.mismatches.clone(),
+            repaired: repair,
+            ..Default::default()
+        };
+        if repair {
+            for mismatch in self.mismatches.drain(..) {
+                self.privilege.as_mut().unwrap().balance = mismatch.ledgerBalance;
+            }
+        }
+        Ok(report)
+    }
 }
 
 
@@ -54,12 +150,1026 @@ async fn get_privilege() {
             }), 
             Some(vec![])
             ));
-    let router = router(repository);
+    let router = router(repository, arc!(testing::checker()), arc!(RuleSet::default()), arc!(RateTable::default()), "secret".to_owned());
+    let res = warp::test::request()
+        .method("GET")
+        .path("/privilege")
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.body(), "{\"balance\":2000,\"status\":\"BRONZE\",\"history\":[],\"tierHistory\":[],\"upcomingExpirations\":[]}");
+}
+
+#[tokio::test]
+async fn refund_event() {
+    let ticket_uid = uuid::uuid!("17ea0b3b-9efb-4be1-8db5-81512fe77c88");
+    let repository = arc!(MockRepository::new(
+            Some(Privilege {
+                id: 1,
+                username: "someone".to_owned(),
+                status: "BRONZE".to_owned(),
+                balance: 2000
+            }),
+            Some(vec![
+                PrivilegeHistory {
+                    id: 1,
+                    privilege_id: 1,
+                    ticket_uid,
+                    datetime: chrono::NaiveDateTime::default(),
+                    balance_diff: 150,
+                    operation_type: "FILL_IN_BALANCE".to_owned(),
+                    rule_version: None,
+                    refund_of: None,
+                    reason: None,
+                    operator: None,
+                    counterparty: None
+                }
+            ])
+            ));
+    let router = router(repository.clone(), arc!(testing::checker()), arc!(RuleSet::default()), arc!(RateTable::default()), "secret".to_owned());
+    let res = warp::test::request()
+        .method("POST")
+        .path("/events/refunds")
+        .header("X-Service-Token", "secret")
+        .json(&TicketRefundEvent { ticket_uid })
+        .reply(&router).await;
+    assert_eq!(res.status(), 204);
+    let added_history = repository.lock().await.added_history.clone();
+    assert_eq!(added_history.len(), 1);
+    assert_eq!(added_history[0].username, "someone");
+    assert_eq!(added_history[0].balance_diff, 150);
+    assert_eq!(added_history[0].operation_type, "REFUND");
+    assert_eq!(added_history[0].refund_of, Some(1));
+}
+
+#[tokio::test]
+async fn refund_event_from_unknown_caller() {
+    let ticket_uid = uuid::uuid!("17ea0b3b-9efb-4be1-8db5-81512fe77c88");
+    let repository = arc!(MockRepository::new(
+            Some(Privilege {
+                id: 1,
+                username: "someone".to_owned(),
+                status: "BRONZE".to_owned(),
+                balance: 2000
+            }),
+            Some(vec![])
+            ));
+    let router = router(repository.clone(), arc!(testing::checker()), arc!(RuleSet::default()), arc!(RateTable::default()), "secret".to_owned());
+    for token in [None, Some("guess")] {
+        let mut request = warp::test::request()
+            .method("POST")
+            .path("/events/refunds")
+            .json(&TicketRefundEvent { ticket_uid });
+        if let Some(token) = token {
+            request = request.header("X-Service-Token", token);
+        }
+        let res = request.reply(&router).await;
+        assert_eq!(res.status(), 401);
+    }
+    assert!(repository.lock().await.added_history.is_empty());
+}
+
+#[tokio::test]
+async fn refund_twice() {
+    let ticket_uid = uuid::uuid!("17ea0b3b-9efb-4be1-8db5-81512fe77c88");
+    let operation = |id: i32, operation_type: &str, refund_of: Option<i32>| PrivilegeHistory {
+        id,
+        privilege_id: 1,
+        ticket_uid,
+        datetime: chrono::NaiveDateTime::default(),
+        balance_diff: 150,
+        operation_type: operation_type.to_owned(),
+        rule_version: None,
+        refund_of,
+        reason: None,
+        operator: None,
+        counterparty: None
+    };
+    let repository = arc!(MockRepository::new(
+            Some(Privilege {
+                id: 1,
+                username: "someone".to_owned(),
+                status: "BRONZE".to_owned(),
+                balance: 2000
+            }),
+            Some(vec![
+                operation(1, "FILL_IN_BALANCE", None),
+                operation(2, "REFUND", Some(1))
+            ])
+            ));
+    let router = router(repository.clone(), arc!(testing::checker()), arc!(RuleSet::default()), arc!(RateTable::default()), "secret".to_owned());
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/privilege?ticket_uid={}", ticket_uid))
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 409);
+    assert!(repository.lock().await.added_history.is_empty());
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres at TEST_DATABASE_URL"]
+async fn concurrent_refunds_conflict() {
+    let test_database = TestDatabase::create().await.unwrap();
+    let ticket_uid = Uuid::new_v4();
+    let mut repositories = vec![];
+    for _ in 0..2 {
+        let repository = arc!(Repository::new(&test_database.connection_str).await.unwrap());
+        repository.lock().await.init().await.unwrap();
+        repositories.push(repository);
+    }
+    repositories[0].lock().await.create_privilege("someone".to_owned()).await.unwrap();
+    repositories[0].lock().await.add_history(PrivilegeHistoryPost {
+        username: "someone".to_owned(),
+        ticket_uid,
+        balance_diff: 150,
+        operation_type: "FILL_IN_BALANCE".to_owned(),
+        ..Default::default()
+    }).await.unwrap();
+    let routers: Vec<_> = repositories.iter()
+        .map(|x| router(x.clone(), arc!(testing::checker()), arc!(RuleSet::default()), arc!(RateTable::default()), "secret".to_owned()))
+        .collect();
+    let refund = |router| warp::test::request()
+        .method("DELETE")
+        .path(&format!("/privilege?ticket_uid={}", ticket_uid))
+        .header("Authorization", testing::bearer("someone"))
+        .reply(router);
+    let (first, second) = tokio::join!(refund(&routers[0]), refund(&routers[1]));
+    let mut statuses = [first.status().as_u16(), second.status().as_u16()];
+    statuses.sort();
+    assert_eq!(statuses, [204, 409]);
+    assert_eq!(repositories[0].lock().await.get_privilege("someone".to_owned()).await.unwrap().balance, 0);
+    // However the race went the unique refund index has the last word
+    let accrual = repositories[0].lock().await.get_privilege_history("someone".to_owned()).await.unwrap().into_iter()
+        .find(|x| x.operation_type == "FILL_IN_BALANCE").unwrap();
+    let error = repositories[1].lock().await.add_history(PrivilegeHistoryPost {
+        username: "someone".to_owned(),
+        ticket_uid,
+        balance_diff: 150,
+        operation_type: "REFUND".to_owned(),
+        refund_of: Some(accrual.id),
+        ..Default::default()
+    }).await.unwrap_err();
+    assert!(matches!(error.downcast_ref::<PrivilegeError>(), Some(PrivilegeError::DuplicateError)));
+    drop(routers);
+    drop(repositories);
+}
+
+/// A ticket whose debit was refunded before: refunding it whole fails and writes nothing
+async fn check_refund_is_all_or_nothing(repository: &mut dyn PrivilegeRepository) {
+    let ticket_uid = Uuid::new_v4();
+    repository.create_privilege("someone".to_owned()).await.unwrap();
+    for operation_type in ["FILL_IN_BALANCE", "DEBIT_THE_ACCOUNT"] {
+        repository.add_history(PrivilegeHistoryPost {
+            username: "someone".to_owned(),
+            ticket_uid,
+            balance_diff: 100,
+            operation_type: operation_type.to_owned(),
+            ..Default::default()
+        }).await.unwrap();
+    }
+    let history = repository.get_privilege_history("someone".to_owned()).await.unwrap();
+    let refund = |operation: &PrivilegeHistory| PrivilegeHistoryPost {
+        username: "someone".to_owned(),
+        ticket_uid,
+        balance_diff: 100,
+        operation_type: "REFUND".to_owned(),
+        refund_of: Some(operation.id),
+        ..Default::default()
+    };
+    repository.add_history(refund(&history[1])).await.unwrap();
+    let error = repository.refund_ticket(ticket_uid, history.iter().map(refund).collect()).await.unwrap_err();
+    assert!(matches!(error.downcast_ref::<PrivilegeError>(), Some(PrivilegeError::DuplicateError)));
+    assert_eq!(repository.get_privilege_history("someone".to_owned()).await.unwrap().len(), 3);
+    assert_eq!(repository.get_privilege("someone".to_owned()).await.unwrap().balance, 100);
+}
+
+#[tokio::test]
+async fn refund_is_all_or_nothing() {
+    check_refund_is_all_or_nothing(&mut MemoryRepository::default()).await;
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres at TEST_DATABASE_URL"]
+async fn postgres_refund_is_all_or_nothing() {
+    let test_database = TestDatabase::create().await.unwrap();
+    let mut repository = Repository::new(&test_database.connection_str).await.unwrap();
+    repository.init().await.unwrap();
+    check_refund_is_all_or_nothing(&mut repository).await;
+    drop(repository);
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres at TEST_DATABASE_URL"]
+async fn debit_below_zero_is_refused() {
+    let test_database = TestDatabase::create().await.unwrap();
+    let mut repository = Repository::new(&test_database.connection_str).await.unwrap();
+    repository.init().await.unwrap();
+    repository.create_privilege("someone".to_owned()).await.unwrap();
+    repository.add_history(PrivilegeHistoryPost {
+        username: "someone".to_owned(),
+        ticket_uid: Uuid::new_v4(),
+        balance_diff: 100,
+        operation_type: "FILL_IN_BALANCE".to_owned(),
+        ..Default::default()
+    }).await.unwrap();
+    // The lots still hold 100 points but the balance drifted below them
+    let mut database = Database::connect(&test_database.connection_str).await.unwrap();
+    database.client().await.unwrap().execute("UPDATE privilege SET balance = 50", &[]).await.unwrap();
+    let error = repository.add_history(PrivilegeHistoryPost {
+        username: "someone".to_owned(),
+        ticket_uid: Uuid::new_v4(),
+        balance_diff: 80,
+        operation_type: "DEBIT_THE_ACCOUNT".to_owned(),
+        ..Default::default()
+    }).await.unwrap_err();
+    assert!(matches!(error.downcast_ref::<PrivilegeError>(), Some(PrivilegeError::InsufficientBalanceError)));
+    assert_eq!(repository.get_privilege("someone".to_owned()).await.unwrap().balance, 50);
+    assert_eq!(repository.get_privilege_history("someone".to_owned()).await.unwrap().len(), 1);
+    assert_eq!(repository.get_expirations("someone".to_owned()).await.unwrap()[0].remaining, 100);
+    drop(database);
+    drop(repository);
+}
+
+#[test]
+fn tier_thresholds() {
+    assert_eq!(tier_for(0, 0), "BRONZE");
+    assert_eq!(tier_for(49_999, 9), "BRONZE");
+    assert_eq!(tier_for(50_000, 0), "SILVER");
+    assert_eq!(tier_for(0, 10), "SILVER");
+    assert_eq!(tier_for(150_000, 3), "GOLD");
+    assert_eq!(tier_for(1_000, 25), "GOLD");
+}
+
+#[tokio::test]
+async fn purchase_promotes_tier() {
+    let mut repository = MockRepository::new(
+            Some(Privilege {
+                id: 1,
+                username: "someone".to_owned(),
+                status: "BRONZE".to_owned(),
+                balance: 0
+            }),
+            Some(vec![]));
+    repository.activity = (60_000, 3);
+    let repository = arc!(repository);
+    let router = router(repository.clone(), arc!(testing::checker()), arc!(RuleSet::default()), arc!(RateTable::default()), "secret".to_owned());
+    let res = warp::test::request()
+        .method("POST")
+        .path("/privilege")
+        .header("Authorization", testing::bearer("someone"))
+        .json(&PurchasePost {
+            ticket_uid: uuid::uuid!("17ea0b3b-9efb-4be1-8db5-81512fe77c88"),
+            price: Money::from_major(1500, "RUB"),
+            paid_from_balance: false,
+            from_airport: None,
+            to_airport: None
+        })
+        .reply(&router).await;
+    assert_eq!(res.status(), 200);
+    let purchase: PurchaseResponse = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(purchase.status, "SILVER");
+    let tier_change = purchase.tier_change.unwrap();
+    assert_eq!(tier_change.previousStatus, "BRONZE");
+    assert_eq!(tier_change.status, "SILVER");
+    assert_eq!(repository.lock().await.tier_history.len(), 1);
+}
+
+#[test]
+fn rules_accrual() {
+    let mut rules = RuleSet::default();
+    rules.promotions.push(Promotion {
+        name: "Summer in Saint Petersburg".to_owned(),
+        from_airport: None,
+        to_airport: Some("LED".to_owned()),
+        starts: NaiveDate::from_ymd_opt(2024, 6, 1),
+        ends: NaiveDate::from_ymd_opt(2024, 8, 31),
+        multiplier: 2.0
+    });
+    let summer = NaiveDate::from_ymd_opt(2024, 7, 1).unwrap();
+    let winter = NaiveDate::from_ymd_opt(2024, 12, 1).unwrap();
+    assert_eq!(rules.accrual("BRONZE", &Money::from_major(1000, "RUB"), Some("SVO"), Some("LED"), winter), 100);
+    assert_eq!(rules.accrual("GOLD", &Money::from_major(1000, "RUB"), Some("SVO"), Some("LED"), winter), 150);
+    assert_eq!(rules.accrual("GOLD", &Money::from_major(1000, "RUB"), Some("SVO"), Some("led"), summer), 300);
+    assert_eq!(rules.accrual("GOLD", &Money::from_major(1000, "RUB"), Some("LED"), Some("SVO"), summer), 150);
+    assert_eq!(rules.accrual("GOLD", &Money::from_major(1000, "RUB"), None, None, summer), 150);
+    // Fractions of a point are dropped only after the percent and multiplier are applied
+    assert_eq!(rules.accrual("SILVER", &Money::new(100_050, "RUB"), None, None, winter), 125);
+    assert_eq!(rules.accrual("SILVER", &Money::new(100_050, "RUB"), None, Some("LED"), summer), 250);
+    assert_eq!(rules.accrual("BRONZE", &Money::new(99_999, "RUB"), None, None, winter), 99);
+}
+
+#[test]
+fn default_rules_are_shipped() {
+    let shipped = RuleSet::load("rules.json").unwrap();
+    let rules = RuleSet::default();
+    assert_eq!(rules.version, shipped.version);
+    assert_eq!(rules.accrual_percent, shipped.accrual_percent);
+    assert_eq!(rules.accrual_percent["SILVER"], 12.5);
+    assert_eq!(rules.accrual_percent["GOLD"], 15.0);
+    assert!(RuleSet::parse(r#"{"version": "", "accrual_percent": {}, "max_bonus_share_percent": 100.0, "min_redeem": 0}"#).is_err());
+}
+
+#[test]
+fn rules_redeemable() {
+    let mut rules = RuleSet::default();
+    let price = Money::from_major(1500, "RUB");
+    assert_eq!(rules.redeemable(2000, &price), 1500);
+    rules.max_bonus_share_percent = 50.0;
+    rules.min_redeem = 100;
+    assert_eq!(rules.redeemable(2000, &price), 750);
+    assert_eq!(rules.redeemable(300, &price), 300);
+    assert_eq!(rules.redeemable(99, &price), 0);
+}
+
+#[tokio::test]
+async fn purchase_applies_rules() {
+    let repository = arc!(MockRepository::new(
+            Some(Privilege {
+                id: 1,
+                username: "someone".to_owned(),
+                status: "BRONZE".to_owned(),
+                balance: 2000
+            }),
+            Some(vec![])));
+    let rules = RuleSet {
+        version: "test-1".to_owned(),
+        max_bonus_share_percent: 50.0,
+        ..Default::default()
+    };
+    let router = router(repository.clone(), arc!(testing::checker()), arc!(rules), arc!(RateTable::default()), "secret".to_owned());
+    let res = warp::test::request()
+        .method("POST")
+        .path("/privilege")
+        .header("Authorization", testing::bearer("someone"))
+        .json(&PurchasePost {
+            ticket_uid: uuid::uuid!("17ea0b3b-9efb-4be1-8db5-81512fe77c88"),
+            price: Money::from_major(1500, "RUB"),
+            paid_from_balance: true,
+            from_airport: None,
+            to_airport: None
+        })
+        .reply(&router).await;
+    assert_eq!(res.status(), 200);
+    let purchase: PurchaseResponse = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(purchase.paid_by_bonuses, 750);
+    assert_eq!(purchase.paid_by_money, Money::from_major(750, "RUB"));
+    let added_history = repository.lock().await.added_history.clone();
+    assert_eq!(added_history.len(), 1);
+    assert_eq!(added_history[0].rule_version.as_deref(), Some("test-1"));
+}
+
+#[tokio::test]
+async fn purchase_in_foreign_currency() {
+    let repository = arc!(MockRepository::new(
+            Some(Privilege {
+                id: 1,
+                username: "someone".to_owned(),
+                status: "BRONZE".to_owned(),
+                balance: 0
+            }),
+            Some(vec![])));
+    let rates = RateTable {
+        base: "RUB".to_owned(),
+        rates: std::collections::HashMap::from([("RUB".to_owned(), 100), ("USD".to_owned(), 9250)])
+    };
+    let router = router(repository.clone(), arc!(testing::checker()), arc!(RuleSet::default()), arc!(rates), "secret".to_owned());
+    let purchase = |currency: &str| PurchasePost {
+        ticket_uid: uuid::uuid!("17ea0b3b-9efb-4be1-8db5-81512fe77c88"),
+        price: Money::from_major(100, currency),
+        paid_from_balance: false,
+        from_airport: None,
+        to_airport: None
+    };
+    let res = warp::test::request()
+        .method("POST")
+        .path("/privilege")
+        .header("Authorization", testing::bearer("someone"))
+        .json(&purchase("USD"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 200);
+    let response: PurchaseResponse = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(response.paid_by_money, Money::from_major(100, "USD"));
+    assert_eq!(repository.lock().await.added_history[0].balance_diff, 925);
+    let res = warp::test::request()
+        .method("POST")
+        .path("/privilege")
+        .header("Authorization", testing::bearer("someone"))
+        .json(&purchase("EUR"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 400);
+}
+
+#[tokio::test]
+async fn get_upcoming_expirations() {
+    let ticket_uid = uuid::uuid!("17ea0b3b-9efb-4be1-8db5-81512fe77c88");
+    let mut repository = MockRepository::new(
+            Some(Privilege {
+                id: 1,
+                username: "someone".to_owned(),
+                status: "BRONZE".to_owned(),
+                balance: 150
+            }),
+            Some(vec![]));
+    repository.lots.push(PointsLot {
+        id: 1,
+        privilege_id: 1,
+        ticket_uid,
+        datetime: NaiveDate::from_ymd_opt(2024, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+        expires: NaiveDate::from_ymd_opt(2025, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+        amount: 200,
+        remaining: 150
+    });
+    let router = router(arc!(repository), arc!(testing::checker()), arc!(RuleSet::default()), arc!(RateTable::default()), "secret".to_owned());
+    let res = warp::test::request()
+        .method("GET")
+        .path("/privilege")
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 200);
+    let privilege: PrivilegeGet = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(privilege.upcomingExpirations.len(), 1);
+    assert_eq!(privilege.upcomingExpirations[0].date, "2025-01-01T00:00:00Z");
+    assert_eq!(privilege.upcomingExpirations[0].ticketUid, ticket_uid);
+    assert_eq!(privilege.upcomingExpirations[0].amount, 150);
+}
+
+/// Books an operation of `amount` points on the ticket for the account
+async fn operate(repository: &mut MemoryRepository, operation_type: &str, ticket_uid: Uuid, amount: i32) -> Result<(), Box<dyn Error>> {
+    repository.add_history(PrivilegeHistoryPost {
+        username: "someone".to_owned(),
+        ticket_uid,
+        balance_diff: amount,
+        operation_type: operation_type.to_owned(),
+        ..Default::default()
+    }).await
+}
+
+async fn remaining_lots(repository: &mut MemoryRepository) -> Vec<(Uuid, i32)> {
+    repository.get_expirations("someone".to_owned()).await.unwrap().iter().map(|x| (x.ticket_uid, x.remaining)).collect()
+}
+
+#[tokio::test]
+async fn debits_consume_oldest_lots_first() {
+    let tickets: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+    let mut repository = MemoryRepository::default();
+    repository.create_privilege("someone".to_owned()).await.unwrap();
+    for (ticket_uid, amount) in tickets.iter().zip([100, 200, 300]) {
+        operate(&mut repository, "FILL_IN_BALANCE", *ticket_uid, amount).await.unwrap();
+    }
+    // The first lot is used up and the second one only in part
+    operate(&mut repository, "DEBIT_THE_ACCOUNT", tickets[3], 250).await.unwrap();
+    assert_eq!(remaining_lots(&mut repository).await, [(tickets[1], 50), (tickets[2], 300)]);
+    operate(&mut repository, "MANUAL_DEBIT", Uuid::new_v4(), 100).await.unwrap();
+    assert_eq!(remaining_lots(&mut repository).await, [(tickets[2], 250)]);
+    // Taking an accrual back draws on the lot of its own ticket first
+    operate(&mut repository, "FILL_IN_BALANCE", tickets[3], 100).await.unwrap();
+    operate(&mut repository, "MANUAL_DEBIT", tickets[3], 150).await.unwrap();
+    assert_eq!(remaining_lots(&mut repository).await, [(tickets[2], 200)]);
+    assert_eq!(repository.get_privilege("someone".to_owned()).await.unwrap().balance, 200);
+}
+
+#[tokio::test]
+async fn refunded_debit_restores_its_lots() {
+    let tickets: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+    let mut repository = MemoryRepository::default();
+    repository.create_privilege("someone".to_owned()).await.unwrap();
+    operate(&mut repository, "FILL_IN_BALANCE", tickets[0], 100).await.unwrap();
+    operate(&mut repository, "FILL_IN_BALANCE", tickets[1], 200).await.unwrap();
+    let before = repository.get_expirations("someone".to_owned()).await.unwrap();
+    operate(&mut repository, "DEBIT_THE_ACCOUNT", tickets[2], 250).await.unwrap();
+    let debit = repository.get_privilege_history("someone".to_owned()).await.unwrap().pop().unwrap();
+    repository.add_history(PrivilegeHistoryPost {
+        username: "someone".to_owned(),
+        ticket_uid: tickets[2],
+        balance_diff: 250,
+        operation_type: "REFUND".to_owned(),
+        refund_of: Some(debit.id),
+        ..Default::default()
+    }).await.unwrap();
+    // Same lots, same expiry, no fresh lot for the refund
+    let after = repository.get_expirations("someone".to_owned()).await.unwrap();
+    assert_eq!(after.iter().map(|x| (x.id, x.expires, x.remaining)).collect::<Vec<_>>(),
+               before.iter().map(|x| (x.id, x.expires, x.remaining)).collect::<Vec<_>>());
+    assert_eq!(repository.get_privilege("someone".to_owned()).await.unwrap().balance, 300);
+}
+
+#[tokio::test]
+async fn debit_over_lots_is_refused() {
+    let (accrued, spent) = (Uuid::new_v4(), Uuid::new_v4());
+    let mut repository = MemoryRepository::default();
+    repository.create_privilege("someone".to_owned()).await.unwrap();
+    operate(&mut repository, "FILL_IN_BALANCE", accrued, 100).await.unwrap();
+    let error = operate(&mut repository, "DEBIT_THE_ACCOUNT", spent, 150).await.unwrap_err();
+    assert!(matches!(error.downcast_ref::<PrivilegeError>(), Some(PrivilegeError::InsufficientBalanceError)));
+    assert_eq!(repository.get_privilege_history("someone".to_owned()).await.unwrap().len(), 1);
+    assert_eq!(remaining_lots(&mut repository).await, [(accrued, 100)]);
+}
+
+#[tokio::test]
+async fn refund_of_spent_points_leaves_a_debt() {
+    let (accrued, spent) = (Uuid::new_v4(), Uuid::new_v4());
+    let mut repository = MemoryRepository::default();
+    repository.create_privilege("someone".to_owned()).await.unwrap();
+    operate(&mut repository, "FILL_IN_BALANCE", accrued, 100).await.unwrap();
+    repository.add_purchase("someone".to_owned(), accrued, 1000).await.unwrap();
+    operate(&mut repository, "DEBIT_THE_ACCOUNT", spent, 80).await.unwrap();
+    let repository = arc!(repository);
+    let router = router(repository.clone(), arc!(testing::checker()), arc!(RuleSet::default()), arc!(RateTable::default()), "secret".to_owned());
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/privilege?ticket_uid={}", accrued))
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 204);
+    let mut repository = repository.lock().await;
+    assert_eq!(repository.get_privilege("someone".to_owned()).await.unwrap().balance, -80);
+    assert!(remaining_lots(&mut repository).await.is_empty());
+    // The next accrual pays the debt off before anything can be spent or expire
+    let next = Uuid::new_v4();
+    operate(&mut repository, "FILL_IN_BALANCE", next, 100).await.unwrap();
+    assert_eq!(repository.get_privilege("someone".to_owned()).await.unwrap().balance, 20);
+    assert_eq!(remaining_lots(&mut repository).await, [(next, 20)]);
+    let error = operate(&mut repository, "DEBIT_THE_ACCOUNT", Uuid::new_v4(), 50).await.unwrap_err();
+    assert!(matches!(error.downcast_ref::<PrivilegeError>(), Some(PrivilegeError::InsufficientBalanceError)));
+}
+
+#[tokio::test]
+async fn lots_expire_at_their_cutoff() {
+    let ticket_uid = Uuid::new_v4();
+    let mut repository = MemoryRepository::default();
+    repository.create_privilege("someone".to_owned()).await.unwrap();
+    operate(&mut repository, "FILL_IN_BALANCE", ticket_uid, 100).await.unwrap();
+    operate(&mut repository, "MANUAL_DEBIT", Uuid::new_v4(), 30).await.unwrap();
+    let expires = repository.get_expirations("someone".to_owned()).await.unwrap()[0].expires;
+    assert_eq!(repository.expire_lots(expires - chrono::Duration::seconds(1)).await.unwrap(), 0);
+    assert_eq!(repository.get_privilege("someone".to_owned()).await.unwrap().balance, 70);
+    assert_eq!(repository.expire_lots(expires).await.unwrap(), 1);
+    assert_eq!(repository.get_privilege("someone".to_owned()).await.unwrap().balance, 0);
+    // Only what was left of the lot expires
+    let expired = repository.get_privilege_history("someone".to_owned()).await.unwrap().pop().unwrap();
+    assert_eq!((expired.operation_type.as_str(), expired.ticket_uid, expired.balance_diff), ("EXPIRED", ticket_uid, 70));
+    assert_eq!(repository.expire_lots(expires).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn get_creates_privilege() {
+    let repository = arc!(MockRepository::new(None, Some(vec![])));
+    let router = router(repository.clone(), arc!(testing::checker()), arc!(RuleSet::default()), arc!(RateTable::default()), "secret".to_owned());
     let res = warp::test::request()
         .method("GET")
         .path("/privilege")
-        .header("X-User-Name", "someone")
+        .header("Authorization", testing::bearer("newcomer"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 200);
+    let privilege: PrivilegeGet = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(privilege.balance, 0);
+    assert_eq!(privilege.status, "BRONZE");
+    assert_eq!(repository.lock().await.privilege.as_ref().unwrap().username, "newcomer");
+}
+
+#[tokio::test]
+async fn adjustment_requires_admin() {
+    let repository = arc!(MemoryRepository::default());
+    seed(repository.clone(), serde_json::from_str(r#"{"privileges": [{"username": "someone", "balance": 2000}]}"#).unwrap()).await.unwrap();
+    let router = router(repository.clone(), arc!(testing::checker()), arc!(RuleSet::default()), arc!(RateTable::default()), "secret".to_owned());
+    let adjustment = AdjustmentPost {
+        username: "someone".to_owned(),
+        amount: -500,
+        reason: "FRAUD".to_owned()
+    };
+    let res = warp::test::request()
+        .method("POST")
+        .path("/privilege/adjustments")
+        .header("Authorization", testing::bearer("someone"))
+        .json(&adjustment)
+        .reply(&router).await;
+    assert_eq!(res.status(), 403);
+    let res = warp::test::request()
+        .method("POST")
+        .path("/privilege/adjustments")
+        .header("Authorization", testing::bearer_with_roles("support", &["bonuses-admin"]))
+        .json(&adjustment)
+        .reply(&router).await;
+    assert_eq!(res.status(), 200);
+    let response: BalanceOperationResponse = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(response.balance, 1500);
+    let history = repository.lock().await.get_privilege_history("someone".to_owned()).await.unwrap();
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[1].operation_type, "MANUAL_DEBIT");
+    assert_eq!(history[1].balance_diff, 500);
+    assert_eq!(history[1].reason.as_deref(), Some("FRAUD"));
+    assert_eq!(history[1].operator.as_deref(), Some("support"));
+}
+
+#[tokio::test]
+async fn transfer_points() {
+    let repository = arc!(MemoryRepository::default());
+    seed(repository.clone(), serde_json::from_str(r#"{"privileges": [{"username": "someone", "balance": 2000}, {"username": "friend"}]}"#).unwrap()).await.unwrap();
+    let expires = repository.lock().await.get_expirations("someone".to_owned()).await.unwrap()[0].expires;
+    let router = router(repository.clone(), arc!(testing::checker()), arc!(RuleSet::default()), arc!(RateTable::default()), "secret".to_owned());
+    let transfer = |sender: &str, recipient: &str| warp::test::request()
+        .method("POST")
+        .path("/privilege/transfers")
+        .header("Authorization", testing::bearer(sender))
+        .json(&TransferPost {
+            recipient: recipient.to_owned(),
+            amount: 300
+        })
+        .reply(&router);
+    let res = transfer("someone", "friend").await;
+    assert_eq!(res.status(), 200);
+    let response: BalanceOperationResponse = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(response.balance, 1700);
+    let res = transfer("friend", "someone").await;
+    assert_eq!(res.status(), 200);
+    let response: BalanceOperationResponse = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(response.balance, 0);
+    // Sending the points back and forth does not give them a new lifetime
+    let lots = repository.lock().await.get_expirations("someone".to_owned()).await.unwrap();
+    assert_eq!(lots.iter().map(|x| x.remaining).sum::<i32>(), 2000);
+    assert!(lots.iter().all(|x| x.expires == expires));
+    let res = transfer("someone", "nobody").await;
+    assert_eq!(res.status(), 404);
+}
+
+#[tokio::test]
+async fn transfer_over_daily_limit() {
+    let repository = arc!(MemoryRepository::default());
+    seed(repository.clone(), serde_json::from_str(r#"{"privileges": [{"username": "someone", "balance": 20000}, {"username": "friend"}]}"#).unwrap()).await.unwrap();
+    let router = router(repository.clone(), arc!(testing::checker()), arc!(RuleSet::default()), arc!(RateTable::default()), "secret".to_owned());
+    let transfer = |amount: i32| warp::test::request()
+        .method("POST")
+        .path("/privilege/transfers")
+        .header("Authorization", testing::bearer("someone"))
+        .json(&TransferPost {
+            recipient: "friend".to_owned(),
+            amount
+        })
+        .reply(&router);
+    assert_eq!(transfer(9_900).await.status(), 200);
+    let res = transfer(200).await;
+    assert_eq!(res.status(), 422);
+    assert_eq!(res.body(), "Daily transfer limit exceeded");
+    assert_eq!(repository.lock().await.get_privilege("friend".to_owned()).await.unwrap().balance, 9_900);
+}
+
+#[tokio::test]
+async fn transfer_over_balance() {
+    let repository = arc!(MemoryRepository::default());
+    seed(repository.clone(), serde_json::from_str(r#"{"privileges": [{"username": "someone", "balance": 200}, {"username": "friend"}]}"#).unwrap()).await.unwrap();
+    let router = router(repository.clone(), arc!(testing::checker()), arc!(RuleSet::default()), arc!(RateTable::default()), "secret".to_owned());
+    let res = warp::test::request()
+        .method("POST")
+        .path("/privilege/transfers")
+        .header("Authorization", testing::bearer("someone"))
+        .json(&TransferPost {
+            recipient: "friend".to_owned(),
+            amount: 300
+        })
+        .reply(&router).await;
+    assert_eq!(res.status(), 422);
+    assert_eq!(res.body(), "Balance is too low");
+    assert_eq!(repository.lock().await.get_privilege("someone".to_owned()).await.unwrap().balance, 200);
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres at TEST_DATABASE_URL"]
+async fn concurrent_transfers_keep_daily_limit() {
+    let test_database = TestDatabase::create().await.unwrap();
+    let mut repositories = vec![];
+    for _ in 0..2 {
+        let repository = arc!(Repository::new(&test_database.connection_str).await.unwrap());
+        repository.lock().await.init().await.unwrap();
+        repositories.push(repository);
+    }
+    seed(repositories[0].clone(), serde_json::from_str(r#"{"privileges": [{"username": "someone", "balance": 20000}, {"username": "friend"}]}"#).unwrap()).await.unwrap();
+    let routers: Vec<_> = repositories.iter()
+        .map(|x| router(x.clone(), arc!(testing::checker()), arc!(RuleSet::default()), arc!(RateTable::default()), "secret".to_owned()))
+        .collect();
+    let transfer = |router| warp::test::request()
+        .method("POST")
+        .path("/privilege/transfers")
+        .header("Authorization", testing::bearer("someone"))
+        .json(&TransferPost {
+            recipient: "friend".to_owned(),
+            amount: 6_000
+        })
+        .reply(router);
+    let (first, second) = tokio::join!(transfer(&routers[0]), transfer(&routers[1]));
+    let mut statuses = [first.status().as_u16(), second.status().as_u16()];
+    statuses.sort();
+    assert_eq!(statuses, [200, 422]);
+    assert_eq!(repositories[0].lock().await.get_privilege("friend".to_owned()).await.unwrap().balance, 6_000);
+    drop(routers);
+    drop(repositories);
+}
+
+#[tokio::test]
+async fn seed_completes_interrupted_accounts() {
+    let repository = arc!(MemoryRepository::default());
+    // A seed that stopped right after opening the account
+    repository.lock().await.create_privilege("someone".to_owned()).await.unwrap();
+    let fixtures: Fixtures = serde_json::from_str(r#"{"privileges": [{"username": "someone", "status": "SILVER", "balance": 2000}]}"#).unwrap();
+    seed(repository.clone(), fixtures.clone()).await.unwrap();
+    seed(repository.clone(), fixtures).await.unwrap();
+    let privilege = repository.lock().await.get_privilege("someone".to_owned()).await.unwrap();
+    assert_eq!(privilege.balance, 2000);
+    assert_eq!(privilege.status, "SILVER");
+    assert_eq!(repository.lock().await.get_privilege_history("someone".to_owned()).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn get_summary() {
+    let repository = arc!(MemoryRepository::default());
+    seed(repository.clone(), serde_json::from_str(r#"{"privileges": [{"username": "someone", "status": "SILVER", "balance": 2000}]}"#).unwrap()).await.unwrap();
+    let router = router(repository, arc!(testing::checker()), arc!(RuleSet::default()), arc!(RateTable::default()), "secret".to_owned());
+    let res = warp::test::request()
+        .method("GET")
+        .path("/privilege?summary=true")
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.body(), "{\"balance\":2000,\"status\":\"SILVER\"}");
+}
+
+#[tokio::test]
+async fn get_history_page() {
+    let operation = |id: i32| PrivilegeHistory {
+        id,
+        privilege_id: 1,
+        ticket_uid: Uuid::new_v4(),
+        datetime: chrono::NaiveDateTime::default(),
+        balance_diff: 150,
+        operation_type: "FILL_IN_BALANCE".to_owned(),
+        rule_version: None,
+        refund_of: None,
+        reason: None,
+        operator: None,
+        counterparty: None
+    };
+    let repository = arc!(MockRepository::new(
+            Some(Privilege {
+                id: 1,
+                username: "someone".to_owned(),
+                status: "BRONZE".to_owned(),
+                balance: 450
+            }),
+            Some(vec![operation(3), operation(2), operation(1)])
+            ));
+    let router = router(repository.clone(), arc!(testing::checker()), arc!(RuleSet::default()), arc!(RateTable::default()), "secret".to_owned());
+    let res = warp::test::request()
+        .method("GET")
+        .path("/privilege/history?page=2&size=2&operationType=FILL_IN_BALANCE&from=2024-01-01&to=2024-01-31")
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 200);
+    let page: PrivilegeHistoryPage = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(page.page, 2);
+    assert_eq!(page.totalElements, 3);
+    assert_eq!(page.items.len(), 1);
+    let (filter, offset, limit) = repository.lock().await.history_page.clone().unwrap();
+    assert_eq!((offset, limit), (2, 2));
+    assert_eq!(filter.operation_type.as_deref(), Some("FILL_IN_BALANCE"));
+    assert_eq!(filter.to, NaiveDate::from_ymd_opt(2024, 2, 1).unwrap().and_hms_opt(0, 0, 0));
+    let res = warp::test::request()
+        .method("GET")
+        .path("/privilege/history?size=1000")
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 400);
+}
+
+#[tokio::test]
+async fn reconcile_balances() {
+    let mut repository = MockRepository::new(
+            Some(Privilege {
+                id: 1,
+                username: "someone".to_owned(),
+                status: "BRONZE".to_owned(),
+                balance: 2000
+            }),
+            Some(vec![]));
+    repository.mismatches.push(BalanceMismatch {
+        username: "someone".to_owned(),
+        balance: Some(2000),
+        ledgerBalance: 1850
+    });
+    let repository = arc!(repository);
+    let router = router(repository.clone(), arc!(testing::checker()), arc!(RuleSet::default()), arc!(RateTable::default()), "secret".to_owned());
+    let res = warp::test::request()
+        .method("POST")
+        .path("/privilege/reconciliation")
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 403);
+    let res = warp::test::request()
+        .method("GET")
+        .path("/privilege/reconciliation")
+        .header("Authorization", testing::bearer_with_roles("support", &["bonuses-admin"]))
+        .reply(&router).await;
+    assert_eq!(res.status(), 200);
+    let report: ReconciliationReport = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(report.mismatches.len(), 1);
+    assert!(!report.repaired);
+    assert_eq!(repository.lock().await.privilege.as_ref().unwrap().balance, 2000);
+    let res = warp::test::request()
+        .method("POST")
+        .path("/privilege/reconciliation")
+        .header("Authorization", testing::bearer_with_roles("support", &["bonuses-admin"]))
         .reply(&router).await;
     assert_eq!(res.status(), 200);
-    assert_eq!(res.body(), "{\"balance\":2000,\"status\":\"BRONZE\",\"history\":[]}");
+    let report: ReconciliationReport = serde_json::from_slice(res.body()).unwrap();
+    assert!(report.repaired);
+    assert_eq!(repository.lock().await.privilege.as_ref().unwrap().balance, 1850);
+}
+
+#[test]
+fn ledger_classification() {
+    let posting = |amount, counter_account| Posting { amount, counter_account };
+    assert_eq!(classify("FILL_IN_BALANCE", 150, None), posting(150, "system:accrual"));
+    assert_eq!(classify("DEBIT_THE_ACCOUNT", 150, None), posting(-150, "system:redemption"));
+    assert_eq!(classify("EXPIRED", 150, None), posting(-150, "system:expiry"));
+    assert_eq!(classify("MANUAL_CREDIT", 150, None), posting(150, "system:adjustment"));
+    assert_eq!(classify("MANUAL_DEBIT", 150, None), posting(-150, "system:adjustment"));
+    assert_eq!(classify("TRANSFER_IN", 150, None), posting(150, "system:transfer"));
+    assert_eq!(classify("TRANSFER_OUT", 150, None), posting(-150, "system:transfer"));
+    // A refund undoes what the refunded operation did
+    assert_eq!(classify("REFUND", 150, Some("DEBIT_THE_ACCOUNT")), posting(150, "system:refund"));
+    assert_eq!(classify("REFUND", 150, Some("FILL_IN_BALANCE")), posting(-150, "system:refund"));
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres at TEST_DATABASE_URL"]
+async fn reconcile_posts_legacy_history() {
+    let test_database = TestDatabase::create().await.unwrap();
+    // A database from before the ledger, whose balance drifted from its history
+    let mut database = Database::connect(&test_database.connection_str).await.unwrap();
+    database.client().await.unwrap().batch_execute("
+        CREATE TABLE privilege
+        (
+            id       SERIAL PRIMARY KEY,
+            username VARCHAR(80) NOT NULL UNIQUE,
+            status   VARCHAR(80) NOT NULL DEFAULT 'BRONZE',
+            balance  INT
+        );
+        CREATE TABLE privilege_history
+        (
+            id             SERIAL PRIMARY KEY,
+            privilege_id   INT REFERENCES privilege (id),
+            ticket_uid     uuid        NOT NULL,
+            datetime       TIMESTAMP   NOT NULL,
+            balance_diff   INT         NOT NULL,
+            operation_type VARCHAR(20) NOT NULL
+        );
+        INSERT INTO privilege(username, balance) VALUES ('someone', 400);
+        INSERT INTO privilege_history(privilege_id, ticket_uid, datetime, balance_diff, operation_type) VALUES
+            (1, gen_random_uuid(), now(), 500, 'FILL_IN_BALANCE'),
+            (1, gen_random_uuid(), now(), 150, 'DEBIT_THE_ACCOUNT');
+    ").await.unwrap();
+    let mut repository = Repository::new(&test_database.connection_str).await.unwrap();
+    repository.init().await.unwrap();
+    let report = repository.reconcile(false).await.unwrap();
+    assert_eq!(report.unpostedOperations, vec![1, 2]);
+    assert!(!is_consistent(&report));
+    let report = repository.reconcile(true).await.unwrap();
+    assert_eq!(report.mismatches, vec![BalanceMismatch {
+        username: "someone".to_owned(),
+        balance: Some(400),
+        ledgerBalance: 350
+    }]);
+    assert_eq!(repository.get_privilege("someone".to_owned()).await.unwrap().balance, 350);
+    assert!(is_consistent(&repository.reconcile(false).await.unwrap()));
+    drop(database);
+    drop(repository);
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres at TEST_DATABASE_URL"]
+async fn seed_fixtures() {
+    let fixtures = Fixtures::load("fixtures.json").unwrap();
+    let test_database = TestDatabase::create().await.unwrap();
+    let repository = arc!(Repository::new(&test_database.connection_str).await.unwrap());
+    repository.lock().await.init().await.unwrap();
+    seed(repository.clone(), fixtures.clone()).await.unwrap();
+    seed(repository.clone(), fixtures).await.unwrap();
+    let privilege = repository.lock().await.get_privilege("test-max".to_owned()).await.unwrap();
+    assert_eq!(privilege.status, "GOLD");
+    assert_eq!(privilege.balance, 1500);
+    let report = repository.lock().await.reconcile(false).await.unwrap();
+    assert!(is_consistent(&report));
+    drop(repository);
+}
+
+#[derive(Debug, Clone)]
+enum Operation {
+    Accrue { user: usize, ticket: usize, amount: i32 },
+    Debit { user: usize, ticket: usize, amount: i32 },
+    Refund { user: usize, record: usize },
+    Transfer { from: usize, to: usize, amount: i32 },
+    Expire
+}
+
+fn operation() -> impl Strategy<Value = Operation> {
+    prop_oneof![
+        (0..2usize, 0..3usize, 1..500i32).prop_map(|(user, ticket, amount)| Operation::Accrue { user, ticket, amount }),
+        (0..2usize, 0..3usize, 1..500i32).prop_map(|(user, ticket, amount)| Operation::Debit { user, ticket, amount }),
+        (0..2usize, 0..8usize).prop_map(|(user, record)| Operation::Refund { user, record }),
+        (0..2usize, 1..500i32).prop_map(|(from, amount)| Operation::Transfer { from, to: 1 - from, amount }),
+        Just(Operation::Expire)
+    ]
+}
+
+/// What the operations left behind, without the ids and times that differ between backends
+type Outcome = (Vec<bool>, Vec<(String, i32, Vec<(String, i32, Option<usize>)>, Vec<i32>)>, bool);
+
+async fn run_operations(repository: &mut dyn PrivilegeRepository, prefix: &str, operations: &[Operation]) -> Outcome {
+    let users: Vec<String> = (0..2).map(|x| format!("{}-{}", prefix, x)).collect();
+    let tickets: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+    for user in &users {
+        repository.create_privilege(user.clone()).await.unwrap();
+    }
+    let mut results = vec![];
+    for operation in operations {
+        let result = match operation {
+            Operation::Accrue { user, ticket, amount } | Operation::Debit { user, ticket, amount } => {
+                let operation_type = if matches!(operation, Operation::Accrue { .. }) { "FILL_IN_BALANCE" } else { "DEBIT_THE_ACCOUNT" };
+                repository.add_history(PrivilegeHistoryPost {
+                    username: users[*user].clone(),
+                    ticket_uid: tickets[*ticket],
+                    balance_diff: *amount,
+                    operation_type: operation_type.to_owned(),
+                    ..Default::default()
+                }).await.is_ok()
+            },
+            Operation::Refund { user, record } => {
+                let mut history = repository.get_privilege_history(users[*user].clone()).await.unwrap();
+                history.sort_by_key(|x| x.id);
+                match history.get(*record) {
+                    Some(original) => repository.add_history(PrivilegeHistoryPost {
+                        username: users[*user].clone(),
+                        ticket_uid: original.ticket_uid,
+                        balance_diff: original.balance_diff,
+                        operation_type: "REFUND".to_owned(),
+                        refund_of: Some(original.id),
+                        ..Default::default()
+                    }).await.is_ok(),
+                    None => false
+                }
+            },
+            Operation::Transfer { from, to, amount } => {
+                repository.transfer(users[*from].clone(), users[*to].clone(), *amount, 1_000, Uuid::new_v4()).await.is_ok()
+            },
+            Operation::Expire => {
+                let later = chrono::offset::Utc::now().naive_local() + chrono::Duration::days(400);
+                repository.expire_lots(later).await.is_ok()
+            }
+        };
+        results.push(result);
+    }
+    let mut accounts = vec![];
+    for user in &users {
+        let privilege = repository.get_privilege(user.clone()).await.unwrap();
+        let mut history = repository.get_privilege_history(user.clone()).await.unwrap();
+        history.sort_by_key(|x| x.id);
+        let records = history.iter()
+            .map(|x| (x.operation_type.clone(),
+                      x.balance_diff,
+                      x.refund_of.and_then(|id| history.iter().position(|y| y.id == id))))
+            .collect();
+        let lots = repository.get_expirations(user.clone()).await.unwrap().iter().map(|x| x.remaining).collect();
+        accounts.push((privilege.status, privilege.balance, records, lots));
+    }
+    let report = repository.reconcile(false).await.unwrap();
+    let consistent = report.mismatches.iter().all(|x| !users.contains(&x.username))
+        && report.unbalancedOperations.is_empty()
+        && report.unpostedOperations.is_empty();
+    (results, accounts, consistent)
+}
+
+#[test]
+fn memory_repository_stays_consistent() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    proptest!(ProptestConfig::with_cases(32), |(operations in proptest::collection::vec(operation(), 1..20))| {
+        let (_, _, consistent) = runtime.block_on(run_operations(&mut MemoryRepository::default(), "user", &operations));
+        prop_assert!(consistent);
+    });
+}
+
+#[test]
+#[ignore = "needs Postgres at TEST_DATABASE_URL"]
+fn memory_repository_matches_postgres() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let test_database = runtime.block_on(TestDatabase::create()).unwrap();
+    let postgres = std::cell::RefCell::new(runtime.block_on(async {
+        let mut repository = Repository::new(&test_database.connection_str).await.unwrap();
+        repository.init().await.unwrap();
+        repository
+    }));
+    proptest!(ProptestConfig::with_cases(32), |(operations in proptest::collection::vec(operation(), 1..20))| {
+        let prefix = Uuid::new_v4().to_string();
+        let expected = runtime.block_on(run_operations(&mut MemoryRepository::default(), &prefix, &operations));
+        let actual = runtime.block_on(run_operations(&mut *postgres.borrow_mut(), &prefix, &operations));
+        prop_assert_eq!(actual, expected);
+    });
 }
@@ -0,0 +1,56 @@
+use std::{error::Error, sync::Arc};
+use chrono::Duration;
+use tokio::sync::Mutex;
+use structs::TierHistory;
+use crate::PrivilegeRepository;
+
+/// Purchases older than this no longer count towards the tier
+pub const ROLLING_WINDOW_DAYS: i64 = 365;
+pub const TIER_JOB_PERIOD_SECS: u64 = 60 * 60;
+
+struct TierRule {
+    status: &'static str,
+    min_spend: i64,
+    min_flights: i64
+}
+
+// Ordered from the highest tier, either threshold is enough to qualify
+const TIER_RULES: [TierRule; 3] = [
+    TierRule { status: "GOLD", min_spend: 150_000, min_flights: 25 },
+    TierRule { status: "SILVER", min_spend: 50_000, min_flights: 10 },
+    TierRule { status: "BRONZE", min_spend: 0, min_flights: 0 },
+];
+
+pub fn tier_for(spend: i64, flights: i64) -> &'static str {
+    TIER_RULES.iter()
+        .find(|x| spend >= x.min_spend || flights >= x.min_flights)
+        .map(|x| x.status)
+        .unwrap_or("BRONZE")
+}
+
+/// Recomputes the user's tier from the purchases in the rolling window and stores the change, if any.
+pub async fn evaluate_tier(username: String,
+                           privilege_repository: Arc<Mutex<dyn PrivilegeRepository>>) -> Result<Option<TierHistory>, Box<dyn Error>> {
+    let since = chrono::offset::Utc::now().naive_local() - Duration::days(ROLLING_WINDOW_DAYS);
+    let mut repository = privilege_repository.lock().await;
+    let privilege = repository.get_privilege(username).await?;
+    let (spend, flights) = repository.get_activity(privilege.id, since).await?;
+    let status = tier_for(spend, flights);
+    if status == privilege.status {
+        return Ok(None);
+    }
+    Ok(Some(repository.change_status(privilege.id, status.to_owned()).await?))
+}
+
+/// Periodically re-evaluates every account so that tiers are demoted once purchases leave the window.
+pub async fn run_tier_job(privilege_repository: Arc<Mutex<dyn PrivilegeRepository>>) {
+    loop {
+        let privileges = privilege_repository.lock().await.list_privileges().await.unwrap_or_default();
+        for privilege in privileges {
+            if evaluate_tier(privilege.username.clone(), privilege_repository.clone()).await.is_err() {
+                tracing::error!(privilege_id = privilege.id, "failed to evaluate tier");
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(TIER_JOB_PERIOD_SECS)).await;
+    }
+}
@@ -0,0 +1,141 @@
+use std::{error::Error, str::FromStr, time::Duration};
+use custom_error::custom_error;
+use tokio::time::Instant;
+use tokio_postgres::{Client, Config, NoTls};
+
+mod migrations;
+pub use migrations::Migration;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(test)]
+mod test;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Attempts to connect at startup before giving up, about 15s with the backoff in between
+const STARTUP_ATTEMPTS: u32 = 5;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long sent data may stay unacknowledged before a connection counts as dead
+const TCP_USER_TIMEOUT: Duration = Duration::from_secs(10);
+const STATEMENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+custom_error!{pub DatabaseError
+    Unavailable{reason: String, retry_in_ms: u128} = "Database is unavailable ({reason}), next attempt in {retry_in_ms} ms"
+}
+
+/// Hands out ids like a SERIAL column does, counting up and never reusing the ids of
+/// deleted rows. For the repositories that keep their data in memory.
+#[derive(Debug, Default)]
+pub struct Sequence(i32);
+
+impl Sequence {
+    pub fn nextval(&mut self) -> i32 {
+        self.0 += 1;
+        self.0
+    }
+}
+
+/// Postgres client that notices when its connection is gone and opens a new one.
+/// While the database stays down, attempts are spaced out with exponential backoff
+/// and calls in between fail right away instead of waiting for the database.
+pub struct Database {
+    connection_str: String,
+    client: Client,
+    failures: u32,
+    next_attempt: Instant,
+    last_error: String
+}
+
+/// Wait before the next attempt after `failures` failed ones in a row
+fn backoff(failures: u32) -> Duration {
+    MIN_BACKOFF.saturating_mul(2u32.saturating_pow(failures)).min(MAX_BACKOFF)
+}
+
+/// Adds the timeouts the connection string leaves out, so that a lost server fails
+/// the calls instead of holding them, and the repository lock with them, forever.
+fn config(connection_str: &str) -> Result<Config, tokio_postgres::Error> {
+    let mut config = Config::from_str(connection_str)?;
+    if config.get_connect_timeout().is_none() {
+        config.connect_timeout(CONNECT_TIMEOUT);
+    }
+    if config.get_tcp_user_timeout().is_none() {
+        config.tcp_user_timeout(TCP_USER_TIMEOUT);
+    }
+    if config.get_options().is_none() {
+        config.options(format!("-c statement_timeout={}", STATEMENT_TIMEOUT.as_millis()));
+    }
+    Ok(config)
+}
+
+async fn connect(connection_str: &str) -> Result<Client, tokio_postgres::Error> {
+    let (client, connection) = config(connection_str)?.connect(NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            tracing::error!(error = %e, "database connection closed");
+        }
+    });
+    Ok(client)
+}
+
+impl Database {
+    /// Connects, retrying with backoff for a while as the database may start after the service
+    pub async fn connect(connection_str: &str) -> Result<Self, Box<dyn Error>> {
+        let mut failures = 0;
+        let client = loop {
+            match connect(connection_str).await {
+                Ok(client) => break client,
+                Err(e) if failures + 1 < STARTUP_ATTEMPTS => {
+                    tracing::warn!(error = %e, retry_in_ms = backoff(failures).as_millis() as u64, "failed to connect to the database");
+                    tokio::time::sleep(backoff(failures)).await;
+                    failures += 1;
+                },
+                Err(e) => return Err(e.into())
+            }
+        };
+        Ok(Self {
+            connection_str: connection_str.to_owned(),
+            client,
+            failures: 0,
+            next_attempt: Instant::now(),
+            last_error: String::new()
+        })
+    }
+
+    /// The open client, reconnecting first if the connection was lost
+    pub async fn client(&mut self) -> Result<&mut Client, DatabaseError> {
+        if self.client.is_closed() {
+            self.reconnect().await?;
+        }
+        Ok(&mut self.client)
+    }
+
+    async fn reconnect(&mut self) -> Result<(), DatabaseError> {
+        let now = Instant::now();
+        if now < self.next_attempt {
+            return Err(DatabaseError::Unavailable {
+                reason: self.last_error.clone(),
+                retry_in_ms: (self.next_attempt - now).as_millis()
+            });
+        }
+        match connect(&self.connection_str).await {
+            Ok(client) => {
+                tracing::info!(failed_attempts = self.failures, "reconnected to the database");
+                self.client = client;
+                self.failures = 0;
+                Ok(())
+            },
+            Err(e) => {
+                let backoff = backoff(self.failures);
+                self.failures += 1;
+                self.next_attempt = Instant::now() + backoff;
+                self.last_error = e.to_string();
+                tracing::warn!(error = %e, retry_in_ms = backoff.as_millis() as u64, "failed to reconnect to the database");
+                Err(DatabaseError::Unavailable {
+                    reason: self.last_error.clone(),
+                    retry_in_ms: backoff.as_millis()
+                })
+            }
+        }
+    }
+}
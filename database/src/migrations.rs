@@ -0,0 +1,59 @@
+use std::error::Error;
+use crate::Database;
+
+/// Key of the advisory lock held while migrating, so that replicas starting
+/// together do not apply the same migration twice
+const MIGRATION_LOCK: i64 = 0x5343_4845_4d41;
+
+/// One step of a service's schema, applied once in the order of `version`
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub sql: &'static str
+}
+
+impl Database {
+    /// Applies the migrations missing from `schema_migrations` and returns their versions.
+    /// Every migration runs in its own transaction together with its bookkeeping row.
+    pub async fn migrate(&mut self, migrations: &[Migration]) -> Result<Vec<i32>, Box<dyn Error>> {
+        let client = self.client().await?;
+        let transaction = client.transaction().await?;
+        transaction.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK]).await?;
+        transaction.batch_execute("
+            CREATE TABLE IF NOT EXISTS schema_migrations
+            (
+                version    INT PRIMARY KEY,
+                name       VARCHAR(255)             NOT NULL,
+                applied_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+            );
+        ").await?;
+        transaction.commit().await?;
+        let mut applied = vec![];
+        for migration in migrations {
+            let transaction = client.transaction().await?;
+            transaction.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK]).await?;
+            let done = transaction.query_opt("
+                SELECT version FROM schema_migrations WHERE version = $1
+            ", &[&migration.version]).await?;
+            if done.is_some() {
+                continue;
+            }
+            transaction.batch_execute(migration.sql).await?;
+            transaction.execute("
+                INSERT INTO schema_migrations(version, name) VALUES ($1, $2)
+            ", &[&migration.version, &migration.name]).await?;
+            transaction.commit().await?;
+            tracing::info!(version = migration.version, name = migration.name, "applied migration");
+            applied.push(migration.version);
+        }
+        Ok(applied)
+    }
+
+    /// Versions recorded in `schema_migrations`, oldest first
+    pub async fn applied_migrations(&mut self) -> Result<Vec<i32>, Box<dyn Error>> {
+        let rows = self.client().await?.query("
+            SELECT version FROM schema_migrations ORDER BY version
+        ", &[]).await?;
+        Ok(rows.iter().map(|x| x.get(0)).collect())
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+use crate::{backoff, config, testing::TestDatabase, Database, Migration, CONNECT_TIMEOUT, MAX_BACKOFF, MIN_BACKOFF, STATEMENT_TIMEOUT, TCP_USER_TIMEOUT};
+
+const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "create", sql: "CREATE TABLE item (id SERIAL PRIMARY KEY, name TEXT NOT NULL);" },
+    Migration { version: 2, name: "seed", sql: "INSERT INTO item(name) VALUES ('first');" }
+];
+
+#[test]
+fn backoff_doubles_up_to_the_limit() {
+    assert_eq!(backoff(0), MIN_BACKOFF);
+    assert_eq!(backoff(1), MIN_BACKOFF * 2);
+    assert_eq!(backoff(3), MIN_BACKOFF * 8);
+    assert_eq!(backoff(6), MAX_BACKOFF);
+    for failures in 1..64 {
+        assert!(backoff(failures) >= backoff(failures - 1));
+    }
+}
+
+#[test]
+fn backoff_does_not_overflow() {
+    assert_eq!(backoff(40), MAX_BACKOFF);
+    assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+}
+
+#[test]
+fn config_adds_timeouts() {
+    let config = config("postgresql://postgres@localhost/postgres").unwrap();
+    assert_eq!(config.get_connect_timeout(), Some(&CONNECT_TIMEOUT));
+    assert_eq!(config.get_tcp_user_timeout(), Some(&TCP_USER_TIMEOUT));
+    assert_eq!(config.get_options(), Some(format!("-c statement_timeout={}", STATEMENT_TIMEOUT.as_millis()).as_str()));
+}
+
+#[test]
+fn config_keeps_given_timeouts() {
+    let config = config("postgresql://postgres@localhost/postgres?connect_timeout=1&options=-c%20statement_timeout%3D500").unwrap();
+    assert_eq!(config.get_connect_timeout(), Some(&Duration::from_secs(1)));
+    assert_eq!(config.get_options(), Some("-c statement_timeout=500"));
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres at TEST_DATABASE_URL"]
+async fn migrate_empty_database() {
+    let test_database = TestDatabase::create().await.unwrap();
+    let mut database = Database::connect(&test_database.connection_str).await.unwrap();
+    assert_eq!(database.migrate(&MIGRATIONS[..1]).await.unwrap(), [1]);
+    assert_eq!(database.migrate(MIGRATIONS).await.unwrap(), [2]);
+    assert!(database.migrate(MIGRATIONS).await.unwrap().is_empty());
+    assert_eq!(database.applied_migrations().await.unwrap(), [1, 2]);
+    let items = database.client().await.unwrap().query("SELECT name FROM item", &[]).await.unwrap();
+    assert_eq!(items.len(), 1);
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres at TEST_DATABASE_URL"]
+async fn replicas_migrate_once() {
+    let test_database = TestDatabase::create().await.unwrap();
+    let mut replicas = vec![];
+    for _ in 0..4 {
+        let connection_str = test_database.connection_str.clone();
+        replicas.push(tokio::spawn(async move {
+            Database::connect(&connection_str).await.unwrap().migrate(MIGRATIONS).await.unwrap()
+        }));
+    }
+    let mut applied = vec![];
+    for replica in replicas {
+        applied.extend(replica.await.unwrap());
+    }
+    applied.sort();
+    assert_eq!(applied, [1, 2]);
+}
+
+#[test]
+#[ignore = "needs Postgres at TEST_DATABASE_URL"]
+fn test_database_is_dropped_on_panic() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let test_database = runtime.block_on(TestDatabase::create()).unwrap();
+    let name = test_database.name.clone();
+    let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _test_database = test_database;
+        panic!("test failed");
+    }));
+    assert!(panicked.is_err());
+    let exists = runtime.block_on(async {
+        let mut admin = Database::connect(&std::env::var("TEST_DATABASE_URL").unwrap()).await.unwrap();
+        admin.client().await.unwrap().query_opt("SELECT 1 FROM pg_database WHERE datname = $1", &[&name]).await.unwrap()
+    });
+    assert!(exists.is_none());
+}
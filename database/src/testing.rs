@@ -0,0 +1,42 @@
+use std::{env, error::Error};
+use uuid::Uuid;
+use crate::Database;
+
+/// Empty database created for one test on the server of `TEST_DATABASE_URL`,
+/// e.g. `postgresql://postgres@localhost:5432/postgres`. It is dropped with the value,
+/// also when the test panics. Tests using it are `#[ignore]`d, run them with `cargo test -- --ignored`.
+pub struct TestDatabase {
+    url: String,
+    pub name: String,
+    pub connection_str: String
+}
+
+impl TestDatabase {
+    pub async fn create() -> Result<Self, Box<dyn Error>> {
+        let url = env::var("TEST_DATABASE_URL").map_err(|_| "TEST_DATABASE_URL is not set")?;
+        let mut admin = Database::connect(&url).await?;
+        let name = format!("test_{}", Uuid::new_v4().simple());
+        admin.client().await?.batch_execute(&format!("CREATE DATABASE {}", name)).await?;
+        let (server, _) = url.rsplit_once('/').ok_or("TEST_DATABASE_URL has no database name")?;
+        let connection_str = format!("{}/{}", server, name);
+        Ok(TestDatabase { url, name, connection_str })
+    }
+}
+
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        let url = self.url.clone();
+        let statement = format!("DROP DATABASE IF EXISTS {} WITH (FORCE)", self.name);
+        // On a runtime of its own, the one of the test is blocked in this call or already gone
+        let dropped = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().map_err(|e| e.to_string())?;
+            runtime.block_on(async {
+                let mut admin = Database::connect(&url).await.map_err(|e| e.to_string())?;
+                admin.client().await.map_err(|e| e.to_string())?.batch_execute(&statement).await.map_err(|e| e.to_string())
+            })
+        }).join();
+        if !matches!(dropped, Ok(Ok(()))) {
+            eprintln!("failed to drop test database {}", self.name);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::{error::Error, net::TcpListener, sync::OnceLock};
+use jwtchecker::testing::Keypair;
+use requester::Reqwester;
+pub mod postman;
+#[cfg(test)]
+mod test;
+
+pub const FLIGHT_FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../flights/fixtures.json");
+pub const BONUS_FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../bonuses/fixtures.json");
+
+/// Generating a key takes a while, so the services of all systems in the process share one
+fn keypair() -> &'static Keypair {
+    static KEYPAIR: OnceLock<Keypair> = OnceLock::new();
+    KEYPAIR.get_or_init(Keypair::generate)
+}
+
+/// Secret the services of a system share for their internal events
+const EVENTS_SECRET: &str = "e2e-events-secret";
+
+fn listen() -> Result<(TcpListener, String), Box<dyn Error>> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let url = format!("http://{}", listener.local_addr()?);
+    Ok((listener, url))
+}
+
+/// Flights, tickets, bonuses and the gateway running in this process on ephemeral ports.
+/// The services keep their data in memory, seeded from the fixtures of the services,
+/// and accept the tokens signed with `bearer`.
+pub struct System {
+    pub gateway: String,
+    pub flights: String,
+    pub tickets: String,
+    pub bonuses: String
+}
+
+impl System {
+    /// Starts the system with the bonus rules shipped in `bonuses/rules.json`
+    pub async fn start() -> Result<Self, Box<dyn Error>> {
+        Self::start_with_rules(bonuses::rules::RuleSet::default()).await
+    }
+
+    pub async fn start_with_rules(rules: bonuses::rules::RuleSet) -> Result<Self, Box<dyn Error>> {
+        let (flights_listener, flights_url) = listen()?;
+        let (tickets_listener, tickets_url) = listen()?;
+        let (bonuses_listener, bonuses_url) = listen()?;
+        let (gateway_listener, gateway_url) = listen()?;
+
+        let flight_repository = flights::arc!(flights::memory::MemoryRepository::default());
+        flights::seed::seed(flight_repository.clone(), flights::seed::Fixtures::load(FLIGHT_FIXTURES)?).await?;
+        let events = flights::arc!(flights::server::Events {
+            tickets: format!("{}/events/flights", tickets_url),
+            secret: EVENTS_SECRET.to_owned(),
+            requester: Box::new(Reqwester {}),
+            queue: vec![]
+        });
+        tokio::task::spawn(telemetry::serve_on(flights::server::router(flight_repository, events, flights::arc!(keypair().checker())), flights_listener));
+
+        let ticket_repository = tickets::arc!(tickets::memory::MemoryRepository::default());
+        let refunds = tickets::arc!(tickets::server::Refunds {
+            bonuses: format!("{}/events/refunds", bonuses_url),
+            secret: EVENTS_SECRET.to_owned(),
+            requester: Box::new(Reqwester {}),
+            queue: vec![],
+            dead_letters: vec![]
+        });
+        let router = tickets::server::router(ticket_repository,
+                                             tickets::arc!(keypair().checker()),
+                                             refunds,
+                                             tickets::arc!(tickets::payments::FakePaymentProvider::default()));
+        tokio::task::spawn(telemetry::serve_on(router, tickets_listener));
+
+        let privilege_repository = bonuses::arc!(bonuses::memory::MemoryRepository::default());
+        bonuses::seed::seed(privilege_repository.clone(), bonuses::seed::Fixtures::load(BONUS_FIXTURES)?).await?;
+        let router = bonuses::server::router(privilege_repository,
+                                             bonuses::arc!(keypair().checker()),
+                                             bonuses::arc!(rules),
+                                             bonuses::arc!(structs::RateTable::default()),
+                                             EVENTS_SECRET.to_owned());
+        tokio::task::spawn(telemetry::serve_on(router, bonuses_listener));
+
+        let services = gateway::arc!(gateway::server::Services::new(&flights_url, &tickets_url, &bonuses_url,
+                                                                    Box::new(Reqwester {}), keypair().checker()));
+        tokio::task::spawn(telemetry::serve_on(gateway::server::router("api/v1", services), gateway_listener));
+
+        Ok(Self {
+            gateway: gateway_url,
+            flights: flights_url,
+            tickets: tickets_url,
+            bonuses: bonuses_url
+        })
+    }
+
+    pub fn token(&self, username: &str) -> String {
+        keypair().token(username)
+    }
+
+    pub fn bearer(&self, username: &str) -> String {
+        keypair().bearer(username)
+    }
+
+    pub fn bearer_with_roles(&self, username: &str, roles: &[&str]) -> String {
+        keypair().bearer_with_roles(username, roles)
+    }
+
+    /// Url of the gateway API, e.g. `api("tickets")`
+    pub fn api(&self, path: &str) -> String {
+        format!("{}/api/v1/{}", self.gateway, path)
+    }
+}
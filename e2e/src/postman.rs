@@ -0,0 +1,487 @@
+use std::{collections::HashMap, error::Error, fs};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Variable {
+    pub key: String,
+    #[serde(default)]
+    pub value: String,
+    #[serde(default = "enabled")]
+    pub enabled: bool
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Header {
+    pub key: String,
+    pub value: String
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Auth {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub bearer: Vec<Variable>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Url {
+    Raw(String),
+    Detailed {
+        raw: String,
+        #[serde(default)]
+        variable: Vec<Variable>
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Body {
+    pub mode: String,
+    #[serde(default)]
+    pub raw: String
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Request {
+    pub method: String,
+    #[serde(default)]
+    pub header: Vec<Header>,
+    pub auth: Option<Auth>,
+    pub url: Url,
+    pub body: Option<Body>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Script {
+    #[serde(default)]
+    pub exec: Vec<String>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Event {
+    pub listen: String,
+    pub script: Script
+}
+
+/// A request or a folder of them
+#[derive(Debug, Clone, Deserialize)]
+pub struct Item {
+    pub name: String,
+    #[serde(default)]
+    pub item: Vec<Item>,
+    pub request: Option<Request>,
+    #[serde(default)]
+    pub event: Vec<Event>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Collection {
+    pub item: Vec<Item>,
+    #[serde(default)]
+    pub variable: Vec<Variable>
+}
+
+impl Collection {
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Environment {
+    #[serde(default)]
+    pub values: Vec<Variable>
+}
+
+impl Environment {
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+/// What happened to one request of the collection
+#[derive(Debug, Clone, Default)]
+pub struct Outcome {
+    pub name: String,
+    /// The request was not sent, see `Runner::skip_requests_to`
+    pub skipped: bool,
+    pub failures: Vec<String>,
+    /// Script statements the runner does not understand
+    pub unsupported: Vec<String>
+}
+
+struct Reply {
+    url: reqwest::Url,
+    status: u16,
+    headers: HashMap<String, String>,
+    body: String
+}
+
+enum Problem {
+    Failed(String),
+    Unsupported
+}
+
+/// Sends the requests of a collection in order and checks their test scripts.
+/// The scripts are not run as JavaScript: the runner understands the statements
+/// the course collections are written with, the rest end up in `Outcome::unsupported`.
+pub struct Runner {
+    client: reqwest::Client,
+    variables: HashMap<String, Value>,
+    skipped: Vec<String>
+}
+
+impl Runner {
+    /// Environment values take precedence over the collection ones, as in Postman
+    pub fn new(collection: &Collection, environment: &Environment) -> Self {
+        let variables = collection.variable.iter()
+            .chain(environment.values.iter())
+            .filter(|x| x.enabled)
+            .map(|x| (x.key.clone(), Value::String(x.value.clone())))
+            .collect();
+        Self {
+            client: reqwest::Client::new(),
+            variables,
+            skipped: vec![]
+        }
+    }
+
+    pub fn set(&mut self, key: &str, value: impl Into<Value>) {
+        self.variables.insert(key.to_owned(), value.into());
+    }
+
+    /// Requests whose url starts with the prefix are not sent, e.g. `{{identityProviderUrl}}`
+    pub fn skip_requests_to(&mut self, prefix: &str) {
+        self.skipped.push(prefix.to_owned());
+    }
+
+    pub async fn run(&mut self, collection: &Collection) -> Vec<Outcome> {
+        let mut outcomes = vec![];
+        let mut items: Vec<&Item> = collection.item.iter().rev().collect();
+        while let Some(item) = items.pop() {
+            items.extend(item.item.iter().rev());
+            if let Some(request) = &item.request {
+                outcomes.push(self.run_request(&item.name, request, &item.event).await);
+            }
+        }
+        outcomes
+    }
+
+    fn substitute(&self, text: &str) -> String {
+        let mut result = text.to_owned();
+        for (key, value) in &self.variables {
+            let value = match value {
+                Value::String(x) => x.clone(),
+                x => x.to_string()
+            };
+            result = result.replace(&format!("{{{{{}}}}}", key), &value);
+        }
+        result
+    }
+
+    fn url(&self, url: &Url) -> String {
+        let (raw, variables) = match url {
+            Url::Raw(raw) => (raw, &vec![]),
+            Url::Detailed { raw, variable } => (raw, variable)
+        };
+        let raw = self.substitute(raw);
+        let (path, query) = match raw.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (raw.as_str(), None)
+        };
+        let path = path.split('/')
+            .map(|segment| {
+                match segment.strip_prefix(':').and_then(|key| variables.iter().find(|x| x.key == key)) {
+                    Some(variable) => self.substitute(&variable.value),
+                    None => segment.to_owned()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        match query {
+            Some(query) => format!("{}?{}", path, query),
+            None => path
+        }
+    }
+
+    async fn send(&self, request: &Request) -> Result<Reply, Box<dyn Error>> {
+        let method = reqwest::Method::from_bytes(request.method.as_bytes())?;
+        let mut builder = self.client.request(method, self.url(&request.url));
+        for header in &request.header {
+            builder = builder.header(&header.key, self.substitute(&header.value));
+        }
+        if let Some(auth) = request.auth.as_ref().filter(|x| x.kind == "bearer") {
+            if let Some(token) = auth.bearer.iter().find(|x| x.key == "token") {
+                builder = builder.header("Authorization", format!("Bearer {}", self.substitute(&token.value)));
+            }
+        }
+        if let Some(body) = request.body.as_ref().filter(|x| x.mode == "raw") {
+            builder = builder.body(self.substitute(&body.raw));
+        }
+        let response = builder.send().await?;
+        let url = response.url().clone();
+        let status = response.status().as_u16();
+        let headers = response.headers().iter()
+            .map(|(key, value)| (key.as_str().to_owned(), value.to_str().unwrap_or_default().to_owned()))
+            .collect();
+        let body = response.text().await?;
+        Ok(Reply { url, status, headers, body })
+    }
+
+    async fn run_request(&mut self, name: &str, request: &Request, events: &[Event]) -> Outcome {
+        let mut outcome = Outcome {
+            name: name.to_owned(),
+            ..Default::default()
+        };
+        let raw_url = match &request.url {
+            Url::Raw(raw) => raw,
+            Url::Detailed { raw, .. } => raw
+        };
+        if self.skipped.iter().any(|x| raw_url.starts_with(x)) {
+            outcome.skipped = true;
+            return outcome;
+        }
+        let reply = match self.send(request).await {
+            Ok(val) => val,
+            Err(e) => {
+                outcome.failures.push(format!("request failed: {}", e));
+                return outcome;
+            }
+        };
+        let mut script = Interpreter {
+            variables: &mut self.variables,
+            reply: &reply,
+            locals: HashMap::new()
+        };
+        for line in events.iter().filter(|x| x.listen == "test").flat_map(|x| x.script.exec.iter()) {
+            match script.execute(line) {
+                Ok(()) => {},
+                Err(Problem::Failed(message)) => outcome.failures.push(message),
+                Err(Problem::Unsupported) => outcome.unsupported.push(line.trim().to_owned())
+            }
+        }
+        outcome
+    }
+}
+
+struct Interpreter<'a> {
+    variables: &'a mut HashMap<String, Value>,
+    reply: &'a Reply,
+    locals: HashMap<String, Value>
+}
+
+/// The argument of `prefix(...)` when the expression is a single such call
+fn call<'a>(expr: &'a str, prefix: &str) -> Option<&'a str> {
+    expr.strip_prefix(prefix)?.strip_suffix(')')
+}
+
+fn string_literal(expr: &str) -> Option<String> {
+    let expr = expr.trim();
+    let inner = expr.strip_prefix('"').and_then(|x| x.strip_suffix('"'))
+        .or_else(|| expr.strip_prefix('\'').and_then(|x| x.strip_suffix('\'')))?;
+    Some(inner.to_owned())
+}
+
+fn matching_paren(text: &str, open: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut quoted = false;
+    for (i, c) in text.char_indices().filter(|(i, _)| *i >= open) {
+        match c {
+            '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            },
+            _ => {}
+        }
+    }
+    None
+}
+
+fn number(value: f64) -> Value {
+    if value.fract() == 0.0 {
+        json!(value as i64)
+    }
+    else {
+        json!(value)
+    }
+}
+
+/// Loose equality of chai's `eq` for the values JSON can hold
+fn same(left: &Value, right: &Value) -> bool {
+    match (left.as_f64(), right.as_f64()) {
+        (Some(left), Some(right)) => left == right,
+        _ => left == right
+    }
+}
+
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::String(x) => x.is_empty(),
+        Value::Array(x) => x.is_empty(),
+        Value::Object(x) => x.is_empty(),
+        _ => false
+    }
+}
+
+fn type_of(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object"
+    }
+}
+
+impl Interpreter<'_> {
+    fn execute(&mut self, line: &str) -> Result<(), Problem> {
+        let line = line.trim().trim_end_matches(';');
+        if line.is_empty() || line.starts_with("//") || line.starts_with("pm.test(") || line == "})" {
+            return Ok(());
+        }
+        if let Some(arg) = call(line, "pm.response.to.have.status(") {
+            let expected: u16 = arg.trim().parse().map_err(|_| Problem::Unsupported)?;
+            if self.reply.status != expected {
+                return Err(Problem::Failed(format!("expected status {} but got {}: {}", expected, self.reply.status, self.reply.body)));
+            }
+            return Ok(());
+        }
+        if let Some(rest) = line.strip_prefix("const ").or_else(|| line.strip_prefix("let ")) {
+            let (name, expr) = rest.split_once(" = ").ok_or(Problem::Unsupported)?;
+            let value = self.eval(expr).ok_or(Problem::Unsupported)?;
+            self.locals.insert(name.trim().to_owned(), value);
+            return Ok(());
+        }
+        if let Some(args) = call(line, "pm.collectionVariables.set(") {
+            let (name, expr) = args.split_once(',').ok_or(Problem::Unsupported)?;
+            let name = string_literal(name).ok_or(Problem::Unsupported)?;
+            let value = self.eval(expr).ok_or(Problem::Unsupported)?;
+            self.variables.insert(name, value);
+            return Ok(());
+        }
+        if line.starts_with("pm.expect(") {
+            let close = matching_paren(line, "pm.expect".len()).ok_or(Problem::Unsupported)?;
+            let actual = self.eval(&line["pm.expect(".len()..close]).ok_or(Problem::Unsupported)?;
+            return self.assert(line, actual, &line[close + 1..]);
+        }
+        Err(Problem::Unsupported)
+    }
+
+    /// Checks a chai chain such as `.to.be.not.undefined` or `.to.eq(1500)`
+    fn assert(&self, line: &str, actual: Value, chain: &str) -> Result<(), Problem> {
+        let chain = chain.strip_prefix('.').ok_or(Problem::Unsupported)?;
+        let (words, expected) = match chain.split_once('(') {
+            Some((words, arg)) => {
+                let arg = arg.strip_suffix(')').ok_or(Problem::Unsupported)?;
+                (words, Some(self.eval(arg).ok_or(Problem::Unsupported)?))
+            },
+            None => (chain, None)
+        };
+        let mut negated = false;
+        let mut check = None;
+        for word in words.split('.') {
+            match word {
+                "to" | "be" | "have" => {},
+                "not" => negated = !negated,
+                word => check = Some(word)
+            }
+        }
+        let passed = match (check.ok_or(Problem::Unsupported)?, &expected) {
+            ("undefined", None) => actual.is_null(),
+            ("empty", None) => is_empty(&actual),
+            ("eq" | "equal" | "equals", Some(expected)) => same(&actual, expected),
+            ("lte", Some(expected)) => matches!((actual.as_f64(), expected.as_f64()), (Some(x), Some(y)) if x <= y),
+            ("gte", Some(expected)) => matches!((actual.as_f64(), expected.as_f64()), (Some(x), Some(y)) if x >= y),
+            ("a" | "an", Some(Value::String(expected))) => type_of(&actual) == expected,
+            ("contains" | "contain" | "include", Some(expected)) => match (&actual, expected) {
+                (Value::String(x), Value::String(y)) => x.contains(y.as_str()),
+                (Value::Array(x), y) => x.iter().any(|x| same(x, y)),
+                _ => false
+            },
+            _ => return Err(Problem::Unsupported)
+        };
+        if passed == negated {
+            return Err(Problem::Failed(format!("{} with {}", line, actual)));
+        }
+        Ok(())
+    }
+
+    fn eval(&self, expr: &str) -> Option<Value> {
+        let expr = expr.trim();
+        if let Some((left, right)) = expr.split_once(" + ") {
+            return match (self.eval(left)?, self.eval(right)?) {
+                (Value::String(x), y) => Some(Value::String(format!("{}{}", x, y.as_str().map(str::to_owned).unwrap_or(y.to_string())))),
+                (x, y) => Some(number(x.as_f64()? + y.as_f64()?))
+            };
+        }
+        if let Some(text) = string_literal(expr) {
+            return Some(Value::String(text));
+        }
+        if let Ok(value) = expr.parse::<f64>() {
+            return Some(number(value));
+        }
+        if expr == "pm.response.json()" {
+            return Some(serde_json::from_str(&self.reply.body).unwrap_or(Value::Null));
+        }
+        if let Some(arg) = call(expr, "pm.collectionVariables.get(") {
+            return Some(self.variables.get(&string_literal(arg)?).cloned().unwrap_or(Value::Null));
+        }
+        if let Some(arg) = call(expr, "pm.response.headers.get(") {
+            let name = string_literal(arg)?.to_lowercase();
+            return Some(self.reply.headers.get(&name).map(|x| Value::String(x.clone())).unwrap_or(Value::Null));
+        }
+        if let Some(arg) = call(expr, "pm.request.url.query.get(") {
+            let name = string_literal(arg)?;
+            return Some(self.reply.url.query_pairs()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| Value::String(value.into_owned()))
+                .unwrap_or(Value::Null));
+        }
+        if let Some(arg) = call(expr, "Number(") {
+            return match self.eval(arg)? {
+                Value::String(x) => Some(x.trim().parse().map(number).unwrap_or(Value::Null)),
+                x => Some(x)
+            };
+        }
+        if let Some(args) = call(expr, "_.find(") {
+            return self.find(args);
+        }
+        let mut segments = expr.split('.');
+        let mut value = self.locals.get(segments.next()?)?.clone();
+        for segment in segments {
+            if segment.is_empty() || !segment.chars().all(|x| x.is_alphanumeric() || x == '_') {
+                return None;
+            }
+            value = value.get(segment).cloned().unwrap_or(Value::Null);
+        }
+        Some(value)
+    }
+
+    /// `_.find(list, { "key": value, ... })`
+    fn find(&self, args: &str) -> Option<Value> {
+        let (list, pattern) = args.split_once(", {")?;
+        let mut fields = vec![];
+        for field in pattern.trim().strip_suffix('}')?.split(',') {
+            let (key, value) = field.split_once(':')?;
+            fields.push((string_literal(key)?, self.eval(value)?));
+        }
+        let Value::Array(items) = self.eval(list)? else {
+            return Some(Value::Null);
+        };
+        Some(items.into_iter()
+            .find(|item| fields.iter().all(|(key, value)| item.get(key).is_some_and(|x| same(x, value))))
+            .unwrap_or(Value::Null))
+    }
+}
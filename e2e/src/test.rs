@@ -0,0 +1,225 @@
+use std::time::Duration;
+use reqwest::Client;
+use serde_json::json;
+use bonuses::rules::RuleSet;
+use structs::{CombinedPurchaseResponse, Money, Payment, PrivilegeGet, FlightPageResponse, TicketResponse, User};
+use crate::{postman::{Collection, Environment, Runner}, System};
+
+const USERNAME: &str = "test-max";
+const SEEDED_BALANCE: i32 = 1500;
+
+const COLLECTION: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../v1/postman/collection.json");
+const ENVIRONMENT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../v1/postman/environment.json");
+
+/// The retry queues of the services are flushed every 10 seconds
+const EVENTUALLY: Duration = Duration::from_secs(30);
+
+async fn purchase(system: &System, client: &Client, paid_from_balance: bool) -> CombinedPurchaseResponse {
+    let res = client.post(system.api("tickets"))
+        .header("Authorization", system.bearer(USERNAME))
+        .json(&json!({
+            "flightNumber": "AFL031",
+            "price": 1500,
+            "paidFromBalance": paid_from_balance
+        }))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    res.json().await.unwrap()
+}
+
+async fn balance(system: &System, client: &Client) -> i32 {
+    let res = client.get(system.api("privilege"))
+        .header("Authorization", system.bearer(USERNAME))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    res.json::<PrivilegeGet>().await.unwrap().balance
+}
+
+async fn ticket_status(system: &System, client: &Client, ticket_uid: uuid::Uuid) -> String {
+    let res = client.get(system.api(&format!("tickets/{}", ticket_uid)))
+        .header("Authorization", system.bearer(USERNAME))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    res.json::<TicketResponse>().await.unwrap().status
+}
+
+/// The money share of a ticket as the tickets service keeps it
+async fn payment(system: &System, client: &Client, ticket_uid: uuid::Uuid) -> Payment {
+    let res = client.get(format!("{}/tickets/{}/payment", system.tickets, ticket_uid))
+        .header("Authorization", system.bearer(USERNAME))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    res.json::<Payment>().await.unwrap()
+}
+
+async fn wait_for_balance(system: &System, client: &Client, expected: i32) {
+    let deadline = tokio::time::Instant::now() + EVENTUALLY;
+    loop {
+        let current = balance(system, client).await;
+        if current == expected {
+            return;
+        }
+        assert!(tokio::time::Instant::now() < deadline, "balance stayed at {} instead of {}", current, expected);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+#[tokio::test]
+async fn system_is_ready() {
+    let system = System::start().await.unwrap();
+    let res = reqwest::get(format!("{}/manage/health/ready", system.gateway)).await.unwrap();
+    assert_eq!(res.status(), 200);
+    let body: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(body["status"], "UP");
+
+    let res = Client::new().get(system.api("flights"))
+        .header("Authorization", system.bearer(USERNAME))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let page: FlightPageResponse = res.json().await.unwrap();
+    assert_eq!(page.items.len(), 1);
+    assert_eq!(page.items[0].flightNumber, "AFL031");
+    assert_eq!(page.items[0].fromAirportCode.as_deref(), Some("LED"));
+}
+
+#[tokio::test]
+async fn purchase_and_cancel() {
+    let system = System::start().await.unwrap();
+    let client = Client::new();
+
+    let purchase = purchase(&system, &client, false).await;
+    assert_eq!(purchase.status, "PAID");
+    assert_eq!(purchase.paidByMoney, 1500);
+    assert_eq!(purchase.paidByBonuses, 0);
+    assert!(purchase.privilege.balance > SEEDED_BALANCE);
+    assert_eq!(balance(&system, &client).await, purchase.privilege.balance);
+
+    let res = client.get(system.api("me"))
+        .header("Authorization", system.bearer(USERNAME))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let user: User = res.json().await.unwrap();
+    assert_eq!(user.tickets.len(), 1);
+    assert_eq!(user.tickets[0].ticketUid, purchase.ticketUid);
+
+    let res = client.delete(system.api(&format!("tickets/{}", purchase.ticketUid)))
+        .header("Authorization", system.bearer(USERNAME))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 204);
+    assert_eq!(ticket_status(&system, &client, purchase.ticketUid).await, "CANCELED");
+    wait_for_balance(&system, &client, SEEDED_BALANCE).await;
+
+    let res = client.delete(system.api(&format!("tickets/{}", purchase.ticketUid)))
+        .header("Authorization", system.bearer(USERNAME))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 400);
+}
+
+#[tokio::test]
+async fn purchase_with_bonuses() {
+    let system = System::start().await.unwrap();
+    let client = Client::new();
+
+    let purchase = purchase(&system, &client, true).await;
+    assert_eq!(purchase.status, "PAID");
+    assert!(purchase.paidByBonuses > 0);
+    assert_eq!(purchase.paidByMoney + purchase.paidByBonuses, 1500);
+    assert_eq!(purchase.privilege.balance, SEEDED_BALANCE - purchase.paidByBonuses);
+
+    let res = client.delete(system.api(&format!("tickets/{}", purchase.ticketUid)))
+        .header("Authorization", system.bearer(USERNAME))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 204);
+    wait_for_balance(&system, &client, SEEDED_BALANCE).await;
+}
+
+#[tokio::test]
+async fn flight_cancellation_refunds_tickets() {
+    let system = System::start().await.unwrap();
+    let client = Client::new();
+
+    let purchase = purchase(&system, &client, false).await;
+    assert_eq!(payment(&system, &client, purchase.ticketUid).await.status, "CAPTURED");
+    let res = client.post(format!("{}/flights/AFL031/status", system.flights))
+        .json(&json!({"status": "CANCELED"}))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 400);
+    let res = client.post(format!("{}/flights/AFL031/status", system.flights))
+        .header("Authorization", system.bearer(USERNAME))
+        .json(&json!({"status": "CANCELED"}))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 403);
+    let res = client.post(format!("{}/flights/AFL031/status", system.flights))
+        .header("Authorization", system.bearer_with_roles("operator", &["flights-admin"]))
+        .json(&json!({"status": "CANCELED"}))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 202);
+
+    wait_for_balance(&system, &client, SEEDED_BALANCE).await;
+    assert_eq!(ticket_status(&system, &client, purchase.ticketUid).await, "FLIGHT_CANCELED");
+    // The money share goes back with the flight as well
+    let refunded = payment(&system, &client, purchase.ticketUid).await;
+    assert_eq!(refunded.status, "REFUNDED");
+    assert_eq!(refunded.amount, Money::from_major(purchase.paidByMoney as i64, "RUB"));
+
+    let res = client.post(system.api("tickets"))
+        .header("Authorization", system.bearer(USERNAME))
+        .json(&json!({
+            "flightNumber": "AFL031",
+            "price": 1500,
+            "paidFromBalance": false
+        }))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 400);
+}
+
+#[tokio::test]
+async fn internal_events_need_the_secret() {
+    let system = System::start().await.unwrap();
+    let client = Client::new();
+    let purchase = purchase(&system, &client, false).await;
+
+    let res = client.post(format!("{}/events/refunds", system.bonuses))
+        .json(&json!({"ticket_uid": purchase.ticketUid}))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 401);
+    let res = client.post(format!("{}/events/flights", system.tickets))
+        .header("X-Service-Token", "guess")
+        .json(&json!({"flight_number": "AFL031", "status": "CANCELED", "datetime": "2021-10-08T20:00:00Z"}))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 401);
+    assert_eq!(ticket_status(&system, &client, purchase.ticketUid).await, "PAID");
+    assert_eq!(balance(&system, &client).await, purchase.privilege.balance);
+}
+
+#[tokio::test]
+async fn rejects_foreign_tokens() {
+    let system = System::start().await.unwrap();
+    let res = Client::new().get(system.api("me"))
+        .header("Authorization", jwtchecker::testing::bearer(USERNAME))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 401);
+}
+
+#[tokio::test]
+async fn postman_collection() {
+    // The course spec credits a flat 10% of the fare whatever the tier, unlike the shipped rules
+    let mut rules = RuleSet::default();
+    rules.accrual_percent.values_mut().for_each(|x| *x = 10.0);
+    let system = System::start_with_rules(rules).await.unwrap();
+    let collection = Collection::load(COLLECTION).unwrap();
+    let mut runner = Runner::new(&collection, &Environment::load(ENVIRONMENT).unwrap());
+    // The token is issued by the identity provider, which is not part of the system
+    runner.skip_requests_to("{{identityProviderUrl}}");
+    runner.set("authorizationToken", system.token(USERNAME));
+    runner.set("serviceUrl", system.gateway.clone());
+    runner.set("flightNumber", "AFL031");
+    let outcomes = runner.run(&collection).await;
+
+    assert_eq!(outcomes.len(), 16);
+    assert_eq!(outcomes.iter().filter(|x| x.skipped).map(|x| x.name.as_str()).collect::<Vec<_>>(), ["[auth] Get Token"]);
+    for outcome in &outcomes {
+        assert!(outcome.unsupported.is_empty(), "{}: unsupported {:?}", outcome.name, outcome.unsupported);
+        assert!(outcome.failures.is_empty(), "{}: {:?}", outcome.name, outcome.failures);
+    }
+}
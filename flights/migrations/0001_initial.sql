@@ -0,0 +1,33 @@
+-- Written to also adopt databases created before migrations were tracked,
+-- hence IF NOT EXISTS everywhere.
+CREATE TABLE IF NOT EXISTS airport
+(
+    id      SERIAL PRIMARY KEY,
+    name    VARCHAR(255),
+    city    VARCHAR(255),
+    country VARCHAR(255)
+);
+
+CREATE TABLE IF NOT EXISTS flight
+(
+    id              SERIAL PRIMARY KEY,
+    flight_number   VARCHAR(20)              NOT NULL,
+    datetime        TIMESTAMP WITH TIME ZONE NOT NULL,
+    from_airport_id INT REFERENCES airport (id),
+    to_airport_id   INT REFERENCES airport (id),
+    price           INT                      NOT NULL
+);
+
+ALTER TABLE airport
+    ADD COLUMN IF NOT EXISTS timezone  VARCHAR(64) NOT NULL DEFAULT 'UTC',
+    ADD COLUMN IF NOT EXISTS iata_code VARCHAR(3),
+    ADD COLUMN IF NOT EXISTS icao_code VARCHAR(4);
+
+ALTER TABLE flight
+    ADD COLUMN IF NOT EXISTS status              VARCHAR(20) NOT NULL DEFAULT 'SCHEDULED'
+        CHECK (status IN ('SCHEDULED', 'BOARDING', 'DEPARTED', 'ARRIVED', 'DELAYED', 'CANCELED')),
+    ADD COLUMN IF NOT EXISTS estimated_departure TIMESTAMP WITH TIME ZONE,
+    ADD COLUMN IF NOT EXISTS actual_departure    TIMESTAMP WITH TIME ZONE,
+    ADD COLUMN IF NOT EXISTS estimated_arrival   TIMESTAMP WITH TIME ZONE,
+    ADD COLUMN IF NOT EXISTS actual_arrival      TIMESTAMP WITH TIME ZONE,
+    ADD COLUMN IF NOT EXISTS currency            CHAR(3)     NOT NULL DEFAULT 'RUB';
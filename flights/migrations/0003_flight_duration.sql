@@ -0,0 +1,3 @@
+-- Planned time in the air, so a flight on schedule has a known arrival.
+-- Flights stored before have none and are not connected to other flights.
+ALTER TABLE flight ADD COLUMN IF NOT EXISTS duration_minutes INT CHECK (duration_minutes > 0);
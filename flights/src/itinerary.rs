@@ -0,0 +1,55 @@
+use chrono::Duration;
+use structs::{Flight, MAX_CONNECTION_HOURS, MIN_CONNECTION_MINUTES};
+
+pub const MAX_LEGS: usize = 3;
+
+fn bookable(flight: &Flight) -> bool {
+    matches!(flight.status.as_str(), "SCHEDULED" | "DELAYED")
+}
+
+fn extend(flights: &[Flight], legs: Vec<Flight>, to: &[i32], itineraries: &mut Vec<Vec<Flight>>) {
+    let last = legs.last().unwrap();
+    if to.contains(&last.to_airport_id) {
+        itineraries.push(legs);
+        return;
+    }
+    if legs.len() >= MAX_LEGS {
+        return;
+    }
+    // A connection can only be checked against a known or planned arrival time
+    let Some(arrival) = last.arrival() else {
+        return;
+    };
+    for next in flights {
+        if next.from_airport_id != last.to_airport_id || !bookable(next) {
+            continue;
+        }
+        if legs.iter().any(|x| x.from_airport_id == next.to_airport_id) {
+            continue;
+        }
+        // An itinerary is sold at one price, so its legs have to share a currency
+        if next.price.currency != last.price.currency {
+            continue;
+        }
+        let connection = next.datetime - arrival;
+        if connection < Duration::minutes(MIN_CONNECTION_MINUTES) || connection > Duration::hours(MAX_CONNECTION_HOURS) {
+            continue;
+        }
+        let mut legs = legs.clone();
+        legs.push(next.clone());
+        extend(flights, legs, to, itineraries);
+    }
+}
+
+/// Returns every itinerary of up to `MAX_LEGS` flights leading from one of the `from`
+/// airports to one of the `to` airports, ordered by departure and then by number of legs.
+pub fn find_itineraries(flights: &[Flight], from: &[i32], to: &[i32]) -> Vec<Vec<Flight>> {
+    let mut itineraries = vec![];
+    for flight in flights {
+        if from.contains(&flight.from_airport_id) && bookable(flight) {
+            extend(flights, vec![flight.clone()], to, &mut itineraries);
+        }
+    }
+    itineraries.sort_by_key(|x| (x[0].datetime, x.len()));
+    itineraries
+}
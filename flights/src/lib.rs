@@ -0,0 +1,28 @@
+pub mod itinerary;
+pub mod memory;
+pub mod migrations;
+pub mod repository;
+pub mod seed;
+pub mod server;
+#[cfg(test)]
+mod test;
+use repository::*;
+use structs::*;
+
+use custom_error::custom_error;
+
+custom_error!{pub FlightError
+    NotFoundError                            = "Ticket was not found",
+    UnknownAirportError{flight_number: String} = "Flight {flight_number} refers to an unknown airport",
+    UnknownTimezoneError{iata_code: String, timezone: String} = "Airport {iata_code} has an unknown timezone {timezone}",
+    InvalidDurationError{flight_number: String} = "Flight {flight_number} has a duration that is not positive",
+}
+
+#[macro_export]
+macro_rules! arc{
+    ($a:expr)=>{
+        {
+            std::sync::Arc::new(tokio::sync::Mutex::new($a))
+        }
+    }
+}
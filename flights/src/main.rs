@@ -1,35 +1,50 @@
-mod repository;
-mod server;
-#[cfg(test)]
-mod test;
-use repository::*;
-use structs::*;
-use server::*;
+use flights::{arc, seed};
+use flights::memory::MemoryRepository;
+use flights::repository::*;
+use flights::server::*;
 
+use jwtchecker::JWTChecker;
+use requester::Reqwester;
 use std::error::Error;
+use std::sync::Arc;
+use tokio::sync::Mutex;
 use std::env;
-use custom_error::custom_error;
 
-custom_error!{pub FlightError
-    NotFoundError                            = "Ticket was not found",
-}
-
-#[macro_export]
-macro_rules! arc{
-    ($a:expr)=>{
-        {
-            std::sync::Arc::new(tokio::sync::Mutex::new($a))
-        }
+/// `STORAGE=memory` keeps the data in the process, otherwise it is stored in `PSQL_CONNECTION`
+async fn open_repository() -> Result<Arc<Mutex<dyn FlightRepository>>, Box<dyn Error>> {
+    if env::var("STORAGE").as_deref() == Ok("memory") {
+        return Ok(arc!(MemoryRepository::default()));
     }
+    Ok(arc!(Repository::new(&env::var("PSQL_CONNECTION")?).await?))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>>{
-    let connection_str = env::var("PSQL_CONNECTION")?;
-    let port = env::var("SERVER_PORT")?.parse()?;
-    let repository = arc!(Repository::new(&connection_str).await?);
+    let _telemetry = telemetry::init("flights");
+    let repository = open_repository().await?;
     repository.lock().await.init().await?;
-    run_server(repository, port).await;
+    // `flights migrate` only brings the schema up to date
+    if env::args().nth(1).as_deref() == Some("migrate") {
+        return Ok(());
+    }
+    // `flights seed <fixtures.json>` loads airports and flights and exits
+    if env::args().nth(1).as_deref() == Some("seed") {
+        let path = env::args().nth(2).unwrap_or_else(|| "fixtures.json".to_owned());
+        seed::seed(repository, seed::Fixtures::load(&path)?).await?;
+        return Ok(());
+    }
+    // Lets a service with `STORAGE=memory` start with data
+    if let Ok(path) = env::var("FIXTURES") {
+        seed::seed(repository.clone(), seed::Fixtures::load(&path)?).await?;
+    }
+    let port = env::var("SERVER_PORT")?.parse()?;
+    let events = arc!(Events {
+        tickets: env::var("TICKETS_EVENTS_URL")?.to_owned(),
+        secret: env::var("EVENTS_SECRET").unwrap_or_default(),
+        requester: Box::new(Reqwester {}),
+        queue: vec![]
+    });
+    run_server(repository, events, arc!(JWTChecker::new(&env::var("RSA_PUB")?)), port).await?;
     Ok(())
 
 }
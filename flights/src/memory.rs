@@ -0,0 +1,86 @@
+use std::error::Error;
+use async_trait::async_trait;
+use database::Sequence;
+use structs::{Airport, Flight};
+use crate::{FlightError, FlightRepository};
+
+/// Airports and flights for `STORAGE=memory`, matched by IATA code and flight number
+/// when upserted the same way as the tables of `Repository`.
+#[derive(Default)]
+pub struct MemoryRepository {
+    airports: Vec<Airport>,
+    flights: Vec<Flight>,
+    airport_ids: Sequence,
+    flight_ids: Sequence
+}
+
+#[async_trait]
+impl FlightRepository for MemoryRepository {
+    async fn init(&mut self) ->  Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+    async fn ping(&mut self) ->  Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+    async fn list(&mut self) ->  Result<Vec<Flight>, Box<dyn Error>> {
+        Ok(self.flights.clone())
+    }
+    async fn get_flight(&mut self, flight_number: String) -> Result<Flight, Box<dyn Error>> {
+        self.flights.iter()
+            .find(|x| x.flight_number == flight_number)
+            .cloned()
+            .ok_or_else(|| FlightError::NotFoundError.into())
+    }
+    async fn get_airport(&mut self, airport_id: i32) -> Result<Airport, Box<dyn Error>> {
+        self.airports.iter()
+            .find(|x| x.id == airport_id)
+            .cloned()
+            .ok_or_else(|| FlightError::NotFoundError.into())
+    }
+    async fn list_airports(&mut self) -> Result<Vec<Airport>, Box<dyn Error>> {
+        Ok(self.airports.clone())
+    }
+    async fn update_flight(&mut self, flight: Flight) -> Result<(), Box<dyn Error>> {
+        for stored in self.flights.iter_mut().filter(|x| x.flight_number == flight.flight_number) {
+            stored.datetime = flight.datetime;
+            stored.status = flight.status.clone();
+            stored.estimated_departure = flight.estimated_departure;
+            stored.actual_departure = flight.actual_departure;
+            stored.estimated_arrival = flight.estimated_arrival;
+            stored.actual_arrival = flight.actual_arrival;
+        }
+        Ok(())
+    }
+    async fn upsert_airport(&mut self, airport: Airport) -> Result<i32, Box<dyn Error>> {
+        if let Some(stored) = self.airports.iter_mut().find(|x| x.iata_code.is_some() && x.iata_code == airport.iata_code) {
+            *stored = Airport { id: stored.id, ..airport };
+            return Ok(stored.id);
+        }
+        if let Some(stored) = self.airports.iter_mut().find(|x| x.iata_code.is_none() && x.name == airport.name && x.city == airport.city) {
+            *stored = Airport { id: stored.id, ..airport };
+            return Ok(stored.id);
+        }
+        let id = self.airport_ids.nextval();
+        self.airports.push(Airport { id, ..airport });
+        Ok(id)
+    }
+    async fn upsert_flight(&mut self, flight: Flight) -> Result<(), Box<dyn Error>> {
+        if [flight.from_airport_id, flight.to_airport_id].iter().any(|id| !self.airports.iter().any(|x| x.id == *id)) {
+            return Err(FlightError::NotFoundError.into());
+        }
+        let mut updated = false;
+        for stored in self.flights.iter_mut().filter(|x| x.flight_number == flight.flight_number) {
+            stored.datetime = flight.datetime;
+            stored.from_airport_id = flight.from_airport_id;
+            stored.to_airport_id = flight.to_airport_id;
+            stored.price = flight.price.clone();
+            stored.duration_minutes = flight.duration_minutes;
+            updated = true;
+        }
+        if !updated {
+            let id = self.flight_ids.nextval();
+            self.flights.push(Flight { id, ..flight });
+        }
+        Ok(())
+    }
+}
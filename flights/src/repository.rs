@@ -1,100 +1,168 @@
 use std::error::Error;
 use async_trait::async_trait;
-use tokio_postgres::{Client, NoTls};
-use structs::{Airport, Flight};
+use database::Database;
+use crate::migrations::MIGRATIONS;
+use tokio_postgres::Row;
+use structs::{Airport, Flight, Money};
 use crate::FlightError;
 
 #[async_trait]
 pub trait FlightRepository: Sync + Send {
     async fn init(&mut self) ->  Result<(), Box<dyn Error>>;
+    async fn ping(&mut self) ->  Result<(), Box<dyn Error>>;
     async fn list(&mut self) ->  Result<Vec<Flight>, Box<dyn Error>>;
     async fn get_flight(&mut self, flight_number: String) -> Result<Flight, Box<dyn Error>>;
     async fn get_airport(&mut self, airport_id: i32) -> Result<Airport, Box<dyn Error>>;
+    async fn list_airports(&mut self) -> Result<Vec<Airport>, Box<dyn Error>>;
+    async fn update_flight(&mut self, flight: Flight) -> Result<(), Box<dyn Error>>;
+    async fn upsert_airport(&mut self, airport: Airport) -> Result<i32, Box<dyn Error>>;
+    async fn upsert_flight(&mut self, flight: Flight) -> Result<(), Box<dyn Error>>;
+}
+
+const FLIGHT_COLUMNS: &str = "id, flight_number, datetime, from_airport_id, to_airport_id, price, currency, status,
+    duration_minutes, estimated_departure, actual_departure, estimated_arrival, actual_arrival";
+const AIRPORT_COLUMNS: &str = "id, name, city, country, timezone, iata_code, icao_code";
+
+fn flight_from_row(row: &Row) -> Flight {
+    Flight {
+        id: row.get("id"),
+        flight_number: row.get("flight_number"),
+        datetime: row.get("datetime"),
+        from_airport_id: row.get("from_airport_id"),
+        to_airport_id: row.get("to_airport_id"),
+        price: Money::new(row.get("price"), row.get("currency")),
+        status: row.get("status"),
+        duration_minutes: row.get("duration_minutes"),
+        estimated_departure: row.get("estimated_departure"),
+        actual_departure: row.get("actual_departure"),
+        estimated_arrival: row.get("estimated_arrival"),
+        actual_arrival: row.get("actual_arrival")
+    }
+}
+
+fn airport_from_row(row: &Row) -> Airport {
+    Airport {
+        id: row.get("id"),
+        name: row.get("name"),
+        city: row.get("city"),
+        country: row.get("country"),
+        timezone: row.get("timezone"),
+        iata_code: row.get("iata_code"),
+        icao_code: row.get("icao_code")
+    }
 }
 
 pub struct Repository {
-    client: Client
+    db: Database
 }
 
 impl Repository {
     pub async fn new(connection_str: &str) -> Result<Self,  Box<dyn Error>> {
-        let (client, connection) = tokio_postgres::connect(connection_str, NoTls).await?;
-        tokio::spawn(async move {
-            connection.await
-        });
-        Ok(Self { 
-            client
+        Ok(Self {
+            db: Database::connect(connection_str).await?
         })
     }
 }
 
 #[async_trait]
 impl FlightRepository for Repository {
+    #[tracing::instrument(skip_all)]
+    async fn ping(&mut self) ->  Result<(), Box<dyn Error>> {
+        self.db.client().await?.simple_query("SELECT 1").await?;
+        Ok(())
+    }
+    #[tracing::instrument(skip_all)]
     async fn init(&mut self) ->  Result<(), Box<dyn Error>> {
-        self.client.execute("
-            CREATE TABLE IF NOT EXISTS airport
-            (
-                id      SERIAL PRIMARY KEY,
-                name    VARCHAR(255),
-                city    VARCHAR(255),
-                country VARCHAR(255)
-            );
-        ", &[]).await?;
-        self.client.execute("
-            CREATE TABLE IF NOT EXISTS flight
-            (
-                id              SERIAL PRIMARY KEY,
-                flight_number   VARCHAR(20)              NOT NULL,
-                datetime        TIMESTAMP WITH TIME ZONE NOT NULL,
-                from_airport_id INT REFERENCES airport (id),
-                to_airport_id   INT REFERENCES airport (id),
-                price           INT                      NOT NULL
-            );
-        ", &[]).await?;
+        self.db.migrate(MIGRATIONS).await?;
         Ok(())
     }
+    #[tracing::instrument(skip_all)]
     async fn list(&mut self) ->  Result<Vec<Flight>, Box<dyn Error>> {
-        let mut list = vec![];
-        for row in self.client.query("
-            SELECT * FROM flight
-        ", &[]).await? {
-            list.push(Flight {
-                id: row.get(0),
-                flight_number: row.get(1),
-                datetime: row.get(2),
-                from_airport_id: row.get(3),
-                to_airport_id: row.get(4),
-                price: row.get(5)
-            })
-        }
-        Ok(list)
+        let rows = self.db.client().await?.query(&format!("
+            SELECT {} FROM flight
+        ", FLIGHT_COLUMNS), &[]).await?;
+        Ok(rows.iter().map(flight_from_row).collect())
     }
+    #[tracing::instrument(skip_all)]
     async fn get_flight(&mut self, flight_number: String) ->  Result<Flight, Box<dyn Error>> {
-        for row in self.client.query(&format!("
-        SELECT * FROM flight WHERE flight_number = '{}'
-        ", flight_number), &[]).await? {
-            return Ok(Flight {
-                id: row.get(0),
-                flight_number: row.get(1),
-                datetime: row.get(2),
-                from_airport_id: row.get(3),
-                to_airport_id: row.get(4),
-                price: row.get(5)
-           })
-        }
-        Err(FlightError::NotFoundError.into())
+        let Some(row) = self.db.client().await?.query_opt(&format!("
+            SELECT {} FROM flight WHERE flight_number = $1
+        ", FLIGHT_COLUMNS), &[&flight_number]).await? else {
+            return Err(FlightError::NotFoundError.into());
+        };
+        Ok(flight_from_row(&row))
     }
+    #[tracing::instrument(skip_all)]
     async fn get_airport(&mut self, airport_id: i32) ->  Result<Airport, Box<dyn Error>> {
-        for row in self.client.query(&format!("
-        SELECT * FROM airport WHERE id = '{}'
-        ", airport_id), &[]).await? {
-            return Ok(Airport {
-                id: row.get(0),
-                name: row.get(1),
-                city: row.get(2),
-                country: row.get(3)
-           })
+        let Some(row) = self.db.client().await?.query_opt(&format!("
+            SELECT {} FROM airport WHERE id = $1
+        ", AIRPORT_COLUMNS), &[&airport_id]).await? else {
+            return Err(FlightError::NotFoundError.into());
+        };
+        Ok(airport_from_row(&row))
+    }
+    #[tracing::instrument(skip_all)]
+    async fn list_airports(&mut self) ->  Result<Vec<Airport>, Box<dyn Error>> {
+        let rows = self.db.client().await?.query(&format!("
+            SELECT {} FROM airport
+        ", AIRPORT_COLUMNS), &[]).await?;
+        Ok(rows.iter().map(airport_from_row).collect())
+    }
+    #[tracing::instrument(skip_all)]
+    async fn update_flight(&mut self, flight: Flight) -> Result<(), Box<dyn Error>> {
+        self.db.client().await?.execute("
+            UPDATE flight SET
+                datetime = $1,
+                status = $2,
+                estimated_departure = $3,
+                actual_departure = $4,
+                estimated_arrival = $5,
+                actual_arrival = $6
+            WHERE flight_number = $7
+        ", &[&flight.datetime, &flight.status, &flight.estimated_departure, &flight.actual_departure,
+             &flight.estimated_arrival, &flight.actual_arrival, &flight.flight_number]).await?;
+        Ok(())
+    }
+    #[tracing::instrument(skip_all)]
+    async fn upsert_airport(&mut self, airport: Airport) -> Result<i32, Box<dyn Error>> {
+        let client = self.db.client().await?;
+        let updated = client.query_opt("
+            UPDATE airport SET name = $1, city = $2, country = $3, timezone = $4, icao_code = $6
+            WHERE iata_code = $5
+            RETURNING id
+        ", &[&airport.name, &airport.city, &airport.country, &airport.timezone, &airport.iata_code, &airport.icao_code]).await?;
+        if let Some(row) = updated {
+            return Ok(row.get(0));
         }
-        Err(FlightError::NotFoundError.into())
+        // Airports stored before they had codes are completed instead of added again
+        let completed = client.query_opt("
+            UPDATE airport SET country = $3, timezone = $4, iata_code = $5, icao_code = $6
+            WHERE id = (SELECT id FROM airport WHERE iata_code IS NULL AND name = $1 AND city = $2 ORDER BY id LIMIT 1)
+            RETURNING id
+        ", &[&airport.name, &airport.city, &airport.country, &airport.timezone, &airport.iata_code, &airport.icao_code]).await?;
+        if let Some(row) = completed {
+            return Ok(row.get(0));
+        }
+        let row = client.query_one("
+            INSERT INTO airport(name, city, country, timezone, iata_code, icao_code)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id
+        ", &[&airport.name, &airport.city, &airport.country, &airport.timezone, &airport.iata_code, &airport.icao_code]).await?;
+        Ok(row.get(0))
+    }
+    #[tracing::instrument(skip_all)]
+    async fn upsert_flight(&mut self, flight: Flight) -> Result<(), Box<dyn Error>> {
+        let client = self.db.client().await?;
+        let updated = client.execute("
+            UPDATE flight SET datetime = $2, from_airport_id = $3, to_airport_id = $4, price = $5, currency = $6, duration_minutes = $7
+            WHERE flight_number = $1
+        ", &[&flight.flight_number, &flight.datetime, &flight.from_airport_id, &flight.to_airport_id, &flight.price.amount, &flight.price.currency, &flight.duration_minutes]).await?;
+        if updated == 0 {
+            client.execute("
+                INSERT INTO flight(flight_number, datetime, from_airport_id, to_airport_id, price, currency, status, duration_minutes)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ", &[&flight.flight_number, &flight.datetime, &flight.from_airport_id, &flight.to_airport_id, &flight.price.amount, &flight.price.currency, &flight.status, &flight.duration_minutes]).await?;
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,109 @@
+use std::{collections::HashMap, error::Error, fs, sync::Arc};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use structs::{Airport, Flight, Money};
+use tokio::sync::Mutex;
+use crate::{FlightError, FlightRepository};
+
+fn default_timezone() -> String {
+    "UTC".to_owned()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AirportFixture {
+    pub name: String,
+    pub city: String,
+    pub country: Option<String>,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    pub iata_code: String,
+    pub icao_code: Option<String>
+}
+
+/// Flight between two airports of the fixtures, referred to by IATA code
+#[derive(Debug, Clone, Deserialize)]
+pub struct FlightFixture {
+    pub flight_number: String,
+    pub datetime: DateTime<Utc>,
+    pub from: String,
+    pub to: String,
+    /// In minor units of its currency, e.g. `{"amount": 150050, "currency": "RUB"}` for 1500.50 RUB
+    pub price: Money,
+    /// Planned time in the air. Flights without one can not be part of a connecting itinerary.
+    #[serde(default)]
+    pub duration_minutes: Option<i32>
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Fixtures {
+    #[serde(default)]
+    pub airports: Vec<AirportFixture>,
+    #[serde(default)]
+    pub flights: Vec<FlightFixture>
+}
+
+impl Fixtures {
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+/// Writes the fixtures over what is stored, matching airports by IATA code and
+/// flights by number, so seeding the same file again changes nothing.
+/// The statuses of existing flights are kept.
+pub async fn seed(repository: Arc<Mutex<dyn FlightRepository>>, fixtures: Fixtures) -> Result<(), Box<dyn Error>> {
+    // Flights may also refer to airports stored before
+    let mut airport_ids: HashMap<String, i32> = repository.lock().await.list_airports().await?
+        .into_iter()
+        .filter_map(|x| Some((x.iata_code?, x.id)))
+        .collect();
+    // Checked before anything is written, so a bad fixture leaves everything as it was
+    if let Some(airport) = fixtures.airports.iter().find(|x| x.timezone.parse::<Tz>().is_err()) {
+        return Err(FlightError::UnknownTimezoneError {
+            iata_code: airport.iata_code.clone(),
+            timezone: airport.timezone.clone()
+        }.into());
+    }
+    let known = |code: &String| airport_ids.contains_key(code) || fixtures.airports.iter().any(|x| &x.iata_code == code);
+    if let Some(flight) = fixtures.flights.iter().find(|x| !known(&x.from) || !known(&x.to)) {
+        return Err(FlightError::UnknownAirportError { flight_number: flight.flight_number.clone() }.into());
+    }
+    if let Some(flight) = fixtures.flights.iter().find(|x| x.duration_minutes.is_some_and(|x| x <= 0)) {
+        return Err(FlightError::InvalidDurationError { flight_number: flight.flight_number.clone() }.into());
+    }
+    let airports = fixtures.airports.len();
+    for airport in fixtures.airports {
+        let id = repository.lock().await.upsert_airport(Airport {
+            id: 0,
+            name: airport.name,
+            city: airport.city,
+            country: airport.country,
+            timezone: airport.timezone,
+            iata_code: Some(airport.iata_code.clone()),
+            icao_code: airport.icao_code
+        }).await?;
+        airport_ids.insert(airport.iata_code, id);
+    }
+    for flight in &fixtures.flights {
+        let (Some(from), Some(to)) = (airport_ids.get(&flight.from), airport_ids.get(&flight.to)) else {
+            return Err(FlightError::UnknownAirportError { flight_number: flight.flight_number.clone() }.into());
+        };
+        repository.lock().await.upsert_flight(Flight {
+            id: 0,
+            flight_number: flight.flight_number.clone(),
+            datetime: flight.datetime,
+            from_airport_id: *from,
+            to_airport_id: *to,
+            price: flight.price.clone(),
+            status: "SCHEDULED".to_owned(),
+            duration_minutes: flight.duration_minutes,
+            estimated_departure: None,
+            actual_departure: None,
+            estimated_arrival: None,
+            actual_arrival: None
+        }).await?;
+    }
+    tracing::info!(airports, flights = fixtures.flights.len(), "seeded fixtures");
+    Ok(())
+}
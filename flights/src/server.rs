@@ -1,28 +1,78 @@
-use std::{convert::Infallible, sync::Arc, error::Error};
+use std::{collections::HashMap, convert::Infallible, sync::Arc, error::Error};
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 use warp::{reply::{self, Reply}, Filter, Rejection};
+use jwtchecker::{JWTChecker, JWTError, SERVICE_TOKEN_HEADER};
+use requester::{RequestMethod, Requester};
 
-use crate::{Flight, FlightRepository, WebFlight, WebFlightPage};
+use crate::{itinerary::find_itineraries, Airport, Flight, Money, FlightRepository, FlightStatusEvent, FlightStatusPost, WebFlight, WebFlightPage, WebItinerary};
 
 pub type WebResult<T> = std::result::Result<T, Rejection>;
 
+/// Realm role of the operations staff allowed to change flight statuses
+const ADMIN_ROLE: &str = "flights-admin";
+
+fn format_utc(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+/// Timezone of the airport. Seeding rejects unknown zones, so one showing up here was stored
+/// by hand: it is reported and the airport's times are shown in UTC, with their offset.
+fn airport_timezone(airport: &Airport) -> Tz {
+    airport.timezone.parse::<Tz>().unwrap_or_else(|_| {
+        tracing::error!(airport_id = airport.id, timezone = %airport.timezone, "unknown airport timezone, showing times in UTC");
+        Tz::UTC
+    })
+}
+
+/// Formats the time in the airport's timezone with its offset
+fn format_local(time: DateTime<Utc>, airport: &Airport) -> String {
+    time.with_timezone(&airport_timezone(airport)).format("%Y-%m-%dT%H:%M:%S%:z").to_string()
+}
+
 async fn flight_to_webflight(flight: Flight, flight_repository: Arc<Mutex<dyn FlightRepository>>) -> Result<WebFlight, Box<dyn Error>> {
     let mut repo = flight_repository.lock().await;
-    let from_airport = repo.get_airport(flight.from_airport_id).await.unwrap();
+    let from_airport = repo.get_airport(flight.from_airport_id).await?;
     let to_airtport = repo.get_airport(flight.to_airport_id).await?;
     let from_airport_str = format!("{} {}", from_airport.city, from_airport.name);
     let to_airtport_str = format!("{} {}", to_airtport.city, to_airtport.name);
-    let flight_date = flight.datetime.format("%Y-%m-%d %H:%M").to_string();
+    // The legacy `date` has no zone marker, so it is the local time of departure, as on the ticket
+    let flight_date = flight.datetime.with_timezone(&airport_timezone(&from_airport)).format("%Y-%m-%d %H:%M").to_string();
+    let arrival = flight.arrival();
     Ok(WebFlight { 
         flightNumber: flight.flight_number,
         fromAirport: from_airport_str,
         toAirport: to_airtport_str,
+        fromAirportCode: from_airport.iata_code.clone(),
+        toAirportCode: to_airtport.iata_code.clone(),
         date: flight_date,
-        price: flight.price 
+        departureLocal: format_local(flight.datetime, &from_airport),
+        departureUtc: format_utc(flight.datetime),
+        arrivalLocal: arrival.map(|x| format_local(x, &to_airtport)),
+        arrivalUtc: arrival.map(format_utc),
+        price: flight.price,
+        status: flight.status,
+        durationMinutes: flight.duration_minutes,
+        estimatedDeparture: flight.estimated_departure.map(|x| format_local(x, &from_airport)),
+        actualDeparture: flight.actual_departure.map(|x| format_local(x, &from_airport)),
+        estimatedArrival: flight.estimated_arrival.map(|x| format_local(x, &to_airtport)),
+        actualArrival: flight.actual_arrival.map(|x| format_local(x, &to_airtport))
     })
 }
 
+fn transition_allowed(from: &str, to: &str) -> bool {
+    match from {
+        "SCHEDULED" => matches!(to, "BOARDING" | "DELAYED" | "CANCELED"),
+        // A delay can be lifted, which puts the flight back on schedule
+        "DELAYED" => matches!(to, "SCHEDULED" | "BOARDING" | "DELAYED" | "CANCELED"),
+        "BOARDING" => matches!(to, "DEPARTED" | "DELAYED" | "CANCELED"),
+        "DEPARTED" => to == "ARRIVED",
+        _ => false
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Paging {
     pub page: usize,
@@ -51,6 +101,53 @@ async fn list_handler(paging: Paging,
     })));
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionQuery {
+    pub from: String,
+    pub to: String,
+    pub date: Option<NaiveDate>,
+}
+
+async fn connections_handler(query: ConnectionQuery,
+                             flight_repository: Arc<Mutex<dyn FlightRepository>>) -> WebResult<Box<dyn Reply>> {
+    let Ok(airports) = flight_repository.lock().await.list_airports().await else {
+        let reply = warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
+        return Ok(Box::new(reply));
+    };
+    let Ok(mut flight_list) = flight_repository.lock().await.list().await else {
+        let reply = warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
+        return Ok(Box::new(reply));
+    };
+    let airports_in = |city: &str| -> Vec<i32> {
+        airports.iter()
+            .filter(|x| x.city.to_lowercase() == city.to_lowercase() || x.iata_code.as_deref() == Some(&city.to_uppercase()))
+            .map(|x| x.id)
+            .collect()
+    };
+    let from = airports_in(&query.from);
+    let to = airports_in(&query.to);
+    if let Some(date) = query.date {
+        flight_list.retain(|x| !from.contains(&x.from_airport_id) || x.datetime.date_naive() == date);
+    }
+    let mut web_itineraries = vec![];
+    for itinerary in find_itineraries(&flight_list, &from, &to) {
+        let mut web_flights = vec![];
+        for flight in itinerary {
+            let Ok(webflight) = flight_to_webflight(flight, flight_repository.clone()).await else {
+                let reply = warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
+                return Ok(Box::new(reply));
+            };
+            web_flights.push(webflight);
+        }
+        // The legs of an itinerary share a currency
+        web_itineraries.push(WebItinerary {
+            price: Money::new(web_flights.iter().map(|x| x.price.amount).sum(), &web_flights[0].price.currency),
+            flights: web_flights
+        });
+    }
+    Ok(Box::new(reply::json(&web_itineraries)))
+}
+
 async fn get_handler(id: String,
                      flight_repository: Arc<Mutex<dyn FlightRepository>>) -> WebResult<Box<dyn Reply>> {
     let Ok(flight) = flight_repository.lock().await.get_flight(id).await else {
@@ -64,45 +161,173 @@ async fn get_handler(id: String,
     return Ok(Box::new(reply::json(&flight)));
 }
 
-async fn health_check_handler() -> WebResult<impl Reply> {
-    return Ok(warp::reply::with_status("Up and running", warp::http::StatusCode::OK))
+async fn status_handler(id: String,
+                        auth_token: String,
+                        body: FlightStatusPost,
+                        flight_repository: Arc<Mutex<dyn FlightRepository>>,
+                        events: Arc<Mutex<Events>>,
+                        checker: Arc<Mutex<JWTChecker>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = checker.lock().await.clone();
+    match jwtchecker.decode_header_with_role(&auth_token, ADMIN_ROLE) {
+        Ok(_) => {},
+        Err(JWTError::MissingRole) => return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::FORBIDDEN))),
+        Err(_) => return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
+    }
+
+    let Ok(mut flight) = flight_repository.lock().await.get_flight(id.clone()).await else {
+        let reply = warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
+        return Ok(Box::new(reply));
+    };
+    if !matches!(body.status.as_str(), "SCHEDULED" | "BOARDING" | "DEPARTED" | "ARRIVED" | "DELAYED" | "CANCELED") {
+        let reply = warp::reply::with_status("Unknown flight status", warp::http::StatusCode::BAD_REQUEST);
+        return Ok(Box::new(reply));
+    }
+    if !transition_allowed(&flight.status, &body.status) {
+        let reply = warp::reply::with_status("Status transition is not allowed", warp::http::StatusCode::CONFLICT);
+        return Ok(Box::new(reply));
+    }
+    match body.status.as_str() {
+        "DELAYED" => {
+            let Some(datetime) = body.datetime else {
+                let reply = warp::reply::with_status("Delayed flight requires a new datetime", warp::http::StatusCode::BAD_REQUEST);
+                return Ok(Box::new(reply));
+            };
+            flight.datetime = datetime;
+            flight.estimated_departure = Some(datetime);
+            flight.estimated_arrival = body.arrival.or(flight.estimated_arrival);
+        },
+        "SCHEDULED" => {
+            if let Some(datetime) = body.datetime {
+                flight.datetime = datetime;
+            }
+            flight.estimated_departure = None;
+            flight.estimated_arrival = None;
+        },
+        "DEPARTED" => {
+            flight.actual_departure = Some(body.datetime.unwrap_or(Utc::now()));
+        },
+        "ARRIVED" => {
+            flight.actual_arrival = Some(body.arrival.unwrap_or(Utc::now()));
+        },
+        _ => {}
+    }
+    flight.status = body.status;
+    let Ok(_) = flight_repository.lock().await.update_flight(flight.clone()).await else {
+        let reply = warp::reply::with_status("Encountered an error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+        return Ok(Box::new(reply));
+    };
+    let queue = &mut events.lock().await.queue;
+    queue.push(FlightStatusEvent {
+        flight_number: id,
+        status: flight.status,
+        datetime: flight.datetime
+    });
+    telemetry::set_queue_depth("flight_status_events", queue.len());
+    Ok(Box::new(warp::reply::with_status("Status updated", warp::http::StatusCode::ACCEPTED)))
+}
+
+async fn health_check_handler(repository: Arc<Mutex<dyn FlightRepository>>) -> WebResult<impl Reply> {
+    let database = telemetry::check(async { repository.lock().await.ping().await }).await;
+    Ok(telemetry::Readiness::new(vec![("database", database)]).reply())
 }
 
 fn with_arc<T: Send + ?Sized>(arc: Arc<Mutex<T>>) -> impl Filter<Extract = (Arc<Mutex<T>>,), Error = Infallible> + Clone {
     warp::any().map(move || arc.clone())
 }
 
-pub fn router(repository: Arc<Mutex<dyn FlightRepository>>) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    let log = warp::log::custom(|info| {
-        eprintln!(
-            "{} {} {}",
-            info.method(),
-            info.path(),
-            info.status(),
-        );
-    });
+#[derive(Clone)]
+pub struct Events {
+    pub tickets: String,
+    /// Shared with tickets, which only accepts events carrying it
+    pub secret: String,
+    pub requester: Box<dyn Requester>,
+    pub queue: Vec<FlightStatusEvent>
+}
+
+pub fn router(repository: Arc<Mutex<dyn FlightRepository>>, events: Arc<Mutex<Events>>, checker: Arc<Mutex<JWTChecker>>) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     let list_route = warp::path!("flights")
+        .and(telemetry::route("/flights"))
         .and(warp::get())
         .and(warp::query::<Paging>())
         .and(with_arc(repository.clone()))
         .and_then(list_handler);
+    let connections_route = warp::path!("flights" / "connections")
+        .and(telemetry::route("/flights/connections"))
+        .and(warp::get())
+        .and(warp::query::<ConnectionQuery>())
+        .and(with_arc(repository.clone()))
+        .and_then(connections_handler);
     let get_route = warp::path!("flights" / String)
+        .and(telemetry::route("/flights/{flight_number}"))
         .and(warp::get())
         .and(with_arc(repository.clone()))
         .and_then(get_handler);
+    let status_route = warp::path!("flights" / String / "status")
+        .and(telemetry::route("/flights/{flight_number}/status"))
+        .and(warp::post())
+        .and(warp::header("Authorization"))
+        .and(warp::body::json())
+        .and(with_arc(repository.clone()))
+        .and(with_arc(events.clone()))
+        .and(with_arc(checker.clone()))
+        .and_then(status_handler);
     let health_route = warp::path!("manage" / "health")
+        .and(telemetry::route("/manage/health"))
+        .or(warp::path!("manage" / "health" / "ready").and(telemetry::route("/manage/health/ready")))
+        .unify()
         .and(warp::get())
+        .and(with_arc(repository.clone()))
         .and_then(health_check_handler);
-    let routes = get_route
+    let routes = connections_route
+        .or(get_route)
         .or(list_route)
+        .or(status_route)
         .or(health_route)
-        .with(log);
+        .or(telemetry::live_route())
+        .or(telemetry::metrics_route());
+
+    tokio::task::spawn(async move {
+        loop {
+            {
+                let mut requester;
+                let tickets_url;
+                let secret;
+                {
+                    let events_ptr = &events.lock().await;
+                    requester = events_ptr.requester.clone();
+                    tickets_url = events_ptr.tickets.clone();
+                    secret = events_ptr.secret.clone();
+                }
+                let queue = &mut events.lock().await.queue;
+                let mut successful = true;
+                while !queue.is_empty() && successful {
+                    tracing::info!("sending flight status event");
+                    let event = queue[0].clone();
+                    match requester.send(
+                        tickets_url.clone(),
+                        RequestMethod::POST,
+                        HashMap::from([
+                            (SERVICE_TOKEN_HEADER.to_owned(), secret.clone())
+                        ]),
+                        serde_json::to_string(&event).unwrap()).await {
+                        Ok(response) if response.code < 500 => {
+                            queue.remove(0);
+                        },
+                        _ => {
+                            successful = false;
+                        }
+                    }
+                }
+                telemetry::set_queue_depth("flight_status_events", queue.len());
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        }
+    });
+
     routes
 }
 
-pub async fn run_server(repository: Arc<Mutex<dyn FlightRepository>>, port: u16) {
-    let router = router(repository);
-    warp::serve(router)
-        .run(([0, 0, 0, 0], port))
-        .await
+pub async fn run_server(repository: Arc<Mutex<dyn FlightRepository>>, events: Arc<Mutex<Events>>, checker: Arc<Mutex<JWTChecker>>, port: u16) -> std::io::Result<()> {
+    let router = router(repository, events, checker);
+    telemetry::serve(router, port).await
 }
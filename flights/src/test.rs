@@ -1,8 +1,11 @@
 use std::error::Error;
-use crate::{arc, repository::FlightRepository, server::router};
+use crate::{arc, memory::MemoryRepository, itinerary::find_itineraries, migrations::MIGRATIONS, repository::{FlightRepository, Repository}, seed::{seed, Fixtures}, server::{router, Events}};
 use async_trait::async_trait;
+use database::{testing::TestDatabase, Database};
 use chrono::{Utc, TimeZone};
-use structs::{Flight, Airport};
+use proptest::prelude::*;
+use requester::{Requester, Response};
+use structs::{Flight, Airport, FlightStatusPost, Money};
 
 
 struct MockRepository {
@@ -25,6 +28,9 @@ impl FlightRepository for MockRepository {
     async fn init(&mut self) ->  Result<(), Box<dyn Error>> {
         Ok(())
     }
+    async fn ping(&mut self) ->  Result<(), Box<dyn Error>> {
+        Ok(())
+    }
     async fn list(&mut self) ->  Result<Vec<Flight>, Box<dyn Error>> {
         Ok(self.flights.clone().unwrap())
     }
@@ -34,33 +40,670 @@ impl FlightRepository for MockRepository {
     async fn get_airport(&mut self, airport_id: i32) -> Result<Airport, Box<dyn Error>> {
         Ok(self.airport.clone().unwrap())
     }
+    async fn list_airports(&mut self) -> Result<Vec<Airport>, Box<dyn Error>> {
+        Ok(self.airport.clone().into_iter().collect())
+    }
+    async fn update_flight(&mut self, flight: Flight) -> Result<(), Box<dyn Error>> {
+        if let Some(flights) = self.flights.as_mut() {
+            flights[0] = flight;
+        }
+        Ok(())
+    }
+    async fn upsert_airport(&mut self, airport: Airport) -> Result<i32, Box<dyn Error>> {
+        Ok(1)
+    }
+    async fn upsert_flight(&mut self, flight: Flight) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+// Never acknowledges events, so they stay in the queue for inspection
+#[derive(Clone)]
+struct MockRequester {}
+
+#[async_trait]
+impl Requester for MockRequester {
+    #[allow(unused_variables)]
+    async fn send(&mut self,
+                  url:String,
+                  method:requester::RequestMethod,
+                  headers:std::collections::HashMap<String,String>,
+                  body:String) -> Result<Response, Box<dyn Error>> {
+        Ok(Response {
+            code: 503,
+            body: "".to_owned(),
+            header: std::collections::HashMap::new()
+        })
+    }
+}
+
+fn create_events() -> std::sync::Arc<tokio::sync::Mutex<Events>> {
+    arc!(Events {
+        tickets: "".to_owned(),
+        secret: "secret".to_owned(),
+        requester: Box::new(MockRequester {}),
+        queue: vec![]
+    })
+}
+
+fn create_checker() -> std::sync::Arc<tokio::sync::Mutex<jwtchecker::JWTChecker>> {
+    arc!(jwtchecker::testing::checker())
+}
+
+fn admin() -> String {
+    jwtchecker::testing::bearer_with_roles("operator", &["flights-admin"])
+}
+
+fn create_repository(status: &str) -> MockRepository {
+    MockRepository::new(
+        Some(vec![
+             Flight {
+                 id: 1,
+                 flight_number: "AFL31".to_owned(),
+                 datetime: Utc.timestamp_opt(1589717600, 0).unwrap(),
+                 from_airport_id: 1,
+                 to_airport_id: 2,
+                 price: Money::from_major(1500, "RUB"),
+                 status: status.to_owned(),
+                 duration_minutes: None,
+                 estimated_departure: None,
+                 actual_departure: None,
+                 estimated_arrival: None,
+                 actual_arrival: None
+             }
+        ]),
+        Some(Airport {
+            id: 1,
+            name: "Airport".to_owned(),
+            city: "City".to_owned(),
+            country: Some("Country".to_owned()),
+            timezone: "Europe/Moscow".to_owned(),
+            iata_code: Some("SVO".to_owned()),
+            icao_code: Some("UUEE".to_owned())
+        }))
 }
 
 
 #[tokio::test]
 async fn get_flight() {
-    let repository = arc!(MockRepository::new(
-            Some(vec![
-                 Flight {
-                     id: 1,
-                     flight_number: "AFL31".to_owned(),
-                     datetime: Utc.timestamp_opt(1589717600, 0).unwrap(),
-                     from_airport_id: 1,
-                     to_airport_id: 2,
-                     price: 1500
-                 }
-            ]),
-            Some(Airport {
-                id: 1,
-                name: "Airport".to_owned(),
-                city: "City".to_owned(),
-                country: Some("Country".to_owned())
-            })));
-    let router = router(repository);
+    let repository = arc!(create_repository("SCHEDULED"));
+    let router = router(repository, create_events(), create_checker());
     let res = warp::test::request()
         .method("GET")
         .path("/flights?page=1&size=5")
         .reply(&router).await;
     assert_eq!(res.status(), 200);
-    assert_eq!(res.body(), "{\"page\":1,\"pageSize\":5,\"totalElements\":1,\"items\":[{\"flightNumber\":\"AFL31\",\"fromAirport\":\"City Airport\",\"toAirport\":\"City Airport\",\"date\":\"2020-05-17 12:13\",\"price\":1500}]}");
+    assert_eq!(res.body(), "{\"page\":1,\"pageSize\":5,\"totalElements\":1,\"items\":[{\"flightNumber\":\"AFL31\",\"fromAirport\":\"City Airport\",\"toAirport\":\"City Airport\",\"fromAirportCode\":\"SVO\",\"toAirportCode\":\"SVO\",\"date\":\"2020-05-17 15:13\",\"departureLocal\":\"2020-05-17T15:13:20+03:00\",\"departureUtc\":\"2020-05-17T12:13:20Z\",\"arrivalLocal\":null,\"arrivalUtc\":null,\"price\":{\"amount\":150000,\"currency\":\"RUB\"},\"status\":\"SCHEDULED\",\"durationMinutes\":null,\"estimatedDeparture\":null,\"actualDeparture\":null,\"estimatedArrival\":null,\"actualArrival\":null}]}");
+}
+
+#[tokio::test]
+async fn delay_flight() {
+    let repository = arc!(create_repository("SCHEDULED"));
+    let events = create_events();
+    let router = router(repository, events.clone(), create_checker());
+    let res = warp::test::request()
+        .method("POST")
+        .path("/flights/AFL31/status")
+        .header("Authorization", admin())
+        .json(&FlightStatusPost {
+            status: "DELAYED".to_owned(),
+            datetime: Some(Utc.timestamp_opt(1589721200, 0).unwrap()),
+            arrival: None
+        })
+        .reply(&router).await;
+    assert_eq!(res.status(), 202);
+    let queue = events.lock().await.queue.clone();
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue[0].status, "DELAYED");
+    let res = warp::test::request()
+        .method("GET")
+        .path("/flights/AFL31")
+        .reply(&router).await;
+    assert_eq!(res.body(), "{\"flightNumber\":\"AFL31\",\"fromAirport\":\"City Airport\",\"toAirport\":\"City Airport\",\"fromAirportCode\":\"SVO\",\"toAirportCode\":\"SVO\",\"date\":\"2020-05-17 16:13\",\"departureLocal\":\"2020-05-17T16:13:20+03:00\",\"departureUtc\":\"2020-05-17T13:13:20Z\",\"arrivalLocal\":null,\"arrivalUtc\":null,\"price\":{\"amount\":150000,\"currency\":\"RUB\"},\"status\":\"DELAYED\",\"durationMinutes\":null,\"estimatedDeparture\":\"2020-05-17T16:13:20+03:00\",\"actualDeparture\":null,\"estimatedArrival\":null,\"actualArrival\":null}");
+}
+
+#[tokio::test]
+async fn flight_status_requires_admin() {
+    let repository = arc!(create_repository("SCHEDULED"));
+    let events = create_events();
+    let router = router(repository, events.clone(), create_checker());
+    let status = FlightStatusPost {
+        status: "CANCELED".to_owned(),
+        datetime: None,
+        arrival: None
+    };
+    let res = warp::test::request()
+        .method("POST")
+        .path("/flights/AFL31/status")
+        .json(&status)
+        .reply(&router).await;
+    assert_eq!(res.status(), 400);
+    let res = warp::test::request()
+        .method("POST")
+        .path("/flights/AFL31/status")
+        .header("Authorization", jwtchecker::testing::bearer("someone"))
+        .json(&status)
+        .reply(&router).await;
+    assert_eq!(res.status(), 403);
+    let res = warp::test::request()
+        .method("POST")
+        .path("/flights/AFL31/status")
+        .header("Authorization", "Bearer forged")
+        .json(&status)
+        .reply(&router).await;
+    assert_eq!(res.status(), 401);
+    assert!(events.lock().await.queue.is_empty());
+}
+
+#[tokio::test]
+async fn lift_delay() {
+    let repository = arc!(create_repository("DELAYED"));
+    let events = create_events();
+    let router = router(repository.clone(), events.clone(), create_checker());
+    let res = warp::test::request()
+        .method("POST")
+        .path("/flights/AFL31/status")
+        .header("Authorization", admin())
+        .json(&FlightStatusPost {
+            status: "SCHEDULED".to_owned(),
+            datetime: None,
+            arrival: None
+        })
+        .reply(&router).await;
+    assert_eq!(res.status(), 202);
+    let queue = events.lock().await.queue.clone();
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue[0].status, "SCHEDULED");
+    let res = warp::test::request()
+        .method("GET")
+        .path("/flights/AFL31")
+        .reply(&router).await;
+    let flight: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(flight["status"], "SCHEDULED");
+    assert_eq!(flight["estimatedDeparture"], serde_json::Value::Null);
+}
+
+#[tokio::test]
+async fn unknown_flight_status() {
+    let repository = arc!(create_repository("SCHEDULED"));
+    let events = create_events();
+    let router = router(repository, events.clone(), create_checker());
+    let res = warp::test::request()
+        .method("POST")
+        .path("/flights/AFL31/status")
+        .header("Authorization", admin())
+        .json(&FlightStatusPost {
+            status: "LOST".to_owned(),
+            datetime: None,
+            arrival: None
+        })
+        .reply(&router).await;
+    assert_eq!(res.status(), 400);
+    assert!(events.lock().await.queue.is_empty());
+}
+
+#[tokio::test]
+async fn forbidden_flight_status_transition() {
+    let repository = arc!(create_repository("DEPARTED"));
+    let events = create_events();
+    let router = router(repository, events.clone(), create_checker());
+    let res = warp::test::request()
+        .method("POST")
+        .path("/flights/AFL31/status")
+        .header("Authorization", admin())
+        .json(&FlightStatusPost {
+            status: "CANCELED".to_owned(),
+            datetime: None,
+            arrival: None
+        })
+        .reply(&router).await;
+    assert_eq!(res.status(), 409);
+    assert!(events.lock().await.queue.is_empty());
+}
+
+#[tokio::test]
+async fn arrive_flight() {
+    let repository = arc!(create_repository("DEPARTED"));
+    let events = create_events();
+    let router = router(repository.clone(), events.clone(), create_checker());
+    let res = warp::test::request()
+        .method("POST")
+        .path("/flights/AFL31/status")
+        .header("Authorization", admin())
+        .json(&FlightStatusPost {
+            status: "ARRIVED".to_owned(),
+            datetime: None,
+            arrival: Some(Utc.timestamp_opt(1589728400, 0).unwrap())
+        })
+        .reply(&router).await;
+    assert_eq!(res.status(), 202);
+    let flight = repository.lock().await.flights.clone().unwrap()[0].clone();
+    assert_eq!(flight.status, "ARRIVED");
+    assert_eq!(flight.actual_arrival, Some(Utc.timestamp_opt(1589728400, 0).unwrap()));
+}
+
+fn leg(flight_number: &str, from: i32, to: i32, departure: i64, arrival: Option<i64>) -> Flight {
+    Flight {
+        id: 0,
+        flight_number: flight_number.to_owned(),
+        datetime: Utc.timestamp_opt(departure, 0).unwrap(),
+        from_airport_id: from,
+        to_airport_id: to,
+        price: Money::from_major(1000, "RUB"),
+        status: "SCHEDULED".to_owned(),
+        duration_minutes: None,
+        estimated_departure: None,
+        actual_departure: None,
+        estimated_arrival: arrival.map(|x| Utc.timestamp_opt(x, 0).unwrap()),
+        actual_arrival: None
+    }
+}
+
+#[test]
+fn connecting_itineraries() {
+    let flights = vec![
+        leg("AFL1", 1, 2, 0, Some(3600)),
+        leg("AFL2", 2, 3, 3600 + 50 * 60, Some(3 * 3600)),
+        // Connection is too short
+        leg("AFL3", 2, 3, 3600 + 30 * 60, Some(3 * 3600)),
+        leg("AFL4", 1, 3, 7200, None),
+        // Would fly back to the origin
+        leg("AFL5", 2, 1, 2 * 3600, Some(3 * 3600)),
+    ];
+    let itineraries = find_itineraries(&flights, &[1], &[3]);
+    let numbers: Vec<Vec<String>> = itineraries.iter()
+        .map(|x| x.iter().map(|f| f.flight_number.clone()).collect())
+        .collect();
+    assert_eq!(numbers, vec![
+        vec!["AFL1".to_owned(), "AFL2".to_owned()],
+        vec!["AFL4".to_owned()]
+    ]);
+}
+
+#[test]
+fn no_connection_without_arrival_time() {
+    let flights = vec![
+        leg("AFL1", 1, 2, 0, None),
+        leg("AFL2", 2, 3, 3 * 3600, Some(5 * 3600)),
+    ];
+    assert!(find_itineraries(&flights, &[1], &[3]).is_empty());
+}
+
+#[test]
+fn no_connection_across_currencies() {
+    let mut abroad = leg("THY2", 2, 3, 2 * 3600, Some(5 * 3600));
+    abroad.price = Money::from_major(100, "TRY");
+    let flights = vec![
+        leg("AFL1", 1, 2, 0, Some(3600)),
+        abroad,
+    ];
+    assert!(find_itineraries(&flights, &[1], &[3]).is_empty());
+}
+
+#[tokio::test]
+async fn connecting_itineraries_of_seeded_flights() {
+    let fixtures: Fixtures = serde_json::from_str(r#"{
+        "airports": [
+            {"name": "Пулково", "city": "Санкт-Петербург", "timezone": "Europe/Moscow", "iata_code": "LED"},
+            {"name": "Шереметьево", "city": "Москва", "timezone": "Europe/Moscow", "iata_code": "SVO"},
+            {"name": "Казань", "city": "Казань", "timezone": "Europe/Moscow", "iata_code": "KZN"}
+        ],
+        "flights": [
+            {"flight_number": "AFL031", "datetime": "2021-10-08T17:00:00Z", "from": "LED", "to": "SVO", "price": {"amount": 150050, "currency": "RUB"}, "duration_minutes": 90},
+            {"flight_number": "AFL032", "datetime": "2021-10-08T20:00:00Z", "from": "SVO", "to": "KZN", "price": {"amount": 200025, "currency": "RUB"}, "duration_minutes": 95}
+        ]
+    }"#).unwrap();
+    let repository = arc!(MemoryRepository::default());
+    seed(repository.clone(), fixtures).await.unwrap();
+    let router = router(repository, create_events(), create_checker());
+    let res = warp::test::request()
+        .method("GET")
+        .path("/flights/connections?from=LED&to=KZN")
+        .reply(&router).await;
+    assert_eq!(res.status(), 200);
+    let itineraries: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(itineraries.as_array().unwrap().len(), 1);
+    assert_eq!(itineraries[0]["flights"][0]["flightNumber"], "AFL031");
+    assert_eq!(itineraries[0]["flights"][0]["arrivalUtc"], "2021-10-08T18:30:00Z");
+    assert_eq!(itineraries[0]["flights"][1]["flightNumber"], "AFL032");
+    assert_eq!(itineraries[0]["price"], serde_json::json!({"amount": 350075, "currency": "RUB"}));
+    // A delay moves the planned arrival along, and lifting it keeps the flight connected
+    for (status, datetime) in [("DELAYED", 1_633_714_200), ("SCHEDULED", 1_633_712_400)] {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/flights/AFL031/status")
+            .header("Authorization", admin())
+            .json(&FlightStatusPost {
+                status: status.to_owned(),
+                datetime: Some(Utc.timestamp_opt(datetime, 0).unwrap()),
+                arrival: None
+            })
+            .reply(&router).await;
+        assert_eq!(res.status(), 202);
+        let res = warp::test::request()
+            .method("GET")
+            .path("/flights/connections?from=LED&to=KZN")
+            .reply(&router).await;
+        let itineraries: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(itineraries.as_array().unwrap().len(), 1);
+    }
+}
+
+#[tokio::test]
+async fn seed_non_positive_duration() {
+    let fixtures: Fixtures = serde_json::from_str(r#"{
+        "airports": [{"name": "Пулково", "city": "Санкт-Петербург", "timezone": "Europe/Moscow", "iata_code": "LED"}],
+        "flights": [{"flight_number": "AFL032", "datetime": "2021-10-08T17:00:00Z", "from": "LED", "to": "LED", "price": {"amount": 150000, "currency": "RUB"}, "duration_minutes": 0}]
+    }"#).unwrap();
+    let repository = arc!(MemoryRepository::default());
+    let error = seed(repository.clone(), fixtures).await.unwrap_err();
+    assert_eq!(error.to_string(), "Flight AFL032 has a duration that is not positive");
+    assert!(repository.lock().await.list_airports().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn health_checks() {
+    let repository = arc!(create_repository("SCHEDULED"));
+    let router = router(repository, create_events(), create_checker());
+    let res = warp::test::request()
+        .method("GET")
+        .path("/manage/health/live")
+        .reply(&router).await;
+    assert_eq!(res.status(), 200);
+    let res = warp::test::request()
+        .method("GET")
+        .path("/manage/health/ready")
+        .reply(&router).await;
+    assert_eq!(res.status(), 200);
+    assert!(String::from_utf8_lossy(res.body()).starts_with("{\"status\":\"UP\",\"components\":{\"database\":{\"status\":\"UP\""));
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres at TEST_DATABASE_URL"]
+async fn prices_keep_minor_units() {
+    let test_database = TestDatabase::create().await.unwrap();
+    // A flight stored while prices were whole major units
+    let mut database = Database::connect(&test_database.connection_str).await.unwrap();
+    database.migrate(&MIGRATIONS[..1]).await.unwrap();
+    database.client().await.unwrap().batch_execute("
+        INSERT INTO airport(name, city, country) VALUES ('Sheremetyevo', 'Moscow', 'Russia'), ('Pulkovo', 'St. Petersburg', 'Russia');
+        INSERT INTO flight(flight_number, datetime, from_airport_id, to_airport_id, price) VALUES ('AFL031', now(), 1, 2, 1500);
+        INSERT INTO flight(flight_number, datetime, from_airport_id, to_airport_id, price, currency) VALUES ('JAL031', now(), 1, 2, 1500, 'JPY');
+    ").await.unwrap();
+    let mut repository = Repository::new(&test_database.connection_str).await.unwrap();
+    repository.init().await.unwrap();
+    assert_eq!(repository.get_flight("AFL031".to_owned()).await.unwrap().price, Money::new(150_000, "RUB"));
+    assert_eq!(repository.get_flight("JAL031".to_owned()).await.unwrap().price, Money::new(1500, "JPY"));
+    let mut flight = repository.get_flight("AFL031".to_owned()).await.unwrap();
+    flight.price = Money::new(150_050, "RUB");
+    repository.upsert_flight(flight).await.unwrap();
+    assert_eq!(repository.get_flight("AFL031".to_owned()).await.unwrap().price, Money::new(150_050, "RUB"));
+    drop(database);
+    drop(repository);
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres at TEST_DATABASE_URL"]
+async fn seed_fixtures() {
+    let fixtures = Fixtures::load("fixtures.json").unwrap();
+    let test_database = TestDatabase::create().await.unwrap();
+    let repository = arc!(Repository::new(&test_database.connection_str).await.unwrap());
+    repository.lock().await.init().await.unwrap();
+    // The airports of booking-system/postgres.yml, stored without codes
+    Database::connect(&test_database.connection_str).await.unwrap().client().await.unwrap().batch_execute("
+        INSERT INTO airport(name, city, country) VALUES ('Шереметьево', 'Москва', 'Россия');
+        INSERT INTO airport(name, city, country) VALUES ('Пулково', 'Санкт-Петербург', 'Россия');
+    ").await.unwrap();
+    seed(repository.clone(), fixtures.clone()).await.unwrap();
+    seed(repository.clone(), fixtures).await.unwrap();
+    let airports = repository.lock().await.list_airports().await.unwrap();
+    assert_eq!(airports.len(), 2);
+    assert!(airports.iter().all(|x| x.iata_code.is_some()));
+    let flights = repository.lock().await.list().await.unwrap();
+    assert_eq!(flights.len(), 1);
+    assert_eq!(flights[0].flight_number, "AFL031");
+    assert_eq!(flights[0].price, Money::from_major(1500, "RUB"));
+    assert_eq!(flights[0].duration_minutes, Some(90));
+    drop(repository);
+}
+
+#[tokio::test]
+async fn seed_unknown_airport() {
+    let fixtures: Fixtures = serde_json::from_str(r#"{
+        "airports": [{"name": "Пулково", "city": "Санкт-Петербург", "timezone": "Europe/Moscow", "iata_code": "LED"}],
+        "flights": [{"flight_number": "AFL032", "datetime": "2021-10-08T17:00:00Z", "from": "LED", "to": "KZN", "price": {"amount": 150000, "currency": "RUB"}}]
+    }"#).unwrap();
+    let repository = arc!(MemoryRepository::default());
+    let error = seed(repository.clone(), fixtures).await.unwrap_err();
+    assert_eq!(error.to_string(), "Flight AFL032 refers to an unknown airport");
+    assert!(repository.lock().await.list_airports().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn seed_completes_airports_without_codes() {
+    let repository = arc!(MemoryRepository::default());
+    // Stored before airports had codes
+    repository.lock().await.upsert_airport(Airport {
+        id: 0,
+        name: "Пулково".to_owned(),
+        city: "Санкт-Петербург".to_owned(),
+        country: Some("Россия".to_owned()),
+        timezone: "UTC".to_owned(),
+        iata_code: None,
+        icao_code: None
+    }).await.unwrap();
+    seed(repository.clone(), Fixtures::load("fixtures.json").unwrap()).await.unwrap();
+    let airports = repository.lock().await.list_airports().await.unwrap();
+    assert_eq!(airports.len(), 2);
+    assert_eq!(airports[0].iata_code.as_deref(), Some("LED"));
+    assert_eq!(airports[0].timezone, "Europe/Moscow");
+}
+
+#[tokio::test]
+async fn seed_unknown_timezone() {
+    let fixtures: Fixtures = serde_json::from_str(r#"{
+        "airports": [
+            {"name": "Пулково", "city": "Санкт-Петербург", "timezone": "Europe/Moscow", "iata_code": "LED"},
+            {"name": "Казань", "city": "Казань", "timezone": "Europe/Kazan", "iata_code": "KZN"}
+        ]
+    }"#).unwrap();
+    let repository = arc!(MemoryRepository::default());
+    let error = seed(repository.clone(), fixtures).await.unwrap_err();
+    assert_eq!(error.to_string(), "Airport KZN has an unknown timezone Europe/Kazan");
+    assert!(repository.lock().await.list_airports().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn stored_unknown_timezone_shows_utc() {
+    let mut repository = create_repository("SCHEDULED");
+    repository.airport.as_mut().unwrap().timezone = "Mars/Olympus".to_owned();
+    let router = router(arc!(repository), create_events(), create_checker());
+    let res = warp::test::request()
+        .method("GET")
+        .path("/flights/AFL31")
+        .reply(&router).await;
+    let flight: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(flight["date"], "2020-05-17 12:13");
+    assert_eq!(flight["departureLocal"], "2020-05-17T12:13:20+00:00");
+}
+
+#[tokio::test]
+async fn local_times_follow_daylight_saving() {
+    let fixtures: Fixtures = serde_json::from_str(r#"{
+        "airports": [
+            {"name": "Brandenburg", "city": "Berlin", "timezone": "Europe/Berlin", "iata_code": "BER"},
+            {"name": "Kennedy", "city": "New York", "timezone": "America/New_York", "iata_code": "JFK"}
+        ],
+        "flights": [
+            {"flight_number": "WINTER", "datetime": "2021-01-15T09:00:00Z", "from": "BER", "to": "JFK", "price": {"amount": 50000, "currency": "RUB"}},
+            {"flight_number": "SUMMER", "datetime": "2021-07-15T09:00:00Z", "from": "BER", "to": "JFK", "price": {"amount": 50000, "currency": "RUB"}},
+            {"flight_number": "BEFORE", "datetime": "2021-03-28T00:30:00Z", "from": "BER", "to": "JFK", "price": {"amount": 50000, "currency": "RUB"}},
+            {"flight_number": "AFTER", "datetime": "2021-03-28T01:30:00Z", "from": "BER", "to": "JFK", "price": {"amount": 50000, "currency": "RUB"}}
+        ]
+    }"#).unwrap();
+    let repository = arc!(MemoryRepository::default());
+    seed(repository.clone(), fixtures).await.unwrap();
+    let router = router(repository, create_events(), create_checker());
+    let expected = [
+        ("WINTER", "2021-01-15 10:00", "2021-01-15T10:00:00+01:00"),
+        ("SUMMER", "2021-07-15 11:00", "2021-07-15T11:00:00+02:00"),
+        // Berlin moves its clocks from 02:00 to 03:00 at 01:00 UTC
+        ("BEFORE", "2021-03-28 01:30", "2021-03-28T01:30:00+01:00"),
+        ("AFTER", "2021-03-28 03:30", "2021-03-28T03:30:00+02:00")
+    ];
+    for (flight_number, date, departure) in expected {
+        let res = warp::test::request()
+            .method("GET")
+            .path(&format!("/flights/{}", flight_number))
+            .reply(&router).await;
+        let flight: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(flight["date"], date);
+        assert_eq!(flight["departureLocal"], departure);
+    }
+    // Estimated times are local to their airport: New York moved its clocks two weeks before Berlin
+    let res = warp::test::request()
+        .method("POST")
+        .path("/flights/AFTER/status")
+        .header("Authorization", admin())
+        .json(&FlightStatusPost {
+            status: "DELAYED".to_owned(),
+            datetime: Some("2021-03-28T02:30:00Z".parse().unwrap()),
+            arrival: Some("2021-03-28T11:30:00Z".parse().unwrap())
+        })
+        .reply(&router).await;
+    assert_eq!(res.status(), 202);
+    let res = warp::test::request()
+        .method("GET")
+        .path("/flights/AFTER")
+        .reply(&router).await;
+    let flight: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(flight["estimatedDeparture"], "2021-03-28T04:30:00+02:00");
+    assert_eq!(flight["estimatedArrival"], "2021-03-28T07:30:00-04:00");
+}
+
+#[tokio::test]
+async fn seeded_flights_in_memory() {
+    let repository = arc!(MemoryRepository::default());
+    seed(repository.clone(), Fixtures::load("fixtures.json").unwrap()).await.unwrap();
+    let router = router(repository, create_events(), create_checker());
+    let res = warp::test::request()
+        .method("GET")
+        .path("/flights/AFL031")
+        .reply(&router).await;
+    assert_eq!(res.status(), 200);
+    let flight: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(flight["fromAirport"], "Санкт-Петербург Пулково");
+    assert_eq!(flight["toAirport"], "Москва Шереметьево");
+    assert_eq!(flight["price"], serde_json::json!({"amount": 150000, "currency": "RUB"}));
+    let res = warp::test::request()
+        .method("POST")
+        .path("/flights/AFL031/status")
+        .header("Authorization", admin())
+        .json(&FlightStatusPost {
+            status: "CANCELED".to_owned(),
+            datetime: None,
+            arrival: None
+        })
+        .reply(&router).await;
+    assert_eq!(res.status(), 202);
+    let res = warp::test::request()
+        .method("GET")
+        .path("/flights/AFL031")
+        .reply(&router).await;
+    let flight: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(flight["status"], "CANCELED");
+}
+
+#[derive(Debug, Clone)]
+enum Operation {
+    Airport { code: Option<usize>, name: usize },
+    Flight { number: usize, from: usize, to: usize, price: i64 },
+    Status { number: usize, status: usize }
+}
+
+const CODES: [&str; 3] = ["SVO", "LED", "KZN"];
+const NAMES: [&str; 2] = ["Шереметьево", "Пулково"];
+const STATUSES: [&str; 4] = ["SCHEDULED", "DELAYED", "BOARDING", "CANCELED"];
+
+fn operation() -> impl Strategy<Value = Operation> {
+    prop_oneof![
+        (proptest::option::of(0..3usize), 0..2usize).prop_map(|(code, name)| Operation::Airport { code, name }),
+        (0..3usize, 0..4usize, 0..4usize, 1..1_000_000i64).prop_map(|(number, from, to, price)| Operation::Flight { number, from, to, price }),
+        (0..3usize, 0..4usize).prop_map(|(number, status)| Operation::Status { number, status })
+    ]
+}
+
+/// Airports as (id, name, iata_code) and flights as (id, number, from, to, price, status), by id
+type Outcome = (Vec<bool>, Vec<(i32, String, Option<String>)>, Vec<(i32, String, i32, i32, Money, String)>);
+
+async fn run_operations(repository: &mut dyn FlightRepository, operations: &[Operation]) -> Outcome {
+    let mut results = vec![];
+    for operation in operations {
+        let result = match operation {
+            Operation::Airport { code, name } => repository.upsert_airport(Airport {
+                id: 0,
+                name: NAMES[*name].to_owned(),
+                city: "Город".to_owned(),
+                country: None,
+                timezone: "Europe/Moscow".to_owned(),
+                iata_code: code.map(|x| CODES[x].to_owned()),
+                icao_code: None
+            }).await.is_ok(),
+            Operation::Flight { number, from, to, price } => {
+                let mut airports = repository.list_airports().await.unwrap();
+                airports.sort_by_key(|x| x.id);
+                match (airports.get(*from), airports.get(*to)) {
+                    (Some(from), Some(to)) => repository.upsert_flight(Flight {
+                        id: 0,
+                        flight_number: format!("AFL{:03}", number),
+                        datetime: Utc.timestamp_opt(1_633_712_400, 0).unwrap(),
+                        from_airport_id: from.id,
+                        to_airport_id: to.id,
+                        price: Money::new(*price, "RUB"),
+                        status: "SCHEDULED".to_owned(),
+                        duration_minutes: None,
+                        estimated_departure: None,
+                        actual_departure: None,
+                        estimated_arrival: None,
+                        actual_arrival: None
+                    }).await.is_ok(),
+                    _ => false
+                }
+            },
+            Operation::Status { number, status } => match repository.get_flight(format!("AFL{:03}", number)).await {
+                Ok(flight) => repository.update_flight(Flight { status: STATUSES[*status].to_owned(), ..flight }).await.is_ok(),
+                Err(_) => false
+            }
+        };
+        results.push(result);
+    }
+    let mut airports = repository.list_airports().await.unwrap();
+    airports.sort_by_key(|x| x.id);
+    let mut flights = repository.list().await.unwrap();
+    flights.sort_by_key(|x| x.id);
+    (results,
+     airports.into_iter().map(|x| (x.id, x.name, x.iata_code)).collect(),
+     flights.into_iter().map(|x| (x.id, x.flight_number, x.from_airport_id, x.to_airport_id, x.price, x.status)).collect())
+}
+
+#[test]
+#[ignore = "needs Postgres at TEST_DATABASE_URL"]
+fn memory_repository_matches_postgres() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let test_database = runtime.block_on(TestDatabase::create()).unwrap();
+    let postgres = std::cell::RefCell::new(runtime.block_on(async {
+        let mut repository = Repository::new(&test_database.connection_str).await.unwrap();
+        repository.init().await.unwrap();
+        (repository, Database::connect(&test_database.connection_str).await.unwrap())
+    }));
+    proptest!(ProptestConfig::with_cases(32), |(operations in proptest::collection::vec(operation(), 1..20))| {
+        let expected = runtime.block_on(run_operations(&mut MemoryRepository::default(), &operations));
+        let actual = runtime.block_on(async {
+            let (repository, database) = &mut *postgres.borrow_mut();
+            // Ids start over like they do in a new `MemoryRepository`
+            database.client().await.unwrap().batch_execute("TRUNCATE flight, airport RESTART IDENTITY").await.unwrap();
+            run_operations(repository, &operations).await
+        });
+        prop_assert_eq!(actual, expected);
+    });
 }
@@ -0,0 +1,21 @@
+pub mod server;
+#[cfg(test)]
+mod test;
+
+use custom_error::custom_error;
+
+custom_error!{pub GatewayError
+    TicketNotFoundError                             = "Ticket was not found",
+    FlightNotFoundError                             = "Flight was not found",
+    PaymentDeclinedError                            = "Payment was declined",
+    PaymentFailedError                              = "Payment could not be made",
+}
+
+#[macro_export]
+macro_rules! arc{
+    ($a:expr)=>{
+        {
+            std::sync::Arc::new(tokio::sync::Mutex::new($a))
+        }
+    }
+}
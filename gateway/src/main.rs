@@ -1,38 +1,19 @@
 use std::error::Error;
-use custom_error::custom_error;
 use std::env;
 use jwtchecker::JWTChecker;
 
 use requester::Reqwester;
-mod server;
-#[cfg(test)]
-mod test;
-use server::*;
-
-custom_error!{pub GatewayError
-    TicketNotFoundError                             = "Ticket was not found",
-    FlightNotFoundError                             = "Flight was not found",
-}
-
-#[macro_export]
-macro_rules! arc{
-    ($a:expr)=>{
-        {
-            std::sync::Arc::new(tokio::sync::Mutex::new($a))
-        }
-    }
-}
+use gateway::arc;
+use gateway::server::*;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>>{
+    let _telemetry = telemetry::init("gateway");
     let port = env::var("SERVER_PORT")?.parse()?;
-    run_server("api/v1", port, arc!(Services { 
-        flights: env::var("FLIGHTS_URL")?.to_owned(),
-        tickets: env::var("TICKETS_URL")?.to_owned(),
-        bonuses: env::var("BONUSES_URL")?.to_owned(),
-        requester: Box::new(Reqwester {}),
-        queue: vec![],
-        checker: JWTChecker::new(&env::var("RSA_PUB")?),
-    })).await;
+    run_server("api/v1", port, arc!(Services::new(&env::var("FLIGHTS_BASE_URL")?,
+                                                  &env::var("TICKETS_BASE_URL")?,
+                                                  &env::var("BONUSES_BASE_URL")?,
+                                                  Box::new(Reqwester {}),
+                                                  JWTChecker::new(&env::var("RSA_PUB")?)))).await?;
     Ok(())
 }
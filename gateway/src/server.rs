@@ -1,11 +1,12 @@
-use std::{collections::HashMap, convert::Infallible, sync::Arc, error::Error};
+use std::{collections::{HashMap, HashSet}, convert::Infallible, sync::Arc, error::Error};
 use serde::{Deserialize, Serialize};
 use tokio::{sync::Mutex};
 use uuid::Uuid;
 use warp::{reply::{self, Reply}, Filter, Rejection};
 use requester::{send_typed, RequestMethod, Requester, RequesterError};
-use structs::{Balance, CombinedPurchaseResponse, HealthCheckResponse, PrivilegeGet, PurchasePost, PurchaseResponse, Ticket, TicketPost, TicketPostBalance, TicketResponse, User, WebFlight, WebFlightPage};
+use structs::{Balance, BASE_CURRENCY, Money, Booking, BookingPost, BookingPurchaseResponse, BookingRequest, BookingResponse, BookingTicketPost, BookingTicketResponse, CombinedPurchaseResponse, Passenger, HealthCheckResponse, ItineraryPost, ItineraryPurchaseResponse, PaymentPost, PrivilegeGet, PrivilegeHistoryQuery, PurchasePost, PurchaseResponse, Ticket, TransferPost, TicketPost, TicketPostBalance, TicketResponse, User, valid_passenger, FlightPageResponse, FlightResponse, ItineraryResponse, WebFlight, WebFlightPage, WebItinerary};
 use jwtchecker::JWTChecker;
+use crate::GatewayError;
 
 pub type WebResult<T> = std::result::Result<T, Rejection>;
 
@@ -34,7 +35,80 @@ async fn list_flights_handler(auth_token: String,
         let reply = warp::reply::with_status("Internal error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
         return Ok(Box::new(reply));
     };
-    return Ok(Box::new(reply::json(&flights)));
+    return Ok(Box::new(reply::json(&FlightPageResponse {
+        page: flights.page,
+        pageSize: flights.pageSize,
+        totalElements: flights.totalElements,
+        items: flights.items.into_iter().map(webflight_to_response).collect()
+    })));
+}
+
+/// Prices travel between the services in minor units, the API only has whole units of a currency
+fn webflight_to_response(flight: WebFlight) -> FlightResponse {
+    FlightResponse {
+        flightNumber: flight.flightNumber,
+        fromAirport: flight.fromAirport,
+        toAirport: flight.toAirport,
+        fromAirportCode: flight.fromAirportCode,
+        toAirportCode: flight.toAirportCode,
+        date: flight.date,
+        departureLocal: flight.departureLocal,
+        departureUtc: flight.departureUtc,
+        arrivalLocal: flight.arrivalLocal,
+        arrivalUtc: flight.arrivalUtc,
+        price: flight.price.major() as i32,
+        currency: flight.price.currency,
+        status: flight.status,
+        durationMinutes: flight.durationMinutes,
+        estimatedDeparture: flight.estimatedDeparture,
+        actualDeparture: flight.actualDeparture,
+        estimatedArrival: flight.estimatedArrival,
+        actualArrival: flight.actualArrival
+    }
+}
+
+fn itinerary_to_response(itinerary: WebItinerary) -> ItineraryResponse {
+    ItineraryResponse {
+        price: itinerary.price.major() as i32,
+        currency: itinerary.price.currency,
+        flights: itinerary.flights.into_iter().map(webflight_to_response).collect()
+    }
+}
+
+fn flight_purchasable(status: &str) -> bool {
+    matches!(status, "SCHEDULED" | "DELAYED" | "BOARDING")
+}
+
+fn flight_refundable(status: &str) -> bool {
+    matches!(status, "SCHEDULED" | "DELAYED" | "BOARDING" | "CANCELED")
+}
+
+/// Whether the flight state forbids cancelling its ticket. Fails closed: when flights can not
+/// tell the state, the cancellation is refused rather than let through unchecked.
+async fn refund_blocked(requester: &mut Box<dyn Requester>,
+                        flights_url: &str,
+                        flight_number: &str) -> Option<warp::reply::WithStatus<&'static str>> {
+    let unavailable = warp::reply::with_status("Flight service unavailable, try again later", warp::http::StatusCode::SERVICE_UNAVAILABLE);
+    let response = match requester.send(
+        format!("{}/{}", flights_url, flight_number),
+        RequestMethod::GET,
+        HashMap::new(),
+        "".to_string()).await {
+        Ok(response) => response,
+        Err(_) => return Some(unavailable)
+    };
+    match response.code {
+        200 => {},
+        404 => return Some(warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND)),
+        _ => return Some(unavailable)
+    }
+    let Ok(flight) = serde_json::from_str::<WebFlight>(&response.body) else {
+        return Some(unavailable);
+    };
+    if !flight_refundable(&flight.status) {
+        return Some(warp::reply::with_status("Ticket can not be refunded after departure", warp::http::StatusCode::BAD_REQUEST));
+    }
+    None
 }
 
 async fn ticket_to_responseticket(ticket: Ticket, 
@@ -59,7 +133,8 @@ async fn ticket_to_responseticket(ticket: Ticket,
                 fromAirport: "Departure airport".to_owned(),
                 toAirport: "Destination airport".to_owned(),
                 status: ticket.status,
-                price: ticket.price
+                price: ticket.price.major() as i32,
+                currency: ticket.price.currency
             });
         }
     };
@@ -69,7 +144,8 @@ async fn ticket_to_responseticket(ticket: Ticket,
         flightNumber: flight.flightNumber,
         fromAirport: flight.fromAirport,
         toAirport: flight.toAirport,
-        price: flight.price,
+        price: flight.price.major() as i32,
+        currency: flight.price.currency,
         status: ticket.status
     })
 }
@@ -180,6 +256,52 @@ async fn get_privilege_handler(auth_token: String,
     return Ok(Box::new(reply::json(&privilege)));
 }
 
+async fn get_privilege_history_handler(auth_token: String,
+                                       query: PrivilegeHistoryQuery,
+                                       services: Arc<Mutex<Services>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = services.lock().await.checker.clone();
+    if jwtchecker.decode_header(&auth_token).is_err() {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
+    }
+
+    let privilege_url = services.lock().await.bonuses.clone();
+    let requester = &mut services.lock().await.requester.clone();
+    let Ok(response) = requester.send(
+        format!("{}/history?{}", privilege_url, serde_urlencoded::to_string(&query).unwrap()),
+        RequestMethod::GET,
+        HashMap::from([
+            ("Authorization".to_owned(), auth_token)
+        ]),
+        "".to_string()).await else {
+        return Ok(Box::new(warp::reply::with_status("Bonus Service unavailable", warp::http::StatusCode::SERVICE_UNAVAILABLE)));
+    };
+    let code = warp::http::StatusCode::from_u16(response.code).unwrap_or(warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+    Ok(Box::new(warp::reply::with_status(warp::reply::with_header(response.body, "Content-Type", "application/json"), code)))
+}
+
+async fn post_transfer_handler(auth_token: String,
+                               body: TransferPost,
+                               services: Arc<Mutex<Services>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = services.lock().await.checker.clone();
+    if jwtchecker.decode_header(&auth_token).is_err() {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
+    }
+
+    let privilege_url = services.lock().await.bonuses.clone();
+    let requester = &mut services.lock().await.requester.clone();
+    let Ok(response) = requester.send(
+        format!("{}/transfers", privilege_url),
+        RequestMethod::POST,
+        HashMap::from([
+            ("Authorization".to_owned(), auth_token)
+        ]),
+        serde_json::to_string(&body).unwrap()).await else {
+        return Ok(Box::new(warp::reply::with_status("Bonus Service unavailable", warp::http::StatusCode::SERVICE_UNAVAILABLE)));
+    };
+    let code = warp::http::StatusCode::from_u16(response.code).unwrap_or(warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+    Ok(Box::new(warp::reply::with_status(response.body, code)))
+}
+
 async fn get_user_handler(auth_token: String,
                                services: Arc<Mutex<Services>>) -> WebResult<Box<dyn Reply>> {
     let jwtchecker = services.lock().await.checker.clone();
@@ -190,9 +312,9 @@ async fn get_user_handler(auth_token: String,
     let privilege_url = services.lock().await.bonuses.clone();
     let ticket_url = services.lock().await.tickets.clone();
     let mut requester = &mut services.lock().await.requester.clone();
-    let privilege = match send_typed::<PrivilegeGet>(
+    let privilege = match send_typed::<Balance>(
         &mut requester,
-        privilege_url,
+        format!("{}?summary=true", privilege_url),
         RequestMethod::GET,
         HashMap::from([
             ("Authorization".to_owned(), auth_token.clone())
@@ -204,10 +326,9 @@ async fn get_user_handler(auth_token: String,
                 let reply = warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
                 return Ok(Box::new(reply));
             }
-            PrivilegeGet {
+            Balance {
                 balance: 0,
-                status: "Generic status".to_owned(),
-                history: vec![]
+                status: "Generic status".to_owned()
             }
         }
     };
@@ -238,10 +359,7 @@ async fn get_user_handler(auth_token: String,
     }
     return Ok(Box::new(reply::json(&User{
         tickets: response_tickets,
-        privilege: Balance {
-            balance: privilege.balance,
-            status: privilege.status
-        }
+        privilege
     })));
 }
 
@@ -257,13 +375,9 @@ async fn post_ticket_handler(auth_token: String,
     let ticket_url = services.lock().await.tickets.clone();
     let privilege_url = services.lock().await.bonuses.clone();
     let requester = &mut services.lock().await.requester.clone();
-    let ticket_post = TicketPost {
-        flight_number: body.flightNumber,
-        price: body.price
-    };
     let flight = match send_typed::<WebFlight>(
         requester,
-        format!("{}/{}", flight_url, ticket_post.flight_number),
+        format!("{}/{}", flight_url, body.flightNumber),
         RequestMethod::GET,
         HashMap::new(),
         "".to_owned()).await {
@@ -278,9 +392,16 @@ async fn post_ticket_handler(auth_token: String,
             return Ok(Box::new(reply));
         }
     };
-    if flight.price != ticket_post.price {
+    if !flight_purchasable(&flight.status) {
+        return Ok(Box::new(warp::reply::with_status("Flight is not available for purchase", warp::http::StatusCode::BAD_REQUEST)));
+    }
+    if flight.price.major() != body.price as i64 {
         return Ok(Box::new(warp::reply::with_status("Ticket price does not match", warp::http::StatusCode::BAD_REQUEST)));
     }
+    let ticket_post = TicketPost {
+        flight_number: body.flightNumber,
+        price: flight.price
+    };
     let ticket = match send_typed::<Ticket>(
         requester,
         ticket_url.clone(),
@@ -302,8 +423,10 @@ async fn post_ticket_handler(auth_token: String,
     };
     let privilege_post = PurchasePost {
         ticket_uid: ticket.ticket_uid,
-        price: ticket.price,
-        paid_from_balance: body.paidFromBalance
+        price: ticket.price.clone(),
+        paid_from_balance: body.paidFromBalance,
+        from_airport: flight.fromAirportCode.clone(),
+        to_airport: flight.toAirportCode.clone()
     };
     let Ok(purchase) = send_typed::<PurchaseResponse>(
         requester,
@@ -330,6 +453,16 @@ async fn post_ticket_handler(auth_token: String,
         }
         return Ok(Box::new(reply));
     };
+    if let Err(e) = charge_money(requester, services.clone(), &auth_token, ticket.ticket_uid, &purchase.paid_by_money).await {
+        let returned = rollback_tickets(requester, services.clone(), &auth_token, &[ticket], 1).await;
+        let reply = match (e, returned) {
+            (GatewayError::PaymentDeclinedError, true) => warp::reply::with_status("Payment was declined", warp::http::StatusCode::PAYMENT_REQUIRED),
+            (GatewayError::PaymentDeclinedError, false) => warp::reply::with_status("Payment was declined and the ticket could not be returned", warp::http::StatusCode::INTERNAL_SERVER_ERROR),
+            (_, true) => warp::reply::with_status("Failed to purchase tickets", warp::http::StatusCode::INTERNAL_SERVER_ERROR),
+            (_, false) => warp::reply::with_status("Failed to purchase tickets and to return them", warp::http::StatusCode::INTERNAL_SERVER_ERROR)
+        };
+        return Ok(Box::new(reply));
+    }
     let Ok(ticket) = ticket_to_responseticket(ticket, services.clone()).await else {
         let reply = warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
         return Ok(Box::new(reply));
@@ -341,7 +474,8 @@ async fn post_ticket_handler(auth_token: String,
         toAirport: ticket.toAirport,
         date: ticket.date,
         price: ticket.price,
-        paidByMoney: purchase.paid_by_money,
+        currency: ticket.currency,
+        paidByMoney: purchase.paid_by_money.major() as i32,
         paidByBonuses: purchase.paid_by_bonuses,
         status: ticket.status,
         privilege: Balance { 
@@ -384,6 +518,14 @@ async fn delete_ticket_handler(ticket_uid: Uuid,
         let reply = warp::reply::with_status("Ticket already canceled", warp::http::StatusCode::BAD_REQUEST);
         return Ok(Box::new(reply));
     }
+    if ticket.booking_uid.is_some() {
+        let reply = warp::reply::with_status("Ticket is part of an itinerary", warp::http::StatusCode::BAD_REQUEST);
+        return Ok(Box::new(reply));
+    }
+    let flights_url = services.lock().await.flights.clone();
+    if let Some(reply) = refund_blocked(requester, &flights_url, &ticket.flight_number).await {
+        return Ok(Box::new(reply));
+    }
     let response = match requester.send(
         format!("{}/{}/cancel", ticket_url, ticket_uid),
         RequestMethod::DELETE,
@@ -411,54 +553,672 @@ async fn delete_ticket_handler(ticket_uid: Uuid,
         ticket_uid,
         auth_token 
     });
+    telemetry::set_queue_depth("bonus_refunds", queue.len());
     
     let reply = warp::reply::with_status("", warp::http::StatusCode::NO_CONTENT);
     return Ok(Box::new(reply));
 }
 
-async fn health_check_handler(services: Arc<Mutex<Services>>) -> WebResult<Box<dyn Reply>> {
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ItineraryQuery {
+    pub from: String,
+    pub to: String,
+    pub date: Option<String>,
+}
+
+async fn list_itineraries_handler(auth_token: String,
+                                  query: ItineraryQuery,
+                                  services: Arc<Mutex<Services>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = services.lock().await.checker.clone();
+    if jwtchecker.decode_header(&auth_token).is_err() {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
+    }
+
+    let flight_url = services.lock().await.flights.clone();
+    let requester = &mut services.lock().await.requester.clone();
+    let Ok(itineraries) = send_typed::<Vec<WebItinerary>>(
+        requester,
+        format!("{}/connections?{}", flight_url, serde_urlencoded::to_string(&query).unwrap()),
+        RequestMethod::GET,
+        HashMap::new(),
+        "".to_string()).await else {
+        let reply = warp::reply::with_status("Internal error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+        return Ok(Box::new(reply));
+    };
+    Ok(Box::new(reply::json(&itineraries.into_iter().map(itinerary_to_response).collect::<Vec<_>>())))
+}
+
+/// Has the tickets service charge the money share of a ticket. Purchases paid in full with
+/// bonuses are not charged. The money is refunded by the tickets service whenever the ticket
+/// is canceled or deleted.
+async fn charge_money(requester: &mut Box<dyn Requester>,
+                      services: Arc<Mutex<Services>>,
+                      auth_token: &str,
+                      ticket_uid: Uuid,
+                      amount: &Money) -> Result<(), GatewayError> {
+    if amount.amount <= 0 {
+        return Ok(());
+    }
     let ticket_url = services.lock().await.tickets.clone();
-    let ticket_url = ticket_url[0..ticket_url.len() - 8].to_owned();
+    let response = requester.send(
+        format!("{}/{}/payment", ticket_url, ticket_uid),
+        RequestMethod::POST,
+        HashMap::from([
+            ("Authorization".to_owned(), auth_token.to_owned())
+        ]),
+        serde_json::to_string(&PaymentPost { amount: amount.clone() }).unwrap()).await;
+    match response {
+        Ok(response) if response.code == 200 => Ok(()),
+        Ok(response) if response.code == 402 => Err(GatewayError::PaymentDeclinedError),
+        _ => Err(GatewayError::PaymentFailedError)
+    }
+}
+
+/// Returns the bonus operations of the given tickets, queueing the refunds that could not
+/// be sent right away.
+async fn refund_purchases(requester: &mut Box<dyn Requester>,
+                          services: Arc<Mutex<Services>>,
+                          auth_token: &str,
+                          tickets: &[Ticket]) {
     let privilege_url = services.lock().await.bonuses.clone();
-    let privilege_url = privilege_url[0..privilege_url.len() - 10].to_owned();
+    for ticket in tickets {
+        let refunded = match requester.send(
+            format!("{}?ticket_uid={}", privilege_url, ticket.ticket_uid),
+            RequestMethod::DELETE,
+            HashMap::from([
+                ("Authorization".to_owned(), auth_token.to_owned())
+            ]),
+            "".to_owned()).await {
+            Ok(response) => response.code == 204,
+            Err(_) => false
+        };
+        if !refunded {
+            let queue = &mut services.lock().await.queue;
+            queue.push(QueuedRequest {
+                ticket_uid: ticket.ticket_uid,
+                auth_token: auth_token.to_owned()
+            });
+            telemetry::set_queue_depth("bonus_refunds", queue.len());
+        }
+    }
+}
+
+/// Undoes a failed ticket purchase: refunds the bonus operations of the first
+/// `purchased` tickets and deletes every created ticket.
+async fn rollback_tickets(requester: &mut Box<dyn Requester>,
+                            services: Arc<Mutex<Services>>,
+                            auth_token: &str,
+                            tickets: &[Ticket],
+                            purchased: usize) -> bool {
+    let ticket_url = services.lock().await.tickets.clone();
+    refund_purchases(requester, services.clone(), auth_token, &tickets[..purchased]).await;
+    let mut successful = true;
+    for ticket in tickets {
+        let deleted = match requester.send(
+            format!("{}/{}", ticket_url, ticket.ticket_uid),
+            RequestMethod::DELETE,
+            HashMap::from([
+                ("Authorization".to_owned(), auth_token.to_owned())
+            ]),
+            "".to_owned()).await {
+            Ok(response) => response.code == 204,
+            Err(_) => false
+        };
+        if !deleted {
+            tracing::error!(ticket_uid = %ticket.ticket_uid, "failed to delete ticket of a failed purchase");
+        }
+        successful = successful && deleted;
+    }
+    successful
+}
+
+async fn post_itinerary_handler(auth_token: String,
+                                body: ItineraryPost,
+                                services: Arc<Mutex<Services>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = services.lock().await.checker.clone();
+    if jwtchecker.decode_header(&auth_token).is_err() {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
+    }
+    if body.flightNumbers.is_empty() {
+        return Ok(Box::new(warp::reply::with_status("Itinerary has no flights", warp::http::StatusCode::BAD_REQUEST)));
+    }
+
     let flight_url = services.lock().await.flights.clone();
-    let flight_url = flight_url[0..flight_url.len() - 8].to_owned();
+    let booking_url = services.lock().await.bookings.clone();
+    let privilege_url = services.lock().await.bonuses.clone();
     let requester = &mut services.lock().await.requester.clone();
-    let tickets = if let Ok(ticket_response) = requester.send(
-        format!("{}/manage/health", ticket_url),
+    let mut flights = vec![];
+    for flight_number in &body.flightNumbers {
+        let flight = match send_typed::<WebFlight>(
+            requester,
+            format!("{}/{}", flight_url, flight_number),
+            RequestMethod::GET,
+            HashMap::new(),
+            "".to_owned()).await {
+            Ok(val) => val,
+            Err(e) => {
+                let reply = if e.is::<RequesterError>() {
+                    warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND)
+                }
+                else {
+                    warp::reply::with_status("Internal error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)
+                };
+                return Ok(Box::new(reply));
+            }
+        };
+        if !flight_purchasable(&flight.status) {
+            return Ok(Box::new(warp::reply::with_status("Flight is not available for purchase", warp::http::StatusCode::BAD_REQUEST)));
+        }
+        flights.push(flight);
+    }
+    let currency = flights[0].price.currency.clone();
+    if flights.iter().any(|x| x.price.currency != currency) {
+        return Ok(Box::new(warp::reply::with_status("Flights are priced in different currencies", warp::http::StatusCode::BAD_REQUEST)));
+    }
+    if !flights.windows(2).all(|legs| legs[0].connects_to(&legs[1])) {
+        return Ok(Box::new(warp::reply::with_status("Flights of the itinerary do not connect", warp::http::StatusCode::BAD_REQUEST)));
+    }
+    // Compared the way the itinerary was listed, its total in whole units
+    if Money::new(flights.iter().map(|x| x.price.amount).sum(), &currency).major() != body.price as i64 {
+        return Ok(Box::new(warp::reply::with_status("Itinerary price does not match", warp::http::StatusCode::BAD_REQUEST)));
+    }
+
+    // The itinerary is a booking without passengers, so it is canceled and tracked like one
+    let booking_post = BookingPost {
+        tickets: flights.iter().map(|flight| BookingTicketPost {
+            flight_number: flight.flightNumber.clone(),
+            price: flight.price.clone(),
+            passenger_name: None,
+            document_number: None
+        }).collect()
+    };
+    let Ok(booking) = send_typed::<Booking>(
+        requester,
+        booking_url.clone(),
+        RequestMethod::POST,
+        HashMap::from([
+            ("Authorization".to_owned(), auth_token.clone())
+        ]),
+        serde_json::to_string(&booking_post).unwrap()).await else {
+        return Ok(Box::new(warp::reply::with_status("Failed to purchase itinerary", warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
+    };
+    let mut purchases = vec![];
+    for (ticket, flight) in booking.tickets.iter().zip(flights.iter()) {
+        let privilege_post = PurchasePost {
+            ticket_uid: ticket.ticket_uid,
+            price: ticket.price.clone(),
+            paid_from_balance: body.paidFromBalance,
+            from_airport: flight.fromAirportCode.clone(),
+            to_airport: flight.toAirportCode.clone()
+        };
+        let Ok(purchase) = send_typed::<PurchaseResponse>(
+            requester,
+            privilege_url.clone(),
+            RequestMethod::POST,
+            HashMap::from([
+                ("Authorization".to_owned(), auth_token.clone())
+            ]),
+            serde_json::to_string(&privilege_post).unwrap()).await else {
+            return Ok(Box::new(failed_booking(requester, services.clone(), &auth_token, &booking, purchases.len()).await));
+        };
+        match charge_money(requester, services.clone(), &auth_token, ticket.ticket_uid, &purchase.paid_by_money).await {
+            Ok(_) => {},
+            Err(GatewayError::PaymentDeclinedError) => {
+                return Ok(Box::new(declined_booking(requester, services.clone(), &auth_token, &booking, purchases.len() + 1).await));
+            },
+            Err(_) => {
+                return Ok(Box::new(failed_booking(requester, services.clone(), &auth_token, &booking, purchases.len() + 1).await));
+            }
+        }
+        purchases.push(purchase);
+    }
+
+    let response_tickets = booking.tickets.iter().zip(flights.iter()).map(|(ticket, flight)| TicketResponse {
+        ticketUid: ticket.ticket_uid,
+        flightNumber: flight.flightNumber.clone(),
+        fromAirport: flight.fromAirport.clone(),
+        toAirport: flight.toAirport.clone(),
+        date: flight.date.clone(),
+        price: ticket.price.major() as i32,
+        currency: ticket.price.currency.clone(),
+        status: ticket.status.clone()
+    }).collect();
+    let last_purchase = purchases.last().unwrap().clone();
+    Ok(Box::new(reply::json(&ItineraryPurchaseResponse {
+        bookingUid: booking.booking_uid,
+        pnr: booking.pnr.clone(),
+        tickets: response_tickets,
+        price: body.price,
+        currency: currency.clone(),
+        paidByMoney: paid_by_money(&purchases, &currency),
+        paidByBonuses: purchases.iter().map(|x| x.paid_by_bonuses).sum(),
+        privilege: Balance {
+            balance: last_purchase.balance,
+            status: last_purchase.status
+        }
+    })))
+}
+
+async fn delete_itinerary_handler(booking_uid: Uuid,
+                                  auth_token: String,
+                                  services: Arc<Mutex<Services>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = services.lock().await.checker.clone();
+    if jwtchecker.decode_header(&auth_token).is_err() {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
+    }
+
+    let ticket_url = services.lock().await.tickets.clone();
+    let tickets_base = services.lock().await.tickets_base.clone();
+    let flights_url = services.lock().await.flights.clone();
+    let requester = &mut services.lock().await.requester.clone();
+    let mut tickets = match send_typed::<Vec<Ticket>>(
+        requester,
+        ticket_url.clone(),
         RequestMethod::GET,
-        HashMap::new(),
-        "".to_owned()).await { 
-        ticket_response.code == 200
-    } else {
-        false
+        HashMap::from([
+            ("Authorization".to_owned(), auth_token.clone())
+        ]),
+        "".to_string()).await {
+        Ok(val) => val,
+        Err(e) => {
+            let reply = if e.is::<RequesterError>() {
+                warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND)
+            }
+            else {
+                warp::reply::with_status("Internal error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)
+            };
+            return Ok(Box::new(reply));
+        }
+    };
+    tickets.retain(|x| x.booking_uid == Some(booking_uid));
+    if tickets.is_empty() {
+        return Ok(Box::new(warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND)));
+    }
+    // Tickets of canceled flights were already refunded when the flight was canceled
+    tickets.retain(|x| x.status == "PAID");
+    if tickets.is_empty() {
+        return Ok(Box::new(warp::reply::with_status("Itinerary already canceled", warp::http::StatusCode::BAD_REQUEST)));
+    }
+    for ticket in &tickets {
+        if let Some(reply) = refund_blocked(requester, &flights_url, &ticket.flight_number).await {
+            return Ok(Box::new(reply));
+        }
+    }
+    let Ok(response) = requester.send(
+        format!("{}/itineraries/{}/cancel", tickets_base, booking_uid),
+        RequestMethod::DELETE,
+        HashMap::from([
+            ("Authorization".to_owned(), auth_token.clone())
+        ]),
+        "".to_string()).await else {
+        return Ok(Box::new(warp::reply::with_status("Internal error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
+    };
+    match response.code {
+        204 => {},
+        404 => return Ok(Box::new(warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND))),
+        409 => return Ok(Box::new(warp::reply::with_status("Itinerary already canceled", warp::http::StatusCode::BAD_REQUEST))),
+        _ => return Ok(Box::new(warp::reply::with_status("Internal error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)))
+    }
+    let queue = &mut services.lock().await.queue;
+    for ticket in tickets {
+        queue.push(QueuedRequest {
+            ticket_uid: ticket.ticket_uid,
+            auth_token: auth_token.clone()
+        });
+    }
+    telemetry::set_queue_depth("bonus_refunds", queue.len());
+    Ok(Box::new(warp::reply::with_status("", warp::http::StatusCode::NO_CONTENT)))
+}
+
+/// Total paid with money over several purchases, in whole units of their common currency
+fn paid_by_money(purchases: &[PurchaseResponse], currency: &str) -> i32 {
+    Money::new(purchases.iter().map(|x| x.paid_by_money.amount).sum(), currency).major() as i32
+}
+
+async fn booking_to_response(booking: Booking,
+                             services: Arc<Mutex<Services>>) -> Result<BookingResponse, Box<dyn Error>> {
+    let mut tickets = vec![];
+    let mut currency = BASE_CURRENCY.to_owned();
+    for ticket in booking.tickets {
+        let passenger = Passenger {
+            name: ticket.passenger_name.clone().unwrap_or_default(),
+            documentNumber: ticket.document_number.clone().unwrap_or_default()
+        };
+        let ticket = ticket_to_responseticket(ticket, services.clone()).await?;
+        currency = ticket.currency;
+        tickets.push(BookingTicketResponse {
+            ticketUid: ticket.ticketUid,
+            flightNumber: ticket.flightNumber,
+            fromAirport: ticket.fromAirport,
+            toAirport: ticket.toAirport,
+            date: ticket.date,
+            price: ticket.price,
+            status: ticket.status,
+            passenger
+        });
+    }
+    Ok(BookingResponse {
+        pnr: booking.pnr,
+        bookingUid: booking.booking_uid,
+        status: booking.status,
+        price: tickets.iter().map(|x| x.price).sum(),
+        currency,
+        tickets
+    })
+}
+
+/// Undoes a partially purchased booking: refunds the first `purchased` tickets and deletes the booking.
+async fn rollback_booking(requester: &mut Box<dyn Requester>,
+                          services: Arc<Mutex<Services>>,
+                          auth_token: &str,
+                          booking: &Booking,
+                          purchased: usize) -> bool {
+    let booking_url = services.lock().await.bookings.clone();
+    refund_purchases(requester, services.clone(), auth_token, &booking.tickets[..purchased]).await;
+    let deleted = match requester.send(
+        format!("{}/{}", booking_url, booking.pnr),
+        RequestMethod::DELETE,
+        HashMap::from([
+            ("Authorization".to_owned(), auth_token.to_owned())
+        ]),
+        "".to_owned()).await {
+        Ok(response) => response.code == 204,
+        Err(_) => false
     };
-    let bonuses = if let Ok(privilege_response) = requester.send(
-        format!("{}/manage/health", privilege_url),
+    if !deleted {
+        tracing::error!(pnr = %booking.pnr, "failed to delete booking of a failed purchase");
+    }
+    deleted
+}
+
+/// Rolls back a booking whose purchase failed, telling whether its tickets could be returned
+async fn failed_booking(requester: &mut Box<dyn Requester>,
+                        services: Arc<Mutex<Services>>,
+                        auth_token: &str,
+                        booking: &Booking,
+                        purchased: usize) -> warp::reply::WithStatus<&'static str> {
+    if !rollback_booking(requester, services, auth_token, booking, purchased).await {
+        return warp::reply::with_status("Failed to purchase tickets and to return them", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    warp::reply::with_status("Failed to purchase tickets", warp::http::StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Rolls back a booking whose payment was declined
+async fn declined_booking(requester: &mut Box<dyn Requester>,
+                          services: Arc<Mutex<Services>>,
+                          auth_token: &str,
+                          booking: &Booking,
+                          purchased: usize) -> warp::reply::WithStatus<&'static str> {
+    if !rollback_booking(requester, services, auth_token, booking, purchased).await {
+        return warp::reply::with_status("Payment was declined and the tickets could not be returned", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    warp::reply::with_status("Payment was declined", warp::http::StatusCode::PAYMENT_REQUIRED)
+}
+
+async fn post_booking_handler(auth_token: String,
+                              body: BookingRequest,
+                              services: Arc<Mutex<Services>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = services.lock().await.checker.clone();
+    if jwtchecker.decode_header(&auth_token).is_err() {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
+    }
+    if body.flightNumbers.is_empty() || body.passengers.is_empty() {
+        return Ok(Box::new(warp::reply::with_status("Booking needs at least one flight and one passenger", warp::http::StatusCode::BAD_REQUEST)));
+    }
+    if !body.passengers.iter().all(|x| valid_passenger(&x.name, &x.documentNumber)) {
+        return Ok(Box::new(warp::reply::with_status("Invalid passenger name or document number", warp::http::StatusCode::BAD_REQUEST)));
+    }
+    let documents: HashSet<String> = body.passengers.iter().map(|x| x.documentNumber.trim().to_uppercase()).collect();
+    if documents.len() != body.passengers.len() {
+        return Ok(Box::new(warp::reply::with_status("Passengers share a document number", warp::http::StatusCode::BAD_REQUEST)));
+    }
+
+    let flight_url = services.lock().await.flights.clone();
+    let booking_url = services.lock().await.bookings.clone();
+    let privilege_url = services.lock().await.bonuses.clone();
+    let requester = &mut services.lock().await.requester.clone();
+    let mut booking_post = BookingPost { tickets: vec![] };
+    let mut flights = HashMap::new();
+    for flight_number in &body.flightNumbers {
+        let flight = match send_typed::<WebFlight>(
+            requester,
+            format!("{}/{}", flight_url, flight_number),
+            RequestMethod::GET,
+            HashMap::new(),
+            "".to_owned()).await {
+            Ok(val) => val,
+            Err(e) => {
+                let reply = if e.is::<RequesterError>() {
+                    warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND)
+                }
+                else {
+                    warp::reply::with_status("Internal error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)
+                };
+                return Ok(Box::new(reply));
+            }
+        };
+        if !flight_purchasable(&flight.status) {
+            return Ok(Box::new(warp::reply::with_status("Flight is not available for purchase", warp::http::StatusCode::BAD_REQUEST)));
+        }
+        if flights.values().any(|x: &WebFlight| x.price.currency != flight.price.currency) {
+            return Ok(Box::new(warp::reply::with_status("Flights are priced in different currencies", warp::http::StatusCode::BAD_REQUEST)));
+        }
+        for passenger in &body.passengers {
+            booking_post.tickets.push(BookingTicketPost {
+                flight_number: flight.flightNumber.clone(),
+                price: flight.price.clone(),
+                passenger_name: Some(passenger.name.trim().to_owned()),
+                document_number: Some(passenger.documentNumber.trim().to_owned())
+            });
+        }
+        flights.insert(flight.flightNumber.clone(), flight);
+    }
+    if booking_post.tickets.iter().map(|x| x.price.major()).sum::<i64>() != body.price as i64 {
+        return Ok(Box::new(warp::reply::with_status("Booking price does not match", warp::http::StatusCode::BAD_REQUEST)));
+    }
+
+    let Ok(booking) = send_typed::<Booking>(
+        requester,
+        booking_url.clone(),
+        RequestMethod::POST,
+        HashMap::from([
+            ("Authorization".to_owned(), auth_token.clone())
+        ]),
+        serde_json::to_string(&booking_post).unwrap()).await else {
+        return Ok(Box::new(warp::reply::with_status("Failed to create booking", warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
+    };
+    let mut purchases = vec![];
+    for ticket in &booking.tickets {
+        let flight: Option<&WebFlight> = flights.get(&ticket.flight_number);
+        let privilege_post = PurchasePost {
+            ticket_uid: ticket.ticket_uid,
+            price: ticket.price.clone(),
+            paid_from_balance: body.paidFromBalance,
+            from_airport: flight.and_then(|x| x.fromAirportCode.clone()),
+            to_airport: flight.and_then(|x| x.toAirportCode.clone())
+        };
+        let Ok(purchase) = send_typed::<PurchaseResponse>(
+            requester,
+            privilege_url.clone(),
+            RequestMethod::POST,
+            HashMap::from([
+                ("Authorization".to_owned(), auth_token.clone())
+            ]),
+            serde_json::to_string(&privilege_post).unwrap()).await else {
+            return Ok(Box::new(failed_booking(requester, services.clone(), &auth_token, &booking, purchases.len()).await));
+        };
+        match charge_money(requester, services.clone(), &auth_token, ticket.ticket_uid, &purchase.paid_by_money).await {
+            Ok(_) => {},
+            Err(GatewayError::PaymentDeclinedError) => {
+                return Ok(Box::new(declined_booking(requester, services.clone(), &auth_token, &booking, purchases.len() + 1).await));
+            },
+            Err(_) => {
+                return Ok(Box::new(failed_booking(requester, services.clone(), &auth_token, &booking, purchases.len() + 1).await));
+            }
+        }
+        purchases.push(purchase);
+    }
+
+    let Ok(response) = booking_to_response(booking, services.clone()).await else {
+        let reply = warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
+        return Ok(Box::new(reply));
+    };
+    let last_purchase = purchases.last().unwrap().clone();
+    Ok(Box::new(reply::json(&BookingPurchaseResponse {
+        pnr: response.pnr,
+        bookingUid: response.bookingUid,
+        status: response.status,
+        price: response.price,
+        paidByMoney: paid_by_money(&purchases, &response.currency),
+        currency: response.currency,
+        tickets: response.tickets,
+        paidByBonuses: purchases.iter().map(|x| x.paid_by_bonuses).sum(),
+        privilege: Balance {
+            balance: last_purchase.balance,
+            status: last_purchase.status
+        }
+    })))
+}
+
+async fn get_booking_handler(pnr: String,
+                             auth_token: String,
+                             services: Arc<Mutex<Services>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = services.lock().await.checker.clone();
+    if jwtchecker.decode_header(&auth_token).is_err() {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
+    }
+
+    let booking_url = services.lock().await.bookings.clone();
+    let requester = &mut services.lock().await.requester.clone();
+    let booking = match send_typed::<Booking>(
+        requester,
+        format!("{}/{}", booking_url, pnr),
         RequestMethod::GET,
-        HashMap::new(),
-        "".to_owned()).await { 
-        privilege_response.code == 200
-    } else {
-        false
+        HashMap::from([
+            ("Authorization".to_owned(), auth_token)
+        ]),
+        "".to_string()).await {
+        Ok(val) => val,
+        Err(e) => {
+            let reply = if e.is::<RequesterError>() {
+                warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND)
+            }
+            else {
+                warp::reply::with_status("Internal error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)
+            };
+            return Ok(Box::new(reply));
+        }
+    };
+    let Ok(response) = booking_to_response(booking, services.clone()).await else {
+        let reply = warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
+        return Ok(Box::new(reply));
     };
-    let flights = if let Ok(flight_response) = requester.send(
-        format!("{}/manage/health", flight_url),
+    Ok(Box::new(reply::json(&response)))
+}
+
+async fn delete_booking_handler(pnr: String,
+                                auth_token: String,
+                                services: Arc<Mutex<Services>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = services.lock().await.checker.clone();
+    if jwtchecker.decode_header(&auth_token).is_err() {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
+    }
+
+    let booking_url = services.lock().await.bookings.clone();
+    let flights_url = services.lock().await.flights.clone();
+    let requester = &mut services.lock().await.requester.clone();
+    let booking = match send_typed::<Booking>(
+        requester,
+        format!("{}/{}", booking_url, pnr),
         RequestMethod::GET,
-        HashMap::new(),
-        "".to_owned()).await { 
-        flight_response.code == 200
-    } else {
-        false
+        HashMap::from([
+            ("Authorization".to_owned(), auth_token.clone())
+        ]),
+        "".to_string()).await {
+        Ok(val) => val,
+        Err(e) => {
+            let reply = if e.is::<RequesterError>() {
+                warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND)
+            }
+            else {
+                warp::reply::with_status("Internal error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)
+            };
+            return Ok(Box::new(reply));
+        }
+    };
+    // A booking hit by a flight cancellation can still be canceled for its other flights
+    if booking.status != "CONFIRMED" && booking.status != "FLIGHT_CANCELED" {
+        return Ok(Box::new(warp::reply::with_status("Booking already canceled", warp::http::StatusCode::BAD_REQUEST)));
+    }
+    // Tickets of canceled flights were already refunded when the flight was canceled
+    let paid_tickets: Vec<Ticket> = booking.tickets.into_iter().filter(|x| x.status == "PAID").collect();
+    for ticket in &paid_tickets {
+        if let Some(reply) = refund_blocked(requester, &flights_url, &ticket.flight_number).await {
+            return Ok(Box::new(reply));
+        }
+    }
+    let Ok(response) = requester.send(
+        format!("{}/{}/cancel", booking_url, pnr),
+        RequestMethod::DELETE,
+        HashMap::from([
+            ("Authorization".to_owned(), auth_token.clone())
+        ]),
+        "".to_string()).await else {
+        return Ok(Box::new(warp::reply::with_status("Internal error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
     };
+    if response.code != 204 {
+        return Ok(Box::new(warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND)));
+    }
+    let queue = &mut services.lock().await.queue;
+    for ticket in paid_tickets {
+        queue.push(QueuedRequest {
+            ticket_uid: ticket.ticket_uid,
+            auth_token: auth_token.clone()
+        });
+    }
+    telemetry::set_queue_depth("bonus_refunds", queue.len());
+    Ok(Box::new(warp::reply::with_status("", warp::http::StatusCode::NO_CONTENT)))
+}
+
+async fn check_downstreams(services: Arc<Mutex<Services>>) -> Vec<(&'static str, telemetry::ComponentHealth)> {
+    let services = services.lock().await.clone();
+    let check = |base_url: String| {
+        let mut requester = services.requester.clone();
+        telemetry::check(async move {
+            let response = requester.send(
+                format!("{}/manage/health/ready", base_url),
+                RequestMethod::GET,
+                HashMap::new(),
+                "".to_owned()).await.map_err(|e| e.to_string())?;
+            match response.code {
+                200 => Ok(()),
+                code => Err(format!("responded with {}", code))
+            }
+        })
+    };
+    // Checked at once, so the probe waits for the slowest service only
+    let (tickets, bonuses, flights) = tokio::join!(check(services.tickets_base.clone()),
+                                                   check(services.bonuses_base.clone()),
+                                                   check(services.flights_base.clone()));
+    vec![("tickets", tickets), ("bonuses", bonuses), ("flights", flights)]
+}
+
+async fn health_check_handler(services: Arc<Mutex<Services>>) -> WebResult<Box<dyn Reply>> {
+    let components = check_downstreams(services).await;
+    let is_up = |name: &str| components.iter().any(|(x, health)| *x == name && health.is_up());
     Ok(Box::new(reply::json(&HealthCheckResponse {
         gateway: true,
-        flights,
-        tickets,
-        bonuses
+        flights: is_up("flights"),
+        tickets: is_up("tickets"),
+        bonuses: is_up("bonuses")
     })))
 }
 
+async fn readiness_handler(services: Arc<Mutex<Services>>) -> WebResult<Box<dyn Reply>> {
+    let components = check_downstreams(services).await;
+    Ok(Box::new(telemetry::Readiness::new(components).reply()))
+}
+
 async fn default_handler() -> WebResult<Box<dyn Reply>> {
     return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)));
 }
@@ -469,14 +1229,39 @@ fn with_arc<T: Send + ?Sized>(arc: Arc<Mutex<T>>) -> impl Filter<Extract = (Arc<
 
 #[derive(Clone)]
 pub struct Services {
+    pub flights_base: String,
+    pub tickets_base: String,
+    pub bonuses_base: String,
     pub flights: String,
     pub tickets: String,
+    pub bookings: String,
     pub bonuses: String,
     pub requester: Box<dyn Requester>,
     pub queue: Vec<QueuedRequest>,
+    /// Bonus refunds rejected for good, kept for an operator instead of being retried
+    pub dead_letters: Vec<QueuedRequest>,
     pub checker: JWTChecker
 }
 
+impl Services {
+    /// Addresses the resources of each service below its base URL
+    pub fn new(flights_base: &str, tickets_base: &str, bonuses_base: &str, requester: Box<dyn Requester>, checker: JWTChecker) -> Self {
+        Self {
+            flights_base: flights_base.to_owned(),
+            tickets_base: tickets_base.to_owned(),
+            bonuses_base: bonuses_base.to_owned(),
+            flights: format!("{}/flights", flights_base),
+            tickets: format!("{}/tickets", tickets_base),
+            bookings: format!("{}/bookings", tickets_base),
+            bonuses: format!("{}/privilege", bonuses_base),
+            requester,
+            queue: vec![],
+            dead_letters: vec![],
+            checker
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct QueuedRequest {
     pub ticket_uid: Uuid,
@@ -484,70 +1269,137 @@ pub struct QueuedRequest {
 }
 
 pub fn router(root_url: &str, services: Arc<Mutex<Services>>) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    let log = warp::log::custom(|info| {
-        eprintln!(
-            "{} {} {}",
-            info.method(),
-            info.path(),
-            info.status(),
-        );
-    });
+    // The request metrics name the routes below `root_url` without it
     let list_flights_route = warp::path!("flights")
+        .and(telemetry::route("/flights"))
         .and(warp::get())
         .and(warp::header("Authorization"))
         .and(warp::query::<Paging>())
         .and(with_arc(services.clone()))
         .and_then(list_flights_handler);
     let list_tickets_route = warp::path!("tickets")
+        .and(telemetry::route("/tickets"))
         .and(warp::get())
         .and(warp::header("Authorization"))
         .and(with_arc(services.clone()))
         .and_then(list_tickets_handler);
     let get_ticket_route = warp::path!("tickets" / Uuid)
+        .and(telemetry::route("/tickets/{ticket_uid}"))
         .and(warp::get())
         .and(warp::header("Authorization"))
         .and(with_arc(services.clone()))
         .and_then(get_ticket_handler);
     let get_privilege_route = warp::path!("privilege")
+        .and(telemetry::route("/privilege"))
         .and(warp::get())
         .and(warp::header("Authorization"))
         .and(with_arc(services.clone()))
         .and_then(get_privilege_handler);
+    let get_privilege_history_route = warp::path!("privilege" / "history")
+        .and(telemetry::route("/privilege/history"))
+        .and(warp::get())
+        .and(warp::header("Authorization"))
+        .and(warp::query::<PrivilegeHistoryQuery>())
+        .and(with_arc(services.clone()))
+        .and_then(get_privilege_history_handler);
+    let post_transfer_route = warp::path!("privilege" / "transfers")
+        .and(telemetry::route("/privilege/transfers"))
+        .and(warp::post())
+        .and(warp::header("Authorization"))
+        .and(warp::body::json())
+        .and(with_arc(services.clone()))
+        .and_then(post_transfer_handler);
     let get_user_route = warp::path!("me")
+        .and(telemetry::route("/me"))
         .and(warp::get())
         .and(warp::header("Authorization"))
         .and(with_arc(services.clone()))
         .and_then(get_user_handler);
     let post_ticket_route = warp::path!("tickets")
+        .and(telemetry::route("/tickets"))
         .and(warp::post())
         .and(warp::header("Authorization"))
         .and(warp::body::json())
         .and(with_arc(services.clone()))
         .and_then(post_ticket_handler);
     let delete_ticket_route = warp::path!("tickets"/ Uuid)
+        .and(telemetry::route("/tickets/{ticket_uid}"))
         .and(warp::delete())
         .and(warp::header("Authorization"))
         .and(with_arc(services.clone()))
         .and_then(delete_ticket_handler);
+    let list_itineraries_route = warp::path!("itineraries")
+        .and(telemetry::route("/itineraries"))
+        .and(warp::get())
+        .and(warp::header("Authorization"))
+        .and(warp::query::<ItineraryQuery>())
+        .and(with_arc(services.clone()))
+        .and_then(list_itineraries_handler);
+    let post_itinerary_route = warp::path!("itineraries")
+        .and(telemetry::route("/itineraries"))
+        .and(warp::post())
+        .and(warp::header("Authorization"))
+        .and(warp::body::json())
+        .and(with_arc(services.clone()))
+        .and_then(post_itinerary_handler);
+    let delete_itinerary_route = warp::path!("itineraries" / Uuid)
+        .and(telemetry::route("/itineraries/{booking_uid}"))
+        .and(warp::delete())
+        .and(warp::header("Authorization"))
+        .and(with_arc(services.clone()))
+        .and_then(delete_itinerary_handler);
+    let post_booking_route = warp::path!("bookings")
+        .and(telemetry::route("/bookings"))
+        .and(warp::post())
+        .and(warp::header("Authorization"))
+        .and(warp::body::json())
+        .and(with_arc(services.clone()))
+        .and_then(post_booking_handler);
+    let get_booking_route = warp::path!("bookings" / String)
+        .and(telemetry::route("/bookings/{pnr}"))
+        .and(warp::get())
+        .and(warp::header("Authorization"))
+        .and(with_arc(services.clone()))
+        .and_then(get_booking_handler);
+    let delete_booking_route = warp::path!("bookings" / String)
+        .and(telemetry::route("/bookings/{pnr}"))
+        .and(warp::delete())
+        .and(warp::header("Authorization"))
+        .and(with_arc(services.clone()))
+        .and_then(delete_booking_handler);
     let health_route = warp::path!("manage" / "health")
+        .and(telemetry::route("/manage/health"))
         .and(warp::get())
         .and(with_arc(services.clone()))
         .and_then(health_check_handler);
+    let ready_route = warp::path!("manage" / "health" / "ready")
+        .and(telemetry::route("/manage/health/ready"))
+        .and(warp::get())
+        .and(with_arc(services.clone()))
+        .and_then(readiness_handler);
     let default_route = warp::any()
         .and_then(default_handler);
     let routes = list_flights_route
         .or(list_tickets_route)
         .or(get_ticket_route)
         .or(get_privilege_route)
+        .or(get_privilege_history_route)
+        .or(post_transfer_route)
         .or(get_user_route)
         .or(post_ticket_route)
         .or(delete_ticket_route)
+        .or(list_itineraries_route)
+        .or(post_itinerary_route)
+        .or(delete_itinerary_route)
+        .or(post_booking_route)
+        .or(get_booking_route)
+        .or(delete_booking_route)
         .or(default_route);
     let mut root_route = warp::any().boxed();
     for segment in root_url.split("/") {
         root_route = root_route.and(warp::path(segment.to_owned())).boxed();
     }
-    let routes = (root_route.and(routes)).or(health_route).with(log);
+    let routes = (root_route.and(routes)).or(health_route).or(ready_route).or(telemetry::live_route()).or(telemetry::metrics_route());
 
     tokio::task::spawn(async move {
         loop {
@@ -559,23 +1411,34 @@ pub fn router(root_url: &str, services: Arc<Mutex<Services>>) -> impl Filter<Ext
                     requester = services_ptr.requester.clone();
                     privilege_url = services_ptr.bonuses.clone();
                 }
-                let queue = &mut services.lock().await.queue;
+                let services = &mut *services.lock().await;
                 let mut successful = true;
-                while queue.len() > 0 && successful {
-                    println!("Sending queued request");
-                    let request = queue[0].clone();
-                    if let Ok(_) = requester.send(
+                while services.queue.len() > 0 && successful {
+                    tracing::info!("sending queued request");
+                    let request = services.queue[0].clone();
+                    match requester.send(
                         format!("{}?ticket_uid={}", privilege_url, request.ticket_uid),
                         RequestMethod::DELETE,
                         HashMap::from([
                             ("Authorization".to_owned(), request.auth_token)
                         ]),
-                        "".to_owned()).await  {
-                        queue.remove(0);
-                    } else {
-                        successful = false;
+                        "".to_owned()).await {
+                        // A conflict means the points of the ticket were refunded before
+                        Ok(response) if response.code < 300 || response.code == 409 => {
+                            services.queue.remove(0);
+                        },
+                        Ok(response) if response.code < 500 => {
+                            tracing::error!(ticket_uid = %request.ticket_uid, code = response.code, "bonus refund was rejected");
+                            let request = services.queue.remove(0);
+                            services.dead_letters.push(request);
+                        },
+                        _ => {
+                            successful = false;
+                        }
                     }
                 }
+                telemetry::set_queue_depth("bonus_refunds", services.queue.len());
+                telemetry::set_queue_depth("bonus_refunds_dead_letters", services.dead_letters.len());
             }
             tokio::time::sleep(std::time::Duration::from_secs(10)).await;
         }
@@ -584,9 +1447,7 @@ pub fn router(root_url: &str, services: Arc<Mutex<Services>>) -> impl Filter<Ext
     routes
 }
 
-pub async fn run_server(root_url: &str, port: u16, services: Arc<Mutex<Services>>) {
+pub async fn run_server(root_url: &str, port: u16, services: Arc<Mutex<Services>>) -> std::io::Result<()> {
     let router = router(root_url, services);
-    warp::serve(router)
-        .run(([0, 0, 0, 0], port))
-        .await
+    telemetry::serve(router, port).await
 }
@@ -1,23 +1,40 @@
-use std::{error::Error, collections::HashMap};
-use crate::{arc, server::{router, Services}};
+use std::{error::Error, collections::HashMap, sync::{Arc, atomic::{AtomicUsize, Ordering}}};
+use crate::{arc, server::{router, QueuedRequest, Services}};
+use structs::{Booking, BookingRequest, ItineraryPost, ItineraryPurchaseResponse, Money, Passenger, PurchaseResponse, Ticket, WebFlight};
 use async_trait::async_trait;
 use requester::{Response, Requester};
 use warp::{Filter, reply::Reply, reject::Rejection};
 
 
+/// Replays the responses in order and records the requests. Clones share both, as handlers
+/// clone the requester
 #[derive(Clone)]
 struct MockRequester {
-    current_request: usize,
-    responses: Vec<Response>
+    current_request: Arc<AtomicUsize>,
+    responses: Vec<Response>,
+    requests: Arc<std::sync::Mutex<Vec<String>>>,
+    bodies: Arc<std::sync::Mutex<Vec<String>>>
 }
 
 impl MockRequester {
     pub fn new(responses: Vec<Response>) -> Self {
         MockRequester {
-            current_request: 0,
-            responses
+            current_request: Arc::new(AtomicUsize::new(0)),
+            responses,
+            requests: Arc::new(std::sync::Mutex::new(vec![])),
+            bodies: Arc::new(std::sync::Mutex::new(vec![]))
         }
     }
+
+    /// Method and url of every request sent so far
+    fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Body of every request sent so far
+    fn bodies(&self) -> Vec<String> {
+        self.bodies.lock().unwrap().clone()
+    }
 }
 
 #[async_trait]
@@ -28,21 +45,31 @@ impl Requester for MockRequester {
                   method:requester::RequestMethod,
                   headers:std::collections::HashMap<String,String>,
                   body:String) -> Result<Response, Box<dyn Error>> {
-        if self.current_request >= self.responses.len() {
+        self.requests.lock().unwrap().push(format!("{} {}", method.as_str(), url));
+        self.bodies.lock().unwrap().push(body);
+        let current_request = self.current_request.fetch_add(1, Ordering::SeqCst);
+        if current_request >= self.responses.len() {
             panic!("Ran out of respones");
         }
-        self.current_request += 1;
-        Ok(self.responses[self.current_request - 1].clone())
+        Ok(self.responses[current_request].clone())
+    }
+}
+
+fn response(code: u16, body: &str) -> Response {
+    Response {
+        code,
+        body: body.to_owned(),
+        header: HashMap::new()
     }
 }
 
 fn create_router(responses: Vec<Response>) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    let services = Services {
-        flights: "".to_owned(),
-        tickets: "".to_owned(),
-        bonuses: "".to_owned(),
-        requester: Box::new(MockRequester::new(responses))
-    };
+    create_router_with(MockRequester::new(responses))
+}
+
+fn create_router_with(requester: MockRequester) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let services = Services::new("http://flights", "http://tickets", "http://bonuses",
+                                 Box::new(requester), jwtchecker::testing::checker());
     router("api/v1", arc!(services))
 }
 
@@ -74,19 +101,379 @@ async fn health_check() {
     assert_eq!(res.body(), "{\"gateway\":true,\"flights\":true,\"tickets\":true,\"bonuses\":false}");
 }
 
+#[tokio::test]
+async fn rejected_bonus_refunds_are_dead_lettered() {
+    // Delivered, refunded before, rejected for good
+    let requester = MockRequester::new(vec![response(204, ""), response(409, ""), response(422, "")]);
+    let mut services = Services::new("http://flights", "http://tickets", "http://bonuses",
+                                     Box::new(requester.clone()), jwtchecker::testing::checker());
+    services.queue = (0..3).map(|_| QueuedRequest { ticket_uid: uuid::Uuid::new_v4(), auth_token: "Bearer token".to_owned() }).collect();
+    let rejected = services.queue[2].ticket_uid;
+    let services = arc!(services);
+    let _router = router("api/v1", services.clone());
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(requester.requests().len(), 3);
+    let services = services.lock().await;
+    assert!(services.queue.is_empty());
+    assert_eq!(services.dead_letters.iter().map(|x| x.ticket_uid).collect::<Vec<_>>(), [rejected]);
+}
+
+/// Never answers
+#[derive(Clone)]
+struct HangingRequester;
+
+#[async_trait]
+impl Requester for HangingRequester {
+    async fn send(&mut self,
+                  _url: String,
+                  _method: requester::RequestMethod,
+                  _headers: HashMap<String, String>,
+                  _body: String) -> Result<Response, Box<dyn Error>> {
+        tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        Err("no answer".into())
+    }
+}
+
+#[tokio::test]
+async fn readiness_answers_before_the_probe_times_out() {
+    let services = Services::new("http://flights", "http://tickets", "http://bonuses",
+                                 Box::new(HangingRequester), jwtchecker::testing::checker());
+    let router = router("api/v1", arc!(services));
+    let started = std::time::Instant::now();
+    let res = warp::test::request()
+        .method("GET")
+        .path("/manage/health/ready")
+        .reply(&router).await;
+    assert_eq!(res.status(), 503);
+    assert!(started.elapsed() < std::time::Duration::from_secs(1), "{:?}", started.elapsed());
+}
+
+const FLIGHT_PAGE: &str = "{\"page\":1,\"pageSize\":1,\"totalElements\":1,\"items\":[{\"flightNumber\":\"AFL031\",\"fromAirport\":\"Санкт-Петербург Пулково\",\"toAirport\":\"Москва Шереметьево\",\"fromAirportCode\":\"LED\",\"toAirportCode\":\"SVO\",\"date\":\"2021-10-08 20:00\",\"departureLocal\":\"2021-10-08 20:00\",\"departureUtc\":\"2021-10-08T17:00:00Z\",\"arrivalLocal\":null,\"arrivalUtc\":null,\"price\":{\"amount\":150050,\"currency\":\"RUB\"},\"status\":\"SCHEDULED\",\"durationMinutes\":null,\"estimatedDeparture\":null,\"actualDeparture\":null,\"estimatedArrival\":null,\"actualArrival\":null}]}";
+/// `FLIGHT_PAGE` as the API answers with it
+const FLIGHT_PAGE_RESPONSE: &str = "{\"page\":1,\"pageSize\":1,\"totalElements\":1,\"items\":[{\"flightNumber\":\"AFL031\",\"fromAirport\":\"Санкт-Петербург Пулково\",\"toAirport\":\"Москва Шереметьево\",\"fromAirportCode\":\"LED\",\"toAirportCode\":\"SVO\",\"date\":\"2021-10-08 20:00\",\"departureLocal\":\"2021-10-08 20:00\",\"departureUtc\":\"2021-10-08T17:00:00Z\",\"arrivalLocal\":null,\"arrivalUtc\":null,\"price\":1500,\"currency\":\"RUB\",\"status\":\"SCHEDULED\",\"durationMinutes\":null,\"estimatedDeparture\":null,\"actualDeparture\":null,\"estimatedArrival\":null,\"actualArrival\":null}]}";
+
 #[tokio::test]
 async fn get_flights() {
     let router = create_router(vec![
         Response {
             code: 200,
-            body: "{\n  \"page\": 1,\n  \"pageSize\": 1,\n  \"totalElements\": 1,\n  \"items\": [\n    {\"flightNumber\": \"AFL031\",\n      \"fromAirport\": \"Sheremetevo\",\n      \"toAirport\": \"Pulkovo\",\n      \"date\": \"2021-10-08 20:00\",\n      \"price\": 1500\n    }\n  ]\n}".to_owned(),
+            body: FLIGHT_PAGE.to_owned(),
             header: HashMap::new()
         }
     ]);
     let res = warp::test::request()
         .method("GET")
         .path("/api/v1/flights")
+        .header("Authorization", jwtchecker::testing::bearer("test-max"))
         .reply(&router).await;
     assert_eq!(res.status(), 200);
-    assert_eq!(res.body().to_owned(), "{\"page\":1,\"pageSize\":1,\"totalElements\":1,\"items\":[{\"flightNumber\":\"AFL031\",\"fromAirport\":\"Sheremetevo\",\"toAirport\":\"Pulkovo\",\"date\":\"2021-10-08 20:00\",\"price\":1500}]}");
+    assert_eq!(res.body(), FLIGHT_PAGE_RESPONSE);
+}
+
+const TICKET: &str = "{\"id\":1,\"ticket_uid\":\"17ea0b3b-9efb-4be1-8db5-81512fe77c88\",\"username\":\"test-max\",\"flight_number\":\"AFL031\",\"price\":{\"amount\":150000,\"currency\":\"RUB\"},\"status\":\"PAID\",\"booking_uid\":null,\"passenger_name\":null,\"document_number\":null}";
+
+#[tokio::test]
+async fn cancel_refused_while_flights_unavailable() {
+    for flights in [response(503, ""), response(500, ""), response(200, "not a flight")] {
+        let router = create_router(vec![
+            response(200, TICKET),
+            flights
+        ]);
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/api/v1/tickets/17ea0b3b-9efb-4be1-8db5-81512fe77c88")
+            .header("Authorization", jwtchecker::testing::bearer("test-max"))
+            .reply(&router).await;
+        assert_eq!(res.status(), 503);
+    }
+}
+
+#[tokio::test]
+async fn cancel_unknown_flight() {
+    let router = create_router(vec![
+        response(200, TICKET),
+        response(404, "")
+    ]);
+    let res = warp::test::request()
+        .method("DELETE")
+        .path("/api/v1/tickets/17ea0b3b-9efb-4be1-8db5-81512fe77c88")
+        .header("Authorization", jwtchecker::testing::bearer("test-max"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 404);
+}
+
+/// A scheduled flight of an itinerary, `departure` and `arrival` in UTC
+fn leg(flight_number: &str, from: &str, to: &str, departure: &str, arrival: &str) -> Response {
+    priced_leg(flight_number, from, to, departure, arrival, Money::from_major(1000, "RUB"))
+}
+
+fn priced_leg(flight_number: &str, from: &str, to: &str, departure: &str, arrival: &str, price: Money) -> Response {
+    let flight = WebFlight {
+        flightNumber: flight_number.to_owned(),
+        fromAirport: from.to_owned(),
+        toAirport: to.to_owned(),
+        fromAirportCode: Some(from.to_owned()),
+        toAirportCode: Some(to.to_owned()),
+        date: departure.to_owned(),
+        departureLocal: departure.to_owned(),
+        departureUtc: departure.to_owned(),
+        arrivalLocal: Some(arrival.to_owned()),
+        arrivalUtc: Some(arrival.to_owned()),
+        price,
+        status: "SCHEDULED".to_owned(),
+        durationMinutes: None,
+        estimatedDeparture: None,
+        actualDeparture: None,
+        estimatedArrival: None,
+        actualArrival: None
+    };
+    response(200, &serde_json::to_string(&flight).unwrap())
+}
+
+fn itinerary_ticket(ticket_uid: uuid::Uuid, flight_number: &str, booking_uid: uuid::Uuid, status: &str) -> Ticket {
+    Ticket {
+        id: 1,
+        ticket_uid,
+        username: "test-max".to_owned(),
+        flight_number: flight_number.to_owned(),
+        price: Money::from_major(1000, "RUB"),
+        status: status.to_owned(),
+        booking_uid: Some(booking_uid),
+        passenger_name: None,
+        document_number: None
+    }
+}
+
+/// The booking the tickets service creates for the itinerary
+fn itinerary_booking() -> Response {
+    response(200, &serde_json::to_string(&Booking {
+        id: 1,
+        booking_uid: BOOKING,
+        pnr: "ABC234".to_owned(),
+        username: "test-max".to_owned(),
+        status: "CONFIRMED".to_owned(),
+        tickets: vec![
+            itinerary_ticket(FIRST_TICKET, "AFL1", BOOKING, "PAID"),
+            itinerary_ticket(SECOND_TICKET, "AFL2", BOOKING, "PAID")
+        ]
+    }).unwrap())
+}
+
+fn paid_with_money(amount: i64) -> Response {
+    response(200, &serde_json::to_string(&PurchaseResponse {
+        paid_by_money: Money::from_major(amount, "RUB"),
+        paid_by_bonuses: 0,
+        balance: 100,
+        status: "BRONZE".to_owned(),
+        tier_change: None
+    }).unwrap())
+}
+
+const FIRST_TICKET: uuid::Uuid = uuid::uuid!("17ea0b3b-9efb-4be1-8db5-81512fe77c88");
+const SECOND_TICKET: uuid::Uuid = uuid::uuid!("27ea0b3b-9efb-4be1-8db5-81512fe77c88");
+const BOOKING: uuid::Uuid = uuid::uuid!("5dd9a1b4-1b6e-4f43-9a3a-6b5c2a8f3e10");
+
+fn post_itinerary() -> warp::test::RequestBuilder {
+    warp::test::request()
+        .method("POST")
+        .path("/api/v1/itineraries")
+        .header("Authorization", jwtchecker::testing::bearer("test-max"))
+        .json(&ItineraryPost {
+            flightNumbers: vec!["AFL1".to_owned(), "AFL2".to_owned()],
+            price: 2000,
+            paidFromBalance: false
+        })
+}
+
+#[tokio::test]
+async fn itinerary_legs_must_connect() {
+    let first = leg("AFL1", "LED", "SVO", "2021-10-08T10:00:00Z", "2021-10-08T11:30:00Z");
+    let disconnected = [
+        // Leaves from another airport
+        leg("AFL2", "VKO", "KZN", "2021-10-08T13:00:00Z", "2021-10-08T14:30:00Z"),
+        // Leaves before the first leg lands
+        leg("AFL2", "SVO", "KZN", "2021-10-08T11:00:00Z", "2021-10-08T12:30:00Z"),
+        // Leaves too soon after the first leg lands
+        leg("AFL2", "SVO", "KZN", "2021-10-08T12:00:00Z", "2021-10-08T13:30:00Z"),
+        // Leaves on another day
+        leg("AFL2", "SVO", "KZN", "2021-10-10T13:00:00Z", "2021-10-10T14:30:00Z")
+    ];
+    for second in disconnected {
+        let requester = MockRequester::new(vec![first.clone(), second]);
+        let router = create_router_with(requester.clone());
+        let res = post_itinerary().reply(&router).await;
+        assert_eq!(res.status(), 400);
+        assert_eq!(res.body(), "Flights of the itinerary do not connect");
+        // Nothing was bought
+        assert_eq!(requester.requests().len(), 2);
+    }
+}
+
+#[tokio::test]
+async fn purchase_itinerary() {
+    let requester = MockRequester::new(vec![
+        leg("AFL1", "LED", "SVO", "2021-10-08T10:00:00Z", "2021-10-08T11:30:00Z"),
+        leg("AFL2", "SVO", "KZN", "2021-10-08T13:00:00Z", "2021-10-08T14:30:00Z"),
+        itinerary_booking(),
+        paid_with_money(1000),
+        response(200, ""),
+        paid_with_money(1000),
+        response(200, "")
+    ]);
+    let router = create_router_with(requester.clone());
+    let res = post_itinerary().reply(&router).await;
+    assert_eq!(res.status(), 200);
+    let purchase: ItineraryPurchaseResponse = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(purchase.tickets.len(), 2);
+    assert_eq!(purchase.paidByMoney, 2000);
+    assert_eq!(purchase.bookingUid, BOOKING);
+    assert_eq!(purchase.pnr, "ABC234");
+    // The itinerary is booked at once, without passengers
+    let requests = requester.requests();
+    assert_eq!(requests[2], "POST http://tickets/bookings");
+    // The tickets service charges the money share of every ticket
+    assert_eq!(requests[4], format!("POST http://tickets/tickets/{}/payment", FIRST_TICKET));
+    assert_eq!(requests[6], format!("POST http://tickets/tickets/{}/payment", SECOND_TICKET));
+}
+
+#[tokio::test]
+async fn itinerary_is_booked_in_minor_units() {
+    let requester = MockRequester::new(vec![
+        priced_leg("AFL1", "LED", "SVO", "2021-10-08T10:00:00Z", "2021-10-08T11:30:00Z", Money::new(100_050, "RUB")),
+        priced_leg("AFL2", "SVO", "KZN", "2021-10-08T13:00:00Z", "2021-10-08T14:30:00Z", Money::new(99_950, "RUB")),
+        itinerary_booking(),
+        paid_with_money(1000),
+        response(200, ""),
+        paid_with_money(1000),
+        response(200, "")
+    ]);
+    let router = create_router_with(requester.clone());
+    let res = post_itinerary().reply(&router).await;
+    assert_eq!(res.status(), 200);
+    let booking: serde_json::Value = serde_json::from_str(&requester.bodies()[2]).unwrap();
+    assert_eq!(booking["tickets"][0]["price"], serde_json::json!({"amount": 100_050, "currency": "RUB"}));
+    assert_eq!(booking["tickets"][1]["price"], serde_json::json!({"amount": 99_950, "currency": "RUB"}));
+}
+
+#[tokio::test]
+async fn failed_itinerary_is_rolled_back() {
+    for (charged, deleted, status, body) in [(500, 204, 500, "Failed to purchase tickets"),
+                                             (500, 500, 500, "Failed to purchase tickets and to return them"),
+                                             (402, 204, 402, "Payment was declined")] {
+        let requester = MockRequester::new(vec![
+            leg("AFL1", "LED", "SVO", "2021-10-08T10:00:00Z", "2021-10-08T11:30:00Z"),
+            leg("AFL2", "SVO", "KZN", "2021-10-08T13:00:00Z", "2021-10-08T14:30:00Z"),
+            itinerary_booking(),
+            paid_with_money(1000),
+            response(200, ""),
+            paid_with_money(1000),
+            // The second ticket can not be paid for
+            response(charged, ""),
+            // Bonus refunds of both tickets
+            response(204, ""),
+            response(204, ""),
+            response(deleted, "")
+        ]);
+        let router = create_router_with(requester.clone());
+        let res = post_itinerary().reply(&router).await;
+        assert_eq!(res.status(), status);
+        assert_eq!(res.body(), body);
+        // Deleting the booking has the tickets service refund the money of the first ticket
+        let requests = requester.requests();
+        assert_eq!(requests[7], format!("DELETE http://bonuses/privilege?ticket_uid={}", FIRST_TICKET));
+        assert_eq!(requests.last().unwrap(), &"DELETE http://tickets/bookings/ABC234".to_owned());
+    }
+}
+
+#[tokio::test]
+async fn cancel_itinerary_at_once() {
+    let tickets = vec![
+        itinerary_ticket(FIRST_TICKET, "AFL1", BOOKING, "PAID"),
+        itinerary_ticket(SECOND_TICKET, "AFL2", BOOKING, "PAID")
+    ];
+    let requester = MockRequester::new(vec![
+        response(200, &serde_json::to_string(&tickets).unwrap()),
+        leg("AFL1", "LED", "SVO", "2021-10-08T10:00:00Z", "2021-10-08T11:30:00Z"),
+        leg("AFL2", "SVO", "KZN", "2021-10-08T13:00:00Z", "2021-10-08T14:30:00Z"),
+        response(204, "")
+    ]);
+    let router = create_router_with(requester.clone());
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/api/v1/itineraries/{}", BOOKING))
+        .header("Authorization", jwtchecker::testing::bearer("test-max"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 204);
+    // One request cancels every ticket of the itinerary and refunds their money
+    assert_eq!(requester.requests().last().unwrap(), &format!("DELETE http://tickets/itineraries/{}/cancel", BOOKING));
+}
+
+#[tokio::test]
+async fn cancel_itinerary_refused_by_tickets() {
+    let tickets = vec![itinerary_ticket(FIRST_TICKET, "AFL1", BOOKING, "PAID")];
+    let requester = MockRequester::new(vec![
+        response(200, &serde_json::to_string(&tickets).unwrap()),
+        leg("AFL1", "LED", "SVO", "2021-10-08T10:00:00Z", "2021-10-08T11:30:00Z"),
+        response(409, "")
+    ]);
+    let router = create_router_with(requester.clone());
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/api/v1/itineraries/{}", BOOKING))
+        .header("Authorization", jwtchecker::testing::bearer("test-max"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 400);
+    // Nothing is refunded
+    assert_eq!(requester.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn cancel_itinerary_after_flight_cancellation() {
+    let tickets = vec![
+        itinerary_ticket(FIRST_TICKET, "AFL1", BOOKING, "FLIGHT_CANCELED"),
+        itinerary_ticket(SECOND_TICKET, "AFL2", BOOKING, "PAID")
+    ];
+    let requester = MockRequester::new(vec![
+        response(200, &serde_json::to_string(&tickets).unwrap()),
+        leg("AFL2", "SVO", "KZN", "2021-10-08T13:00:00Z", "2021-10-08T14:30:00Z"),
+        response(204, "")
+    ]);
+    let router = create_router_with(requester.clone());
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/api/v1/itineraries/{}", BOOKING))
+        .header("Authorization", jwtchecker::testing::bearer("test-max"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 204);
+    // Only the flight that still flies is looked up here
+    assert_eq!(requester.requests()[1], "GET http://flights/flights/AFL2");
+}
+
+#[tokio::test]
+async fn booking_rejects_invalid_passengers() {
+    let passengers = [
+        vec![Passenger { name: "".to_owned(), documentNumber: "4510 123456".to_owned() }],
+        vec![Passenger { name: "Ivan Ivanov".to_owned(), documentNumber: "x".to_owned() }],
+        vec![
+            Passenger { name: "Ivan Ivanov".to_owned(), documentNumber: "4510 123456".to_owned() },
+            Passenger { name: "Petr Petrov".to_owned(), documentNumber: "4510 123456".to_owned() }
+        ]
+    ];
+    for passengers in passengers {
+        let requester = MockRequester::new(vec![]);
+        let router = create_router_with(requester.clone());
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/v1/bookings")
+            .header("Authorization", jwtchecker::testing::bearer("test-max"))
+            .json(&BookingRequest {
+                flightNumbers: vec!["AFL1".to_owned()],
+                passengers,
+                price: 1000,
+                paidFromBalance: false
+            })
+            .reply(&router).await;
+        assert_eq!(res.status(), 400);
+        assert!(requester.requests().is_empty());
+    }
 }
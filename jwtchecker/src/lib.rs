@@ -2,9 +2,28 @@ use serde::{Deserialize, Serialize};
 use jsonwebtoken::{decode, Validation, Algorithm, DecodingKey};
 use custom_error::custom_error;
 
+#[cfg(feature = "testing")]
+pub mod testing;
 
 custom_error!{pub JWTError
-    InvalidToken    = "The token is invalid"
+    InvalidToken    = "The token is invalid",
+    MissingRole     = "The token lacks the required role"
+}
+
+/// Header with the secret the services share to call each other's internal endpoints
+pub const SERVICE_TOKEN_HEADER: &str = "X-Service-Token";
+
+/// Checks the secret sent by another service. Nothing matches an unset secret, so the
+/// internal endpoints stay closed when the deployment does not configure one.
+pub fn service_token_matches(secret: &str, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return false;
+    };
+    if secret.is_empty() || secret.len() != token.len() {
+        return false;
+    }
+    // Compares every byte so the time taken does not tell how much of the secret matched
+    secret.bytes().zip(token.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
 #[derive(Clone)]
@@ -26,15 +45,35 @@ impl JWTChecker {
         self.decode(&token)
     }
     pub fn decode(&self, token: &str) -> Result<String, JWTError> {
+        Ok(self.decode_claims(token)?.preferred_username)
+    }
+    /// Same as `decode_header`, but also requires the realm role to be granted to the user
+    pub fn decode_header_with_role(&self, header: &str, role: &str) -> Result<String, JWTError> {
+        let token = header.replace("Bearer ", "");
+        let claims = self.decode_claims(&token)?;
+        if !claims.realm_access.roles.iter().any(|x| x == role) {
+            return Err(JWTError::MissingRole);
+        }
+        Ok(claims.preferred_username)
+    }
+    fn decode_claims(&self, token: &str) -> Result<Claims, JWTError> {
         match decode::<Claims>(token, &self.key, &self.validation) {
-            Ok(token_message) => { Ok(token_message.claims.preferred_username) }
+            Ok(token_message) => { Ok(token_message.claims) }
             Err(_) => { Err(JWTError::InvalidToken) }
         }
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct RealmAccess {
+   #[serde(default)]
+   roles: Vec<String>
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct Claims {
    sub: String,
-   preferred_username: String
+   preferred_username: String,
+   #[serde(default)]
+   realm_access: RealmAccess
 }
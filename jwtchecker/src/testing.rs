@@ -0,0 +1,86 @@
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use serde::Serialize;
+use rsa::{pkcs1::{EncodeRsaPrivateKey, EncodeRsaPublicKey}, pkcs8::LineEnding, RsaPrivateKey};
+use crate::JWTChecker;
+
+pub const TEST_RSA_PUB: &str = include_str!("test_key.pub.pem");
+const TEST_RSA_PRIV: &str = include_str!("test_key.pem");
+
+#[derive(Serialize)]
+struct TestRealmAccess {
+    roles: Vec<String>
+}
+
+#[derive(Serialize)]
+struct TestClaims {
+    sub: String,
+    preferred_username: String,
+    exp: u64,
+    realm_access: TestRealmAccess
+}
+
+pub fn checker() -> JWTChecker {
+    JWTChecker::new(TEST_RSA_PUB)
+}
+
+pub fn token(username: &str) -> String {
+    token_with_roles(username, &[])
+}
+
+pub fn token_with_roles(username: &str, roles: &[&str]) -> String {
+    sign(TEST_RSA_PRIV, username, roles)
+}
+
+fn sign(rsa_priv: &str, username: &str, roles: &[&str]) -> String {
+    let claims = TestClaims {
+        sub: username.to_owned(),
+        preferred_username: username.to_owned(),
+        exp: 4102444800,
+        realm_access: TestRealmAccess {
+            roles: roles.iter().map(|x| x.to_string()).collect()
+        }
+    };
+    let key = EncodingKey::from_rsa_pem(rsa_priv.as_bytes()).unwrap();
+    encode(&Header::new(Algorithm::RS256), &claims, &key).unwrap()
+}
+
+pub fn bearer(username: &str) -> String {
+    format!("Bearer {}", token(username))
+}
+
+pub fn bearer_with_roles(username: &str, roles: &[&str]) -> String {
+    format!("Bearer {}", token_with_roles(username, roles))
+}
+
+/// A keypair made on the spot, for setups that should not share the committed test key
+#[derive(Clone)]
+pub struct Keypair {
+    pub rsa_pub: String,
+    rsa_priv: String
+}
+
+impl Keypair {
+    pub fn generate() -> Self {
+        let key = RsaPrivateKey::new(&mut rsa::rand_core::OsRng, 2048).unwrap();
+        Self {
+            rsa_pub: key.to_public_key().to_pkcs1_pem(LineEnding::LF).unwrap(),
+            rsa_priv: key.to_pkcs1_pem(LineEnding::LF).unwrap().to_string()
+        }
+    }
+
+    pub fn checker(&self) -> JWTChecker {
+        JWTChecker::new(&self.rsa_pub)
+    }
+
+    pub fn token(&self, username: &str) -> String {
+        sign(&self.rsa_priv, username, &[])
+    }
+
+    pub fn bearer(&self, username: &str) -> String {
+        self.bearer_with_roles(username, &[])
+    }
+
+    pub fn bearer_with_roles(&self, username: &str, roles: &[&str]) -> String {
+        format!("Bearer {}", sign(&self.rsa_priv, username, roles))
+    }
+}
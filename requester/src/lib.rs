@@ -1,4 +1,4 @@
-use std::{collections::HashMap, error::Error};
+use std::{collections::HashMap, error::Error, time::Instant};
 use dyn_clone::{clone_trait_object, DynClone};
 use async_trait::async_trait;
 use custom_error::custom_error;
@@ -15,6 +15,16 @@ pub enum RequestMethod {
     DELETE
 }
 
+impl RequestMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RequestMethod::GET => "GET",
+            RequestMethod::POST => "POST",
+            RequestMethod::DELETE => "DELETE"
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Response {
     pub code: u16,
@@ -37,18 +47,40 @@ pub struct Reqwester {}
 
 #[async_trait]
 impl Requester for Reqwester {
+    #[tracing::instrument(name = "outgoing request", skip_all, fields(
+        otel.name = %format!("{} {}", method.as_str(), url.split('?').next().unwrap_or_default()),
+        otel.kind = "client",
+        url = %url,
+        status = tracing::field::Empty))]
     async fn send(&mut self, url: String, method: RequestMethod, headers: HashMap<String, String>, body: String) -> Result<Response, Box<dyn Error>> {
         let client = reqwest::Client::new();
         let mut builder = match method {
-            RequestMethod::GET => client.get(url),
-            RequestMethod::POST => client.post(url),
-            RequestMethod::DELETE => client.delete(url)
+            RequestMethod::GET => client.get(&url),
+            RequestMethod::POST => client.post(&url),
+            RequestMethod::DELETE => client.delete(&url)
         };
         builder = builder.body(body);
+        if !headers.keys().any(|x| x.eq_ignore_ascii_case(telemetry::REQUEST_ID_HEADER)) {
+            builder = builder.header(telemetry::REQUEST_ID_HEADER, telemetry::request_id());
+        }
+        for header in telemetry::trace_headers() {
+            builder = builder.header(header.0, header.1);
+        }
         for header in headers {
             builder = builder.header(header.0, header.1);
         }
-        let response = builder.send().await?;
+        let started = Instant::now();
+        let response = match builder.send().await {
+            Ok(response) => response,
+            Err(e) => {
+                telemetry::observe_outgoing(method.as_str(), &url, None, started);
+                tracing::warn!(error = %e, "request to another service failed");
+                return Err(e.into());
+            }
+        };
+        telemetry::observe_outgoing(method.as_str(), &url, Some(response.status().as_u16()), started);
+        tracing::Span::current().record("status", response.status().as_u16());
+        tracing::debug!("request to another service");
         let mut response_headers = HashMap::new();
         for header in response.headers() {
             response_headers.insert(header.0.to_string(), header.1.to_str()?.to_string());
@@ -1,7 +1,12 @@
 use serde::{Deserialize, Serialize};
-use chrono::{NaiveDateTime, DateTime, Utc};
+use chrono::{NaiveDate, NaiveDateTime, DateTime, Duration, Utc};
 use uuid::Uuid;
 
+mod money;
+pub use money::*;
+#[cfg(test)]
+mod test;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Privilege {
     pub id: i32,
@@ -17,22 +22,131 @@ pub struct PrivilegeHistory {
     pub ticket_uid: Uuid,
     pub datetime: NaiveDateTime,
     pub balance_diff: i32,
-    pub operation_type: String
+    pub operation_type: String,
+    pub rule_version: Option<String>,
+    pub refund_of: Option<i32>,
+    pub reason: Option<String>,
+    pub operator: Option<String>,
+    pub counterparty: Option<String>
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct PrivilegeHistoryPost {
     pub username: String,
     pub ticket_uid: Uuid,
     pub balance_diff: i32,
-    pub operation_type: String
+    pub operation_type: String,
+    pub rule_version: Option<String>,
+    pub refund_of: Option<i32>,
+    pub reason: Option<String>,
+    pub operator: Option<String>,
+    pub counterparty: Option<String>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdjustmentPost {
+    pub username: String,
+    pub amount: i32,
+    pub reason: String
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferPost {
+    pub recipient: String,
+    pub amount: i32
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BalanceOperationResponse {
+    pub operationUid: Uuid,
+    pub balance: i32
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TierHistory {
+    pub id: i32,
+    pub privilege_id: i32,
+    pub datetime: NaiveDateTime,
+    pub previous_status: String,
+    pub status: String
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BalanceMismatch {
+    pub username: String,
+    pub balance: Option<i32>,
+    pub ledgerBalance: i32
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReconciliationReport {
+    pub checkedAccounts: usize,
+    pub mismatches: Vec<BalanceMismatch>,
+    pub unbalancedOperations: Vec<i32>,
+    pub unpostedOperations: Vec<i32>,
+    pub repaired: bool
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PrivilegeHistoryQuery {
+    pub page: Option<usize>,
+    pub size: Option<usize>,
+    pub operationType: Option<String>,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub ticketUid: Option<Uuid>
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrivilegeHistoryPage {
+    pub page: usize,
+    pub pageSize: usize,
+    pub totalElements: usize,
+    pub items: Vec<PrivilegeHistoryGet>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PointsLot {
+    pub id: i32,
+    pub privilege_id: i32,
+    pub ticket_uid: Uuid,
+    pub datetime: NaiveDateTime,
+    pub expires: NaiveDateTime,
+    pub amount: i32,
+    pub remaining: i32
+}
+
+#[allow(non_snake_case)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PrivilegeGet {
     pub balance: i32,
     pub status: String,
-    pub history: Vec<PrivilegeHistoryGet>
+    pub history: Vec<PrivilegeHistoryGet>,
+    #[serde(default)]
+    pub tierHistory: Vec<TierChangeGet>,
+    #[serde(default)]
+    pub upcomingExpirations: Vec<ExpirationGet>
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExpirationGet {
+    pub date: String,
+    pub ticketUid: Uuid,
+    pub amount: i32
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TierChangeGet {
+    pub date: String,
+    pub previousStatus: String,
+    pub status: String
 }
 
 #[allow(non_snake_case)]
@@ -41,7 +155,9 @@ pub struct PrivilegeHistoryGet {
     pub date: String,
     pub ticketUid: Uuid,
     pub balanceDiff: i32,
-    pub operationType: String
+    pub operationType: String,
+    #[serde(default)]
+    pub ruleVersion: Option<String>
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -59,16 +175,22 @@ pub struct User {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PurchasePost {
     pub ticket_uid: Uuid,
-    pub price: i32,
-    pub paid_from_balance: bool
+    pub price: Money,
+    pub paid_from_balance: bool,
+    #[serde(default)]
+    pub from_airport: Option<String>,
+    #[serde(default)]
+    pub to_airport: Option<String>
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PurchaseResponse {
-    pub paid_by_money: i32,
+    pub paid_by_money: Money,
     pub paid_by_bonuses: i32,
     pub balance: i32,
-    pub status: String
+    pub status: String,
+    #[serde(default)]
+    pub tier_change: Option<TierChangeGet>
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -78,7 +200,37 @@ pub struct Flight {
     pub datetime: DateTime<Utc>,
     pub from_airport_id: i32,
     pub to_airport_id: i32,
-    pub price: i32
+    pub price: Money,
+    pub status: String,
+    /// Planned time in the air, unknown for flights stored before it was kept
+    pub duration_minutes: Option<i32>,
+    pub estimated_departure: Option<DateTime<Utc>>,
+    pub actual_departure: Option<DateTime<Utc>>,
+    pub estimated_arrival: Option<DateTime<Utc>>,
+    pub actual_arrival: Option<DateTime<Utc>>
+}
+
+impl Flight {
+    /// Best known arrival time: the actual one, then the estimate, then the one planned from the departure
+    pub fn arrival(&self) -> Option<DateTime<Utc>> {
+        self.actual_arrival
+            .or(self.estimated_arrival)
+            .or_else(|| self.duration_minutes.map(|x| self.datetime + Duration::minutes(x as i64)))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlightStatusPost {
+    pub status: String,
+    pub datetime: Option<DateTime<Utc>>,
+    pub arrival: Option<DateTime<Utc>>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlightStatusEvent {
+    pub flight_number: String,
+    pub status: String,
+    pub datetime: DateTime<Utc>
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -86,7 +238,10 @@ pub struct Airport {
     pub id: i32,
     pub name: String,
     pub city: String,
-    pub country: Option<String>
+    pub country: Option<String>,
+    pub timezone: String,
+    pub iata_code: Option<String>,
+    pub icao_code: Option<String>
 }
 
 #[allow(non_snake_case)]
@@ -95,8 +250,20 @@ pub struct WebFlight {
     pub flightNumber: String,
     pub fromAirport: String,
     pub toAirport: String,
+    pub fromAirportCode: Option<String>,
+    pub toAirportCode: Option<String>,
     pub date: String,
-    pub price: i32
+    pub departureLocal: String,
+    pub departureUtc: String,
+    pub arrivalLocal: Option<String>,
+    pub arrivalUtc: Option<String>,
+    pub price: Money,
+    pub status: String,
+    pub durationMinutes: Option<i32>,
+    pub estimatedDeparture: Option<String>,
+    pub actualDeparture: Option<String>,
+    pub estimatedArrival: Option<String>,
+    pub actualArrival: Option<String>
 }
 
 #[allow(non_snake_case)]
@@ -108,20 +275,167 @@ pub struct WebFlightPage {
   pub items: Vec<WebFlight>
 }
 
+/// Shortest layover between two legs of an itinerary
+pub const MIN_CONNECTION_MINUTES: i64 = 45;
+/// Longest layover between two legs of an itinerary
+pub const MAX_CONNECTION_HOURS: i64 = 24;
+
+impl WebFlight {
+    /// Whether `next` leaves from the airport this flight arrives at, late enough to make the connection
+    pub fn connects_to(&self, next: &WebFlight) -> bool {
+        let same_airport = match (&self.toAirportCode, &next.fromAirportCode) {
+            (Some(arrival), Some(departure)) => arrival == departure,
+            _ => self.toAirport == next.fromAirport
+        };
+        let Ok(departure) = DateTime::parse_from_rfc3339(&next.departureUtc) else {
+            return false;
+        };
+        // A connection can only be checked against a known or planned arrival time
+        let arrival = self.arrivalUtc.as_deref().and_then(|x| DateTime::parse_from_rfc3339(x).ok()).or_else(|| {
+            let departure = DateTime::parse_from_rfc3339(&self.departureUtc).ok()?;
+            Some(departure + Duration::minutes(self.durationMinutes? as i64))
+        });
+        let Some(arrival) = arrival else {
+            return false;
+        };
+        let connection = departure - arrival;
+        same_airport && connection >= Duration::minutes(MIN_CONNECTION_MINUTES) && connection <= Duration::hours(MAX_CONNECTION_HOURS)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebItinerary {
+    pub flights: Vec<WebFlight>,
+    pub price: Money
+}
+
+/// `WebFlight` as the gateway answers with it, the price in whole units of its currency
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlightResponse {
+    pub flightNumber: String,
+    pub fromAirport: String,
+    pub toAirport: String,
+    pub fromAirportCode: Option<String>,
+    pub toAirportCode: Option<String>,
+    pub date: String,
+    pub departureLocal: String,
+    pub departureUtc: String,
+    pub arrivalLocal: Option<String>,
+    pub arrivalUtc: Option<String>,
+    pub price: i32,
+    pub currency: String,
+    pub status: String,
+    pub durationMinutes: Option<i32>,
+    pub estimatedDeparture: Option<String>,
+    pub actualDeparture: Option<String>,
+    pub estimatedArrival: Option<String>,
+    pub actualArrival: Option<String>
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlightPageResponse {
+  pub page: usize,
+  pub pageSize: usize,
+  pub totalElements: usize,
+  pub items: Vec<FlightResponse>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ItineraryResponse {
+    pub flights: Vec<FlightResponse>,
+    pub price: i32,
+    pub currency: String
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Ticket {
     pub id: i32,
     pub ticket_uid: Uuid,
     pub username: String,
     pub flight_number: String,
-    pub price: i32,
-    pub status: String
+    pub price: Money,
+    pub status: String,
+    pub booking_uid: Option<Uuid>,
+    pub passenger_name: Option<String>,
+    pub document_number: Option<String>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Booking {
+    pub id: i32,
+    pub booking_uid: Uuid,
+    pub pnr: String,
+    pub username: String,
+    pub status: String,
+    pub tickets: Vec<Ticket>
+}
+
+/// A ticket of a booking. Itineraries are booked without passengers, as the account holder travels.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BookingTicketPost {
+    pub flight_number: String,
+    pub price: Money,
+    #[serde(default)]
+    pub passenger_name: Option<String>,
+    #[serde(default)]
+    pub document_number: Option<String>
+}
+
+impl BookingTicketPost {
+    /// Tickets either name a valid passenger with a document or no passenger at all
+    pub fn has_valid_passenger(&self) -> bool {
+        match (&self.passenger_name, &self.document_number) {
+            (Some(name), Some(document_number)) => valid_passenger(name, document_number),
+            (None, None) => true,
+            _ => false
+        }
+    }
+}
+
+/// Whether a passenger name and a travel document number look like ones printed in a passport
+pub fn valid_passenger(name: &str, document_number: &str) -> bool {
+    let name = name.trim();
+    let document_number = document_number.trim();
+    let name_valid = !name.is_empty()
+        && name.chars().count() <= 100
+        && name.chars().any(char::is_alphabetic)
+        && name.chars().all(|x| x.is_alphabetic() || matches!(x, ' ' | '-' | '\'' | '.'));
+    let document_valid = (4..=20).contains(&document_number.len())
+        && document_number.chars().any(|x| x.is_ascii_digit())
+        && document_number.chars().all(|x| x.is_ascii_alphanumeric() || matches!(x, ' ' | '-'));
+    name_valid && document_valid
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BookingPost {
+    pub tickets: Vec<BookingTicketPost>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TicketRefundEvent {
+    pub ticket_uid: Uuid
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TicketPost {
     pub flight_number: String,
-    pub price: i32,
+    pub price: Money
+}
+
+/// The money share of a ticket, one per ticket. `status` is one of AUTHORIZED, CAPTURED,
+/// DECLINED, VOIDED and REFUNDED.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Payment {
+    pub ticket_uid: Uuid,
+    pub amount: Money,
+    pub status: String
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaymentPost {
+    pub amount: Money
 }
 
 #[allow(non_snake_case)]
@@ -142,6 +456,7 @@ pub struct TicketResponse {
     pub toAirport: String,
     pub date: String,
     pub price: i32,
+    pub currency: String,
     pub status: String
 }
 
@@ -154,9 +469,85 @@ pub struct CombinedPurchaseResponse {
     pub toAirport: String,
     pub date: String,
     pub price: i32,
+    pub currency: String,
+    pub paidByMoney: i32,
+    pub paidByBonuses: i32,
+    pub status: String,
+    pub privilege: Balance
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ItineraryPost {
+    pub flightNumbers: Vec<String>,
+    pub price: i32,
+    pub paidFromBalance: bool,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ItineraryPurchaseResponse {
+    pub bookingUid: Uuid,
+    pub pnr: String,
+    pub tickets: Vec<TicketResponse>,
+    pub price: i32,
+    pub currency: String,
     pub paidByMoney: i32,
     pub paidByBonuses: i32,
+    pub privilege: Balance
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Passenger {
+    pub name: String,
+    pub documentNumber: String
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BookingRequest {
+    pub flightNumbers: Vec<String>,
+    pub passengers: Vec<Passenger>,
+    pub price: i32,
+    pub paidFromBalance: bool
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BookingTicketResponse {
+    pub ticketUid: Uuid,
+    pub flightNumber: String,
+    pub fromAirport: String,
+    pub toAirport: String,
+    pub date: String,
+    pub price: i32,
+    pub status: String,
+    pub passenger: Passenger
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BookingResponse {
+    pub pnr: String,
+    pub bookingUid: Uuid,
     pub status: String,
+    pub price: i32,
+    pub currency: String,
+    pub tickets: Vec<BookingTicketResponse>
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BookingPurchaseResponse {
+    pub pnr: String,
+    pub bookingUid: Uuid,
+    pub status: String,
+    pub price: i32,
+    pub currency: String,
+    pub tickets: Vec<BookingTicketResponse>,
+    pub paidByMoney: i32,
+    pub paidByBonuses: i32,
     pub privilege: Balance
 }
 
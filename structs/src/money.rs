@@ -0,0 +1,126 @@
+use std::{collections::HashMap, error::Error, fs};
+use serde::{Deserialize, Serialize};
+use custom_error::custom_error;
+
+/// Currency bonus points and reports are kept in
+pub const BASE_CURRENCY: &str = "RUB";
+
+custom_error!{pub MoneyError
+    CurrencyMismatch                = "Amounts are in different currencies",
+    UnknownCurrency{currency: String} = "There is no rate for {currency}",
+    InvalidRates                    = "Rate table is invalid"
+}
+
+/// Number of digits after the decimal point of the ISO 4217 currency
+pub fn minor_unit_exponent(currency: &str) -> u32 {
+    match currency {
+        "JPY" | "KRW" | "VND" | "CLP" | "ISK" => 0,
+        "BHD" | "KWD" | "OMR" | "JOD" | "TND" => 3,
+        _ => 2
+    }
+}
+
+fn minor_units(currency: &str) -> i64 {
+    10_i64.pow(minor_unit_exponent(currency))
+}
+
+/// Divides rounding half away from zero, all the rounding in conversions goes through here
+fn div_round(numerator: i128, denominator: i128) -> i128 {
+    let quotient = numerator / denominator;
+    let remainder = numerator % denominator;
+    if remainder.abs() * 2 >= denominator.abs() {
+        quotient + numerator.signum() * denominator.signum()
+    }
+    else {
+        quotient
+    }
+}
+
+/// An amount in minor units (kopecks, cents) of an ISO 4217 currency.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Money {
+    pub amount: i64,
+    pub currency: String
+}
+
+impl Money {
+    pub fn new(amount: i64, currency: &str) -> Self {
+        Money { amount, currency: currency.to_owned() }
+    }
+    pub fn zero(currency: &str) -> Self {
+        Money::new(0, currency)
+    }
+    pub fn from_major(major: i64, currency: &str) -> Self {
+        Money::new(major * minor_units(currency), currency)
+    }
+    /// Whole major units, the fraction is rounded down
+    pub fn major(&self) -> i64 {
+        self.amount.div_euclid(minor_units(&self.currency))
+    }
+    pub fn checked_add(&self, other: &Money) -> Result<Money, MoneyError> {
+        if self.currency != other.currency {
+            return Err(MoneyError::CurrencyMismatch);
+        }
+        Ok(Money::new(self.amount + other.amount, &self.currency))
+    }
+    pub fn checked_sub(&self, other: &Money) -> Result<Money, MoneyError> {
+        if self.currency != other.currency {
+            return Err(MoneyError::CurrencyMismatch);
+        }
+        Ok(Money::new(self.amount - other.amount, &self.currency))
+    }
+    /// Share of the amount given in basis points (1/100 of a percent), rounded down to a minor unit
+    pub fn share(&self, basis_points: i64) -> Money {
+        let amount = self.amount as i128 * basis_points as i128 / 10_000;
+        Money::new(amount as i64, &self.currency)
+    }
+}
+
+/// Exchange rates as the number of minor units of the base currency paid for one major unit
+/// of the other currency, e.g. `"USD": 9250` with a RUB base means 92.50 RUB per dollar.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateTable {
+    pub base: String,
+    pub rates: HashMap<String, i64>
+}
+
+impl Default for RateTable {
+    fn default() -> Self {
+        RateTable {
+            base: BASE_CURRENCY.to_owned(),
+            rates: HashMap::from([(BASE_CURRENCY.to_owned(), minor_units(BASE_CURRENCY))])
+        }
+    }
+}
+
+impl RateTable {
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let mut table: RateTable = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if table.rates.values().any(|x| *x <= 0) {
+            return Err(MoneyError::InvalidRates.into());
+        }
+        table.rates.insert(table.base.clone(), minor_units(&table.base));
+        Ok(table)
+    }
+
+    fn rate(&self, currency: &str) -> Result<i128, MoneyError> {
+        match self.rates.get(currency) {
+            Some(rate) => Ok(*rate as i128),
+            None => Err(MoneyError::UnknownCurrency { currency: currency.to_owned() })
+        }
+    }
+
+    /// Converts through the base currency, rounding half away from zero once per step
+    pub fn convert(&self, money: &Money, currency: &str) -> Result<Money, MoneyError> {
+        if money.currency == currency {
+            return Ok(money.clone());
+        }
+        let base = div_round(money.amount as i128 * self.rate(&money.currency)?, minor_units(&money.currency) as i128);
+        let amount = div_round(base * minor_units(currency) as i128, self.rate(currency)?);
+        Ok(Money::new(amount as i64, currency))
+    }
+
+    pub fn to_base(&self, money: &Money) -> Result<Money, MoneyError> {
+        self.convert(money, &self.base.clone())
+    }
+}
@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+use crate::{minor_unit_exponent, Money, RateTable};
+
+#[test]
+fn money_units() {
+    assert_eq!(minor_unit_exponent("RUB"), 2);
+    assert_eq!(minor_unit_exponent("JPY"), 0);
+    assert_eq!(minor_unit_exponent("KWD"), 3);
+    assert_eq!(Money::from_major(15, "RUB"), Money::new(1500, "RUB"));
+    assert_eq!(Money::from_major(15, "JPY"), Money::new(15, "JPY"));
+    assert_eq!(Money::from_major(15, "KWD"), Money::new(15_000, "KWD"));
+    // Whole major units round down, negative amounts too
+    assert_eq!(Money::new(1599, "RUB").major(), 15);
+    assert_eq!(Money::new(-1, "RUB").major(), -1);
+}
+
+#[test]
+fn money_arithmetic() {
+    assert_eq!(Money::new(150, "USD").checked_add(&Money::new(99, "USD")).unwrap(), Money::new(249, "USD"));
+    assert_eq!(Money::new(150, "USD").checked_sub(&Money::new(199, "USD")).unwrap(), Money::new(-49, "USD"));
+    assert!(Money::new(100, "USD").checked_add(&Money::new(100, "RUB")).is_err());
+    assert!(Money::new(100, "USD").checked_sub(&Money::new(100, "RUB")).is_err());
+    assert_eq!(Money::new(1999, "RUB").share(1_250), Money::new(249, "RUB"));
+    assert_eq!(Money::new(1999, "RUB").share(10_000), Money::new(1999, "RUB"));
+}
+
+#[test]
+fn rates_convert() {
+    let rates = RateTable {
+        base: "RUB".to_owned(),
+        rates: HashMap::from([
+            ("RUB".to_owned(), 100),
+            ("USD".to_owned(), 9250),
+            ("JPY".to_owned(), 62)
+        ])
+    };
+    assert_eq!(rates.to_base(&Money::new(1999, "USD")).unwrap(), Money::new(184_908, "RUB"));
+    assert_eq!(rates.to_base(&Money::new(1000, "JPY")).unwrap(), Money::new(62_000, "RUB"));
+    assert_eq!(rates.convert(&Money::from_major(100, "RUB"), "USD").unwrap(), Money::new(108, "USD"));
+    // Halves round away from zero
+    assert_eq!(rates.convert(&Money::new(1, "JPY"), "RUB").unwrap(), Money::new(62, "RUB"));
+    assert_eq!(rates.convert(&Money::new(-4625, "RUB"), "USD").unwrap(), Money::new(-50, "USD"));
+    assert_eq!(rates.convert(&Money::new(1234, "USD"), "USD").unwrap(), Money::new(1234, "USD"));
+    assert!(rates.to_base(&Money::new(100, "EUR")).is_err());
+}
+
+#[test]
+fn rates_load() {
+    let path = std::env::temp_dir().join(format!("rates-{}.json", std::process::id()));
+    std::fs::write(&path, r#"{"base": "RUB", "rates": {"USD": 9250}}"#).unwrap();
+    let rates = RateTable::load(path.to_str().unwrap()).unwrap();
+    assert_eq!(rates.to_base(&Money::new(100, "RUB")).unwrap(), Money::new(100, "RUB"));
+    std::fs::write(&path, r#"{"base": "RUB", "rates": {"USD": 0}}"#).unwrap();
+    assert!(RateTable::load(path.to_str().unwrap()).is_err());
+    std::fs::remove_file(path).unwrap();
+}
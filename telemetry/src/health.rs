@@ -0,0 +1,75 @@
+use std::{collections::BTreeMap, fmt::Display, future::Future, time::{Duration, Instant}};
+use serde::Serialize;
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+/// How long a dependency may take to answer before it is reported as down. Checks run at
+/// once, so a readiness probe answers within the 1s Kubernetes waits for it by default.
+pub const CHECK_TIMEOUT: Duration = Duration::from_millis(800);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ComponentHealth {
+    pub status: &'static str,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>
+}
+
+impl ComponentHealth {
+    pub fn is_up(&self) -> bool {
+        self.status == "UP"
+    }
+}
+
+/// Runs one check of a dependency, timing it and giving up after `CHECK_TIMEOUT`.
+pub async fn check<F, E>(probe: F) -> ComponentHealth
+where
+    F: Future<Output = Result<(), E>>,
+    E: Display
+{
+    let started = Instant::now();
+    let error = match tokio::time::timeout(CHECK_TIMEOUT, probe).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some(format!("no answer in {} ms", CHECK_TIMEOUT.as_millis()))
+    };
+    ComponentHealth {
+        status: if error.is_none() { "UP" } else { "DOWN" },
+        latency_ms: started.elapsed().as_millis() as u64,
+        error
+    }
+}
+
+/// Breakdown served by `/manage/health/ready`, the service is ready when every component is up.
+#[derive(Debug, Clone, Serialize)]
+pub struct Readiness {
+    pub status: &'static str,
+    pub components: BTreeMap<&'static str, ComponentHealth>
+}
+
+impl Readiness {
+    pub fn new(components: Vec<(&'static str, ComponentHealth)>) -> Self {
+        let components: BTreeMap<_, _> = components.into_iter().collect();
+        let ready = components.values().all(|x| x.is_up());
+        Readiness {
+            status: if ready { "UP" } else { "DOWN" },
+            components
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.status == "UP"
+    }
+
+    pub fn reply(&self) -> impl Reply {
+        let code = if self.is_ready() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+        warp::reply::with_status(warp::reply::json(self), code)
+    }
+}
+
+/// `GET /manage/health/live`, answering as long as the process serves requests
+pub fn live_route() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("manage" / "health" / "live")
+        .and(crate::route("/manage/health/live"))
+        .and(warp::get())
+        .map(|| warp::reply::json(&serde_json::json!({ "status": "UP" })))
+}
@@ -0,0 +1,101 @@
+use std::{fmt::Debug, io::Write};
+use serde_json::{Map, Value};
+use tracing::{field::{Field, Visit}, span::{Attributes, Id, Record}, Event, Subscriber};
+use tracing_subscriber::{fmt::MakeWriter, layer::Context, registry::LookupSpan, Layer};
+use crate::redact::{is_sensitive, redact, REDACTED};
+
+/// Writes every event as one JSON object per line, together with the fields of the
+/// spans it happened in. Sensitive fields and tokens are masked before writing.
+pub struct JsonLayer<W> {
+    service: &'static str,
+    writer: W
+}
+
+impl<W> JsonLayer<W> {
+    pub fn new(service: &'static str, writer: W) -> Self {
+        JsonLayer { service, writer }
+    }
+}
+
+struct SpanFields(Map<String, Value>);
+
+struct Visitor<'a>(&'a mut Map<String, Value>);
+
+impl Visitor<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        // Hints for span export only
+        if field.name().starts_with("otel.") {
+            return;
+        }
+        let value = match value {
+            _ if is_sensitive(field.name()) => Value::String(REDACTED.to_owned()),
+            Value::String(text) => Value::String(redact(&text)),
+            value => value
+        };
+        self.0.insert(field.name().to_owned(), value);
+    }
+}
+
+impl Visit for Visitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.insert(field, Value::String(format!("{:?}", value)));
+    }
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, Value::String(value.to_owned()));
+    }
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, Value::from(value));
+    }
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, Value::from(value));
+    }
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, Value::from(value));
+    }
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, Value::from(value));
+    }
+}
+
+impl<S, W> Layer<S> for JsonLayer<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + 'static
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Map::new();
+        attrs.record(&mut Visitor(&mut fields));
+        span.extensions_mut().insert(SpanFields(fields));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(fields) = extensions.get_mut::<SpanFields>() {
+            values.record(&mut Visitor(&mut fields.0));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut line = Map::new();
+        line.insert("timestamp".to_owned(), Value::String(chrono::Utc::now().to_rfc3339()));
+        line.insert("level".to_owned(), Value::String(event.metadata().level().to_string()));
+        line.insert("service".to_owned(), Value::String(self.service.to_owned()));
+        line.insert("target".to_owned(), Value::String(event.metadata().target().to_owned()));
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(fields) = span.extensions().get::<SpanFields>() {
+                    line.extend(fields.0.clone());
+                }
+            }
+        }
+        event.record(&mut Visitor(&mut line));
+        let mut writer = self.writer.make_writer();
+        let _ = writeln!(writer, "{}", Value::Object(line));
+    }
+}
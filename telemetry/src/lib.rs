@@ -0,0 +1,152 @@
+use std::{cell::Cell, convert::Infallible, time::Instant};
+use hyper::{header::HeaderValue, service::{make_service_fn, service_fn, Service}, Body, Request, Server};
+use tracing::Instrument;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use uuid::Uuid;
+use warp::{Filter, Reply};
+
+mod health;
+mod layer;
+mod metrics;
+mod otel;
+mod redact;
+#[cfg(test)]
+mod test;
+pub use health::{check, live_route, ComponentHealth, Readiness, CHECK_TIMEOUT};
+pub use layer::JsonLayer;
+pub use metrics::{count_bonus_points, count_ticket_cancellations, count_tickets_sold, metrics_route, observe_outgoing, route, set_queue_depth, QueryTimingLayer, UNMATCHED};
+pub use otel::{set_remote_parent, trace_headers, Telemetry};
+pub use redact::{is_sensitive, redact};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Sets up JSON logs on stderr and, when a collector is configured, span export.
+/// The level is taken from `RUST_LOG` and defaults to `info`. The returned guard
+/// exports the last spans when the service exits.
+pub fn init(service: &'static str) -> Telemetry {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let (otel, provider, otel_error) = match otel::layer(service) {
+        Ok(Some((layer, provider))) => (Some(layer), Some(provider), None),
+        Ok(None) => (None, None, None),
+        Err(e) => (None, None, Some(e))
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(JsonLayer::new(service, std::io::stderr))
+        .with(QueryTimingLayer)
+        .with(otel)
+        .init();
+    if let Some(e) = otel_error {
+        tracing::error!(error = %e, "failed to set up span export");
+    }
+    Telemetry { provider }
+}
+
+/// Id of the request being handled by the current task
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|x| x.clone()).ok()
+}
+
+/// Id to pass on to other services: the current one, or a new one for work
+/// that was not started by a request, like background jobs.
+pub fn request_id() -> String {
+    current_request_id().unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+fn incoming_request_id(request: &Request<Body>) -> String {
+    request.headers().get(REQUEST_ID_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .filter(|x| !x.is_empty() && x.len() <= 128)
+        .map(str::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/// Serves the filter like `warp::serve`, handling each request under its `X-Request-Id`.
+/// The id is taken from the request or generated, logged with every event of the request,
+/// sent along with calls to other services and returned in the response. The request span
+/// continues the trace of the caller. Returns once SIGTERM or Ctrl-C stopped the server,
+/// or with the error when the port could not be bound.
+pub async fn serve<F>(filter: F, port: u16) -> std::io::Result<()>
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: Reply
+{
+    match tokio::net::TcpListener::bind(("0.0.0.0", port)).await.and_then(|x| x.into_std()) {
+        Ok(listener) => serve_on(filter, listener).await,
+        Err(e) => {
+            tracing::error!(error = %e, port, "failed to bind");
+            Err(e)
+        }
+    }
+}
+
+/// Same as `serve` on a socket that is already bound, e.g. to an ephemeral port.
+pub async fn serve_on<F>(filter: F, listener: std::net::TcpListener) -> std::io::Result<()>
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: Reply
+{
+    let service = warp::service(filter);
+    let make_service = make_service_fn(move |_| {
+        let service = service.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let mut service = service.clone();
+                let request_id = incoming_request_id(&request);
+                let method = request.method().to_string();
+                let span = tracing::info_span!("request",
+                                               otel.name = %format!("{} {}", request.method(), request.uri().path()),
+                                               otel.kind = "server",
+                                               request_id = %request_id,
+                                               method = %request.method(),
+                                               path = %request.uri().path(),
+                                               status = tracing::field::Empty);
+                set_remote_parent(&span, request.headers());
+                let handled = REQUEST_ID.scope(request_id.clone(), async move {
+                    let started = Instant::now();
+                    let mut response = service.call(request).await?;
+                    if let Ok(value) = HeaderValue::from_str(&request_id) {
+                        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+                    }
+                    tracing::Span::current().record("status", response.status().as_u16());
+                    tracing::info!(elapsed_ms = started.elapsed().as_millis() as u64, "request handled");
+                    let route = metrics::ROUTE.with(|x| x.get());
+                    metrics::observe_request(&method, route, response.status().as_u16(), started);
+                    Ok::<_, Infallible>(response)
+                }.instrument(span));
+                metrics::ROUTE.scope(Cell::new(UNMATCHED), handled)
+            }))
+        }
+    });
+    let server = match Server::from_tcp(listener) {
+        Ok(server) => server,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to listen");
+            return Err(std::io::Error::other(e));
+        }
+    };
+    if let Err(e) = server.serve(make_service).with_graceful_shutdown(shutdown_signal()).await {
+        tracing::error!(error = %e, "server stopped");
+        return Err(std::io::Error::other(e));
+    }
+    Ok(())
+}
+
+/// Resolves on Ctrl-C or on the SIGTERM Kubernetes sends before stopping a pod
+async fn shutdown_signal() {
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => { signal.recv().await; },
+            Err(_) => std::future::pending().await
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate => {}
+    }
+    tracing::info!("shutting down");
+}
@@ -0,0 +1,117 @@
+use std::{cell::Cell, convert::Infallible, sync::LazyLock, time::Instant};
+use prometheus::{register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge_vec, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, TextEncoder};
+use tracing::{span::{Attributes, Id}, Subscriber};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+use warp::{Filter, Rejection, Reply};
+
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "http_requests_total", "Handled requests", &["method", "route", "status"]).unwrap());
+static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| register_histogram_vec!(
+    "http_request_duration_seconds", "Time to handle a request", &["method", "route", "status"]).unwrap());
+static OUTGOING_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "outgoing_requests_total", "Requests sent to other services", &["method", "target", "status"]).unwrap());
+static OUTGOING_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| register_histogram_vec!(
+    "outgoing_request_duration_seconds", "Time until another service answered", &["method", "target", "status"]).unwrap());
+static DB_QUERY_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| register_histogram_vec!(
+    "db_query_duration_seconds", "Time spent in repository calls", &["query"]).unwrap());
+static QUEUE_DEPTH: LazyLock<IntGaugeVec> = LazyLock::new(|| register_int_gauge_vec!(
+    "retry_queue_depth", "Requests waiting to be retried", &["queue"]).unwrap());
+static TICKETS_SOLD: LazyLock<IntCounter> = LazyLock::new(|| register_int_counter!(
+    "tickets_sold_total", "Tickets created, including the ones of bookings").unwrap());
+static TICKET_CANCELLATIONS: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "ticket_cancellations_total", "Tickets canceled by their owners, with their flights or when a purchase was rolled back", &["reason"]).unwrap());
+static BONUS_POINTS: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "bonus_points_total", "Bonus points accrued or spent on purchases", &["operation"]).unwrap());
+
+/// Route label of requests no route matched
+pub const UNMATCHED: &str = "unmatched";
+
+tokio::task_local! {
+    pub(crate) static ROUTE: Cell<&'static str>;
+}
+
+/// Names the route a request matched in the request metrics, e.g. `/tickets/{ticket_uid}`,
+/// so that every route is counted once however many resources it serves. Goes right after
+/// the path of a route.
+pub fn route(template: &'static str) -> impl Filter<Extract = (), Error = Infallible> + Clone {
+    warp::any()
+        .map(move || {
+            let _ = ROUTE.try_with(|x| x.set(template));
+        })
+        .untuple_one()
+}
+
+pub fn observe_request(method: &str, route: &str, status: u16, started: Instant) {
+    let labels = [method, route, &status.to_string()];
+    HTTP_REQUESTS.with_label_values(&labels).inc();
+    HTTP_REQUEST_DURATION.with_label_values(&labels).observe(started.elapsed().as_secs_f64());
+}
+
+/// Records a call to another service, `status` is `None` when no response came back.
+pub fn observe_outgoing(method: &str, url: &str, status: Option<u16>, started: Instant) {
+    let target = url.split("://").last().unwrap_or_default().split(['/', '?']).next().unwrap_or_default();
+    let status = status.map(|x| x.to_string()).unwrap_or_else(|| "error".to_owned());
+    let labels = [method, target, status.as_str()];
+    OUTGOING_REQUESTS.with_label_values(&labels).inc();
+    OUTGOING_REQUEST_DURATION.with_label_values(&labels).observe(started.elapsed().as_secs_f64());
+}
+
+/// Call whenever the queue changes, not only when it is flushed
+pub fn set_queue_depth(queue: &str, depth: usize) {
+    QUEUE_DEPTH.with_label_values(&[queue]).set(depth as i64);
+}
+
+pub fn count_tickets_sold(count: usize) {
+    TICKETS_SOLD.inc_by(count as u64);
+}
+
+/// Counts every canceled ticket, `reason` is `owner`, `flight` or `rollback`
+pub fn count_ticket_cancellations(reason: &str, count: usize) {
+    TICKET_CANCELLATIONS.with_label_values(&[reason]).inc_by(count as u64);
+}
+
+pub fn count_bonus_points(accrued: i32, spent: i32) {
+    BONUS_POINTS.with_label_values(&["accrued"]).inc_by(accrued.max(0) as u64);
+    BONUS_POINTS.with_label_values(&["spent"]).inc_by(spent.max(0) as u64);
+}
+
+/// Times the spans of repository methods, which are instrumented anyway for tracing.
+pub struct QueryTimingLayer;
+
+struct Started(Instant);
+
+impl<S> Layer<S> for QueryTimingLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if !attrs.metadata().target().ends_with("::repository") {
+            return;
+        }
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(Started(Instant::now()));
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let extensions = span.extensions();
+        if let Some(started) = extensions.get::<Started>() {
+            DB_QUERY_DURATION.with_label_values(&[span.name()]).observe(started.0.elapsed().as_secs_f64());
+        }
+    }
+}
+
+/// `GET /manage/metrics` in the Prometheus text format
+pub fn metrics_route() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("manage" / "metrics")
+        .and(route("/manage/metrics"))
+        .and(warp::get())
+        .map(|| {
+            let mut buffer = vec![];
+            let _ = TextEncoder::new().encode(&prometheus::gather(), &mut buffer);
+            warp::reply::with_header(buffer, "Content-Type", TextEncoder::new().format_type().to_owned())
+        })
+}
@@ -0,0 +1,77 @@
+use std::{collections::HashMap, env, error::Error};
+use hyper::HeaderMap;
+use opentelemetry::{global, propagation::Extractor, trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace::{Tracer, TracerProvider}, Resource};
+use tracing::Span;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+type Export<S> = (OpenTelemetryLayer<S, Tracer>, TracerProvider);
+
+/// Builds the layer exporting spans over OTLP/HTTP when `OTEL_EXPORTER_OTLP_ENDPOINT`
+/// is set, e.g. to `http://localhost:4318` for a local collector, together with the
+/// provider that has to be shut down to export the last batch.
+pub fn layer<S>(service: &'static str) -> Result<Option<Export<S>>, Box<dyn Error>>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>
+{
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let Ok(endpoint) = env::var("OTEL_EXPORTER_OTLP_ENDPOINT") else {
+        return Ok(None);
+    };
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new(vec![KeyValue::new("service.name", service)]))
+        .build();
+    let tracer = provider.tracer(service);
+    global::set_tracer_provider(provider.clone());
+    Ok(Some((tracing_opentelemetry::layer().with_tracer(tracer), provider)))
+}
+
+/// Exports the spans still waiting in the batch when dropped. Hold it in `main` until
+/// the server has stopped.
+#[must_use]
+pub struct Telemetry {
+    pub(crate) provider: Option<TracerProvider>
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        let Some(provider) = self.provider.take() else {
+            return;
+        };
+        if let Err(e) = provider.shutdown() {
+            tracing::error!(error = %e, "failed to export the last spans");
+        }
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|x| x.to_str().ok())
+    }
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|x| x.as_str()).collect()
+    }
+}
+
+/// Continues the trace of the caller if the request carries a `traceparent` header
+pub fn set_remote_parent(span: &Span, headers: &HeaderMap) {
+    let parent = global::get_text_map_propagator(|x| x.extract(&HeaderExtractor(headers)));
+    span.set_parent(parent);
+}
+
+/// `traceparent` headers of the current span for a call to another service
+pub fn trace_headers() -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    let context = Span::current().context();
+    global::get_text_map_propagator(|x| x.inject_context(&context, &mut headers));
+    headers
+}
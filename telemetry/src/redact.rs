@@ -0,0 +1,67 @@
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Fields whose values never reach the logs: credentials and personal data of passengers
+const SENSITIVE_FIELDS: &[&str] = &[
+    "authorization",
+    "token",
+    "password",
+    "username",
+    "recipient",
+    "counterparty",
+    "operator",
+    "passenger_name",
+    "document_number",
+    "email",
+];
+
+pub fn is_sensitive(field: &str) -> bool {
+    let field = field.to_ascii_lowercase();
+    SENSITIVE_FIELDS.iter().any(|x| field.contains(x))
+}
+
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')
+}
+
+fn looks_like_jwt(run: &str) -> bool {
+    run.starts_with("eyJ") && run.split('.').count() == 3 && run.split('.').all(|x| !x.is_empty())
+}
+
+/// Masks the JWTs inside a word, also when they are quoted or end a sentence
+fn redact_jwts(word: &str) -> String {
+    let mut redacted = String::new();
+    let mut run = String::new();
+    for c in word.chars().map(Some).chain([None]) {
+        if let Some(c) = c.filter(|x| is_token_char(*x)) {
+            run.push(c);
+            continue;
+        }
+        let token = run.trim_end_matches('.');
+        if looks_like_jwt(token) {
+            redacted.push_str(REDACTED);
+            redacted.push_str(&run[token.len()..]);
+        }
+        else {
+            redacted.push_str(&run);
+        }
+        run.clear();
+        redacted.extend(c);
+    }
+    redacted
+}
+
+/// Masks bearer tokens and JWTs found anywhere in free text.
+pub fn redact(text: &str) -> String {
+    let mut words = vec![];
+    let mut bearer = false;
+    for word in text.split(' ') {
+        if bearer {
+            words.push(REDACTED.to_owned());
+        }
+        else {
+            words.push(redact_jwts(word));
+        }
+        bearer = word.trim_matches(|c: char| !c.is_ascii_alphabetic()).eq_ignore_ascii_case("bearer");
+    }
+    words.join(" ")
+}
@@ -0,0 +1,28 @@
+-- Written to also adopt databases created before migrations were tracked,
+-- hence IF NOT EXISTS everywhere.
+CREATE TABLE IF NOT EXISTS ticket
+(
+    id            SERIAL PRIMARY KEY,
+    ticket_uid    uuid UNIQUE NOT NULL,
+    username      VARCHAR(80) NOT NULL,
+    flight_number VARCHAR(20) NOT NULL,
+    price         INT         NOT NULL,
+    status        VARCHAR(20) NOT NULL
+);
+
+ALTER TABLE ticket DROP CONSTRAINT IF EXISTS ticket_status_check;
+ALTER TABLE ticket ADD CONSTRAINT ticket_status_check
+    CHECK (status IN ('PAID', 'CANCELED', 'FLIGHT_CANCELED'));
+ALTER TABLE ticket ADD COLUMN IF NOT EXISTS booking_uid uuid;
+ALTER TABLE ticket ADD COLUMN IF NOT EXISTS passenger_name VARCHAR(255);
+ALTER TABLE ticket ADD COLUMN IF NOT EXISTS document_number VARCHAR(40);
+ALTER TABLE ticket ADD COLUMN IF NOT EXISTS currency CHAR(3) NOT NULL DEFAULT 'RUB';
+
+CREATE TABLE IF NOT EXISTS booking
+(
+    id          SERIAL PRIMARY KEY,
+    booking_uid uuid UNIQUE NOT NULL,
+    pnr         VARCHAR(6)  UNIQUE NOT NULL,
+    username    VARCHAR(80) NOT NULL,
+    status      VARCHAR(20) NOT NULL CHECK (status IN ('CONFIRMED', 'CANCELED'))
+);
@@ -0,0 +1,21 @@
+-- Every group of tickets is a booking: itineraries bought before this migration get a
+-- booking row too, and a booking can lose a flight to a cancellation.
+ALTER TABLE booking DROP CONSTRAINT IF EXISTS booking_status_check;
+ALTER TABLE booking ADD CONSTRAINT booking_status_check
+    CHECK (status IN ('CONFIRMED', 'CANCELED', 'FLIGHT_CANCELED'));
+
+-- The PNR is derived from the booking uid, with the digits 0 and 1 the PNR alphabet leaves out replaced
+INSERT INTO booking(booking_uid, pnr, username, status)
+SELECT booking_uid,
+       translate(upper(substr(md5(booking_uid::text), 1, 6)), '01', 'XY'),
+       min(username),
+       CASE
+           WHEN bool_or(status = 'FLIGHT_CANCELED') THEN 'FLIGHT_CANCELED'
+           WHEN bool_and(status = 'CANCELED') THEN 'CANCELED'
+           ELSE 'CONFIRMED'
+       END
+FROM ticket
+WHERE booking_uid IS NOT NULL
+  AND booking_uid NOT IN (SELECT booking_uid FROM booking)
+GROUP BY booking_uid
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,8 @@
+-- Prices were whole major units, they are minor units of their currency from now on.
+-- The exponents are the ones of `structs::minor_unit_exponent`.
+ALTER TABLE ticket ALTER COLUMN price TYPE BIGINT
+    USING price::BIGINT * CASE
+        WHEN currency IN ('JPY', 'KRW', 'VND', 'CLP', 'ISK') THEN 1
+        WHEN currency IN ('BHD', 'KWD', 'OMR', 'JOD', 'TND') THEN 1000
+        ELSE 100
+    END;
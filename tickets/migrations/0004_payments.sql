@@ -0,0 +1,11 @@
+-- The money share of each ticket, kept so that a cancellation finds what to refund after a restart.
+-- Payments outlive their tickets: a deleted ticket still has its payment to refund.
+CREATE TABLE IF NOT EXISTS payment
+(
+    id         SERIAL PRIMARY KEY,
+    ticket_uid uuid        NOT NULL UNIQUE,
+    amount     BIGINT      NOT NULL,
+    currency   CHAR(3)     NOT NULL,
+    status     VARCHAR(20) NOT NULL
+        CHECK (status IN ('AUTHORIZED', 'CAPTURED', 'DECLINED', 'VOIDED', 'REFUNDED'))
+);
@@ -0,0 +1,31 @@
+pub mod memory;
+pub mod migrations;
+pub mod payments;
+pub mod repository;
+pub mod server;
+#[cfg(test)]
+mod test;
+use repository::*;
+
+use custom_error::custom_error;
+
+custom_error!{pub TicketError
+    NotFoundError                            = "Ticket was not found",
+    InvalidStatusError                       = "Ticket status is not known",
+    AlreadyCanceledError                     = "Booking is already canceled",
+}
+
+custom_error!{pub PaymentError
+    NotFoundError                            = "Payment was not found",
+    InvalidStateError{status: String}        = "Payment is {status}",
+    DeclinedError                            = "Payment was declined",
+}
+
+#[macro_export]
+macro_rules! arc{
+    ($a:expr)=>{
+        {
+            std::sync::Arc::new(tokio::sync::Mutex::new($a))
+        }
+    }
+}
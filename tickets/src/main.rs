@@ -1,34 +1,41 @@
-mod repository;
-mod server;
-#[cfg(test)]
-mod test;
 use jwtchecker::JWTChecker;
-use repository::*;
-use server::*;
+use requester::Reqwester;
+use tickets::arc;
+use tickets::memory::MemoryRepository;
+use tickets::payments::FakePaymentProvider;
+use tickets::repository::*;
+use tickets::server::*;
 
 use std::error::Error;
+use std::sync::Arc;
+use tokio::sync::Mutex;
 use std::env;
-use custom_error::custom_error;
 
-custom_error!{pub TicketError
-    NotFoundError                            = "Ticket was not found",
-}
-
-#[macro_export]
-macro_rules! arc{
-    ($a:expr)=>{
-        {
-            std::sync::Arc::new(tokio::sync::Mutex::new($a))
-        }
+/// `STORAGE=memory` keeps the data in the process, otherwise it is stored in `PSQL_CONNECTION`
+async fn open_repository() -> Result<Arc<Mutex<dyn TicketRepository>>, Box<dyn Error>> {
+    if env::var("STORAGE").as_deref() == Ok("memory") {
+        return Ok(arc!(MemoryRepository::default()));
     }
+    Ok(arc!(Repository::new(&env::var("PSQL_CONNECTION")?).await?))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>>{
-    let connection_str = env::var("PSQL_CONNECTION")?;
-    let port = env::var("SERVER_PORT")?.parse()?;
-    let repository = arc!(Repository::new(&connection_str).await?);
+    let _telemetry = telemetry::init("tickets");
+    let repository = open_repository().await?;
     repository.lock().await.init().await?;
-    run_server(repository, port, arc!(JWTChecker::new(&env::var("RSA_PUB")?))).await;
+    // `tickets migrate` only brings the schema up to date
+    if env::args().nth(1).as_deref() == Some("migrate") {
+        return Ok(());
+    }
+    let port = env::var("SERVER_PORT")?.parse()?;
+    let refunds = arc!(Refunds {
+        bonuses: env::var("BONUSES_EVENTS_URL")?.to_owned(),
+        secret: env::var("EVENTS_SECRET").unwrap_or_default(),
+        requester: Box::new(Reqwester {}),
+        queue: vec![],
+        dead_letters: vec![]
+    });
+    run_server(repository, port, arc!(JWTChecker::new(&env::var("RSA_PUB")?)), refunds, arc!(FakePaymentProvider::default())).await?;
     Ok(())
 }
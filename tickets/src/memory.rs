@@ -0,0 +1,155 @@
+use std::error::Error;
+use async_trait::async_trait;
+use database::Sequence;
+use structs::{Booking, BookingPost, Money, Payment, Ticket, TicketPost};
+use uuid::Uuid;
+use crate::{repository::generate_pnr, PaymentError, TicketError, TicketRepository};
+
+/// Tickets, bookings and their payments held by the process, used with `STORAGE=memory`.
+/// Booking tickets and statuses change together as in the transactions of `Repository`.
+#[derive(Default)]
+pub struct MemoryRepository {
+    tickets: Vec<Ticket>,
+    bookings: Vec<Booking>,
+    payments: Vec<Payment>,
+    ticket_ids: Sequence,
+    booking_ids: Sequence
+}
+
+impl MemoryRepository {
+    fn add_ticket(&mut self, username: &str, flight_number: &str, price: &Money, booking_uid: Option<Uuid>,
+                  passenger_name: Option<String>, document_number: Option<String>) -> Uuid {
+        let ticket_uid = Uuid::new_v4();
+        self.tickets.push(Ticket {
+            id: self.ticket_ids.nextval(),
+            ticket_uid,
+            username: username.to_owned(),
+            flight_number: flight_number.to_owned(),
+            price: price.clone(),
+            status: "PAID".to_owned(),
+            booking_uid,
+            passenger_name,
+            document_number
+        });
+        ticket_uid
+    }
+}
+
+#[async_trait]
+impl TicketRepository for MemoryRepository {
+    async fn init(&mut self) ->  Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+    async fn ping(&mut self) ->  Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+    async fn list(&mut self) ->  Result<Vec<Ticket>, Box<dyn Error>> {
+        Ok(self.tickets.clone())
+    }
+    async fn get(&mut self, uuid: Uuid) ->  Result<Ticket, Box<dyn Error>> {
+        self.tickets.iter()
+            .find(|x| x.ticket_uid == uuid)
+            .cloned()
+            .ok_or_else(|| TicketError::NotFoundError.into())
+    }
+    async fn create(&mut self, ticket: TicketPost, username: String) ->  Result<Uuid, Box<dyn Error>> {
+        Ok(self.add_ticket(&username, &ticket.flight_number, &ticket.price, None, None, None))
+    }
+    async fn cancel(&mut self, uuid: Uuid) ->  Result<(), Box<dyn Error>> {
+        self.set_status(uuid, "CANCELED".to_owned()).await
+    }
+    async fn set_status(&mut self, uuid: Uuid, status: String) ->  Result<(), Box<dyn Error>> {
+        for ticket in self.tickets.iter_mut().filter(|x| x.ticket_uid == uuid) {
+            // Checked per row like the constraint of the table, a missing ticket is not an error
+            if !["PAID", "CANCELED", "FLIGHT_CANCELED"].contains(&status.as_str()) {
+                return Err(TicketError::InvalidStatusError.into());
+            }
+            ticket.status = status.clone();
+            if status != "FLIGHT_CANCELED" {
+                continue;
+            }
+            for booking in self.bookings.iter_mut().filter(|x| Some(x.booking_uid) == ticket.booking_uid && x.status == "CONFIRMED") {
+                booking.status = "FLIGHT_CANCELED".to_owned();
+            }
+        }
+        Ok(())
+    }
+    async fn delete(&mut self, uuid: Uuid) ->  Result<(), Box<dyn Error>> {
+        self.tickets.retain(|x| x.ticket_uid != uuid);
+        Ok(())
+    }
+    async fn create_booking(&mut self, booking: BookingPost, username: String) ->  Result<String, Box<dyn Error>> {
+        let booking_uid = Uuid::new_v4();
+        let mut pnr = generate_pnr();
+        while self.bookings.iter().any(|x| x.pnr == pnr) {
+            pnr = generate_pnr();
+        }
+        self.bookings.push(Booking {
+            id: self.booking_ids.nextval(),
+            booking_uid,
+            pnr: pnr.clone(),
+            username: username.clone(),
+            status: "CONFIRMED".to_owned(),
+            tickets: vec![]
+        });
+        for ticket in booking.tickets {
+            self.add_ticket(&username, &ticket.flight_number, &ticket.price, Some(booking_uid),
+                            ticket.passenger_name, ticket.document_number);
+        }
+        Ok(pnr)
+    }
+    async fn get_booking(&mut self, pnr: String) ->  Result<Booking, Box<dyn Error>> {
+        let Some(booking) = self.bookings.iter().find(|x| x.pnr == pnr) else {
+            return Err(TicketError::NotFoundError.into());
+        };
+        Ok(Booking {
+            tickets: self.tickets.iter()
+                .filter(|x| x.booking_uid == Some(booking.booking_uid))
+                .cloned()
+                .collect(),
+            ..booking.clone()
+        })
+    }
+    async fn cancel_booking(&mut self, booking_uid: Uuid) ->  Result<(), Box<dyn Error>> {
+        let Some(booking) = self.bookings.iter_mut().find(|x| x.booking_uid == booking_uid) else {
+            return Err(TicketError::NotFoundError.into());
+        };
+        if booking.status == "CANCELED" {
+            return Err(TicketError::AlreadyCanceledError.into());
+        }
+        booking.status = "CANCELED".to_owned();
+        for ticket in self.tickets.iter_mut().filter(|x| x.booking_uid == Some(booking_uid) && x.status == "PAID") {
+            ticket.status = "CANCELED".to_owned();
+        }
+        Ok(())
+    }
+    async fn delete_booking(&mut self, pnr: String) ->  Result<(), Box<dyn Error>> {
+        let Some(booking) = self.bookings.iter().find(|x| x.pnr == pnr) else {
+            return Ok(());
+        };
+        let booking_uid = booking.booking_uid;
+        self.tickets.retain(|x| x.booking_uid != Some(booking_uid));
+        self.bookings.retain(|x| x.pnr != pnr);
+        Ok(())
+    }
+    async fn get_payment(&mut self, ticket_uid: Uuid) ->  Result<Payment, Box<dyn Error>> {
+        self.payments.iter()
+            .find(|x| x.ticket_uid == ticket_uid)
+            .cloned()
+            .ok_or_else(|| PaymentError::NotFoundError.into())
+    }
+    async fn save_payment(&mut self, payment: Payment) ->  Result<(), Box<dyn Error>> {
+        match self.payments.iter_mut().find(|x| x.ticket_uid == payment.ticket_uid) {
+            Some(saved) => *saved = payment,
+            None => self.payments.push(payment)
+        }
+        Ok(())
+    }
+    async fn list_unsettled_payments(&mut self) ->  Result<Vec<Payment>, Box<dyn Error>> {
+        Ok(self.payments.iter()
+            .filter(|x| x.status == "AUTHORIZED" || x.status == "CAPTURED")
+            .filter(|x| !self.tickets.iter().any(|ticket| ticket.ticket_uid == x.ticket_uid && ticket.status == "PAID"))
+            .cloned()
+            .collect())
+    }
+}
@@ -0,0 +1,9 @@
+use database::Migration;
+
+/// Schema of the service, newest last. Applied migrations must never be edited, changes go into a new file.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "initial", sql: include_str!("../migrations/0001_initial.sql") },
+    Migration { version: 2, name: "itinerary_bookings", sql: include_str!("../migrations/0002_itinerary_bookings.sql") },
+    Migration { version: 3, name: "price_minor_units", sql: include_str!("../migrations/0003_price_minor_units.sql") },
+    Migration { version: 4, name: "payments", sql: include_str!("../migrations/0004_payments.sql") },
+];
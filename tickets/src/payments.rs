@@ -0,0 +1,143 @@
+use std::{collections::HashMap, error::Error, sync::Arc};
+use async_trait::async_trait;
+use structs::{Money, Payment};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+use crate::{PaymentError, TicketRepository};
+
+/// Charges the part of a purchase that is paid with money. Payments are identified by
+/// the ticket they were made for, so retrying an operation for the same ticket is safe.
+#[async_trait]
+pub trait PaymentProvider: Sync + Send {
+    /// Puts a hold on the amount
+    async fn authorize(&mut self, ticket_uid: Uuid, amount: Money) -> Result<(), Box<dyn Error>>;
+    /// Charges a previously authorized amount
+    async fn capture(&mut self, ticket_uid: Uuid, amount: &Money) -> Result<(), Box<dyn Error>>;
+    /// Returns a captured amount or releases a hold that was not captured
+    async fn refund(&mut self, ticket_uid: Uuid, amount: &Money) -> Result<(), Box<dyn Error>>;
+}
+
+/// Keeps payments in memory and accepts everything up to an optional limit. Like a real
+/// provider it refunds what it is asked to, even the payments it forgot on a restart.
+#[derive(Default)]
+pub struct FakePaymentProvider {
+    pub payments: HashMap<Uuid, Payment>,
+    pub decline_above: Option<i64>
+}
+
+#[async_trait]
+impl PaymentProvider for FakePaymentProvider {
+    async fn authorize(&mut self, ticket_uid: Uuid, amount: Money) -> Result<(), Box<dyn Error>> {
+        if self.decline_above.is_some_and(|x| amount.amount > x) {
+            return Err(PaymentError::DeclinedError.into());
+        }
+        self.payments.insert(ticket_uid, Payment { ticket_uid, amount, status: "AUTHORIZED".to_owned() });
+        Ok(())
+    }
+    async fn capture(&mut self, ticket_uid: Uuid, amount: &Money) -> Result<(), Box<dyn Error>> {
+        let Some(payment) = self.payments.get_mut(&ticket_uid) else {
+            return Err(PaymentError::NotFoundError.into());
+        };
+        match payment.status.as_str() {
+            "AUTHORIZED" if &payment.amount == amount => payment.status = "CAPTURED".to_owned(),
+            "CAPTURED" if &payment.amount == amount => (),
+            status => return Err(PaymentError::InvalidStateError { status: status.to_owned() }.into())
+        }
+        Ok(())
+    }
+    async fn refund(&mut self, ticket_uid: Uuid, amount: &Money) -> Result<(), Box<dyn Error>> {
+        let status = match self.payments.get(&ticket_uid).map(|x| x.status.as_str()) {
+            Some("AUTHORIZED") => "VOIDED",
+            Some("VOIDED") => return Ok(()),
+            _ => "REFUNDED"
+        };
+        self.payments.insert(ticket_uid, Payment { ticket_uid, amount: amount.clone(), status: status.to_owned() });
+        Ok(())
+    }
+}
+
+/// Authorizes and captures the money share of a ticket, recording every step so that a
+/// cancellation still finds the payment after a restart. Charging the same amount again
+/// returns the captured payment, a declined payment may be retried.
+pub async fn charge(repository: Arc<Mutex<dyn TicketRepository>>,
+                    provider: Arc<Mutex<dyn PaymentProvider>>,
+                    ticket_uid: Uuid,
+                    amount: Money) -> Result<Payment, Box<dyn Error>> {
+    match repository.lock().await.get_payment(ticket_uid).await {
+        Ok(payment) if payment.status == "CAPTURED" && payment.amount == amount => return Ok(payment),
+        Ok(payment) if payment.status != "DECLINED" => {
+            return Err(PaymentError::InvalidStateError { status: payment.status }.into());
+        },
+        Ok(_) => {},
+        Err(e) if matches!(e.downcast_ref::<PaymentError>(), Some(PaymentError::NotFoundError)) => {},
+        Err(e) => return Err(e)
+    }
+    let mut provider = provider.lock().await;
+    let mut payment = Payment { ticket_uid, amount: amount.clone(), status: "DECLINED".to_owned() };
+    if provider.authorize(ticket_uid, amount.clone()).await.is_err() {
+        repository.lock().await.save_payment(payment).await?;
+        return Err(PaymentError::DeclinedError.into());
+    }
+    payment.status = "AUTHORIZED".to_owned();
+    let saved = repository.lock().await.save_payment(payment.clone()).await.map_err(|e| e.to_string());
+    if let Err(e) = saved {
+        let _ = provider.refund(ticket_uid, &amount).await;
+        return Err(e.into());
+    }
+    if provider.capture(ticket_uid, &amount).await.is_err() {
+        // A hold that can not be released now stays AUTHORIZED and is voided with its ticket
+        if provider.refund(ticket_uid, &amount).await.is_ok() {
+            payment.status = "VOIDED".to_owned();
+            repository.lock().await.save_payment(payment).await?;
+        }
+        return Err(PaymentError::DeclinedError.into());
+    }
+    payment.status = "CAPTURED".to_owned();
+    repository.lock().await.save_payment(payment.clone()).await?;
+    Ok(payment)
+}
+
+/// Returns the money taken for a ticket. Tickets paid with bonuses only have no payment.
+pub async fn refund(repository: Arc<Mutex<dyn TicketRepository>>,
+                    provider: Arc<Mutex<dyn PaymentProvider>>,
+                    ticket_uid: Uuid) -> Result<(), Box<dyn Error>> {
+    let mut payment = match repository.lock().await.get_payment(ticket_uid).await {
+        Ok(payment) => payment,
+        Err(e) if matches!(e.downcast_ref::<PaymentError>(), Some(PaymentError::NotFoundError)) => return Ok(()),
+        Err(e) => return Err(e)
+    };
+    let status = match payment.status.as_str() {
+        "CAPTURED" => "REFUNDED",
+        "AUTHORIZED" => "VOIDED",
+        _ => return Ok(())
+    };
+    provider.lock().await.refund(ticket_uid, &payment.amount).await?;
+    payment.status = status.to_owned();
+    repository.lock().await.save_payment(payment).await
+}
+
+/// Refunds the canceled tickets right away. Failures are logged and left to `refund_unsettled`.
+pub async fn refund_tickets(repository: Arc<Mutex<dyn TicketRepository>>,
+                            provider: Arc<Mutex<dyn PaymentProvider>>,
+                            ticket_uids: &[Uuid]) {
+    for ticket_uid in ticket_uids {
+        if let Err(e) = refund(repository.clone(), provider.clone(), *ticket_uid).await {
+            tracing::error!(ticket_uid = %ticket_uid, error = %e, "failed to refund payment");
+        }
+    }
+}
+
+/// Refunds the payments still holding money for tickets that are no longer paid,
+/// returning how many were refunded
+pub async fn refund_unsettled(repository: Arc<Mutex<dyn TicketRepository>>,
+                              provider: Arc<Mutex<dyn PaymentProvider>>) -> Result<usize, Box<dyn Error>> {
+    let payments = repository.lock().await.list_unsettled_payments().await?;
+    let mut refunded = 0;
+    for payment in payments {
+        match refund(repository.clone(), provider.clone(), payment.ticket_uid).await {
+            Ok(_) => refunded += 1,
+            Err(e) => tracing::error!(ticket_uid = %payment.ticket_uid, error = %e, "failed to refund payment")
+        }
+    }
+    Ok(refunded)
+}
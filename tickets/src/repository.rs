@@ -1,104 +1,259 @@
 use std::error::Error;
 use async_trait::async_trait;
-use tokio_postgres::{Client, NoTls};
-use crate::TicketError;
-use structs::{Ticket, TicketPost};
+use database::Database;
+use crate::migrations::MIGRATIONS;
+use tokio_postgres::Row;
+use crate::{PaymentError, TicketError};
+use structs::{Booking, BookingPost, Money, Payment, Ticket, TicketPost};
 use uuid::Uuid;
 
+const PNR_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const PNR_LENGTH: usize = 6;
+
 #[async_trait]
 pub trait TicketRepository: Sync + Send {
     async fn init(&mut self) ->  Result<(), Box<dyn Error>>;
+    async fn ping(&mut self) ->  Result<(), Box<dyn Error>>;
     async fn list(&mut self) ->  Result<Vec<Ticket>, Box<dyn Error>>;
     async fn get(&mut self, uuid: Uuid) ->  Result<Ticket, Box<dyn Error>>;
     async fn create(&mut self, ticket: TicketPost, username: String) ->  Result<Uuid, Box<dyn Error>>;
     async fn cancel(&mut self, uuid: Uuid) ->  Result<(), Box<dyn Error>>;
+    async fn set_status(&mut self, uuid: Uuid, status: String) ->  Result<(), Box<dyn Error>>;
     async fn delete(&mut self, uuid: Uuid) ->  Result<(), Box<dyn Error>>;
+    async fn create_booking(&mut self, booking: BookingPost, username: String) ->  Result<String, Box<dyn Error>>;
+    async fn get_booking(&mut self, pnr: String) ->  Result<Booking, Box<dyn Error>>;
+    async fn cancel_booking(&mut self, booking_uid: Uuid) ->  Result<(), Box<dyn Error>>;
+    async fn delete_booking(&mut self, pnr: String) ->  Result<(), Box<dyn Error>>;
+    async fn get_payment(&mut self, ticket_uid: Uuid) ->  Result<Payment, Box<dyn Error>>;
+    /// Creates the payment of the ticket or replaces its amount and status
+    async fn save_payment(&mut self, payment: Payment) ->  Result<(), Box<dyn Error>>;
+    /// Payments still holding money for tickets that are no longer paid or no longer exist
+    async fn list_unsettled_payments(&mut self) ->  Result<Vec<Payment>, Box<dyn Error>>;
+}
+
+const TICKET_COLUMNS: &str = "id, ticket_uid, username, flight_number, price, currency, status, booking_uid, passenger_name, document_number";
+
+fn ticket_from_row(row: &Row) -> Ticket {
+    Ticket {
+        id: row.get("id"),
+        ticket_uid: row.get("ticket_uid"),
+        username: row.get("username"),
+        flight_number: row.get("flight_number"),
+        price: Money::new(row.get("price"), row.get("currency")),
+        status: row.get("status"),
+        booking_uid: row.get("booking_uid"),
+        passenger_name: row.get("passenger_name"),
+        document_number: row.get("document_number")
+    }
+}
+
+fn payment_from_row(row: &Row) -> Payment {
+    Payment {
+        ticket_uid: row.get("ticket_uid"),
+        amount: Money::new(row.get("amount"), row.get("currency")),
+        status: row.get("status")
+    }
+}
+
+pub fn generate_pnr() -> String {
+    Uuid::new_v4().as_bytes()[..PNR_LENGTH].iter()
+        .map(|x| PNR_ALPHABET[*x as usize % PNR_ALPHABET.len()] as char)
+        .collect()
 }
 
 pub struct Repository {
-    client: Client
+    db: Database
 }
 
 impl Repository {
     pub async fn new(connection_str: &str) -> Result<Self,  Box<dyn Error>> {
-        let (client, connection) = tokio_postgres::connect(connection_str, NoTls).await?;
-        tokio::spawn(async move {
-            connection.await
-        });
-        Ok(Self { 
-            client
+        Ok(Self {
+            db: Database::connect(connection_str).await?
         })
     }
 }
 
 #[async_trait]
 impl TicketRepository for Repository {
+    #[tracing::instrument(skip_all)]
+    async fn ping(&mut self) ->  Result<(), Box<dyn Error>> {
+        self.db.client().await?.simple_query("SELECT 1").await?;
+        Ok(())
+    }
+    #[tracing::instrument(skip_all)]
     async fn init(&mut self) ->  Result<(), Box<dyn Error>> {
-        self.client.execute("
-            CREATE TABLE IF NOT EXISTS ticket
-            (
-                id            SERIAL PRIMARY KEY,
-                ticket_uid    uuid UNIQUE NOT NULL,
-                username      VARCHAR(80) NOT NULL,
-                flight_number VARCHAR(20) NOT NULL,
-                price         INT         NOT NULL,
-                status        VARCHAR(20) NOT NULL CHECK (status IN ('PAID', 'CANCELED'))
-            );
-        ", &[]).await?;
+        self.db.migrate(MIGRATIONS).await?;
         Ok(())
     }
+    #[tracing::instrument(skip_all)]
     async fn list(&mut self) ->  Result<Vec<Ticket>, Box<dyn Error>> {
         let mut list = vec![];
-        for row in self.client.query("
-            SELECT * FROM ticket
-        ", &[]).await? {
-            list.push(Ticket {
-                id: row.get(0),
-                ticket_uid: row.get(1),
-                username: row.get(2),
-                flight_number: row.get(3),
-                price: row.get(4),
-                status: row.get(5)
-            })
+        for row in self.db.client().await?.query(&format!("
+            SELECT {} FROM ticket
+        ", TICKET_COLUMNS), &[]).await? {
+            list.push(ticket_from_row(&row))
         }
         Ok(list)
     }
+    #[tracing::instrument(skip_all)]
     async fn get(&mut self, uuid: Uuid) ->  Result<Ticket, Box<dyn Error>> {
-        for row in self.client.query(&format!("
-        SELECT * FROM ticket WHERE ticket_uid = '{}'
-        ", uuid), &[]).await? {
-            return Ok(Ticket {
-                id: row.get(0),
-                ticket_uid: row.get(1),
-                username: row.get(2),
-                flight_number: row.get(3),
-                price: row.get(4),
-                status: row.get(5)
-            })
-        }
-        Err(TicketError::NotFoundError.into())
+        let Some(row) = self.db.client().await?.query_opt(&format!("
+            SELECT {} FROM ticket WHERE ticket_uid = $1
+        ", TICKET_COLUMNS), &[&uuid]).await? else {
+            return Err(TicketError::NotFoundError.into());
+        };
+        Ok(ticket_from_row(&row))
     }
+    #[tracing::instrument(skip_all)]
     async fn create(&mut self, ticket: TicketPost, username: String) ->  Result<Uuid, Box<dyn Error>> {
         let ticket_uid = Uuid::new_v4();
-        self.client.batch_execute(&format!("
-            INSERT INTO ticket(ticket_uid, username, flight_number, price, status) VALUES
-                ('{}', '{}', '{}', {}, '{}')
-        ", ticket_uid.clone(), username, ticket.flight_number, ticket.price, "PAID")).await?;
+        self.db.client().await?.execute("
+            INSERT INTO ticket(ticket_uid, username, flight_number, price, currency, status) VALUES
+                ($1, $2, $3, $4, $5, 'PAID')
+        ", &[&ticket_uid, &username, &ticket.flight_number, &ticket.price.amount, &ticket.price.currency]).await?;
         Ok(ticket_uid)
     }
+    #[tracing::instrument(skip_all)]
     async fn cancel(&mut self, uuid: Uuid) ->  Result<(), Box<dyn Error>> {
-        self.client.batch_execute(&format!("
+        self.db.client().await?.batch_execute(&format!("
             UPDATE ticket SET
                 status = 'CANCELED'
             WHERE ticket_uid = '{}'
         ", uuid)).await?;
         Ok(())
     }
+    #[tracing::instrument(skip_all)]
+    async fn set_status(&mut self, uuid: Uuid, status: String) ->  Result<(), Box<dyn Error>> {
+        let transaction = self.db.client().await?.transaction().await?;
+        transaction.execute("
+            UPDATE ticket SET status = $1 WHERE ticket_uid = $2
+        ", &[&status, &uuid]).await?;
+        // A booking that lost one of its flights can no longer be flown as booked
+        if status == "FLIGHT_CANCELED" {
+            transaction.execute("
+                UPDATE booking SET status = 'FLIGHT_CANCELED'
+                WHERE status = 'CONFIRMED' AND booking_uid = (SELECT booking_uid FROM ticket WHERE ticket_uid = $1)
+            ", &[&uuid]).await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+    #[tracing::instrument(skip_all)]
     async fn delete(&mut self, uuid: Uuid) ->  Result<(), Box<dyn Error>> {
-        self.client.batch_execute(&format!("
+        self.db.client().await?.batch_execute(&format!("
             DELETE FROM ticket
             WHERE ticket_uid = '{}'
         ", uuid)).await?;
         Ok(())
     }
+    #[tracing::instrument(skip_all)]
+    async fn create_booking(&mut self, booking: BookingPost, username: String) ->  Result<String, Box<dyn Error>> {
+        let transaction = self.db.client().await?.transaction().await?;
+        let booking_uid = Uuid::new_v4();
+        let mut pnr = generate_pnr();
+        while !transaction.query("SELECT id FROM booking WHERE pnr = $1", &[&pnr]).await?.is_empty() {
+            pnr = generate_pnr();
+        }
+        transaction.execute("
+            INSERT INTO booking(booking_uid, pnr, username, status) VALUES
+                ($1, $2, $3, 'CONFIRMED')
+        ", &[&booking_uid, &pnr, &username]).await?;
+        for ticket in booking.tickets {
+            transaction.execute("
+                INSERT INTO ticket(ticket_uid, username, flight_number, price, currency, status, booking_uid, passenger_name, document_number) VALUES
+                    ($1, $2, $3, $4, $5, 'PAID', $6, $7, $8)
+            ", &[&Uuid::new_v4(), &username, &ticket.flight_number, &ticket.price.amount, &ticket.price.currency,
+                 &booking_uid, &ticket.passenger_name, &ticket.document_number]).await?;
+        }
+        transaction.commit().await?;
+        Ok(pnr)
+    }
+    #[tracing::instrument(skip_all)]
+    async fn get_booking(&mut self, pnr: String) ->  Result<Booking, Box<dyn Error>> {
+        let Some(row) = self.db.client().await?.query("
+            SELECT id, booking_uid, pnr, username, status FROM booking WHERE pnr = $1
+        ", &[&pnr]).await?.into_iter().next() else {
+            return Err(TicketError::NotFoundError.into());
+        };
+        let booking_uid: Uuid = row.get("booking_uid");
+        let mut tickets = vec![];
+        for ticket_row in self.db.client().await?.query(&format!("
+            SELECT {} FROM ticket WHERE booking_uid = $1 ORDER BY id
+        ", TICKET_COLUMNS), &[&booking_uid]).await? {
+            tickets.push(ticket_from_row(&ticket_row));
+        }
+        Ok(Booking {
+            id: row.get("id"),
+            booking_uid,
+            pnr: row.get("pnr"),
+            username: row.get("username"),
+            status: row.get("status"),
+            tickets
+        })
+    }
+    #[tracing::instrument(skip_all)]
+    async fn cancel_booking(&mut self, booking_uid: Uuid) ->  Result<(), Box<dyn Error>> {
+        let transaction = self.db.client().await?.transaction().await?;
+        let Some(row) = transaction.query_opt("
+            SELECT status FROM booking WHERE booking_uid = $1 FOR UPDATE
+        ", &[&booking_uid]).await? else {
+            return Err(TicketError::NotFoundError.into());
+        };
+        if row.get::<_, String>("status") == "CANCELED" {
+            return Err(TicketError::AlreadyCanceledError.into());
+        }
+        // Tickets of canceled flights keep their status, they were refunded with the flight
+        transaction.execute("
+            UPDATE ticket SET status = 'CANCELED' WHERE status = 'PAID' AND booking_uid = $1
+        ", &[&booking_uid]).await?;
+        transaction.execute("
+            UPDATE booking SET status = 'CANCELED' WHERE booking_uid = $1
+        ", &[&booking_uid]).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+    #[tracing::instrument(skip_all)]
+    async fn delete_booking(&mut self, pnr: String) ->  Result<(), Box<dyn Error>> {
+        let transaction = self.db.client().await?.transaction().await?;
+        transaction.execute("
+            DELETE FROM ticket WHERE booking_uid = (SELECT booking_uid FROM booking WHERE pnr = $1)
+        ", &[&pnr]).await?;
+        transaction.execute("
+            DELETE FROM booking WHERE pnr = $1
+        ", &[&pnr]).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+    #[tracing::instrument(skip_all)]
+    async fn get_payment(&mut self, ticket_uid: Uuid) ->  Result<Payment, Box<dyn Error>> {
+        let Some(row) = self.db.client().await?.query_opt("
+            SELECT ticket_uid, amount, currency, status FROM payment WHERE ticket_uid = $1
+        ", &[&ticket_uid]).await? else {
+            return Err(PaymentError::NotFoundError.into());
+        };
+        Ok(payment_from_row(&row))
+    }
+    #[tracing::instrument(skip_all)]
+    async fn save_payment(&mut self, payment: Payment) ->  Result<(), Box<dyn Error>> {
+        self.db.client().await?.execute("
+            INSERT INTO payment(ticket_uid, amount, currency, status) VALUES
+                ($1, $2, $3, $4)
+            ON CONFLICT (ticket_uid) DO UPDATE SET
+                amount = EXCLUDED.amount, currency = EXCLUDED.currency, status = EXCLUDED.status
+        ", &[&payment.ticket_uid, &payment.amount.amount, &payment.amount.currency, &payment.status]).await?;
+        Ok(())
+    }
+    #[tracing::instrument(skip_all)]
+    async fn list_unsettled_payments(&mut self) ->  Result<Vec<Payment>, Box<dyn Error>> {
+        let mut list = vec![];
+        for row in self.db.client().await?.query("
+            SELECT payment.ticket_uid, amount, payment.currency, payment.status FROM payment
+            LEFT JOIN ticket ON ticket.ticket_uid = payment.ticket_uid
+            WHERE payment.status IN ('AUTHORIZED', 'CAPTURED') AND (ticket.status IS NULL OR ticket.status != 'PAID')
+            ORDER BY payment.id
+        ", &[]).await? {
+            list.push(payment_from_row(&row))
+        }
+        Ok(list)
+    }
 }
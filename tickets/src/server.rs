@@ -1,11 +1,12 @@
-use std::{convert::Infallible, sync::Arc};
-use jwtchecker::JWTChecker;
+use std::{collections::HashMap, convert::Infallible, sync::Arc};
+use jwtchecker::{service_token_matches, JWTChecker, SERVICE_TOKEN_HEADER};
+use requester::{RequestMethod, Requester};
 use tokio::sync::Mutex;
 use uuid::Uuid;
 use warp::{reply::{self, Reply}, Filter, Rejection};
-use structs::TicketPost;
+use structs::{BookingPost, FlightStatusEvent, PaymentPost, Ticket, TicketPost, TicketRefundEvent};
 
-use super::TicketRepository;
+use super::{payments::{self, PaymentProvider}, PaymentError, TicketError, TicketRepository};
 
 pub type WebResult<T> = std::result::Result<T, Rejection>;
 
@@ -57,6 +58,7 @@ async fn post_handler(body: TicketPost,
     let Ok(id) = ticket_repository.lock().await.create(body, username).await else {
         return Ok(Box::new(warp::reply::with_status("Encountered an error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
     };
+    telemetry::count_tickets_sold(1);
     let Ok(ticket) = ticket_repository.lock().await.get(id).await else {
         return Ok(Box::new(warp::reply::with_status("Encountered an error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
     };
@@ -66,7 +68,8 @@ async fn post_handler(body: TicketPost,
 async fn cancel_handler(id: Uuid,
                         auth_token: String,
                         ticket_repository: Arc<Mutex<dyn TicketRepository>>,
-                        checker: Arc<Mutex<JWTChecker>>) -> WebResult<Box<dyn Reply>> {
+                        checker: Arc<Mutex<JWTChecker>>,
+                        provider: Arc<Mutex<dyn PaymentProvider>>) -> WebResult<Box<dyn Reply>> {
     let jwtchecker = checker.lock().await.clone();
     let Ok(username) = jwtchecker.decode_header(&auth_token) else {
         return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
@@ -83,19 +86,19 @@ async fn cancel_handler(id: Uuid,
             return Ok(Box::new(not_found_reply));
         }
     }
-    match ticket_repository.lock().await.cancel(id).await {
-        Ok(_) => {
-            return Ok(Box::new(warp::reply::with_status("Deleted ticket", warp::http::StatusCode::NO_CONTENT)))
-        },
-        _ => {}
+    if ticket_repository.lock().await.cancel(id).await.is_err() {
+        return Ok(Box::new(not_found_reply));
     }
-    return Ok(Box::new(not_found_reply));
+    telemetry::count_ticket_cancellations("owner", 1);
+    payments::refund_tickets(ticket_repository, provider, &[id]).await;
+    return Ok(Box::new(warp::reply::with_status("Deleted ticket", warp::http::StatusCode::NO_CONTENT)))
 }
 
 async fn delete_handler(id: Uuid,
                         auth_token: String,
                         ticket_repository: Arc<Mutex<dyn TicketRepository>>,
-                        checker: Arc<Mutex<JWTChecker>>) -> WebResult<Box<dyn Reply>> {
+                        checker: Arc<Mutex<JWTChecker>>,
+                        provider: Arc<Mutex<dyn PaymentProvider>>) -> WebResult<Box<dyn Reply>> {
     let jwtchecker = checker.lock().await.clone();
     let Ok(username) = jwtchecker.decode_header(&auth_token) else {
         return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
@@ -112,39 +115,275 @@ async fn delete_handler(id: Uuid,
             return Ok(Box::new(not_found_reply));
         }
     }
-    match ticket_repository.lock().await.delete(id).await {
-        Ok(_) => {
-            return Ok(Box::new(warp::reply::with_status("Deleted ticket", warp::http::StatusCode::NO_CONTENT)))
+    if ticket_repository.lock().await.delete(id).await.is_err() {
+        return Ok(Box::new(not_found_reply));
+    }
+    telemetry::count_ticket_cancellations("rollback", 1);
+    payments::refund_tickets(ticket_repository, provider, &[id]).await;
+    return Ok(Box::new(warp::reply::with_status("Deleted ticket", warp::http::StatusCode::NO_CONTENT)))
+}
+
+async fn cancel_itinerary_handler(booking_uid: Uuid,
+                                  auth_token: String,
+                                  ticket_repository: Arc<Mutex<dyn TicketRepository>>,
+                                  checker: Arc<Mutex<JWTChecker>>,
+                                  provider: Arc<Mutex<dyn PaymentProvider>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = checker.lock().await.clone();
+    let Ok(username) = jwtchecker.decode_header(&auth_token) else {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
+    };
+
+    let not_found_reply = warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
+    let Ok(tickets) = ticket_repository.lock().await.list().await else {
+        return Ok(Box::new(warp::reply::with_status("Encountered an error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
+    };
+    let tickets: Vec<&Ticket> = tickets.iter().filter(|x| x.booking_uid == Some(booking_uid)).collect();
+    if tickets.is_empty() || tickets.iter().any(|x| x.username != username) {
+        return Ok(Box::new(not_found_reply));
+    }
+    cancel_booking(booking_uid, ticket_repository, provider).await
+}
+
+/// Cancels every paid ticket of the booking at once and refunds their money
+async fn cancel_booking(booking_uid: Uuid,
+                        ticket_repository: Arc<Mutex<dyn TicketRepository>>,
+                        provider: Arc<Mutex<dyn PaymentProvider>>) -> WebResult<Box<dyn Reply>> {
+    let tickets = ticket_repository.lock().await.list().await.unwrap_or_default();
+    let paid = tickets.iter().filter(|x| x.booking_uid == Some(booking_uid) && x.status == "PAID").count();
+    match ticket_repository.lock().await.cancel_booking(booking_uid).await {
+        Ok(_) => {},
+        Err(e) if matches!(e.downcast_ref::<TicketError>(), Some(TicketError::AlreadyCanceledError)) => {
+            return Ok(Box::new(warp::reply::with_status("Booking already canceled", warp::http::StatusCode::CONFLICT)));
+        },
+        Err(_) => {
+            return Ok(Box::new(warp::reply::with_status("Encountered an error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
+        }
+    }
+    telemetry::count_ticket_cancellations("owner", paid);
+    // Refunding is idempotent, tickets of canceled flights that were refunded already are skipped
+    let ticket_uids: Vec<Uuid> = tickets.iter().filter(|x| x.booking_uid == Some(booking_uid)).map(|x| x.ticket_uid).collect();
+    payments::refund_tickets(ticket_repository, provider, &ticket_uids).await;
+    Ok(Box::new(warp::reply::with_status("Canceled booking", warp::http::StatusCode::NO_CONTENT)))
+}
+
+async fn post_booking_handler(body: BookingPost,
+                              auth_token: String,
+                              ticket_repository: Arc<Mutex<dyn TicketRepository>>,
+                              checker: Arc<Mutex<JWTChecker>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = checker.lock().await.clone();
+    let Ok(username) = jwtchecker.decode_header(&auth_token) else {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
+    };
+    if body.tickets.is_empty() {
+        return Ok(Box::new(warp::reply::with_status("Booking has no tickets", warp::http::StatusCode::BAD_REQUEST)));
+    }
+    if !body.tickets.iter().all(|x| x.has_valid_passenger()) {
+        return Ok(Box::new(warp::reply::with_status("Invalid passenger name or document number", warp::http::StatusCode::BAD_REQUEST)));
+    }
+
+    let count = body.tickets.len();
+    let Ok(pnr) = ticket_repository.lock().await.create_booking(body, username).await else {
+        return Ok(Box::new(warp::reply::with_status("Encountered an error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
+    };
+    telemetry::count_tickets_sold(count);
+    let Ok(booking) = ticket_repository.lock().await.get_booking(pnr).await else {
+        return Ok(Box::new(warp::reply::with_status("Encountered an error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
+    };
+    Ok(Box::new(reply::json(&booking)))
+}
+
+async fn get_booking_handler(pnr: String,
+                             auth_token: String,
+                             ticket_repository: Arc<Mutex<dyn TicketRepository>>,
+                             checker: Arc<Mutex<JWTChecker>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = checker.lock().await.clone();
+    let Ok(username) = jwtchecker.decode_header(&auth_token) else {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
+    };
+
+    let not_found_reply = warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
+    let Ok(booking) = ticket_repository.lock().await.get_booking(pnr).await else {
+        return Ok(Box::new(not_found_reply));
+    };
+    if booking.username != username {
+        return Ok(Box::new(not_found_reply));
+    }
+    Ok(Box::new(reply::json(&booking)))
+}
+
+async fn cancel_booking_handler(pnr: String,
+                                auth_token: String,
+                                ticket_repository: Arc<Mutex<dyn TicketRepository>>,
+                                checker: Arc<Mutex<JWTChecker>>,
+                                provider: Arc<Mutex<dyn PaymentProvider>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = checker.lock().await.clone();
+    let Ok(username) = jwtchecker.decode_header(&auth_token) else {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
+    };
+
+    let not_found_reply = warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
+    let Ok(booking) = ticket_repository.lock().await.get_booking(pnr).await else {
+        return Ok(Box::new(not_found_reply));
+    };
+    if booking.username != username {
+        return Ok(Box::new(not_found_reply));
+    }
+    cancel_booking(booking.booking_uid, ticket_repository, provider).await
+}
+
+async fn delete_booking_handler(pnr: String,
+                                auth_token: String,
+                                ticket_repository: Arc<Mutex<dyn TicketRepository>>,
+                                checker: Arc<Mutex<JWTChecker>>,
+                                provider: Arc<Mutex<dyn PaymentProvider>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = checker.lock().await.clone();
+    let Ok(username) = jwtchecker.decode_header(&auth_token) else {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
+    };
+
+    let not_found_reply = warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
+    let Ok(booking) = ticket_repository.lock().await.get_booking(pnr.clone()).await else {
+        return Ok(Box::new(not_found_reply));
+    };
+    if booking.username != username {
+        return Ok(Box::new(not_found_reply));
+    }
+    let Ok(_) = ticket_repository.lock().await.delete_booking(pnr).await else {
+        return Ok(Box::new(warp::reply::with_status("Encountered an error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
+    };
+    telemetry::count_ticket_cancellations("rollback", booking.tickets.len());
+    let ticket_uids: Vec<Uuid> = booking.tickets.iter().map(|x| x.ticket_uid).collect();
+    payments::refund_tickets(ticket_repository, provider, &ticket_uids).await;
+    Ok(Box::new(warp::reply::with_status("Deleted booking", warp::http::StatusCode::NO_CONTENT)))
+}
+
+async fn flight_event_handler(service_token: Option<String>,
+                              event: FlightStatusEvent,
+                              ticket_repository: Arc<Mutex<dyn TicketRepository>>,
+                              refunds: Arc<Mutex<Refunds>>,
+                              provider: Arc<Mutex<dyn PaymentProvider>>) -> WebResult<Box<dyn Reply>> {
+    let secret = refunds.lock().await.secret.clone();
+    if !service_token_matches(&secret, service_token.as_deref()) {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)));
+    }
+    if event.status != "CANCELED" {
+        return Ok(Box::new(warp::reply::with_status("Event processed", warp::http::StatusCode::NO_CONTENT)));
+    }
+    let Ok(tickets) = ticket_repository.lock().await.list().await else {
+        return Ok(Box::new(warp::reply::with_status("Encountered an error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
+    };
+    let mut canceled = vec![];
+    for ticket in tickets {
+        if ticket.flight_number != event.flight_number || ticket.status != "PAID" {
+            continue;
+        }
+        let Ok(_) = ticket_repository.lock().await.set_status(ticket.ticket_uid, "FLIGHT_CANCELED".to_owned()).await else {
+            return Ok(Box::new(warp::reply::with_status("Encountered an error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
+        };
+        let mut refunds = refunds.lock().await;
+        refunds.queue.push(TicketRefundEvent {
+            ticket_uid: ticket.ticket_uid
+        });
+        telemetry::set_queue_depth("refunds", refunds.queue.len());
+        drop(refunds);
+        canceled.push(ticket.ticket_uid);
+    }
+    telemetry::count_ticket_cancellations("flight", canceled.len());
+    // The bonuses go back through the queue, the money is returned here
+    payments::refund_tickets(ticket_repository, provider, &canceled).await;
+    Ok(Box::new(warp::reply::with_status("Event processed", warp::http::StatusCode::NO_CONTENT)))
+}
+
+async fn post_payment_handler(id: Uuid,
+                              body: PaymentPost,
+                              auth_token: String,
+                              ticket_repository: Arc<Mutex<dyn TicketRepository>>,
+                              checker: Arc<Mutex<JWTChecker>>,
+                              provider: Arc<Mutex<dyn PaymentProvider>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = checker.lock().await.clone();
+    let Ok(username) = jwtchecker.decode_header(&auth_token) else {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
+    };
+
+    let Ok(ticket) = ticket_repository.lock().await.get(id).await else {
+        return Ok(Box::new(warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND)));
+    };
+    if ticket.username != username {
+        return Ok(Box::new(warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND)));
+    }
+    if ticket.status != "PAID" {
+        return Ok(Box::new(warp::reply::with_status("Ticket already canceled", warp::http::StatusCode::CONFLICT)));
+    }
+    if body.amount.amount <= 0 {
+        return Ok(Box::new(warp::reply::with_status("Nothing to charge", warp::http::StatusCode::BAD_REQUEST)));
+    }
+    match payments::charge(ticket_repository, provider, id, body.amount).await {
+        Ok(payment) => Ok(Box::new(reply::json(&payment))),
+        Err(e) if matches!(e.downcast_ref::<PaymentError>(), Some(PaymentError::DeclinedError)) => {
+            Ok(Box::new(warp::reply::with_status("Payment was declined", warp::http::StatusCode::PAYMENT_REQUIRED)))
+        },
+        Err(e) if matches!(e.downcast_ref::<PaymentError>(), Some(PaymentError::InvalidStateError { .. })) => {
+            Ok(Box::new(warp::reply::with_status("Ticket was already charged", warp::http::StatusCode::CONFLICT)))
         },
-        _ => {}
+        Err(_) => Ok(Box::new(warp::reply::with_status("Encountered an error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)))
+    }
+}
+
+async fn get_payment_handler(id: Uuid,
+                             auth_token: String,
+                             ticket_repository: Arc<Mutex<dyn TicketRepository>>,
+                             checker: Arc<Mutex<JWTChecker>>) -> WebResult<Box<dyn Reply>> {
+    let jwtchecker = checker.lock().await.clone();
+    let Ok(username) = jwtchecker.decode_header(&auth_token) else {
+        return Ok(Box::new(warp::reply::with_status("Not authorized", warp::http::StatusCode::UNAUTHORIZED)))
+    };
+
+    let not_found_reply = warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
+    let Ok(ticket) = ticket_repository.lock().await.get(id).await else {
+        return Ok(Box::new(not_found_reply));
+    };
+    if ticket.username != username {
+        return Ok(Box::new(not_found_reply));
     }
-    return Ok(Box::new(not_found_reply));
+    let Ok(payment) = ticket_repository.lock().await.get_payment(id).await else {
+        return Ok(Box::new(not_found_reply));
+    };
+    Ok(Box::new(reply::json(&payment)))
 }
 
-async fn health_check_handler() -> WebResult<impl Reply> {
-    return Ok(warp::reply::with_status("Up and running", warp::http::StatusCode::OK))
+async fn health_check_handler(repository: Arc<Mutex<dyn TicketRepository>>) -> WebResult<impl Reply> {
+    let database = telemetry::check(async { repository.lock().await.ping().await }).await;
+    Ok(telemetry::Readiness::new(vec![("database", database)]).reply())
 }
 
 fn with_arc<T: Send + ?Sized>(arc: Arc<Mutex<T>>) -> impl Filter<Extract = (Arc<Mutex<T>>,), Error = Infallible> + Clone {
     warp::any().map(move || arc.clone())
 }
 
-pub fn router(repository: Arc<Mutex<dyn TicketRepository>>, checker: Arc<Mutex<jwtchecker::JWTChecker>>) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    let log = warp::log::custom(|info| {
-        eprintln!(
-            "{} {} {}",
-            info.method(),
-            info.path(),
-            info.status(),
-        );
-    });
+#[derive(Clone)]
+pub struct Refunds {
+    pub bonuses: String,
+    /// Shared by the services: flights sends it with its events, bonuses expects it with the refunds
+    pub secret: String,
+    pub requester: Box<dyn Requester>,
+    pub queue: Vec<TicketRefundEvent>,
+    /// Refunds bonuses rejected for good, kept for an operator instead of being retried
+    pub dead_letters: Vec<TicketRefundEvent>
+}
+
+pub fn router(repository: Arc<Mutex<dyn TicketRepository>>,
+              checker: Arc<Mutex<jwtchecker::JWTChecker>>,
+              refunds: Arc<Mutex<Refunds>>,
+              provider: Arc<Mutex<dyn PaymentProvider>>) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     let list_route = warp::path!("tickets")
+        .and(telemetry::route("/tickets"))
         .and(warp::get())
         .and(warp::header("Authorization"))
         .and(with_arc(repository.clone()))
         .and(with_arc(checker.clone()))
         .and_then(list_handler);
     let create_route = warp::path!("tickets")
+        .and(telemetry::route("/tickets"))
         .and(warp::post())
         .and(warp::body::json())
         .and(warp::header("Authorization"))
@@ -152,39 +391,179 @@ pub fn router(repository: Arc<Mutex<dyn TicketRepository>>, checker: Arc<Mutex<j
         .and(with_arc(checker.clone()))
         .and_then(post_handler);
     let get_route = warp::path!("tickets" / Uuid)
+        .and(telemetry::route("/tickets/{ticket_uid}"))
         .and(warp::get())
         .and(warp::header("Authorization"))
         .and(with_arc(repository.clone()))
         .and(with_arc(checker.clone()))
         .and_then(get_handler);
     let cancel_route = warp::path!("tickets" / Uuid / "cancel")
+        .and(telemetry::route("/tickets/{ticket_uid}/cancel"))
         .and(warp::delete())
         .and(warp::header("Authorization"))
         .and(with_arc(repository.clone()))
         .and(with_arc(checker.clone()))
+        .and(with_arc(provider.clone()))
         .and_then(cancel_handler);
+    let cancel_itinerary_route = warp::path!("itineraries" / Uuid / "cancel")
+        .and(telemetry::route("/itineraries/{booking_uid}/cancel"))
+        .and(warp::delete())
+        .and(warp::header("Authorization"))
+        .and(with_arc(repository.clone()))
+        .and(with_arc(checker.clone()))
+        .and(with_arc(provider.clone()))
+        .and_then(cancel_itinerary_handler);
     let delete_route = warp::path!("tickets" / Uuid)
+        .and(telemetry::route("/tickets/{ticket_uid}"))
         .and(warp::delete())
         .and(warp::header("Authorization"))
         .and(with_arc(repository.clone()))
         .and(with_arc(checker.clone()))
+        .and(with_arc(provider.clone()))
         .and_then(delete_handler);
+    let create_booking_route = warp::path!("bookings")
+        .and(telemetry::route("/bookings"))
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(warp::header("Authorization"))
+        .and(with_arc(repository.clone()))
+        .and(with_arc(checker.clone()))
+        .and_then(post_booking_handler);
+    let get_booking_route = warp::path!("bookings" / String)
+        .and(telemetry::route("/bookings/{pnr}"))
+        .and(warp::get())
+        .and(warp::header("Authorization"))
+        .and(with_arc(repository.clone()))
+        .and(with_arc(checker.clone()))
+        .and_then(get_booking_handler);
+    let cancel_booking_route = warp::path!("bookings" / String / "cancel")
+        .and(telemetry::route("/bookings/{pnr}/cancel"))
+        .and(warp::delete())
+        .and(warp::header("Authorization"))
+        .and(with_arc(repository.clone()))
+        .and(with_arc(checker.clone()))
+        .and(with_arc(provider.clone()))
+        .and_then(cancel_booking_handler);
+    let delete_booking_route = warp::path!("bookings" / String)
+        .and(telemetry::route("/bookings/{pnr}"))
+        .and(warp::delete())
+        .and(warp::header("Authorization"))
+        .and(with_arc(repository.clone()))
+        .and(with_arc(checker.clone()))
+        .and(with_arc(provider.clone()))
+        .and_then(delete_booking_handler);
+    let flight_event_route = warp::path!("events" / "flights")
+        .and(telemetry::route("/events/flights"))
+        .and(warp::post())
+        .and(warp::header::optional::<String>(SERVICE_TOKEN_HEADER))
+        .and(warp::body::json())
+        .and(with_arc(repository.clone()))
+        .and(with_arc(refunds.clone()))
+        .and(with_arc(provider.clone()))
+        .and_then(flight_event_handler);
+    let post_payment_route = warp::path!("tickets" / Uuid / "payment")
+        .and(telemetry::route("/tickets/{ticket_uid}/payment"))
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(warp::header("Authorization"))
+        .and(with_arc(repository.clone()))
+        .and(with_arc(checker.clone()))
+        .and(with_arc(provider.clone()))
+        .and_then(post_payment_handler);
+    let get_payment_route = warp::path!("tickets" / Uuid / "payment")
+        .and(telemetry::route("/tickets/{ticket_uid}/payment"))
+        .and(warp::get())
+        .and(warp::header("Authorization"))
+        .and(with_arc(repository.clone()))
+        .and(with_arc(checker.clone()))
+        .and_then(get_payment_handler);
     let health_route = warp::path!("manage" / "health")
+        .and(telemetry::route("/manage/health"))
+        .or(warp::path!("manage" / "health" / "ready").and(telemetry::route("/manage/health/ready")))
+        .unify()
         .and(warp::get())
+        .and(with_arc(repository.clone()))
         .and_then(health_check_handler);
     let routes = create_route
         .or(get_route)
         .or(list_route)
         .or(cancel_route)
         .or(delete_route)
+        .or(cancel_itinerary_route)
+        .or(create_booking_route)
+        .or(get_booking_route)
+        .or(cancel_booking_route)
+        .or(delete_booking_route)
+        .or(flight_event_route)
+        .or(post_payment_route)
+        .or(get_payment_route)
         .or(health_route)
-        .with(log);
+        .or(telemetry::live_route())
+        .or(telemetry::metrics_route());
+
+    tokio::task::spawn(async move {
+        loop {
+            {
+                let mut requester;
+                let bonuses_url;
+                let secret;
+                {
+                    let refunds_ptr = &refunds.lock().await;
+                    requester = refunds_ptr.requester.clone();
+                    bonuses_url = refunds_ptr.bonuses.clone();
+                    secret = refunds_ptr.secret.clone();
+                }
+                let refunds = &mut *refunds.lock().await;
+                let mut successful = true;
+                while !refunds.queue.is_empty() && successful {
+                    tracing::info!("sending queued refund");
+                    let refund = refunds.queue[0].clone();
+                    match requester.send(
+                        bonuses_url.clone(),
+                        RequestMethod::POST,
+                        HashMap::from([
+                            (SERVICE_TOKEN_HEADER.to_owned(), secret.clone())
+                        ]),
+                        serde_json::to_string(&refund).unwrap()).await {
+                        // A conflict means the points of the ticket were refunded before
+                        Ok(response) if response.code < 300 || response.code == 409 => {
+                            refunds.queue.remove(0);
+                        },
+                        Ok(response) if response.code < 500 => {
+                            tracing::error!(ticket_uid = %refund.ticket_uid, code = response.code, "bonus refund was rejected");
+                            let refund = refunds.queue.remove(0);
+                            refunds.dead_letters.push(refund);
+                        },
+                        _ => {
+                            successful = false;
+                        }
+                    }
+                }
+                telemetry::set_queue_depth("refunds", refunds.queue.len());
+                telemetry::set_queue_depth("refunds_dead_letters", refunds.dead_letters.len());
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        }
+    });
+
+    // Retries the money refunds that failed when their tickets were canceled or deleted
+    tokio::task::spawn(async move {
+        loop {
+            if let Err(e) = payments::refund_unsettled(repository.clone(), provider.clone()).await {
+                tracing::error!(error = %e, "failed to list unsettled payments");
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        }
+    });
+
     routes
 }
 
-pub async fn run_server(repository: Arc<Mutex<dyn TicketRepository>>, port: u16, checker: Arc<Mutex<JWTChecker>>) {
-    let router = router(repository, checker);
-    warp::serve(router)
-        .run(([0, 0, 0, 0], port))
-        .await
+pub async fn run_server(repository: Arc<Mutex<dyn TicketRepository>>,
+                        port: u16,
+                        checker: Arc<Mutex<JWTChecker>>,
+                        refunds: Arc<Mutex<Refunds>>,
+                        provider: Arc<Mutex<dyn PaymentProvider>>) -> std::io::Result<()> {
+    let router = router(repository, checker, refunds, provider);
+    telemetry::serve(router, port).await
 }
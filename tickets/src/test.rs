@@ -1,18 +1,25 @@
 use std::error::Error;
-use crate::{arc, repository::TicketRepository, server::router};
+use crate::{arc, memory::MemoryRepository, payments::{self, FakePaymentProvider, PaymentProvider}, PaymentError, TicketError, migrations::MIGRATIONS, repository::{TicketRepository, Repository}, server::{router, Refunds}};
 use async_trait::async_trait;
-use structs::{Ticket, TicketPost};
+use database::{testing::TestDatabase, Database};
+use chrono::{TimeZone, Utc};
+use jwtchecker::testing;
+use proptest::prelude::*;
+use requester::{Requester, Response};
+use structs::{Booking, BookingPost, BookingTicketPost, FlightStatusEvent, Money, Payment, PaymentPost, Ticket, TicketPost, TicketRefundEvent};
 use uuid::Uuid;
 
 
 struct MockRepository {
-    tickets: (Vec<Ticket>, usize)
+    tickets: (Vec<Ticket>, usize),
+    bookings: Vec<Booking>
 }
 
 impl MockRepository {
     pub fn new(tickets: Vec<Ticket>) -> Self {
         MockRepository {
-            tickets: (tickets, 0)
+            tickets: (tickets, 0),
+            bookings: vec![]
         }
     }
 }
@@ -23,6 +30,9 @@ impl TicketRepository for MockRepository {
     async fn init(&mut self) ->  Result<(), Box<dyn Error>> {
         Ok(())
     }
+    async fn ping(&mut self) ->  Result<(), Box<dyn Error>> {
+        Ok(())
+    }
     async fn list(&mut self) ->  Result<Vec<Ticket>, Box<dyn Error>> {
         Ok(self.tickets.0.clone())
     }
@@ -34,10 +44,110 @@ impl TicketRepository for MockRepository {
         Ok(uuid::uuid!("914619a4-ade7-43cb-b086-9e88ca35a728"))
     }
     async fn cancel(&mut self, uuid: Uuid) ->  Result<(), Box<dyn Error>> {
-        todo!()
+        self.set_status(uuid, "CANCELED".to_owned()).await
+    }
+    async fn set_status(&mut self, uuid: Uuid, status: String) ->  Result<(), Box<dyn Error>> {
+        for ticket in self.tickets.0.iter_mut() {
+            if ticket.ticket_uid == uuid {
+                ticket.status = status.clone();
+            }
+        }
+        Ok(())
+    }
+    async fn delete(&mut self, uuid: Uuid) ->  Result<(), Box<dyn Error>> {
+        self.tickets.0.retain(|x| x.ticket_uid != uuid);
+        Ok(())
+    }
+    async fn create_booking(&mut self, booking: BookingPost, username: String) ->  Result<String, Box<dyn Error>> {
+        let booking_uid = Uuid::new_v4();
+        let tickets = booking.tickets.into_iter().enumerate().map(|(id, x)| Ticket {
+            id: id as i32,
+            ticket_uid: Uuid::new_v4(),
+            username: username.clone(),
+            flight_number: x.flight_number,
+            price: x.price,
+            status: "PAID".to_owned(),
+            booking_uid: Some(booking_uid),
+            passenger_name: x.passenger_name,
+            document_number: x.document_number
+        }).collect();
+        self.bookings.push(Booking {
+            id: self.bookings.len() as i32,
+            booking_uid,
+            pnr: "ABC234".to_owned(),
+            username,
+            status: "CONFIRMED".to_owned(),
+            tickets
+        });
+        Ok("ABC234".to_owned())
+    }
+    async fn get_booking(&mut self, pnr: String) ->  Result<Booking, Box<dyn Error>> {
+        self.bookings.iter()
+            .find(|x| x.pnr == pnr)
+            .cloned()
+            .ok_or_else(|| TicketError::NotFoundError.into())
+    }
+    async fn cancel_booking(&mut self, booking_uid: Uuid) ->  Result<(), Box<dyn Error>> {
+        let Some(booking) = self.bookings.iter_mut().find(|x| x.booking_uid == booking_uid) else {
+            return Err(TicketError::NotFoundError.into());
+        };
+        if booking.status == "CANCELED" {
+            return Err(TicketError::AlreadyCanceledError.into());
+        }
+        booking.status = "CANCELED".to_owned();
+        for ticket in booking.tickets.iter_mut().filter(|x| x.status == "PAID") {
+            ticket.status = "CANCELED".to_owned();
+        }
+        Ok(())
+    }
+    async fn delete_booking(&mut self, pnr: String) ->  Result<(), Box<dyn Error>> {
+        self.bookings.retain(|x| x.pnr != pnr);
+        Ok(())
+    }
+    async fn get_payment(&mut self, ticket_uid: Uuid) ->  Result<Payment, Box<dyn Error>> {
+        Err(PaymentError::NotFoundError.into())
+    }
+    async fn save_payment(&mut self, payment: Payment) ->  Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+    async fn list_unsettled_payments(&mut self) ->  Result<Vec<Payment>, Box<dyn Error>> {
+        Ok(vec![])
+    }
+}
+
+// Answers every request with `code`. The default 503 never acknowledges refunds,
+// so they stay in the queue for inspection.
+#[derive(Clone)]
+struct MockRequester {
+    code: u16
+}
+
+#[async_trait]
+impl Requester for MockRequester {
+    #[allow(unused_variables)]
+    async fn send(&mut self,
+                  url:String,
+                  method:requester::RequestMethod,
+                  headers:std::collections::HashMap<String,String>,
+                  body:String) -> Result<Response, Box<dyn Error>> {
+        Ok(Response {
+            code: self.code,
+            body: "".to_owned(),
+            header: std::collections::HashMap::new()
+        })
     }
 }
 
+fn create_refunds() -> std::sync::Arc<tokio::sync::Mutex<Refunds>> {
+    arc!(Refunds {
+        bonuses: "".to_owned(),
+        secret: "secret".to_owned(),
+        requester: Box::new(MockRequester { code: 503 }),
+        queue: vec![],
+        dead_letters: vec![]
+    })
+}
+
 
 #[tokio::test]
 async fn list_tickets() {
@@ -48,19 +158,22 @@ async fn list_tickets() {
                     ticket_uid: uuid::uuid!("17ea0b3b-9efb-4be1-8db5-81512fe77c88"),
                     username: "someone".to_owned(),
                     flight_number: "AFL31".to_owned(),
-                    price: 50,
-                    status: "PAID".to_owned()
+                    price: Money::from_major(50, "RUB"),
+                    status: "PAID".to_owned(),
+                    booking_uid: None,
+                    passenger_name: None,
+                    document_number: None
                 }
             ]
             ));
-    let router = router(repository);
+    let router = router(repository, arc!(testing::checker()), create_refunds(), arc!(FakePaymentProvider::default()));
     let res = warp::test::request()
         .method("GET")
         .path("/tickets")
-        .header("X-User-Name", "someone")
+        .header("Authorization", testing::bearer("someone"))
         .reply(&router).await;
     assert_eq!(res.status(), 200);
-    assert_eq!(res.body(), "[{\"id\":0,\"ticket_uid\":\"17ea0b3b-9efb-4be1-8db5-81512fe77c88\",\"username\":\"someone\",\"flight_number\":\"AFL31\",\"price\":50,\"status\":\"PAID\"}]");
+    assert_eq!(res.body(), "[{\"id\":0,\"ticket_uid\":\"17ea0b3b-9efb-4be1-8db5-81512fe77c88\",\"username\":\"someone\",\"flight_number\":\"AFL31\",\"price\":{\"amount\":5000,\"currency\":\"RUB\"},\"status\":\"PAID\",\"booking_uid\":null,\"passenger_name\":null,\"document_number\":null}]");
 }
 
 #[tokio::test]
@@ -72,21 +185,768 @@ async fn post_tickets() {
                     ticket_uid: uuid::uuid!("17ea0b3b-9efb-4be1-8db5-81512fe77c88"),
                     username: "someone".to_owned(),
                     flight_number: "AFL31".to_owned(),
-                    price: 50,
-                    status: "PAID".to_owned()
+                    price: Money::from_major(50, "RUB"),
+                    status: "PAID".to_owned(),
+                    booking_uid: None,
+                    passenger_name: None,
+                    document_number: None
                 }
             ]
             ));
-    let router = router(repository);
+    let router = router(repository, arc!(testing::checker()), create_refunds(), arc!(FakePaymentProvider::default()));
     let res = warp::test::request()
         .method("POST")
         .path("/tickets")
-        .header("X-User-Name", "someone")
+        .header("Authorization", testing::bearer("someone"))
         .body(serde_json::to_string(&TicketPost{
             flight_number: "AFL31".to_owned(),
-            price: 50
+            price: Money::from_major(50, "RUB")
         }).unwrap())
         .reply(&router).await;
     assert_eq!(res.status(), 200);
-    assert_eq!(res.body(), "{\"id\":0,\"ticket_uid\":\"17ea0b3b-9efb-4be1-8db5-81512fe77c88\",\"username\":\"someone\",\"flight_number\":\"AFL31\",\"price\":50,\"status\":\"PAID\"}");
+    assert_eq!(res.body(), "{\"id\":0,\"ticket_uid\":\"17ea0b3b-9efb-4be1-8db5-81512fe77c88\",\"username\":\"someone\",\"flight_number\":\"AFL31\",\"price\":{\"amount\":5000,\"currency\":\"RUB\"},\"status\":\"PAID\",\"booking_uid\":null,\"passenger_name\":null,\"document_number\":null}");
+}
+
+#[tokio::test]
+async fn cancel_flight() {
+    let repository = arc!(MockRepository::new(
+            vec![
+                Ticket {
+                    id: 0,
+                    ticket_uid: uuid::uuid!("17ea0b3b-9efb-4be1-8db5-81512fe77c88"),
+                    username: "someone".to_owned(),
+                    flight_number: "AFL31".to_owned(),
+                    price: Money::from_major(50, "RUB"),
+                    status: "PAID".to_owned(),
+                    booking_uid: None,
+                    passenger_name: None,
+                    document_number: None
+                },
+                Ticket {
+                    id: 1,
+                    ticket_uid: uuid::uuid!("914619a4-ade7-43cb-b086-9e88ca35a728"),
+                    username: "someone".to_owned(),
+                    flight_number: "AFL31".to_owned(),
+                    price: Money::from_major(50, "RUB"),
+                    status: "CANCELED".to_owned(),
+                    booking_uid: None,
+                    passenger_name: None,
+                    document_number: None
+                },
+                Ticket {
+                    id: 2,
+                    ticket_uid: uuid::uuid!("3b9d1e0c-5c7f-4a1f-8f6e-2d5a3c1b9e47"),
+                    username: "someone".to_owned(),
+                    flight_number: "SU100".to_owned(),
+                    price: Money::from_major(50, "RUB"),
+                    status: "PAID".to_owned(),
+                    booking_uid: None,
+                    passenger_name: None,
+                    document_number: None
+                }
+            ]
+            ));
+    let refunds = create_refunds();
+    let router = router(repository.clone(), arc!(testing::checker()), refunds.clone(), arc!(FakePaymentProvider::default()));
+    let res = warp::test::request()
+        .method("POST")
+        .path("/events/flights")
+        .header("X-Service-Token", "secret")
+        .json(&FlightStatusEvent {
+            flight_number: "AFL31".to_owned(),
+            status: "CANCELED".to_owned(),
+            datetime: Utc.timestamp_opt(1589717600, 0).unwrap()
+        })
+        .reply(&router).await;
+    assert_eq!(res.status(), 204);
+    let statuses: Vec<String> = repository.lock().await.tickets.0.iter().map(|x| x.status.clone()).collect();
+    assert_eq!(statuses, vec!["FLIGHT_CANCELED", "CANCELED", "PAID"]);
+    let queue = refunds.lock().await.queue.clone();
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue[0].ticket_uid, uuid::uuid!("17ea0b3b-9efb-4be1-8db5-81512fe77c88"));
+}
+
+#[tokio::test]
+async fn rejected_refunds_are_dead_lettered() {
+    // (answer of bonuses, refunds left queued, refunds dead-lettered)
+    for (code, queued, dead_letters) in [(204, 0, 0), (409, 0, 0), (404, 0, 1), (422, 0, 1), (503, 1, 0)] {
+        let refunds = arc!(Refunds {
+            bonuses: "".to_owned(),
+            secret: "secret".to_owned(),
+            requester: Box::new(MockRequester { code }),
+            queue: vec![TicketRefundEvent { ticket_uid: Uuid::new_v4() }],
+            dead_letters: vec![]
+        });
+        let _router = router(arc!(MockRepository::new(vec![])), arc!(testing::checker()), refunds.clone(), arc!(FakePaymentProvider::default()));
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let refunds = refunds.lock().await;
+        assert_eq!((refunds.queue.len(), refunds.dead_letters.len()), (queued, dead_letters), "answered {}", code);
+    }
+}
+
+#[tokio::test]
+async fn flight_event_from_unknown_caller() {
+    let repository = arc!(MockRepository::new(vec![
+                Ticket {
+                    id: 1,
+                    ticket_uid: uuid::uuid!("17ea0b3b-9efb-4be1-8db5-81512fe77c88"),
+                    username: "someone".to_owned(),
+                    flight_number: "AFL31".to_owned(),
+                    price: Money::from_major(50, "RUB"),
+                    status: "PAID".to_owned(),
+                    booking_uid: None,
+                    passenger_name: None,
+                    document_number: None
+                }
+            ]));
+    let refunds = create_refunds();
+    let router = router(repository.clone(), arc!(testing::checker()), refunds.clone(), arc!(FakePaymentProvider::default()));
+    let event = FlightStatusEvent {
+        flight_number: "AFL31".to_owned(),
+        status: "CANCELED".to_owned(),
+        datetime: Utc.timestamp_opt(1589717600, 0).unwrap()
+    };
+    for token in [None, Some("guess")] {
+        let mut request = warp::test::request()
+            .method("POST")
+            .path("/events/flights")
+            .json(&event);
+        if let Some(token) = token {
+            request = request.header("X-Service-Token", token);
+        }
+        let res = request.reply(&router).await;
+        assert_eq!(res.status(), 401);
+    }
+    assert_eq!(repository.lock().await.tickets.0[0].status, "PAID");
+    assert!(refunds.lock().await.queue.is_empty());
+}
+
+#[tokio::test]
+async fn booking_visible_only_to_owner() {
+    let repository = arc!(MockRepository::new(vec![]));
+    let router = router(repository, arc!(testing::checker()), create_refunds(), arc!(FakePaymentProvider::default()));
+    let res = warp::test::request()
+        .method("POST")
+        .path("/bookings")
+        .header("Authorization", testing::bearer("someone"))
+        .json(&BookingPost {
+            tickets: vec![
+                BookingTicketPost {
+                    flight_number: "AFL31".to_owned(),
+                    price: Money::from_major(1500, "RUB"),
+                    passenger_name: Some("Ivan Ivanov".to_owned()),
+                    document_number: Some("4510 123456".to_owned())
+                },
+                BookingTicketPost {
+                    flight_number: "AFL32".to_owned(),
+                    price: Money::from_major(1500, "RUB"),
+                    passenger_name: Some("Ivan Ivanov".to_owned()),
+                    document_number: Some("4510 123456".to_owned())
+                }
+            ]
+        })
+        .reply(&router).await;
+    assert_eq!(res.status(), 200);
+    let booking: Booking = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(booking.pnr, "ABC234");
+    assert_eq!(booking.tickets.len(), 2);
+    assert!(booking.tickets.iter().all(|x| x.booking_uid == Some(booking.booking_uid)));
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/bookings/ABC234")
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 200);
+    let res = warp::test::request()
+        .method("GET")
+        .path("/bookings/ABC234")
+        .header("Authorization", testing::bearer("someone else"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 404);
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres at TEST_DATABASE_URL"]
+async fn prices_keep_minor_units() {
+    let test_database = TestDatabase::create().await.unwrap();
+    // A ticket sold while prices were whole major units
+    let mut database = Database::connect(&test_database.connection_str).await.unwrap();
+    database.migrate(&MIGRATIONS[..2]).await.unwrap();
+    let legacy_uid = Uuid::new_v4();
+    database.client().await.unwrap().execute("
+        INSERT INTO ticket(ticket_uid, username, flight_number, price, status) VALUES ($1, 'someone', 'AFL031', 1500, 'PAID')
+    ", &[&legacy_uid]).await.unwrap();
+    let mut repository = Repository::new(&test_database.connection_str).await.unwrap();
+    repository.init().await.unwrap();
+    assert_eq!(repository.get(legacy_uid).await.unwrap().price, Money::new(150_000, "RUB"));
+    let ticket_uid = repository.create(TicketPost {
+        flight_number: "AFL031".to_owned(),
+        price: Money::new(150_050, "RUB")
+    }, "someone".to_owned()).await.unwrap();
+    assert_eq!(repository.get(ticket_uid).await.unwrap().price, Money::new(150_050, "RUB"));
+    drop(database);
+    drop(repository);
+}
+
+#[tokio::test]
+async fn cancel_booking_in_memory() {
+    let router = router(arc!(MemoryRepository::default()), arc!(testing::checker()), create_refunds(), arc!(FakePaymentProvider::default()));
+    let passenger = |name: &str| BookingTicketPost {
+        flight_number: "AFL31".to_owned(),
+        price: Money::from_major(1500, "RUB"),
+        passenger_name: Some(name.to_owned()),
+        document_number: Some("4510 123456".to_owned())
+    };
+    let res = warp::test::request()
+        .method("POST")
+        .path("/bookings")
+        .header("Authorization", testing::bearer("someone"))
+        .json(&BookingPost { tickets: vec![passenger("Ivan Ivanov"), passenger("Maria Ivanova")] })
+        .reply(&router).await;
+    assert_eq!(res.status(), 200);
+    let booking: Booking = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(booking.tickets.len(), 2);
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/bookings/{}/cancel", booking.pnr))
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 204);
+    let res = warp::test::request()
+        .method("GET")
+        .path(&format!("/bookings/{}", booking.pnr))
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    let booking: Booking = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(booking.status, "CANCELED");
+    assert!(booking.tickets.iter().all(|x| x.status == "CANCELED"));
+}
+
+#[tokio::test]
+async fn cancel_itinerary_in_memory() {
+    let router = router(arc!(MemoryRepository::default()), arc!(testing::checker()), create_refunds(), arc!(FakePaymentProvider::default()));
+    let leg = |flight_number: &str| BookingTicketPost {
+        flight_number: flight_number.to_owned(),
+        price: Money::from_major(1500, "RUB"),
+        passenger_name: None,
+        document_number: None
+    };
+    let res = warp::test::request()
+        .method("POST")
+        .path("/bookings")
+        .header("Authorization", testing::bearer("someone"))
+        .json(&BookingPost { tickets: vec![leg("AFL31"), leg("AFL32")] })
+        .reply(&router).await;
+    assert_eq!(res.status(), 200);
+    let booking: Booking = serde_json::from_slice(res.body()).unwrap();
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/itineraries/{}/cancel", booking.booking_uid))
+        .header("Authorization", testing::bearer("someone-else"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 404);
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/itineraries/{}/cancel", booking.booking_uid))
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 204);
+    // The itinerary is the booking, so canceling one cancels the other
+    let res = warp::test::request()
+        .method("GET")
+        .path(&format!("/bookings/{}", booking.pnr))
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    let booking: Booking = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(booking.status, "CANCELED");
+    assert!(booking.tickets.iter().all(|x| x.status == "CANCELED"));
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/bookings/{}/cancel", booking.pnr))
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 409);
+}
+
+#[tokio::test]
+async fn booking_rejects_invalid_passengers() {
+    let router = router(arc!(MockRepository::new(vec![])), arc!(testing::checker()), create_refunds(), arc!(FakePaymentProvider::default()));
+    let passenger = |name: Option<&str>, document_number: Option<&str>| BookingTicketPost {
+        flight_number: "AFL31".to_owned(),
+        price: Money::from_major(1500, "RUB"),
+        passenger_name: name.map(str::to_owned),
+        document_number: document_number.map(str::to_owned)
+    };
+    let invalid = [
+        passenger(Some(""), Some("4510 123456")),
+        passenger(Some("   "), Some("4510 123456")),
+        passenger(Some("Robert'); DROP TABLE ticket;--"), Some("4510 123456")),
+        passenger(Some("Ivan Ivanov"), Some("")),
+        passenger(Some("Ivan Ivanov"), Some("12")),
+        passenger(Some("Ivan Ivanov"), Some("no digits here")),
+        passenger(Some("Ivan Ivanov"), Some("4510/123456")),
+        passenger(Some("Ivan Ivanov"), None),
+        passenger(None, Some("4510 123456"))
+    ];
+    for ticket in invalid {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/bookings")
+            .header("Authorization", testing::bearer("someone"))
+            .json(&BookingPost { tickets: vec![ticket.clone()] })
+            .reply(&router).await;
+        assert_eq!(res.status(), 400, "{:?}", ticket);
+    }
+    let res = warp::test::request()
+        .method("POST")
+        .path("/bookings")
+        .header("Authorization", testing::bearer("someone"))
+        .json(&BookingPost { tickets: vec![passenger(Some("Anne-Marie O'Neil"), Some("AB-1234567"))] })
+        .reply(&router).await;
+    assert_eq!(res.status(), 200);
+}
+
+#[tokio::test]
+async fn flight_cancellation_reaches_booking() {
+    let router = router(arc!(MemoryRepository::default()), arc!(testing::checker()), create_refunds(), arc!(FakePaymentProvider::default()));
+    let leg = |flight_number: &str| BookingTicketPost {
+        flight_number: flight_number.to_owned(),
+        price: Money::from_major(1500, "RUB"),
+        passenger_name: Some("Ivan Ivanov".to_owned()),
+        document_number: Some("4510 123456".to_owned())
+    };
+    let res = warp::test::request()
+        .method("POST")
+        .path("/bookings")
+        .header("Authorization", testing::bearer("someone"))
+        .json(&BookingPost { tickets: vec![leg("AFL31"), leg("AFL32")] })
+        .reply(&router).await;
+    let booking: Booking = serde_json::from_slice(res.body()).unwrap();
+    let res = warp::test::request()
+        .method("POST")
+        .path("/events/flights")
+        .header("X-Service-Token", "secret")
+        .json(&FlightStatusEvent {
+            flight_number: "AFL31".to_owned(),
+            status: "CANCELED".to_owned(),
+            datetime: Utc.timestamp_opt(1589717600, 0).unwrap()
+        })
+        .reply(&router).await;
+    assert_eq!(res.status(), 204);
+    let res = warp::test::request()
+        .method("GET")
+        .path(&format!("/bookings/{}", booking.pnr))
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    let booking: Booking = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(booking.status, "FLIGHT_CANCELED");
+    let statuses: Vec<&str> = booking.tickets.iter().map(|x| x.status.as_str()).collect();
+    assert_eq!(statuses, ["FLIGHT_CANCELED", "PAID"]);
+    // The rest of the booking can still be canceled
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/bookings/{}/cancel", booking.pnr))
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 204);
+    let res = warp::test::request()
+        .method("GET")
+        .path(&format!("/bookings/{}", booking.pnr))
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    let booking: Booking = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(booking.status, "CANCELED");
+    let statuses: Vec<&str> = booking.tickets.iter().map(|x| x.status.as_str()).collect();
+    assert_eq!(statuses, ["FLIGHT_CANCELED", "CANCELED"]);
+}
+
+/// Can not be reached, so every payment operation fails
+struct UnreachableProvider {}
+
+#[allow(unused_variables)]
+#[async_trait]
+impl PaymentProvider for UnreachableProvider {
+    async fn authorize(&mut self, ticket_uid: Uuid, amount: Money) -> Result<(), Box<dyn Error>> {
+        Err("Payment provider is unreachable".into())
+    }
+    async fn capture(&mut self, ticket_uid: Uuid, amount: &Money) -> Result<(), Box<dyn Error>> {
+        Err("Payment provider is unreachable".into())
+    }
+    async fn refund(&mut self, ticket_uid: Uuid, amount: &Money) -> Result<(), Box<dyn Error>> {
+        Err("Payment provider is unreachable".into())
+    }
+}
+
+/// Books the flights for someone and charges every ticket its price
+async fn paid_booking<F>(router: &F, flight_numbers: &[&str]) -> Booking
+    where F: warp::Filter + 'static, F::Extract: warp::Reply + Send {
+    let res = warp::test::request()
+        .method("POST")
+        .path("/bookings")
+        .header("Authorization", testing::bearer("someone"))
+        .json(&BookingPost {
+            tickets: flight_numbers.iter().map(|x| BookingTicketPost {
+                flight_number: x.to_string(),
+                price: Money::from_major(1500, "RUB"),
+                passenger_name: None,
+                document_number: None
+            }).collect()
+        })
+        .reply(router).await;
+    assert_eq!(res.status(), 200);
+    let booking: Booking = serde_json::from_slice(res.body()).unwrap();
+    for ticket in &booking.tickets {
+        let res = charge(router, ticket.ticket_uid, ticket.price.clone()).await;
+        assert_eq!(res.status(), 200);
+    }
+    booking
+}
+
+async fn charge<F>(router: &F, ticket_uid: Uuid, amount: Money) -> warp::http::Response<warp::hyper::body::Bytes>
+    where F: warp::Filter + 'static, F::Extract: warp::Reply + Send {
+    warp::test::request()
+        .method("POST")
+        .path(&format!("/tickets/{}/payment", ticket_uid))
+        .header("Authorization", testing::bearer("someone"))
+        .json(&PaymentPost { amount })
+        .reply(router).await
+}
+
+async fn payment_status(repository: &std::sync::Arc<tokio::sync::Mutex<MemoryRepository>>, ticket_uid: Uuid) -> String {
+    repository.lock().await.get_payment(ticket_uid).await.unwrap().status
+}
+
+#[tokio::test]
+async fn fake_payment_lifecycle() {
+    let mut payments = FakePaymentProvider {
+        decline_above: Some(100_000),
+        ..Default::default()
+    };
+    let ticket_uid = uuid::uuid!("17ea0b3b-9efb-4be1-8db5-81512fe77c88");
+    let amount = Money::from_major(750, "RUB");
+    assert!(payments.capture(ticket_uid, &amount).await.is_err());
+    payments.authorize(ticket_uid, amount.clone()).await.unwrap();
+    assert!(payments.capture(ticket_uid, &Money::from_major(700, "RUB")).await.is_err());
+    payments.capture(ticket_uid, &amount).await.unwrap();
+    assert_eq!(payments.payments[&ticket_uid].status, "CAPTURED");
+    payments.refund(ticket_uid, &amount).await.unwrap();
+    assert_eq!(payments.payments[&ticket_uid].status, "REFUNDED");
+    assert!(payments.capture(ticket_uid, &amount).await.is_err());
+
+    let declined = uuid::uuid!("27ea0b3b-9efb-4be1-8db5-81512fe77c88");
+    assert!(payments.authorize(declined, Money::from_major(1500, "RUB")).await.is_err());
+    assert!(!payments.payments.contains_key(&declined));
+}
+
+#[tokio::test]
+async fn charge_ticket() {
+    let repository = arc!(MemoryRepository::default());
+    let provider = arc!(FakePaymentProvider { decline_above: Some(200_000), ..Default::default() });
+    let router = router(repository.clone(), arc!(testing::checker()), create_refunds(), provider.clone());
+    let booking = paid_booking(&router, &["AFL31"]).await;
+    let ticket_uid = booking.tickets[0].ticket_uid;
+    assert_eq!(payment_status(&repository, ticket_uid).await, "CAPTURED");
+    assert_eq!(provider.lock().await.payments[&ticket_uid].status, "CAPTURED");
+    // Retrying the same charge is safe, charging another amount is not
+    assert_eq!(charge(&router, ticket_uid, Money::from_major(1500, "RUB")).await.status(), 200);
+    assert_eq!(charge(&router, ticket_uid, Money::from_major(1000, "RUB")).await.status(), 409);
+    let res = warp::test::request()
+        .method("GET")
+        .path(&format!("/tickets/{}/payment", ticket_uid))
+        .header("Authorization", testing::bearer("someone else"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 404);
+    let res = warp::test::request()
+        .method("GET")
+        .path(&format!("/tickets/{}/payment", ticket_uid))
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    let payment: Payment = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(payment.amount, Money::from_major(1500, "RUB"));
+
+    let ticket_uid = repository.lock().await.create(TicketPost {
+        flight_number: "AFL31".to_owned(),
+        price: Money::from_major(2500, "RUB")
+    }, "someone".to_owned()).await.unwrap();
+    let res = charge(&router, ticket_uid, Money::from_major(2500, "RUB")).await;
+    assert_eq!(res.status(), 402);
+    assert_eq!(payment_status(&repository, ticket_uid).await, "DECLINED");
+    repository.lock().await.cancel(ticket_uid).await.unwrap();
+    assert_eq!(charge(&router, ticket_uid, Money::from_major(100, "RUB")).await.status(), 409);
+}
+
+#[tokio::test]
+async fn cancellations_refund_money() {
+    let repository = arc!(MemoryRepository::default());
+    let provider = arc!(FakePaymentProvider::default());
+    let router = router(repository.clone(), arc!(testing::checker()), create_refunds(), provider.clone());
+
+    let ticket_uid = paid_booking(&router, &["AFL31"]).await.tickets[0].ticket_uid;
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/tickets/{}/cancel", ticket_uid))
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 204);
+    assert_eq!(payment_status(&repository, ticket_uid).await, "REFUNDED");
+
+    // A purchase that failed is rolled back by deleting its tickets
+    let booking = paid_booking(&router, &["AFL31", "AFL32"]).await;
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/bookings/{}", booking.pnr))
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 204);
+    for ticket in &booking.tickets {
+        assert_eq!(payment_status(&repository, ticket.ticket_uid).await, "REFUNDED");
+        assert_eq!(provider.lock().await.payments[&ticket.ticket_uid].status, "REFUNDED");
+    }
+}
+
+#[tokio::test]
+async fn flight_cancellation_refunds_money() {
+    let repository = arc!(MemoryRepository::default());
+    let router = router(repository.clone(), arc!(testing::checker()), create_refunds(), arc!(FakePaymentProvider::default()));
+    let booking = paid_booking(&router, &["AFL31", "AFL32"]).await;
+    let res = warp::test::request()
+        .method("POST")
+        .path("/events/flights")
+        .header("X-Service-Token", "secret")
+        .json(&FlightStatusEvent {
+            flight_number: "AFL31".to_owned(),
+            status: "CANCELED".to_owned(),
+            datetime: Utc.timestamp_opt(1589717600, 0).unwrap()
+        })
+        .reply(&router).await;
+    assert_eq!(res.status(), 204);
+    assert_eq!(payment_status(&repository, booking.tickets[0].ticket_uid).await, "REFUNDED");
+    assert_eq!(payment_status(&repository, booking.tickets[1].ticket_uid).await, "CAPTURED");
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/bookings/{}/cancel", booking.pnr))
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&router).await;
+    assert_eq!(res.status(), 204);
+    assert_eq!(payment_status(&repository, booking.tickets[1].ticket_uid).await, "REFUNDED");
+}
+
+#[tokio::test]
+async fn refunds_survive_restart() {
+    let repository = arc!(MemoryRepository::default());
+    let before = router(repository.clone(), arc!(testing::checker()), create_refunds(), arc!(FakePaymentProvider::default()));
+    let ticket_uid = paid_booking(&before, &["AFL31"]).await.tickets[0].ticket_uid;
+
+    // The restarted service has a provider that knows nothing of the payment
+    let provider = arc!(FakePaymentProvider::default());
+    let restarted = router(repository.clone(), arc!(testing::checker()), create_refunds(), provider.clone());
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/tickets/{}/cancel", ticket_uid))
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&restarted).await;
+    assert_eq!(res.status(), 204);
+    assert_eq!(payment_status(&repository, ticket_uid).await, "REFUNDED");
+    let refunded = provider.lock().await.payments[&ticket_uid].clone();
+    assert_eq!((refunded.amount, refunded.status.as_str()), (Money::from_major(1500, "RUB"), "REFUNDED"));
+}
+
+#[tokio::test]
+async fn failed_refunds_are_retried() {
+    let repository = arc!(MemoryRepository::default());
+    let before = router(repository.clone(), arc!(testing::checker()), create_refunds(), arc!(FakePaymentProvider::default()));
+    let booking = paid_booking(&before, &["AFL31", "AFL32"]).await;
+
+    let unreachable = router(repository.clone(), arc!(testing::checker()), create_refunds(), arc!(UnreachableProvider {}));
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/tickets/{}/cancel", booking.tickets[0].ticket_uid))
+        .header("Authorization", testing::bearer("someone"))
+        .reply(&unreachable).await;
+    assert_eq!(res.status(), 204);
+    assert_eq!(payment_status(&repository, booking.tickets[0].ticket_uid).await, "CAPTURED");
+
+    // Only the canceled ticket is refunded, the other one is still paid
+    let provider = arc!(FakePaymentProvider::default());
+    assert_eq!(payments::refund_unsettled(repository.clone(), provider.clone()).await.unwrap(), 1);
+    assert_eq!(payment_status(&repository, booking.tickets[0].ticket_uid).await, "REFUNDED");
+    assert_eq!(payment_status(&repository, booking.tickets[1].ticket_uid).await, "CAPTURED");
+    assert_eq!(payments::refund_unsettled(repository.clone(), provider).await.unwrap(), 0);
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres at TEST_DATABASE_URL"]
+async fn payments_in_postgres() {
+    let test_database = TestDatabase::create().await.unwrap();
+    let mut repository = Repository::new(&test_database.connection_str).await.unwrap();
+    repository.init().await.unwrap();
+    let mut ticket_uids = vec![];
+    for _ in 0..3 {
+        let ticket_uid = repository.create(TicketPost {
+            flight_number: "AFL031".to_owned(),
+            price: Money::new(150_050, "RUB")
+        }, "someone".to_owned()).await.unwrap();
+        repository.save_payment(Payment {
+            ticket_uid,
+            amount: Money::new(150_050, "RUB"),
+            status: "AUTHORIZED".to_owned()
+        }).await.unwrap();
+        ticket_uids.push(ticket_uid);
+    }
+    let mut captured = repository.get_payment(ticket_uids[0]).await.unwrap();
+    captured.status = "CAPTURED".to_owned();
+    repository.save_payment(captured.clone()).await.unwrap();
+    assert_eq!(repository.get_payment(ticket_uids[0]).await.unwrap(), captured);
+    assert!(repository.list_unsettled_payments().await.unwrap().is_empty());
+
+    // Payments of canceled and of deleted tickets still hold money
+    repository.cancel(ticket_uids[0]).await.unwrap();
+    repository.delete(ticket_uids[1]).await.unwrap();
+    let unsettled: Vec<Uuid> = repository.list_unsettled_payments().await.unwrap().iter().map(|x| x.ticket_uid).collect();
+    assert_eq!(unsettled, ticket_uids[..2]);
+    let e = repository.get_payment(Uuid::new_v4()).await.unwrap_err();
+    assert!(matches!(e.downcast_ref::<PaymentError>(), Some(PaymentError::NotFoundError)));
+    drop(repository);
+}
+
+#[tokio::test]
+async fn ids_are_not_reused() {
+    let mut repository = MemoryRepository::default();
+    let ticket = TicketPost { flight_number: "AFL031".to_owned(), price: Money::new(150_000, "RUB") };
+    repository.create(ticket.clone(), "test-max".to_owned()).await.unwrap();
+    let last = repository.create(ticket.clone(), "test-max".to_owned()).await.unwrap();
+    repository.delete(last).await.unwrap();
+    let created = repository.create(ticket, "test-max".to_owned()).await.unwrap();
+    assert_eq!(repository.get(created).await.unwrap().id, 3);
+}
+
+#[derive(Debug, Clone)]
+enum Operation {
+    Buy { user: usize, flight: usize, price: i64 },
+    Book { user: usize, flights: Vec<usize> },
+    SetStatus { ticket: usize, status: usize },
+    Delete { ticket: usize },
+    CancelBooking { booking: usize },
+    DeleteBooking { booking: usize },
+    Pay { ticket: usize, status: usize }
+}
+
+const USERS: [&str; 2] = ["test-max", "test-min"];
+const FLIGHTS: [&str; 2] = ["AFL031", "AFL032"];
+const TICKET_STATUSES: [&str; 4] = ["PAID", "CANCELED", "FLIGHT_CANCELED", "LOST"];
+const PAYMENT_STATUSES: [&str; 5] = ["AUTHORIZED", "CAPTURED", "DECLINED", "VOIDED", "REFUNDED"];
+
+fn operation() -> impl Strategy<Value = Operation> {
+    prop_oneof![
+        (0..2usize, 0..2usize, 1..1_000_000i64).prop_map(|(user, flight, price)| Operation::Buy { user, flight, price }),
+        (0..2usize, proptest::collection::vec(0..2usize, 1..3)).prop_map(|(user, flights)| Operation::Book { user, flights }),
+        (0..6usize, 0..4usize).prop_map(|(ticket, status)| Operation::SetStatus { ticket, status }),
+        (0..6usize).prop_map(|ticket| Operation::Delete { ticket }),
+        (0..3usize).prop_map(|booking| Operation::CancelBooking { booking }),
+        (0..3usize).prop_map(|booking| Operation::DeleteBooking { booking }),
+        (0..6usize, 0..5usize).prop_map(|(ticket, status)| Operation::Pay { ticket, status })
+    ]
+}
+
+type TicketOutcome = (i32, usize, String, String, Money, String, Option<usize>);
+
+/// What the operations left behind. Uids and PNRs are random, so tickets and bookings are
+/// referred to by the order they were created in.
+type Outcome = (Vec<bool>, Vec<TicketOutcome>, Vec<Option<(i32, String, Vec<usize>)>>, Vec<usize>);
+
+async fn run_operations(repository: &mut dyn TicketRepository, operations: &[Operation]) -> Outcome {
+    let mut ticket_uids: Vec<Uuid> = vec![];
+    let mut bookings: Vec<(String, Uuid)> = vec![];
+    let mut results = vec![];
+    for operation in operations {
+        let result = match operation {
+            Operation::Buy { user, flight, price } => {
+                let ticket = TicketPost { flight_number: FLIGHTS[*flight].to_owned(), price: Money::new(*price, "RUB") };
+                let ticket_uid = repository.create(ticket, USERS[*user].to_owned()).await.unwrap();
+                ticket_uids.push(ticket_uid);
+                true
+            },
+            Operation::Book { user, flights } => {
+                let tickets = flights.iter().map(|x| BookingTicketPost {
+                    flight_number: FLIGHTS[*x].to_owned(),
+                    price: Money::new(150_000, "RUB"),
+                    passenger_name: None,
+                    document_number: None
+                }).collect();
+                let pnr = repository.create_booking(BookingPost { tickets }, USERS[*user].to_owned()).await.unwrap();
+                let booking = repository.get_booking(pnr.clone()).await.unwrap();
+                let mut tickets = booking.tickets;
+                tickets.sort_by_key(|x| x.id);
+                ticket_uids.extend(tickets.iter().map(|x| x.ticket_uid));
+                bookings.push((pnr, booking.booking_uid));
+                true
+            },
+            Operation::SetStatus { ticket, status } => match ticket_uids.get(*ticket) {
+                Some(uid) => repository.set_status(*uid, TICKET_STATUSES[*status].to_owned()).await.is_ok(),
+                None => false
+            },
+            Operation::Delete { ticket } => match ticket_uids.get(*ticket) {
+                Some(uid) => repository.delete(*uid).await.is_ok(),
+                None => false
+            },
+            Operation::CancelBooking { booking } => match bookings.get(*booking) {
+                Some((_, booking_uid)) => repository.cancel_booking(*booking_uid).await.is_ok(),
+                None => false
+            },
+            Operation::DeleteBooking { booking } => match bookings.get(*booking) {
+                Some((pnr, _)) => repository.delete_booking(pnr.clone()).await.is_ok(),
+                None => false
+            },
+            Operation::Pay { ticket, status } => match ticket_uids.get(*ticket) {
+                Some(uid) => repository.save_payment(Payment {
+                    ticket_uid: *uid,
+                    amount: Money::new(1_000, "RUB"),
+                    status: PAYMENT_STATUSES[*status].to_owned()
+                }).await.is_ok(),
+                None => false
+            }
+        };
+        results.push(result);
+    }
+    let position = |uid: Uuid| ticket_uids.iter().position(|x| *x == uid).unwrap();
+    let mut tickets = repository.list().await.unwrap();
+    tickets.sort_by_key(|x| x.id);
+    let tickets = tickets.into_iter()
+        .map(|x| (x.id, position(x.ticket_uid), x.username, x.flight_number, x.price, x.status,
+                  x.booking_uid.map(|uid| bookings.iter().position(|y| y.1 == uid).unwrap())))
+        .collect();
+    let mut stored_bookings = vec![];
+    for (pnr, _) in &bookings {
+        stored_bookings.push(repository.get_booking(pnr.clone()).await.ok().map(|x| {
+            let mut tickets: Vec<usize> = x.tickets.iter().map(|y| position(y.ticket_uid)).collect();
+            tickets.sort();
+            (x.id, x.status, tickets)
+        }));
+    }
+    let mut unsettled: Vec<usize> = repository.list_unsettled_payments().await.unwrap().iter().map(|x| position(x.ticket_uid)).collect();
+    unsettled.sort();
+    (results, tickets, stored_bookings, unsettled)
+}
+
+#[test]
+#[ignore = "needs Postgres at TEST_DATABASE_URL"]
+fn memory_repository_matches_postgres() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let test_database = runtime.block_on(TestDatabase::create()).unwrap();
+    let postgres = std::cell::RefCell::new(runtime.block_on(async {
+        let mut repository = Repository::new(&test_database.connection_str).await.unwrap();
+        repository.init().await.unwrap();
+        (repository, Database::connect(&test_database.connection_str).await.unwrap())
+    }));
+    proptest!(ProptestConfig::with_cases(32), |(operations in proptest::collection::vec(operation(), 1..20))| {
+        let expected = runtime.block_on(run_operations(&mut MemoryRepository::default(), &operations));
+        let actual = runtime.block_on(async {
+            let (repository, database) = &mut *postgres.borrow_mut();
+            // Ids start over like they do in a new `MemoryRepository`
+            database.client().await.unwrap().batch_execute("TRUNCATE ticket, booking, payment RESTART IDENTITY").await.unwrap();
+            run_operations(repository, &operations).await
+        });
+        prop_assert_eq!(actual, expected);
+    });
 }